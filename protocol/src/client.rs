@@ -8,18 +8,26 @@
 //! server.
 
 use failure::{Error, ResultExt};
-use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
 use futures::sink::Send;
-use libc;
+use futures::stream;
+use futures::sync::oneshot;
+use futures::task;
+use rand::{self, Rng};
 use state_machine_future::RentToOwn;
+use std::cell::RefCell;
 use std::env;
-use std::io;
-use std::mem;
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::ptr;
+use std::rc::Rc;
+use std::sync::atomic;
 use std::thread;
-use std::time;
-use std::os::unix::io::AsRawFd;
-use tokio_core::reactor::Core;
+use std::time::{self, Duration};
+use tokio_core::reactor::{Core, Handle};
 use tokio_io::AsyncRead;
 use tokio_io::codec::length_delimited::{FramedRead, FramedWrite};
 use tokio_io::io::{ReadHalf, WriteHalf};
@@ -27,62 +35,553 @@ use tokio_serde_bincode::{ReadBincode, WriteBincode};
 use tokio_uds::UnixStream;
 
 use super::*;
+use clock::{SharedClock, SystemClock, Timer};
 
 
 type Ser = WriteBincode<FramedWrite<WriteHalf<UnixStream>>, ClientMessage>;
 type De = ReadBincode<FramedRead<ReadHalf<UnixStream>>, ServerMessage>;
-type UserInputStream = Box<Stream<Item = Vec<u8>, Error = io::Error>>;
-type UserOutputSink = Box<Sink<SinkItem = Vec<u8>, SinkError = io::Error>>;
+
+/// A source of user input for an open.
+pub type UserInputStream = Box<Stream<Item = Vec<u8>, Error = io::Error>>;
+
+/// A source of the user's terminal sizes, as rows and columns, for an open.
+pub type WindowSizeStream = Box<Stream<Item = (u16, u16), Error = io::Error>>;
+
+/// A destination for the output of an open.
+pub type UserOutputSink = Box<Sink<SinkItem = Vec<u8>, SinkError = io::Error>>;
+
+type OutputObserver = Box<FnMut(&[u8])>;
+
+
+/// Optional extras for an open that most callers don't need.
+#[derive(Default)]
+struct OpenHooks {
+    /// Called with each chunk of SSH output as it arrives.
+    observer: Option<OutputObserver>,
+
+    /// Fires if the caller wants to give up on the login.
+    cancel: Option<oneshot::Receiver<()>>,
+
+    /// Called with each chunk of SSH's separate standard error, if the open
+    /// asked for one. Without this, it goes to the user with everything
+    /// else.
+    stderr: Option<OutputObserver>,
+
+    /// The sizes to give SSH's terminal as the user's changes.
+    window_sizes: Option<WindowSizeStream>,
+
+    /// Answers SSH's password prompt on the user's behalf.
+    password: Option<PasswordResponder>,
+
+    /// Holds back SSH's output until it asks for something.
+    banner: Option<BannerFilter>,
+
+    /// The byte that interrupts whatever SSH is doing, which jumps ahead
+    /// of anything the user typed that hasn't been sent yet.
+    interrupt: Option<u8>,
+
+    /// Keeps the user out of the login until SSH asks for something.
+    deferral: Option<Deferral>,
+}
+
+
+/// The longest line of SSH output that we'll look at for a password prompt.
+const MAX_PROMPT_LEN: usize = 256;
+
+/// Answers SSH's first password prompt with a password supplied up front.
+/// The password is zeroed once it has been handed over, or when this is
+/// dropped.
+struct PasswordResponder {
+    password: Vec<u8>,
+    line: Vec<u8>,
+    due: bool,
+    answered: bool,
+}
+
+impl PasswordResponder {
+    fn new(password: String) -> Self {
+        PasswordResponder {
+            password: password.into_bytes(),
+            line: Vec::new(),
+            due: false,
+            answered: false,
+        }
+    }
+
+    /// Look at a chunk of SSH's output. Prompts don't end in a newline, so
+    /// we keep the last line around in case one is split across chunks.
+    fn notice(&mut self, data: &[u8]) {
+        if self.answered {
+            return;
+        }
+
+        match data.iter().rposition(|b| *b == b'\n' || *b == b'\r') {
+            Some(i) => {
+                self.line.clear();
+                self.line.extend_from_slice(&data[i + 1..]);
+            },
+            None => self.line.extend_from_slice(data),
+        }
+
+        if self.line.len() > MAX_PROMPT_LEN {
+            let excess = self.line.len() - MAX_PROMPT_LEN;
+            self.line.drain(..excess);
+        }
+
+        self.due = is_password_prompt(&self.line);
+    }
+
+    /// If SSH is waiting for the password, move it, with a newline, onto
+    /// the end of `buf`. This only ever happens once.
+    fn answer_into(&mut self, buf: &mut Vec<u8>) {
+        if !self.due || self.answered {
+            return;
+        }
+
+        buf.extend_from_slice(&self.password);
+        buf.push(b'\n');
+        zero_bytes(&mut self.password);
+        self.due = false;
+        self.answered = true;
+    }
+}
+
+impl Drop for PasswordResponder {
+    fn drop(&mut self) {
+        zero_bytes(&mut self.password);
+    }
+}
+
+/// Whether a line of SSH's output is asking for a password. This covers
+/// both `user@host's password:` and the keyboard-interactive `Password:`
+/// prompts, but not key passphrases or host key confirmations.
+fn is_password_prompt(line: &[u8]) -> bool {
+    String::from_utf8_lossy(line).trim_end().to_lowercase().ends_with("password:")
+}
+
+/// Whether a line of SSH's output is asking the user for something: a
+/// password, a key passphrase, or whether to trust a host key.
+fn is_prompt(line: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(line).trim_end().to_lowercase();
+
+    is_password_prompt(line) ||
+        (text.contains("passphrase") && text.ends_with(':')) ||
+        (text.contains("(yes/no") && text.ends_with('?'))
+}
+
+
+/// How long SSH has to go quiet, while its output is being held back,
+/// before we decide that it's waiting for the user.
+const BANNER_QUIET_PERIOD: Duration = Duration::from_secs(1);
+
+/// The most output that we'll hold back. Past this, the oldest is dropped.
+const MAX_BANNER_LEN: usize = 64 * 1024;
+
+/// Holds back what SSH prints early in a login, such as a server's banner
+/// or message of the day, until SSH asks the user for something.
+#[derive(Default)]
+struct BannerFilter {
+    held: Vec<u8>,
+    quiet_timer: Option<Timer>,
+}
+
+impl BannerFilter {
+    fn hold(&mut self, data: &[u8]) {
+        self.held.extend_from_slice(data);
+
+        if self.held.len() > MAX_BANNER_LEN {
+            let excess = self.held.len() - MAX_BANNER_LEN;
+            self.held.drain(..excess);
+        }
+    }
+
+    /// The unfinished line at the end of the output, which is where SSH
+    /// leaves any prompt.
+    fn last_line(&self) -> &[u8] {
+        match self.held.iter().rposition(|b| *b == b'\n' || *b == b'\r') {
+            Some(i) => &self.held[i + 1..],
+            None => &self.held,
+        }
+    }
+}
+
+/// Pass a chunk of SSH's output along to the user, unless it's being held
+/// back, in which case this returns true. Once SSH asks for something, the
+/// line that it's waiting on is passed along, the rest of what was held back
+/// is dropped, and output flows as usual from then on.
+fn relay_ssh_output(hooks: &mut OpenHooks, user_buf: &mut Vec<u8>, data: &[u8]) -> bool {
+    let at_prompt = match hooks.banner {
+        Some(ref mut banner) => {
+            banner.hold(data);
+            is_prompt(banner.last_line())
+        },
+
+        None => {
+            user_buf.extend_from_slice(data);
+            return false;
+        },
+    };
+
+    if at_prompt {
+        let banner = hooks.banner.take().unwrap();
+        user_buf.extend_from_slice(banner.last_line());
+    }
+
+    true
+}
+
+/// Stop holding back SSH's output if it has gone quiet, in case it's
+/// asking for something in a way that we don't recognize. As with a prompt,
+/// only the line that it's waiting on is passed along.
+fn poll_banner(
+    hooks: &mut OpenHooks, timers: &TimerSource, user_buf: &mut Vec<u8>, saw_output: bool
+) -> Result<(), Error> {
+    let quiet = match hooks.banner {
+        Some(ref mut banner) if !banner.held.is_empty() => {
+            if saw_output || banner.quiet_timer.is_none() {
+                banner.quiet_timer = Some(timers.timer(BANNER_QUIET_PERIOD)?);
+            }
+
+            banner.quiet_timer.as_mut().unwrap().poll()?.is_ready()
+        },
+
+        _ => false,
+    };
+
+    if quiet {
+        let banner = hooks.banner.take().unwrap();
+        user_buf.extend_from_slice(banner.last_line());
+    }
+
+    Ok(())
+}
+
+
+/// Keeps an open from involving the user until SSH asks for something, or
+/// until the grace period is up. It works alongside a `BannerFilter`, which
+/// holds back SSH's output and spots the prompt.
+struct Deferral {
+    grace: Duration,
+    timer: Option<Timer>,
+    on_interactive: Box<FnMut()>,
+}
+
+/// Bring the user into a deferred open if SSH has asked for something, in
+/// which case the banner filter has already given way, or if the grace
+/// period is up, in which case it's made to give way as if SSH had gone
+/// quiet. The grace period starts the first time this is called.
+fn poll_deferral(
+    hooks: &mut OpenHooks, timers: &TimerSource, user_buf: &mut Vec<u8>
+) -> Result<(), Error> {
+    let expired = match hooks.deferral {
+        None => return Ok(()),
+        Some(_) if hooks.banner.is_none() => false,
+
+        Some(ref mut deferral) => {
+            if deferral.timer.is_none() {
+                deferral.timer = Some(timers.timer(deferral.grace)?);
+            }
+
+            match deferral.timer.as_mut().unwrap().poll()? {
+                Async::Ready(()) => true,
+                Async::NotReady => return Ok(()),
+            }
+        },
+    };
+
+    if expired {
+        let banner = hooks.banner.take().unwrap();
+        user_buf.extend_from_slice(banner.last_line());
+    }
+
+    let mut deferral = hooks.deferral.take().unwrap();
+    (deferral.on_interactive)();
+    Ok(())
+}
+
+
+/// Overwrite a buffer that held a secret, in a way that the compiler won't
+/// optimize out.
+fn zero_bytes(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        unsafe { ptr::write_volatile(b, 0) };
+    }
+
+    atomic::compiler_fence(atomic::Ordering::SeqCst);
+}
+
+
+/// The means of setting timers during an open.
+struct TimerSource {
+    handle: Handle,
+    clock: SharedClock,
+}
+
+impl TimerSource {
+    fn new(core: &Core, clock: &SharedClock) -> Self {
+        TimerSource {
+            handle: core.handle(),
+            clock: clock.clone(),
+        }
+    }
+
+    fn timer(&self, duration: Duration) -> io::Result<Timer> {
+        self.clock.timer(duration, &self.handle)
+    }
+}
+
+
+/// How much SSH output or user input an open will buffer before it stops
+/// reading more in that direction until some has been sent on, so that a
+/// flood one way can't crowd out the other or pile up without limit.
+const MAX_RELAY_BUF: usize = 64 * 1024;
+
+/// The byte that opens treat as an interrupt by default: Ctrl-C.
+pub const DEFAULT_INTERRUPT_BYTE: u8 = 0x03;
+
+/// How many times `Connection::establish` tries to start the daemon.
+pub const DEFAULT_SPAWN_ATTEMPTS: u32 = 3;
+
+/// How much of each delay between attempts to start the daemon is left to
+/// chance by default; see `Connection::establish_with_backoff`.
+pub const DEFAULT_SPAWN_JITTER: f64 = 0.5;
+
+/// Shorten a delay by a random part of `jitter` times its length, so that
+/// clients that start at the same moment don't stay in lockstep. `jitter`
+/// is clamped to lie between zero and one, and `r` should be drawn evenly
+/// from the same range.
+fn jittered_delay(delay: Duration, jitter: f64, r: f64) -> Duration {
+    let jitter = if jitter.is_nan() { 0. } else { jitter.clamp(0., 1.) };
+    delay.mul_f64(1. - jitter * r)
+}
 
 
 /// A connection the stund daemon.
+///
+/// Simple requests like `query_status` borrow the connection, so a single
+/// connection can be used for as many of them as you like, and one of them
+/// failing doesn't end the session. Opening a tunnel consumes the connection
+/// and hands it back afterwards, because the relay between the user and SSH
+/// needs to own the socket while it runs; if the open fails, the daemon hangs
+/// up and there's nothing to hand back. Call `close` when you're done.
 pub struct Connection {
     core: Core,
     ser: Ser,
     de: De,
+    sock_path: PathBuf,
+    clock: SharedClock,
+    window_sizes: Option<WindowSizeStream>,
+    hide_banners: bool,
+    interrupt_byte: Option<u8>,
+    deferral: Option<Deferral>,
+}
+
+
+/// The outcome of each of the opens requested by `Connection::open_many`.
+pub type OpenManyResults = Vec<(OpenParameters, Result<OpenResult, Error>)>;
+
+
+/// How long `Connection::shutdown_daemon` gives the daemon to acknowledge
+/// the exit request, and then to go away.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+/// How often `Connection::run_forever` checks up on its tunnel.
+const FOREGROUND_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+
+/// How a call to `Connection::run_forever` ended.
+#[derive(Debug, PartialEq)]
+pub enum ForegroundOutcome {
+    /// The open didn't leave a tunnel to watch, for the reason given.
+    NotOpened(OpenResult),
+
+    /// The caller asked us to stop, and the tunnel was closed.
+    Interrupted,
+
+    /// The caller asked us to stop, but the tunnel was already open before
+    /// we were called, so it was left running.
+    Detached,
+
+    /// The tunnel went away by itself, or someone else closed it. If SSH
+    /// exited with a status code, it is included.
+    Ended(TunnelState, Option<i32>),
+}
+
+
+/// Information useful for figuring out which daemon a client is talking to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostics {
+    /// The path of the socket over which the client is connected.
+    pub socket_path: PathBuf,
+
+    /// The version of the protocol crate that the client was built with.
+    pub protocol_version: String,
+
+    /// The version of the protocol spoken by the daemon, or `None` if the
+    /// daemon is too old to say.
+    pub daemon_protocol_version: Option<String>,
+
+    /// The version of the daemon, as reported by the daemon itself, or
+    /// `None` if it is too old to say.
+    pub daemon_version: Option<String>,
+}
+
+impl Diagnostics {
+    /// Whether the client and the daemon speak compatible versions of the
+    /// protocol. A daemon too old to report its version is assumed not to.
+    pub fn is_compatible(&self) -> bool {
+        match self.daemon_protocol_version {
+            Some(ref v) => protocols_compatible(&self.protocol_version, v),
+            None => false,
+        }
+    }
+}
+
+
+/// A tunnel opened by `Connection::open_tunnel`, kept together with the
+/// connection that opened it so that the two don't have to be juggled
+/// separately.
+///
+/// The handle owns the connection, since opening a tunnel consumes it; use
+/// `connection` to make other requests over it, or `into_connection` to get
+/// it back. The tunnel itself belongs to the daemon, not to the handle, so
+/// dropping the handle just hangs up and leaves the tunnel running. Only
+/// `close` shuts it down.
+pub struct TunnelHandle {
+    conn: Connection,
+    key: String,
+    result: OpenResult,
+}
+
+impl TunnelHandle {
+    /// The label under which the daemon filed the tunnel.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// How the open went: whether the tunnel was newly opened, replaced an
+    /// existing one, or was already open.
+    pub fn open_result(&self) -> &OpenResult {
+        &self.result
+    }
+
+    /// The connection over which the tunnel was opened.
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+
+    /// Give up the handle, keeping the connection.
+    pub fn into_connection(self) -> Connection {
+        self.conn
+    }
+
+    /// Ask the daemon for the details of the tunnel.
+    pub fn status(&mut self) -> Result<TunnelDetails, Error> {
+        self.conn.tunnel_status(&self.key)
+    }
+
+    /// Check whether the tunnel is actually working. Returns `None` if it's
+    /// no longer open.
+    pub fn check(&mut self) -> Result<Option<TunnelHealth>, Error> {
+        self.conn.check_tunnel(CheckTunnelParameters { host: self.key.clone() })
+    }
+
+    /// Close the tunnel, handing back the connection.
+    pub fn close(mut self) -> Result<(CloseResult, Connection), Error> {
+        let result = self.conn.send_close(CloseParameters { host: self.key, grace_secs: None })?;
+        Ok((result, self.conn))
+    }
 }
 
 impl Connection {
-    fn establish_inner(autolaunch: bool) -> Result<Option<Self>, Error> {
+    /// Connect to the daemon, at `sock_path` if given, or else at the default
+    /// path. If it isn't running and `spawn_attempts` is nonzero, try
+    /// launching it up to that many times, calling `on_retry` after each
+    /// failed attempt but the last.
+    fn establish_inner<F>(
+        sock_path: Option<&Path>, spawn_attempts: u32, jitter: f64, mut on_retry: F
+    ) -> Result<Option<Self>, Error>
+        where F: FnMut(u32, &Error)
+    {
         let core = Core::new().context("couldn't create IO core?")?;
         let handle = core.handle();
-        let sock_path = get_socket_path().context("couldn't get path to talk to daemon")?;
+
+        // A daemon that we launch has to be told about a path other than the
+        // default one.
+
+        let (sock_path, daemon_args) = match sock_path {
+            Some(p) => {
+                check_socket_path(p)?;
+                (p.to_owned(), vec![OsString::from("--socket"), p.as_os_str().to_owned()])
+            },
+
+            None => (get_socket_path().context("couldn't get path to talk to daemon")?, Vec::new()),
+        };
 
         let conn = match UnixStream::connect(&sock_path, &handle) {
             Ok(c) => c,
-            Err(_e) => {
-                if !autolaunch { // should we care about what the error is exatly?
+            Err(e) => {
+                // Only a missing socket, or one that nobody is listening
+                // on, means that the daemon isn't running; anything else
+                // would stop us talking to one that we launched, too.
+
+                match e.kind() {
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => {},
+                    _ => return Err(format_err!("couldn't connect to the daemon at {}: {}",
+                                                sock_path.display(), e)),
+                }
+
+                if spawn_attempts == 0 {
                     return Ok(None);
                 }
 
                 let curr_exe = env::current_exe().context("couldn't get current executable path")?;
+                let mut attempt = 1;
 
-                let status = process::Command::new(&curr_exe)
-                    .arg("daemon")
-                    .status()
-                    .context("daemon launcher reported failure")?;
+                loop {
+                    // Launching the daemon can fail transiently on a busy
+                    // machine, so we give it a few tries, backing off a bit
+                    // more each time. A busy machine may well have several
+                    // clients trying at once, so the delays are jittered.
 
-                thread::sleep(time::Duration::from_millis(300));
+                    let result = process::Command::new(&curr_exe)
+                        .arg("daemon")
+                        .args(&daemon_args)
+                        .status()
+                        .map_err(|e| format_err!("daemon launcher reported failure: {}", e))
+                        .and_then(|status| {
+                            if !status.success() {
+                                return Err(format_err!("failed to launch background daemon"));
+                            }
 
-                if status.success() {
-                    UnixStream::connect(&sock_path, &handle)
-                        .context("failed to connect to daemon even after launching it")?
-                } else {
-                    return Err(format_err!("failed to launch background daemon"));
+                            thread::sleep(time::Duration::from_millis(300));
+
+                            UnixStream::connect(&sock_path, &handle).map_err(|e| {
+                                format_err!("failed to connect to daemon even after launching it: {}", e)
+                            })
+                        });
+
+                    match result {
+                        Ok(c) => break c,
+
+                        Err(e) => {
+                            if attempt >= spawn_attempts {
+                                return Err(format_err!("{} (made {} attempt(s) to launch it)",
+                                                       e, attempt));
+                            }
+
+                            on_retry(attempt, &e);
+                            let delay = time::Duration::from_millis(500 * u64::from(attempt));
+                            let r = rand::thread_rng().gen::<f64>();
+                            thread::sleep(jittered_delay(delay, jitter, r));
+                            attempt += 1;
+                        },
+                    }
                 }
             },
         };
 
-        unsafe {
-            // Without turning on linger, I find that the tokio-ized version
-            // loses the last bytes of the session. Let's just ignore the
-            // return value of setsockopt(), though.
-            let linger = libc::linger { l_onoff: 1, l_linger: 2 };
-            libc::setsockopt(conn.as_raw_fd(), libc::SOL_SOCKET, libc::SO_LINGER,
-                             (&linger as *const libc::linger) as _,
-                             mem::size_of::<libc::linger>() as libc::socklen_t);
-        }
-
         let (read, write) = conn.split();
         let wdelim = FramedWrite::new(write);
         let ser = WriteBincode::new(wdelim);
@@ -93,6 +592,12 @@ impl Connection {
             core: core,
             ser: ser,
             de: de,
+            sock_path,
+            clock: SystemClock::shared(),
+            window_sizes: None,
+            hide_banners: false,
+            interrupt_byte: Some(DEFAULT_INTERRUPT_BYTE),
+            deferral: None,
         }))
     }
 
@@ -100,294 +605,2541 @@ impl Connection {
     ///
     /// If the daemon is not running, returns `Ok(None)`.
     pub fn try_establish() -> Result<Option<Self>, Error> {
-        Self::establish_inner(false)
+        Self::establish_inner(None, 0, 0., |_, _| {})
     }
 
     /// Connect to the daemon, starting it if it is not already running.
+    ///
+    /// If the daemon can't be started, a few more attempts are made before
+    /// giving up.
     pub fn establish() -> Result<Self, Error> {
-        Ok(Self::establish_inner(true)?.unwrap())
+        Self::establish_with_spawn_attempts(DEFAULT_SPAWN_ATTEMPTS, |_, _| {})
+    }
+
+    /// Connect to the daemon, starting it if it is not already running.
+    ///
+    /// This is like `establish`, but lets the caller decide how many times
+    /// to try starting the daemon. Zero is treated like one. If an attempt
+    /// fails and another is to be made, `on_retry` is called with the number
+    /// of the failed attempt and its error, so that the caller can let the
+    /// user know what's going on.
+    pub fn establish_with_spawn_attempts<F>(spawn_attempts: u32, on_retry: F) -> Result<Self, Error>
+        where F: FnMut(u32, &Error)
+    {
+        Self::establish_with_backoff(spawn_attempts, DEFAULT_SPAWN_JITTER, on_retry)
+    }
+
+    /// Connect to the daemon, starting it if it is not already running.
+    ///
+    /// This is like `establish_with_spawn_attempts`, but also lets the
+    /// caller decide how much the delays between attempts are randomized.
+    /// With a `jitter` of zero, the delays are fixed; with a `jitter` of
+    /// one, each delay is anywhere between nothing and its full length.
+    /// Values outside that range are clamped to it.
+    pub fn establish_with_backoff<F>(
+        spawn_attempts: u32, jitter: f64, on_retry: F
+    ) -> Result<Self, Error>
+        where F: FnMut(u32, &Error)
+    {
+        Ok(Self::establish_inner(None, spawn_attempts.max(1), jitter, on_retry)?.unwrap())
+    }
+
+    /// Connect to a daemon listening at `sock_path` rather than at the
+    /// default path.
+    ///
+    /// If `autolaunch` is true and no daemon is running there, one is
+    /// started, told to use the same path, just as `establish` would.
+    /// Otherwise, if there's no daemon to talk to, the error is a
+    /// `DaemonNotRunning` naming the socket.
+    pub fn establish_at<P: AsRef<Path>>(sock_path: P, autolaunch: bool) -> Result<Self, Error> {
+        let sock_path = sock_path.as_ref();
+        let spawn_attempts = if autolaunch { DEFAULT_SPAWN_ATTEMPTS } else { 0 };
+
+        match Self::establish_inner(Some(sock_path), spawn_attempts, DEFAULT_SPAWN_JITTER, |_, _| {})? {
+            Some(conn) => Ok(conn),
+            None => Err(DaemonNotRunning { socket: sock_path.to_owned() }.into()),
+        }
+    }
+
+    /// Get the path of the socket used to talk to the daemon.
+    pub fn socket_path(&self) -> &Path {
+        &self.sock_path
+    }
+
+    /// Gather information about this connection and the daemon on the other
+    /// end of it.
+    ///
+    /// This conducts I/O because the daemon must be asked its version. It
+    /// should be called before anything else is done with the connection,
+    /// since a daemon that speaks an incompatible version of the protocol
+    /// may misunderstand other requests. If the daemon predates version
+    /// queries, it hangs up, so the connection can't be used afterwards;
+    /// the version fields of the result are `None` in that case.
+    pub fn diagnostics(&mut self) -> Result<Diagnostics, Error> {
+        let versions = match self.transact(ClientMessage::QueryVersion, "version query") {
+            Ok(ServerMessage::VersionResponse(v)) => Some(v),
+            Ok(other) => return Err(format_err!("unexpected server reply: {}", other.summary())),
+            Err(_) => None,
+        };
+
+        let (daemon_protocol_version, daemon_version) = match versions {
+            Some(v) => (Some(v.protocol_version), Some(v.daemon_version)),
+            None => (None, None),
+        };
+
+        Ok(Diagnostics {
+            socket_path: self.sock_path.clone(),
+            protocol_version: PROTOCOL_VERSION.to_owned(),
+            daemon_protocol_version,
+            daemon_version,
+        })
     }
 
     /// Close the connection to the daemon.
     ///
     /// This operation conducts I/O because it sends a "Goodbye" message.
     pub fn close(mut self) -> Result<(), Error> {
-        self.core.run(self.ser.send(ClientMessage::Goodbye))?;
+        let _ser = self.core.run(self.ser.send(ClientMessage::Goodbye))?;
+
+        // The Goodbye has been flushed to the socket, but if we exit right
+        // away the daemon may never see it. The daemon hangs up as soon as it
+        // has processed the message, so wait for that to happen -- though not
+        // forever.
+
+        let hangup = self.de.for_each(|_| Ok(()))
+            .map_err(|e| format_err!("error waiting for daemon to hang up: {}", e));
+        let timeout = self.clock.timer(Duration::from_secs(2), &self.core.handle())?
+            .map_err(|e| format_err!("error waiting for daemon to hang up: {}", e));
+
+        let _r = self.core.run(hangup.select(timeout));
         Ok(())
     }
 
+    /// Have the next open keep SSH's terminal the same size as the user's.
+    ///
+    /// Each size that `sizes` yields, as rows and columns, is passed along
+    /// to the daemon while the login is relayed. It should start with the
+    /// terminal's current size and then follow its changes. Sizes that
+    /// arrive before the daemon has launched SSH are held back, and only the
+    /// latest is sent once it has. This applies to whichever of `send_open`
+    /// and friends is called next, including `run_forever`.
+    pub fn relay_window_sizes<S>(&mut self, sizes: S)
+        where S: 'static + Stream<Item = (u16, u16), Error = io::Error>
+    {
+        self.window_sizes = Some(Box::new(sizes));
+    }
+
+    /// Have opens hide what SSH prints before it asks the user for
+    /// anything, such as a server's banner or message of the day.
+    ///
+    /// SSH's output is held back until it looks like a password,
+    /// passphrase, or host key prompt, or until SSH has been quiet for a
+    /// second; then only the line that SSH is waiting on is shown. If the
+    /// login succeeds without SSH asking for anything, the user sees
+    /// nothing. If it fails, everything held back is shown, since it may
+    /// explain why. This is off by default, since it could hide something
+    /// that the user needs to see.
+    pub fn set_hide_banners(&mut self, hide: bool) {
+        self.hide_banners = hide;
+    }
+
+    /// Set the byte that opens treat as an interrupt, or `None` to treat
+    /// every byte the user types alike. The default is Ctrl-C.
+    ///
+    /// When the user types the interrupt byte, anything they typed before
+    /// it that is still waiting to be sent is dropped, and the interrupt is
+    /// sent straight away, so that it isn't held up behind a backlog on a
+    /// busy connection. That is what a terminal does with its own
+    /// interrupt character, so nothing is lost that SSH's terminal wouldn't
+    /// have discarded anyway.
+    pub fn set_interrupt_byte(&mut self, byte: Option<u8>) {
+        self.interrupt_byte = byte;
+    }
+
+    /// Have the next open start out without the user, and only bring them
+    /// in if SSH asks for something.
+    ///
+    /// SSH's output is held back, and nothing is read from the user, until
+    /// SSH prints something that looks like a password, passphrase, or host
+    /// key prompt. If the login succeeds before then, the user sees nothing
+    /// and `on_interactive` is never called; if it fails, everything held
+    /// back is shown, since it may explain why. Otherwise `on_interactive`
+    /// is called, so that the caller can ready the user's terminal, and the
+    /// open carries on as usual, starting with the line that SSH is waiting
+    /// on. The same happens if the login is still going after `grace`, in
+    /// case SSH is asking for something in a way that we don't recognize.
+    /// This applies to whichever of `send_open` and friends is called next.
+    pub fn defer_interaction<F>(&mut self, grace: Duration, on_interactive: F)
+        where F: 'static + FnOnce()
+    {
+        let mut on_interactive = Some(on_interactive);
+
+        self.deferral = Some(Deferral {
+            grace,
+            timer: None,
+            on_interactive: Box::new(move || {
+                if let Some(f) = on_interactive.take() {
+                    f();
+                }
+            }),
+        });
+    }
+
     /// Tell the daemon to open a new SSH connection.
     ///
     /// Because the user may have to type a password or respond to some other
     /// prompt from the server to authenticate themselves, callers of this
     /// function must provide asynchronous I/O types implementing this user
     /// interaction.
+    ///
+    /// If `idle_timeout` is not `None`, the open is aborted with an error if
+    /// neither the user nor SSH produce any data for that long once the
+    /// interactive part of the login has begun. This catches the case where
+    /// SSH is wedged waiting for something that the user cannot see. A
+    /// timeout of zero is treated the same as `None`.
     pub fn send_open<T, R>(
-        mut self, params: OpenParameters, tx_user: T, rx_user: R
+        self, params: OpenParameters, tx_user: T, rx_user: R, idle_timeout: Option<Duration>
     ) -> Result<(OpenResult, Self), Error>
         where T: 'static + Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
               R: 'static + Stream<Item = Vec<u8>, Error = io::Error>
     {
-        let fut = self.ser.send(ClientMessage::Open(params));
-        let wf = OpenWorkflow::start(fut, self.de, Box::new(tx_user), Box::new(rx_user));
-        let (ser, de, result) = self.core.run(wf)?;
-        self.ser = ser;
-        self.de = de;
-        Ok((result, self))
+        self.send_open_inner(ClientMessage::Open(params), tx_user, rx_user, idle_timeout,
+                             OpenHooks::default())
     }
 
-    /// Query the server’s status.
+    /// Tell the daemon to open a tunnel using one of the profiles that it
+    /// has been configured with, rather than parameters of our own.
     ///
-    /// At the moment, the only information that is returned is a list of
-    /// connections that have been opened and their current state.
-    pub fn query_status(mut self) -> Result<(StatusInformation, Self), Error> {
-        let (ser, de) = (self.ser, self.de);
-
-        let fut = ser.send(ClientMessage::QueryStatus)
-            .map_err(|e| format_err!("error sending query-status message to daemon: {}", e))
-            .and_then(move |ser| {
-                de.into_future()
-                    .map_err(|(e, _de)| format_err!("error receiving daemon reply: {}", e))
-                    .map(|(maybe_msg, de)| (maybe_msg, ser, de))
-            }).and_then(|(maybe_msg, ser, de)| {
-                match maybe_msg {
-                    Some(ServerMessage::StatusResponse(info)) => Ok((info, ser, de)),
-                    Some(ServerMessage::Error(msg)) => return Err(format_err!("{}", msg)),
-                    Some(other) => return Err(format_err!("unexpected server reply: {:?}", other)),
-                    None => return Err(format_err!("unexpected disconnection from server")),
-                }
-            });
+    /// The login is relayed just as with `send_open`. If the daemon has no
+    /// such profile, an error is returned.
+    pub fn send_open_profile<T, R>(
+        self, name: &str, tx_user: T, rx_user: R, idle_timeout: Option<Duration>
+    ) -> Result<(OpenResult, Self), Error>
+        where T: 'static + Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
+              R: 'static + Stream<Item = Vec<u8>, Error = io::Error>
+    {
+        let msg = ClientMessage::OpenProfile { name: name.to_owned() };
+        self.send_open_inner(msg, tx_user, rx_user, idle_timeout, OpenHooks::default())
+    }
 
-        let (info, ser, de) = self.core.run(fut)?;
-        self.ser = ser;
-        self.de = de;
-        Ok((info, self))
+    /// Tell the daemon to open a new SSH connection, with a way to give up
+    /// partway through.
+    ///
+    /// This is like `send_open`, but if a message arrives on `cancel` while
+    /// the user is logging in, the daemon is told to kill the half-opened
+    /// tunnel and the result is `OpenResult::Cancelled`. If the login
+    /// finishes before the daemon gets the message, the open succeeds as
+    /// usual. Dropping the sender without sending anything is harmless.
+    pub fn send_open_cancellable<T, R>(
+        self, params: OpenParameters, tx_user: T, rx_user: R, idle_timeout: Option<Duration>,
+        cancel: oneshot::Receiver<()>
+    ) -> Result<(OpenResult, Self), Error>
+        where T: 'static + Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
+              R: 'static + Stream<Item = Vec<u8>, Error = io::Error>
+    {
+        let hooks = OpenHooks { cancel: Some(cancel), ..OpenHooks::default() };
+        self.send_open_inner(ClientMessage::Open(params), tx_user, rx_user, idle_timeout, hooks)
     }
 
-    /// Tell the server to close an existing tunnel.
-    pub fn send_close(mut self, params: CloseParameters) -> Result<(CloseResult, Self), Error> {
-        let (ser, de) = (self.ser, self.de);
+    /// Tell the daemon to open a new SSH connection, answering SSH's
+    /// password prompt with `password`.
+    ///
+    /// **This is discouraged.** It exists for automation that has no way to
+    /// use keys or an SSH agent; anyone else should use those instead. Think
+    /// about the risks before using it:
+    ///
+    /// - Whatever supplies the password -- a file, an environment variable,
+    ///   a command line -- is a place from which it can leak.
+    /// - The password is typed in response to anything that looks like a
+    ///   password prompt, so a malicious or misconfigured server, or a
+    ///   proxy command, can phish it.
+    /// - The password is zeroed once it has been typed, but copies pass
+    ///   through buffers in this library, the kernel, and the daemon, and
+    ///   may linger in memory for a while.
+    ///
+    /// Otherwise, this is like `send_open`. The password is only typed at
+    /// the first prompt; if SSH asks again, presumably because it was
+    /// wrong, the prompt is passed along to the user like any other. Set
+    /// an `idle_timeout` if nobody will be there to answer it. Neither the
+    /// client nor the daemon logs the password, although SSH's terminal
+    /// output is relayed and may be written to a tee file, as usual.
+    pub fn send_open_with_password<T, R>(
+        self, params: OpenParameters, password: String, tx_user: T, rx_user: R,
+        idle_timeout: Option<Duration>
+    ) -> Result<(OpenResult, Self), Error>
+        where T: 'static + Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
+              R: 'static + Stream<Item = Vec<u8>, Error = io::Error>
+    {
+        let hooks = OpenHooks { password: Some(PasswordResponder::new(password)), ..OpenHooks::default() };
+        self.send_open_inner(ClientMessage::Open(params), tx_user, rx_user, idle_timeout, hooks)
+    }
 
-        let fut = ser.send(ClientMessage::Close(params))
-            .map_err(|e| format_err!("error sending close message to daemon: {}", e))
-            .and_then(move |ser| {
-                de.into_future()
-                    .map_err(|(e, _de)| format_err!("error receiving daemon reply: {}", e))
-                    .map(|(maybe_msg, de)| (maybe_msg, ser, de))
-            }).and_then(|(maybe_msg, ser, de)| {
-                match maybe_msg {
-                    Some(ServerMessage::Ok) => Ok((CloseResult::Success, ser, de)),
-                    Some(ServerMessage::TunnelNotOpen) => Ok((CloseResult::NotOpen, ser, de)),
-                    Some(ServerMessage::Error(msg)) => return Err(format_err!("{}", msg)),
-                    Some(other) => return Err(format_err!("unexpected server reply: {:?}", other)),
-                    None => return Err(format_err!("unexpected disconnection from server")),
-                }
-            });
+    /// Tell the daemon to open a new SSH connection, letting the caller
+    /// watch SSH's output as it arrives.
+    ///
+    /// This is like `send_open`, but `observer` is called with each chunk
+    /// of output that the daemon relays from SSH, in the order that the
+    /// chunks arrive. The output is still written to `tx_user` as usual.
+    /// Each chunk is passed to `observer` before it is written to
+    /// `tx_user`, but there is no guarantee that the previous chunk has
+    /// been completely written by then, since `tx_user` may apply
+    /// backpressure. This lets a caller detect particular prompts itself.
+    pub fn send_open_observed<T, R, F>(
+        self, params: OpenParameters, tx_user: T, rx_user: R, idle_timeout: Option<Duration>,
+        observer: F
+    ) -> Result<(OpenResult, Self), Error>
+        where T: 'static + Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
+              R: 'static + Stream<Item = Vec<u8>, Error = io::Error>,
+              F: 'static + FnMut(&[u8])
+    {
+        let hooks = OpenHooks { observer: Some(Box::new(observer)), ..OpenHooks::default() };
+        self.send_open_inner(ClientMessage::Open(params), tx_user, rx_user, idle_timeout, hooks)
+    }
 
-        let (result, ser, de) = self.core.run(fut)?;
-        self.ser = ser;
-        self.de = de;
-        Ok((result, self))
+    /// Tell the daemon to open a new SSH connection, keeping SSH's standard
+    /// error apart from its terminal.
+    ///
+    /// This is like `send_open`, but sets the `separate_stderr` flag in
+    /// `params`, and what SSH writes to its standard error during the login
+    /// is passed to `on_stderr` rather than to `tx_user`. This lets a caller
+    /// present SSH's complaints differently from the login itself.
+    pub fn send_open_with_stderr<T, R, F>(
+        self, mut params: OpenParameters, tx_user: T, rx_user: R, idle_timeout: Option<Duration>,
+        on_stderr: F
+    ) -> Result<(OpenResult, Self), Error>
+        where T: 'static + Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
+              R: 'static + Stream<Item = Vec<u8>, Error = io::Error>,
+              F: 'static + FnMut(&[u8])
+    {
+        params.separate_stderr = true;
+        let hooks = OpenHooks { stderr: Some(Box::new(on_stderr)), ..OpenHooks::default() };
+        self.send_open_inner(ClientMessage::Open(params), tx_user, rx_user, idle_timeout, hooks)
     }
 
-    /// Tell the server to exit.
+    /// Tell the daemon to open a new SSH connection, saving a transcript of
+    /// SSH's output.
     ///
-    /// This, of course, means that all SSH tunnels will be closed. The server
-    /// will not actually exit until the client sends its "Goodbye" message
-    /// and disconnections.
-    pub fn send_exit(mut self) -> Result<Self, Error> {
-        let (ser, de) = (self.ser, self.de);
+    /// This is like `send_open`, but everything that SSH prints during the
+    /// login is also written to `transcript`. If writing to `transcript`
+    /// fails, the transcript is abandoned but the open carries on; the
+    /// error is returned alongside the result so that the caller can report
+    /// it.
+    pub fn send_open_with_transcript<T, R, W>(
+        self, params: OpenParameters, tx_user: T, rx_user: R, idle_timeout: Option<Duration>,
+        mut transcript: W
+    ) -> Result<(OpenResult, Option<io::Error>, Self), Error>
+        where T: 'static + Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
+              R: 'static + Stream<Item = Vec<u8>, Error = io::Error>,
+              W: 'static + Write
+    {
+        let failure = Rc::new(RefCell::new(None));
+        let failure2 = failure.clone();
 
-        let fut = ser.send(ClientMessage::Exit)
-            .map_err(|e| format_err!("error sending exit message to daemon: {}", e))
-            .and_then(move |ser| {
-                de.into_future()
-                    .map_err(|(e, _de)| format_err!("error receiving daemon reply: {}", e))
-                    .map(|(maybe_msg, de)| (maybe_msg, ser, de))
-            }).and_then(|(maybe_msg, ser, de)| {
-                match maybe_msg {
-                    Some(ServerMessage::Ok) => Ok((ser, de)),
-                    Some(ServerMessage::Error(msg)) => return Err(format_err!("{}", msg)),
-                    Some(other) => return Err(format_err!("unexpected server reply: {:?}", other)),
-                    None => return Err(format_err!("unexpected disconnection from server")),
+        let observer = move |data: &[u8]| {
+            let mut failure = failure2.borrow_mut();
+
+            if failure.is_none() {
+                if let Err(e) = transcript.write_all(data).and_then(|_| transcript.flush()) {
+                    *failure = Some(e);
                 }
-            });
+            }
+        };
 
-        let (ser, de) = self.core.run(fut)?;
-        self.ser = ser;
-        self.de = de;
-        Ok(self)
+        let (result, conn) = self.send_open_observed(params, tx_user, rx_user, idle_timeout, observer)?;
+
+        let failure = failure.borrow_mut().take();
+        Ok((result, failure, conn))
     }
-}
 
+    /// Tell the daemon to restart a running tunnel with new parameters.
+    ///
+    /// The old SSH process is killed and a new one is launched with
+    /// `params`, under the same label. The login is relayed just as for
+    /// `send_open`, and a successful restart yields `OpenResult::Replaced`.
+    /// If there's no tunnel with that label, the result is
+    /// `OpenResult::NotOpen`. If the daemon rejects the new parameters, an
+    /// error is returned and the old tunnel stays up.
+    pub fn reconfigure<T, R>(
+        self, label: String, params: OpenParameters, tx_user: T, rx_user: R,
+        idle_timeout: Option<Duration>
+    ) -> Result<(OpenResult, Self), Error>
+        where T: 'static + Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
+              R: 'static + Stream<Item = Vec<u8>, Error = io::Error>
+    {
+        let msg = ClientMessage::Reconfigure(ReconfigureParameters { label, params });
+        self.send_open_inner(msg, tx_user, rx_user, idle_timeout, OpenHooks::default())
+    }
 
-#[derive(StateMachineFuture)]
-#[allow(unused)] // get lots of these spuriously; custom derive stuff?
-enum OpenWorkflow {
-    #[state_machine_future(start, transitions(FirstAck))]
-    Issue {
-        tx_ssh: Send<Ser>,
-        rx_ssh: De,
-        tx_user: UserOutputSink,
-        rx_user: UserInputStream,
-    },
+    fn send_open_inner<T, R>(
+        mut self, mut msg: ClientMessage, tx_user: T, rx_user: R, idle_timeout: Option<Duration>,
+        mut hooks: OpenHooks
+    ) -> Result<(OpenResult, Self), Error>
+        where T: 'static + Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
+              R: 'static + Stream<Item = Vec<u8>, Error = io::Error>
+    {
+        // Daemons that predate `report_key` won't tell us the tunnel's label,
+        // in which case it's what we asked for.
 
-    #[state_machine_future(transitions(Finished, Communicating))]
-    FirstAck {
-        tx_ssh: Ser,
-        rx_ssh: De,
-        tx_user: UserOutputSink,
-        rx_user: UserInputStream,
-        saw_ok: bool,
-    },
+        let requested_key = match msg {
+            ClientMessage::Open(ref mut p) => {
+                p.report_key = true;
+                p.report_pid = true;
+                p.host.clone()
+            },
 
-    #[state_machine_future(transitions(Finished))]
-    Communicating {
-        tx_ssh: Ser,
-        rx_ssh: De,
-        ssh_buf: Vec<u8>,
-        tx_user: UserOutputSink,
-        rx_user: UserInputStream,
-        user_buf: Vec<u8>,
-    },
+            ClientMessage::Reconfigure(ref mut r) => {
+                r.params.report_key = true;
+                r.params.report_pid = true;
+                r.label.clone()
+            },
 
-    #[state_machine_future(ready)]
-    Finished((Ser, De, OpenResult)),
+            ClientMessage::OpenProfile { ref name } => name.clone(),
 
-    #[state_machine_future(error)]
-    Failed(Error),
-}
+            ref other => unreachable!("not an open: {}", other.summary()),
+        };
 
+        let idle_timeout = idle_timeout.filter(|d| *d != Duration::from_secs(0));
+        hooks.window_sizes = self.window_sizes.take();
+        hooks.interrupt = self.interrupt_byte;
 
-impl PollOpenWorkflow for OpenWorkflow {
-    fn poll_issue<'a>(
-        state: &'a mut RentToOwn<'a, Issue>
-    ) -> Poll<AfterIssue, Error> {
-        let ser = try_ready!(state.tx_ssh.poll());
+        hooks.deferral = self.deferral.take();
 
-        let state = state.take();
-        transition!(FirstAck {
-            tx_ssh: ser,
-            rx_ssh: state.rx_ssh,
-            tx_user: state.tx_user,
-            rx_user: state.rx_user,
-            saw_ok: false,
-        })
+        if self.hide_banners || hooks.deferral.is_some() {
+            hooks.banner = Some(BannerFilter::default());
+        }
+
+        let fut = self.ser.send(msg);
+        let wf = OpenWorkflow::start(fut, self.de, Box::new(tx_user), Box::new(rx_user),
+                                     TimerSource::new(&self.core, &self.clock), idle_timeout, hooks);
+        let (ser, de, mut result) = self.core.run(wf)?;
+
+        match result {
+            OpenResult::Success { ref mut key, .. } | OpenResult::Replaced { ref mut key, .. } if key.is_empty() => {
+                *key = requested_key;
+            },
+            _ => {},
+        }
+
+        self.ser = ser;
+        self.de = de;
+        Ok((result, self))
     }
 
-    fn poll_first_ack<'a>(
-        state: &'a mut RentToOwn<'a, FirstAck>
-    ) -> Poll<AfterFirstAck, Error> {
-        while let Async::Ready(msg) = state.rx_ssh.poll()? {
-            match msg {
-                Some(ServerMessage::Ok) => {
-                    state.saw_ok = true;
-                },
+    /// Ask the server what command line it would use to open a tunnel.
+    ///
+    /// Nothing is actually launched. This is useful for debugging how the
+    /// open parameters are translated into SSH options.
+    pub fn dry_run_open(&mut self, params: OpenParameters) -> Result<Vec<String>, Error> {
+        match self.transact(ClientMessage::DryRunOpen(params), "dry-run open message")? {
+            ServerMessage::CommandLine(argv) => Ok(argv),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
 
-                Some(ServerMessage::Error(text)) => {
-                    return Err(format_err!("{}", text));
-                },
+    /// Open several tunnels, one after another.
+    ///
+    /// This is intended for tunnels that don't need any user interaction to
+    /// log in: no user input is provided, and any output from SSH is
+    /// discarded. If SSH goes quiet for `idle_timeout` during a login, it's
+    /// presumably waiting for input that will never come, so that open fails
+    /// with an error rather than hanging the whole batch.
+    ///
+    /// The outcome of each open is returned alongside its parameters, in
+    /// order. A failure to open one tunnel doesn't prevent the others from
+    /// being attempted; since the daemon hangs up after a failed open, a new
+    /// connection is made when that happens.
+    ///
+    /// If reconnecting fails, the error is recorded against each of the
+    /// opens that hadn't been attempted yet, and no connection is returned.
+    pub fn open_many(
+        self, params: Vec<OpenParameters>, idle_timeout: Duration
+    ) -> (OpenManyResults, Option<Self>) {
+        let mut conn = Some(self);
+        let mut reconnect_error = None;
+        let mut results = Vec::with_capacity(params.len());
+
+        for p in params {
+            if let Some(ref msg) = reconnect_error {
+                results.push((p, Err(format_err!("{}", msg))));
+                continue;
+            }
 
-                Some(ServerMessage::TunnelAlreadyOpen) => {
-                    let state = state.take();
-                    transition!(Finished((state.tx_ssh, state.rx_ssh, OpenResult::AlreadyOpen)));
+            let c = match conn.take() {
+                Some(c) => c,
+                None => match Self::establish() {
+                    Ok(c) => c,
+                    Err(e) => {
+                        let msg = format!("couldn't reconnect to the daemon: {}", e);
+                        results.push((p, Err(format_err!("{}", msg))));
+                        reconnect_error = Some(msg);
+                        continue;
+                    },
                 },
+            };
 
-                Some(other) => {
-                    return Err(format_err!("unexpected response from daemon: {:?}", other));
+            let tx_user = Vec::new().sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+            let rx_user = stream::empty();
+
+            match c.send_open(p.clone(), tx_user, rx_user, Some(idle_timeout)) {
+                Ok((result, c)) => {
+                    conn = Some(c);
+                    results.push((p, Ok(result)));
                 },
 
-                None => {
-                    return Err(format_err!("connection closed (?)"));
+                Err(e) => {
+                    results.push((p, Err(e)));
                 },
             }
         }
 
-        if state.saw_ok {
-            let state = state.take();
+        (results, conn)
+    }
 
-            transition!(Communicating {
-                rx_user: state.rx_user,
-                tx_user: state.tx_user,
-                user_buf: Vec::new(),
-                tx_ssh: state.tx_ssh,
-                rx_ssh: state.rx_ssh,
-                ssh_buf: Vec::new(),
+    /// Bring back the tunnels in a manifest kept by the caller, such as
+    /// after the daemon has been restarted by hand.
+    ///
+    /// This is `open_many`, except that tunnels that are already open are
+    /// left alone rather than replaced, even if their parameters ask for
+    /// `force`: the daemon recognizes them however they're labeled, and
+    /// their results are `OpenResult::AlreadyOpen`. So it's safe to call
+    /// this on a daemon that has some or all of the tunnels already.
+    ///
+    /// The daemon only saves its tunnels when asked to with
+    /// `prepare_upgrade`, and doesn't reopen them by itself. If something
+    /// restores them from that file as well, whichever of it and this gets
+    /// to each tunnel first opens it, and the other finds it open.
+    pub fn reopen_all(
+        self, manifest: Vec<OpenParameters>, idle_timeout: Duration
+    ) -> (OpenManyResults, Option<Self>) {
+        let params = manifest.into_iter()
+            .map(|mut p| {
+                p.force = false;
+                p
             })
+            .collect();
+
+        self.open_many(params, idle_timeout)
+    }
+
+    /// Open a tunnel that doesn't need any user interaction and return a
+    /// handle on it.
+    ///
+    /// As with `open_many`, no user input is provided, SSH's output is
+    /// discarded, and the open fails if SSH goes quiet for `idle_timeout`.
+    /// If the open doesn't leave a tunnel running, for instance because SSH
+    /// couldn't connect, an error is returned.
+    pub fn open_tunnel(self, params: OpenParameters, idle_timeout: Duration) -> Result<TunnelHandle, Error> {
+        let host = params.host.clone();
+        let tx_user = Vec::new().sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+        let rx_user = stream::empty();
+        let (result, conn) = self.send_open(params, tx_user, rx_user, Some(idle_timeout))?;
+
+        let key = match result {
+            OpenResult::Success { ref key, .. } | OpenResult::Replaced { ref key, .. } => key.clone(),
+            OpenResult::AlreadyOpen => host,
+            ref other => return Err(format_err!("the tunnel to {} was not opened: {:?}", host, other)),
+        };
+
+        Ok(TunnelHandle { conn, key, result })
+    }
+
+    /// Open a tunnel that doesn't need any user interaction, and don't
+    /// return until it is up and running.
+    ///
+    /// As with `open_many`, no user input is provided and SSH's output is
+    /// discarded. If the login hasn't finished within `timeout`, it is
+    /// cancelled and an error is returned. Once the daemon reports success,
+    /// we double-check its status to make sure that SSH didn't die right
+    /// away. A result of `OpenResult::AlreadyOpen` is checked the same way.
+    pub fn wait_until_established(
+        self, params: OpenParameters, timeout: Duration
+    ) -> Result<(OpenResult, Self), Error> {
+        let host = params.host.clone();
+
+        // If the timer fires after the open has finished, nobody is listening
+        // any more and the cancellation goes nowhere.
+
+        let (tx_cancel, rx_cancel) = oneshot::channel();
+        let timer = self.clock.timer(timeout, &self.core.handle())?;
+        self.core.handle().spawn(timer.then(move |_| {
+            let _r = tx_cancel.send(());
+            Ok(())
+        }));
+
+        let tx_user = Vec::new().sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+        let rx_user = stream::empty();
+        let (result, mut conn) = self.send_open_cancellable(params, tx_user, rx_user, None, rx_cancel)?;
+
+        match result {
+            OpenResult::Cancelled => {
+                return Err(format_err!("the tunnel to {} was not established within {} seconds",
+                                       host, timeout.as_secs()));
+            },
+
+            OpenResult::ConnectFailed(attempts) => {
+                return Err(format_err!("SSH could not connect to {} after up to {} attempts",
+                                       host, attempts));
+            },
+
+            OpenResult::HostUnreachable => {
+                return Err(format_err!("SSH timed out connecting to {}", host));
+            },
+
+            _ => {},
         }
 
-        Ok(Async::NotReady)
+        let key = match result {
+            OpenResult::Success { ref key, .. } | OpenResult::Replaced { ref key, .. } => key.clone(),
+            _ => host.clone(),
+        };
+
+        let info = conn.query_status()?;
+
+        match info.tunnels.iter().find(|t| t.host == key) {
+            Some(t) if t.state == TunnelState::Open => Ok((result, conn)),
+            Some(t) => Err(format_err!("the tunnel to {} is not running after all (state: {:?})",
+                                       host, t.state)),
+            None => Err(format_err!("the daemon has no record of the tunnel to {}", host)),
+        }
     }
 
-    fn poll_communicating<'a>(
-        state: &'a mut RentToOwn<'a, Communicating>
-    ) -> Poll<AfterCommunicating, Error> {
-        // News from the daemon?
+    /// Open a tunnel and then stay in the foreground until it goes away, much
+    /// like `ssh -N`.
+    ///
+    /// The login is relayed through `tx_user` and `rx_user` just as for
+    /// `send_open`. Unlike `send_open`, this doesn't return once the tunnel is
+    /// up: it keeps watching the tunnel until SSH exits, then reports how it
+    /// ended. If a message arrives on `stop` (say, because the user hit
+    /// Ctrl-C), the tunnel is closed and the result is
+    /// `ForegroundOutcome::Interrupted`; during the login, the open is
+    /// cancelled instead. A tunnel that was already open belongs to whoever
+    /// opened it, so it is left alone and the result is
+    /// `ForegroundOutcome::Detached`. The tunnel still belongs to the daemon, so if this
+    /// process is killed outright, the tunnel stays up.
+    ///
+    /// The daemon can't notify us when a tunnel dies, so we ask after it
+    /// every couple of seconds. If the tunnel was already open, we watch the
+    /// existing one. If the tunnel is relabeled, we lose track of it and
+    /// report it as closed.
+    pub fn run_forever<T, R>(
+        self, params: OpenParameters, tx_user: T, rx_user: R, idle_timeout: Option<Duration>,
+        stop: oneshot::Receiver<()>
+    ) -> Result<(ForegroundOutcome, Self), Error>
+        where T: 'static + Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
+              R: 'static + Stream<Item = Vec<u8>, Error = io::Error>
+    {
+        let host = params.host.clone();
 
-        while let Async::Ready(msg) = state.rx_ssh.poll()? {
-            match msg {
-                Some(ServerMessage::SshData(data)) => {
-                    state.user_buf.extend_from_slice(&data);
+        // `stop` has to serve both to cancel the login and to end the watch,
+        // so share it. If the sender is dropped, we just never stop.
+
+        let stop = stop.shared();
+        let (tx_cancel, rx_cancel) = oneshot::channel();
+        self.core.handle().spawn(stop.clone().then(move |r| {
+            if r.is_ok() {
+                let _r = tx_cancel.send(());
+            }
+            Ok(())
+        }));
+
+        let (result, mut conn) = self.send_open_cancellable(params, tx_user, rx_user, idle_timeout,
+                                                           rx_cancel)?;
+
+        // Follow the tunnel under the label that the daemon filed it under.
+
+        let host = match result {
+            OpenResult::Success { ref key, .. } | OpenResult::Replaced { ref key, .. } => key.clone(),
+            OpenResult::AlreadyOpen => host,
+            other => return Ok((ForegroundOutcome::NotOpened(other), conn)),
+        };
+
+        let mut stop = stop.map(|_| ()).or_else(|_| future::empty::<(), Error>());
+
+        loop {
+            let timer = conn.clock.timer(FOREGROUND_POLL_INTERVAL, &conn.core.handle())?
+                .map_err(Error::from);
+
+            match conn.core.run(stop.select2(timer)) {
+                Ok(future::Either::A(_)) => {
+                    if result == OpenResult::AlreadyOpen {
+                        return Ok((ForegroundOutcome::Detached, conn));
+                    }
+
+                    conn.send_close(CloseParameters { host, grace_secs: None })?;
+                    return Ok((ForegroundOutcome::Interrupted, conn));
                 },
 
-                Some(ServerMessage::Ok) => {
-                    // All done!
-                    let mut state = state.take();
-                    transition!(Finished((state.tx_ssh, state.rx_ssh, OpenResult::Success)));
+                Ok(future::Either::B((_, s))) => {
+                    stop = s;
                 },
 
-                Some(ServerMessage::Error(e)) => {
-                    return Err(format_err!("{}", e));
-                }
+                Err(future::Either::A((e, _))) | Err(future::Either::B((e, _))) => {
+                    return Err(e);
+                },
+            }
 
-                Some(other) => {
-                    return Err(format_err!("unexpected message from the daemon: {:?}", other));
+            let info = conn.query_status()?;
+
+            match info.tunnels.into_iter().find(|t| t.host == host) {
+                Some(ref t) if t.state == TunnelState::Open => {},
+
+                Some(t) => {
+                    let code = t.history.last().and_then(|e| e.exit_code);
+                    return Ok((ForegroundOutcome::Ended(t.state, code), conn));
                 },
 
-                None => {},
+                None => {
+                    return Ok((ForegroundOutcome::Ended(TunnelState::Closed, None), conn));
+                },
             }
         }
+    }
 
-        // New text from the user?
+    /// Query the server’s status.
+    ///
+    /// At the moment, the only information that is returned is a list of
+    /// connections that have been opened and their current state.
+    pub fn query_status(&mut self) -> Result<StatusInformation, Error> {
+        match self.transact(ClientMessage::QueryStatus, "query-status message")? {
+            ServerMessage::StatusResponse(info) => Ok(info),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
 
-        while let Async::Ready(bytes) = state.rx_user.poll()? {
-            match bytes {
-                None => {
-                    // EOF on the user input. This can happen in --no-input mode or,
-                    // in principle, if stdin is redirected in some way.
-                    break;
-                },
+    /// Query the server's status, leaving out the tunnels that don't pass
+    /// `filter`.
+    ///
+    /// This is cheaper than `query_status` when there are many tunnels and
+    /// only a few are of interest, such as those that have died. Daemons
+    /// that predate it hang up; check for `capabilities::FILTERED_STATUS`
+    /// first if that matters.
+    pub fn list_filtered(&mut self, filter: StatusFilter) -> Result<StatusInformation, Error> {
+        match self.transact(ClientMessage::QueryStatusFiltered(filter), "filtered status query")? {
+            ServerMessage::StatusResponse(info) => Ok(info),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
 
-                Some(b) => {
-                    state.ssh_buf.extend_from_slice(&b);
-                }
-            }
+    /// Tell the server to close an existing tunnel.
+    pub fn send_close(&mut self, params: CloseParameters) -> Result<CloseResult, Error> {
+        match self.transact(ClientMessage::Close(params), "close message")? {
+            ServerMessage::Ok => Ok(CloseResult::Success),
+            ServerMessage::TunnelNotOpen => Ok(CloseResult::NotOpen),
+            ServerMessage::TunnelClosed { graceful: true } => Ok(CloseResult::Graceful),
+            ServerMessage::TunnelClosed { graceful: false } => Ok(CloseResult::ForceKilled),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
         }
+    }
 
-        // Ready/able to send bytes to the user?
+    /// Tell the daemon to stop reading a tunnel's output until it is
+    /// resumed.
+    pub fn pause_tunnel(&mut self, host: &str) -> Result<(), Error> {
+        match self.transact(ClientMessage::Pause { host: host.to_owned() }, "pause message")? {
+            ServerMessage::Ok => Ok(()),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
 
-        if state.user_buf.len() != 0 {
-            let buf = state.user_buf.clone();
+    /// Tell the daemon to go back to reading a paused tunnel's output.
+    pub fn resume_tunnel(&mut self, host: &str) -> Result<(), Error> {
+        match self.transact(ClientMessage::Resume { host: host.to_owned() }, "resume message")? {
+            ServerMessage::Ok => Ok(()),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
 
-            if let AsyncSink::Ready = state.tx_user.start_send(buf)? {
-                    state.user_buf.clear();
-            }
+    /// Have the daemon append a running tunnel's terminal output to the
+    /// file at `path`, which must be absolute, until told to stop.
+    pub fn tee_to_file(&mut self, host: &str, path: &Path) -> Result<(), Error> {
+        let path = path.to_str().ok_or_else(|| format_err!("path {} is not valid Unicode", path.display()))?;
+        let msg = ClientMessage::TeeToFile { host: host.to_owned(), path: path.to_owned() };
+
+        match self.transact(msg, "tee message")? {
+            ServerMessage::Ok => Ok(()),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
         }
+    }
 
-        // Ready/able to send bytes to the daemon?
+    /// Tell the daemon to stop writing a tunnel's output to a file.
+    pub fn stop_tee(&mut self, host: &str) -> Result<(), Error> {
+        match self.transact(ClientMessage::StopTee { host: host.to_owned() }, "stop-tee message")? {
+            ServerMessage::Ok => Ok(()),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
 
-        if state.ssh_buf.len() != 0 {
-            let buf = state.ssh_buf.clone();
+    /// Interrupt a tunnel's remote command by typing the control character
+    /// for `signum` into its terminal.
+    pub fn signal_tunnel(&mut self, host: &str, signum: i32) -> Result<(), Error> {
+        let msg = ClientMessage::SignalTunnel { host: host.to_owned(), signum };
 
-            if let AsyncSink::Ready = state.tx_ssh.start_send(ClientMessage::UserData(buf))? {
-                state.ssh_buf.clear();
-            }
+        match self.transact(msg, "signal message")? {
+            ServerMessage::Ok => Ok(()),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
+
+    /// Tell the server to change the terminal size of a running tunnel.
+    pub fn send_resize(&mut self, params: ResizeParameters) -> Result<(), Error> {
+        match self.transact(ClientMessage::Resize(params), "resize message")? {
+            ServerMessage::Ok => Ok(()),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
         }
+    }
 
-        // Gotta flush those transmissions.
+    /// Check whether a tunnel is actually working.
+    ///
+    /// If no tunnel with the specified label is known, returns `None`.
+    pub fn check_tunnel(&mut self, params: CheckTunnelParameters) -> Result<Option<TunnelHealth>, Error> {
+        match self.transact(ClientMessage::CheckTunnel(params), "check-tunnel message")? {
+            ServerMessage::TunnelHealthResponse(h) => Ok(Some(h)),
+            ServerMessage::TunnelNotOpen => Ok(None),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
 
-        try_ready!(state.tx_user.poll_complete());
-        try_ready!(state.tx_ssh.poll_complete());
-        Ok(Async::NotReady)
+    /// Query the internal relay counters associated with a tunnel.
+    ///
+    /// This is a diagnostic tool. If no tunnel to the specified host is open,
+    /// returns `None`.
+    pub fn query_relay_counters(
+        &mut self, params: RelayCountersParameters
+    ) -> Result<Option<RelayCounters>, Error> {
+        match self.transact(ClientMessage::QueryRelayCounters(params), "relay-counters query")? {
+            ServerMessage::RelayCountersResponse(c) => Ok(Some(c)),
+            ServerMessage::TunnelNotOpen => Ok(None),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
+
+    /// Ask the daemon how many resources it is using.
+    pub fn daemon_stats(&mut self) -> Result<DaemonStats, Error> {
+        match self.transact(ClientMessage::QueryDaemonStats, "daemon-stats query")? {
+            ServerMessage::DaemonStatsResponse(stats) => Ok(stats),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
+
+    /// Ask the daemon for the settings that it's running with.
+    pub fn get_config(&mut self) -> Result<DaemonConfig, Error> {
+        match self.transact(ClientMessage::GetConfig, "config query")? {
+            ServerMessage::Config(config) => Ok(config),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
+
+    /// Ask the daemon which optional features it supports. The names are
+    /// listed in the `capabilities` module.
+    pub fn capabilities(&mut self) -> Result<Vec<String>, Error> {
+        match self.transact(ClientMessage::Capabilities, "capabilities query")? {
+            ServerMessage::Capabilities(names) => Ok(names),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
+
+    /// Ask the daemon for its metrics, as text in the Prometheus exposition
+    /// format.
+    pub fn metrics(&mut self) -> Result<String, Error> {
+        match self.transact(ClientMessage::Metrics, "metrics query")? {
+            ServerMessage::Metrics(text) => Ok(text),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
+
+    /// Ask the daemon for a snapshot of its internal state.
+    ///
+    /// This is a diagnostic tool, and only works if the daemon was started
+    /// with state dumps enabled.
+    pub fn dump_state(&mut self) -> Result<StateDump, Error> {
+        match self.transact(ClientMessage::DumpState, "dump-state query")? {
+            ServerMessage::StateDumpResponse(dump) => Ok(dump),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
+
+    /// Ask the daemon for the details of a single tunnel, given its label or
+    /// host.
+    ///
+    /// If the daemon has never heard of the tunnel, this returns a
+    /// `DaemonError` with the code `ProtocolError::NotFound`.
+    pub fn tunnel_status(&mut self, host: &str) -> Result<TunnelDetails, Error> {
+        let msg = ClientMessage::TunnelStatus { host: host.to_owned() };
+
+        match self.transact(msg, "tunnel-status query")? {
+            ServerMessage::TunnelStatusResponse(details) => Ok(details),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
+
+    /// Block until a running tunnel's SSH process goes away, and find out how
+    /// it ended.
+    ///
+    /// If there is no such running tunnel, this returns a `DaemonError`
+    /// with the code `ProtocolError::NotFound`.
+    pub fn wait_for_exit(&mut self, host: &str) -> Result<TunnelExit, Error> {
+        let msg = ClientMessage::WaitForExit { host: host.to_owned() };
+
+        match self.transact(msg, "wait-for-exit message")? {
+            ServerMessage::TunnelExitResponse(exit) => Ok(exit),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
+
+    /// Tell the daemon to remove sockets that it had SSH create but that are
+    /// no longer in use, returning their paths.
+    pub fn clean_up(&mut self) -> Result<Vec<String>, Error> {
+        match self.transact(ClientMessage::Cleanup, "cleanup message")? {
+            ServerMessage::SocketsRemoved(paths) => Ok(paths),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
+
+    /// Restrict this connection to queries, so that it can't be used to
+    /// change anything about the daemon. This can't be undone; open a new
+    /// connection to regain full access.
+    pub fn set_read_only(&mut self) -> Result<(), Error> {
+        match self.transact(ClientMessage::SetReadOnly, "read-only message")? {
+            ServerMessage::Ok => Ok(()),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
+
+    /// Tell the server to change the label of an existing tunnel.
+    pub fn send_relabel(&mut self, params: RelabelParameters) -> Result<RelabelResult, Error> {
+        match self.transact(ClientMessage::Relabel(params), "relabel message")? {
+            ServerMessage::Ok => Ok(RelabelResult::Success),
+            ServerMessage::TunnelNotOpen => Ok(RelabelResult::NotFound),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
+
+    /// Fetch up to `lines` of the most recent lines of the daemon log.
+    pub fn get_log(&mut self, lines: usize) -> Result<Vec<String>, Error> {
+        match self.transact(ClientMessage::GetLog { lines }, "get-log message")? {
+            ServerMessage::LogLines(lines) => Ok(lines),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
+
+    /// Tell the server to get ready to be upgraded.
+    ///
+    /// Returns the path of the file in which the server saved the parameters
+    /// of its open tunnels.
+    pub fn prepare_upgrade(&mut self) -> Result<String, Error> {
+        match self.transact(ClientMessage::PrepareUpgrade, "prepare-upgrade message")? {
+            ServerMessage::UpgradePrepared(path) => Ok(path),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
+
+    /// Tell the server to exit.
+    ///
+    /// This, of course, means that all SSH tunnels will be closed. The server
+    /// will not actually exit until the client sends its "Goodbye" message
+    /// and disconnections.
+    ///
+    /// See `ExitParameters` for how the server can be asked to let opens
+    /// that are underway finish first. In that case, this function does not
+    /// return until the server has committed to exiting.
+    pub fn send_exit(&mut self, params: ExitParameters) -> Result<(), Error> {
+        // Daemons that predate `ExitWithParameters` only understand the
+        // plain version, so use it when we can.
+        let msg = if params.wait_for_opens {
+            ClientMessage::ExitWithParameters(params)
+        } else {
+            ClientMessage::Exit
+        };
+
+        match self.transact(msg, "exit message")? {
+            ServerMessage::Ok => Ok(()),
+            other => Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+    }
+
+    /// Shut the daemon down, and make sure that it's gone.
+    ///
+    /// Unlike `send_exit`, this closes the connection, since the daemon
+    /// waits for that before exiting, and then waits until the daemon is no
+    /// longer accepting connections. All SSH tunnels are closed. If the
+    /// daemon doesn't acknowledge the request, or doesn't go away, within
+    /// ten seconds, an error is returned; it may be wedged.
+    pub fn shutdown_daemon(mut self) -> Result<(), Error> {
+        match self.transact_within(ClientMessage::Exit, "exit message", Some(SHUTDOWN_TIMEOUT))? {
+            ServerMessage::Ok => {},
+            other => return Err(format_err!("unexpected server reply: {}", other.summary())),
+        }
+
+        let sock_path = self.sock_path.clone();
+        let clock = self.clock.clone();
+        self.close()?;
+
+        // Older daemons leave their socket file behind, so if it's still
+        // there, see whether anyone is listening on it.
+
+        let deadline = clock.now() + SHUTDOWN_TIMEOUT;
+
+        loop {
+            if !sock_path.exists() || StdUnixStream::connect(&sock_path).is_err() {
+                return Ok(());
+            }
+
+            if clock.now() >= deadline {
+                return Err(format_err!("the daemon acknowledged the exit request but was still \
+                                        running {} seconds later", SHUTDOWN_TIMEOUT.as_secs()));
+            }
+
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Send a message to the daemon and wait for its reply.
+    ///
+    /// Error replies are turned into `Err` values, so callers only need to
+    /// handle the replies that indicate success. `what` describes the
+    /// message for error reporting.
+    fn transact(&mut self, msg: ClientMessage, what: &str) -> Result<ServerMessage, Error> {
+        self.transact_within(msg, what, None)
+    }
+
+    /// Like `transact`, but give up if the daemon hasn't replied within
+    /// `limit`, if it's given.
+    fn transact_within(
+        &mut self, msg: ClientMessage, what: &str, limit: Option<Duration>
+    ) -> Result<ServerMessage, Error> {
+        let timer: Timer = match limit {
+            Some(d) => self.clock.timer(d, &self.core.handle())?,
+            None => Box::new(future::empty()),
+        };
+
+        let too_late = format!("the daemon didn't reply to the {} within {} seconds; it may be wedged",
+                               what, limit.map(|d| d.as_secs()).unwrap_or(0));
+        let timeout = timer.then(move |_| Err(format_err!("{}", too_late)));
+
+        let (ser, de) = (&mut self.ser, &mut self.de);
+
+        let fut = ser.send(msg)
+            .map_err(|e| format_err!("error sending {} to daemon: {}", what, e))
+            .and_then(move |_ser| {
+                de.into_future()
+                    .map_err(|(e, _de)| format_err!("error receiving daemon reply: {}", e))
+            })
+            .map(|(reply, _de)| reply)
+            .select(timeout)
+            .map(|(reply, _timeout)| reply)
+            .map_err(|(e, _other)| e);
+
+        match self.core.run(fut)? {
+            Some(ServerMessage::Error(msg)) => Err(format_err!("{}", msg)),
+            Some(ServerMessage::ErrorCode(code, msg)) => Err(DaemonError { code, message: msg }.into()),
+            Some(reply) => Ok(reply),
+            None => Err(format_err!("unexpected disconnection from server")),
+        }
+    }
+}
+
+
+/// Keeps the terminal in raw mode, putting it back the way it was when
+/// dropped -- including if the program panics.
+///
+/// See `raw_terminal_io`.
+pub struct RawTerminalGuard {
+    saved: Option<libc::termios>,
+}
+
+impl RawTerminalGuard {
+    /// Put the terminal on standard input into raw mode, if there is one.
+    ///
+    /// Signal characters are left alone, so that Ctrl-C still interrupts
+    /// the program rather than being passed along to SSH.
+    pub fn new() -> Result<Self, Error> {
+        if unsafe { libc::isatty(0) } == 0 {
+            return Ok(RawTerminalGuard { saved: None });
+        }
+
+        let mut attrs: libc::termios = unsafe { mem::zeroed() };
+
+        if unsafe { libc::tcgetattr(0, &mut attrs) } != 0 {
+            return Err(format_err!("failed to query terminal attributes: {}", io::Error::last_os_error()));
+        }
+
+        let saved = attrs;
+        unsafe { libc::cfmakeraw(&mut attrs) };
+        attrs.c_lflag |= libc::ISIG;
+
+        if unsafe { libc::tcsetattr(0, libc::TCSANOW, &attrs) } != 0 {
+            return Err(format_err!("failed to put the terminal into raw mode: {}",
+                                   io::Error::last_os_error()));
+        }
+
+        Ok(RawTerminalGuard { saved: Some(saved) })
+    }
+}
+
+impl Drop for RawTerminalGuard {
+    fn drop(&mut self) {
+        if let Some(ref saved) = self.saved {
+            unsafe { libc::tcsetattr(0, libc::TCSANOW, saved) };
+        }
+    }
+}
+
+
+/// A sink that writes to standard output. Terminal output is quick, so the
+/// writes simply block.
+struct StdoutSink;
+
+impl Sink for StdoutSink {
+    type SinkItem = Vec<u8>;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Vec<u8>) -> StartSend<Vec<u8>, io::Error> {
+        let stdout = io::stdout();
+        let mut lock = stdout.lock();
+        lock.write_all(&item)?;
+        lock.flush()?;
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+
+/// Set up the user's terminal for an interactive open.
+///
+/// This puts the terminal into raw mode, so that what the user types goes
+/// straight to SSH, which echoes it as appropriate. The returned sink and
+/// stream relay SSH's output to standard output and the user's typing from
+/// standard input, and can be passed to `send_open` and friends. The
+/// terminal is restored when the guard is dropped, so keep it around until
+/// the open has finished.
+///
+/// Standard input is read by a separate thread. If it's blocked in a read
+/// when the open finishes, it hangs around until that read completes, and
+/// whatever it reads is lost.
+pub fn raw_terminal_io() -> Result<(RawTerminalGuard, UserOutputSink, UserInputStream), Error> {
+    let guard = RawTerminalGuard::new()?;
+    let (stdout, stdin) = terminal_io();
+    Ok((guard, stdout, stdin))
+}
+
+/// Relay SSH's output to standard output and the user's typing from
+/// standard input, like `raw_terminal_io`, but leave the terminal's mode
+/// alone. This is for callers that only put the terminal into raw mode
+/// once they know that the user is needed, such as with
+/// `Connection::defer_interaction`.
+pub fn terminal_io() -> (UserOutputSink, UserInputStream) {
+    let (tx, rx) = futures::sync::mpsc::channel(1);
+
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut lock = stdin.lock();
+        let mut buf = [0u8; 512];
+        let mut tx = tx;
+
+        loop {
+            // Once the stream is dropped, sends fail and we can stop.
+            let item = match lock.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => Ok(buf[..n].to_vec()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+
+            let failed = item.is_err();
+
+            tx = match tx.send(item).wait() {
+                Ok(tx) => tx,
+                Err(_) => break,
+            };
+
+            if failed {
+                break;
+            }
+        }
+    });
+
+    let stream = rx.then(|r| r.expect("mpsc receivers cannot fail"));
+
+    (Box::new(StdoutSink), Box::new(stream))
+}
+
+
+#[derive(StateMachineFuture)]
+#[allow(unused)] // get lots of these spuriously; custom derive stuff?
+enum OpenWorkflow {
+    #[state_machine_future(start, transitions(FirstAck))]
+    Issue {
+        tx_ssh: Send<Ser>,
+        rx_ssh: De,
+        tx_user: UserOutputSink,
+        rx_user: UserInputStream,
+        timers: TimerSource,
+        idle_timeout: Option<Duration>,
+        hooks: OpenHooks,
+    },
+
+    #[state_machine_future(transitions(Finished, Communicating))]
+    FirstAck {
+        tx_ssh: Ser,
+        rx_ssh: De,
+        tx_user: UserOutputSink,
+        rx_user: UserInputStream,
+        timers: TimerSource,
+        idle_timeout: Option<Duration>,
+        hooks: OpenHooks,
+        cancel_wanted: bool,
+        cancelling: bool,
+        user_buf: Vec<u8>,
+        window_size: Option<(u16, u16)>,
+    },
+
+    #[state_machine_future(transitions(Finished))]
+    Communicating {
+        tx_ssh: Ser,
+        rx_ssh: De,
+        ssh_buf: Vec<u8>,
+        tx_user: UserOutputSink,
+        rx_user: UserInputStream,
+        user_buf: Vec<u8>,
+        idle_timeout: Option<Duration>,
+        idle_timer: Option<Timer>,
+        timers: TimerSource,
+        hooks: OpenHooks,
+        cancel_wanted: bool,
+        cancelling: bool,
+        ending: Option<Result<OpenResult, Error>>,
+        pong_wanted: bool,
+        window_size: Option<(u16, u16)>,
+        result: OpenResult,
+    },
+
+    #[state_machine_future(ready)]
+    Finished((Ser, De, OpenResult)),
+
+    #[state_machine_future(error)]
+    Failed(Error),
+}
+
+
+impl PollOpenWorkflow for OpenWorkflow {
+    fn poll_issue<'a>(
+        state: &'a mut RentToOwn<'a, Issue>
+    ) -> Poll<AfterIssue, Error> {
+        let ser = try_ready!(state.tx_ssh.poll());
+
+        let state = state.take();
+        transition!(FirstAck {
+            tx_ssh: ser,
+            rx_ssh: state.rx_ssh,
+            tx_user: state.tx_user,
+            rx_user: state.rx_user,
+            timers: state.timers,
+            idle_timeout: state.idle_timeout,
+            hooks: state.hooks,
+            cancel_wanted: false,
+            cancelling: false,
+            user_buf: Vec::new(),
+            window_size: None,
+        })
+    }
+
+    fn poll_first_ack<'a>(
+        state: &'a mut RentToOwn<'a, FirstAck>
+    ) -> Poll<AfterFirstAck, Error> {
+        // The daemon may take a while to get back to us -- for instance, if
+        // it's waiting for a tunnel that we're replacing to die -- and our
+        // caller may give up in the meantime. The daemon reads the
+        // cancellation once the login starts, or ignores it if the login
+        // never does.
+
+        let cancel_fired = match state.hooks.cancel.as_mut().map(|rx| rx.poll()) {
+            Some(Ok(Async::Ready(()))) => Some(true),
+            Some(Err(oneshot::Canceled)) => Some(false),
+            _ => None,
+        };
+
+        if let Some(fired) = cancel_fired {
+            state.hooks.cancel = None;
+
+            if fired {
+                state.cancel_wanted = true;
+                state.cancelling = true;
+            }
+        }
+
+        if state.cancel_wanted {
+            if let AsyncSink::Ready = state.tx_ssh.start_send(ClientMessage::Cancel)? {
+                state.cancel_wanted = false;
+            }
+        }
+
+        state.tx_ssh.poll_complete()?;
+
+        // The daemon can't resize SSH's terminal until it has launched SSH,
+        // so just remember the latest size for now.
+
+        {
+            let st: &mut FirstAck = &mut *state;
+            poll_window_sizes(&mut st.hooks, &mut st.window_size)?;
+        }
+
+        // SSH output should only ever follow the acknowledgement, but if
+        // some arrives first, hold on to it for the Communicating state
+        // rather than treating it as a protocol error.
+
+        let msg = loop {
+            match try_ready!(state.rx_ssh.poll()) {
+                Some(ServerMessage::SshData(data)) => {
+                    if let Some(ref mut observer) = state.hooks.observer {
+                        observer(&data);
+                    }
+
+                    if let Some(ref mut responder) = state.hooks.password {
+                        responder.notice(&data);
+                    }
+
+                    let st: &mut FirstAck = &mut *state;
+                    relay_ssh_output(&mut st.hooks, &mut st.user_buf, &data);
+                },
+
+                Some(ServerMessage::SshStderr(data)) => {
+                    match state.hooks.stderr {
+                        Some(ref mut on_stderr) => on_stderr(&data),
+                        None => state.user_buf.extend_from_slice(&data),
+                    }
+                },
+
+                msg => break msg,
+            }
+        };
+
+        match msg {
+            Some(ServerMessage::Ok) | Some(ServerMessage::TunnelReplaced) => {
+                // We must move on as soon as we see this message: the
+                // daemon may have sent SSH output right behind it, and
+                // if we kept reading here we'd choke on it. Anything
+                // still queued up in `rx_ssh` will be picked up by the
+                // Communicating state.
+                // The daemon may tell us the tunnel's key later on; if
+                // not, `send_open_inner` fills it in.
+                let result = match msg {
+                    Some(ServerMessage::TunnelReplaced) => OpenResult::Replaced { key: String::new(), pid: None },
+                    _ => OpenResult::Success { key: String::new(), pid: None },
+                };
+
+                let state = state.take();
+
+                let idle_timer = match state.idle_timeout {
+                    Some(d) => Some(state.timers.timer(d)?),
+                    None => None,
+                };
+
+                transition!(Communicating {
+                    rx_user: state.rx_user,
+                    tx_user: state.tx_user,
+                    user_buf: state.user_buf,
+                    tx_ssh: state.tx_ssh,
+                    rx_ssh: state.rx_ssh,
+                    ssh_buf: Vec::new(),
+                    idle_timeout: state.idle_timeout,
+                    idle_timer,
+                    timers: state.timers,
+                    hooks: state.hooks,
+                    cancel_wanted: state.cancel_wanted,
+                    cancelling: state.cancelling,
+                    ending: None,
+                    pong_wanted: false,
+                    window_size: state.window_size,
+                    result,
+                })
+            },
+
+            Some(ServerMessage::Error(text)) => {
+                Err(format_err!("{}", text))
+            },
+
+            Some(ServerMessage::ErrorCode(code, text)) => {
+                Err(DaemonError { code, message: text }.into())
+            },
+
+            Some(ServerMessage::TunnelAlreadyOpen) => {
+                let state = state.take();
+                transition!(Finished((state.tx_ssh, state.rx_ssh, OpenResult::AlreadyOpen)));
+            },
+
+            Some(ServerMessage::ShuttingDown) => {
+                let state = state.take();
+                transition!(Finished((state.tx_ssh, state.rx_ssh, OpenResult::DaemonShuttingDown)));
+            },
+
+            Some(ServerMessage::TunnelNotOpen) => {
+                let state = state.take();
+                transition!(Finished((state.tx_ssh, state.rx_ssh, OpenResult::NotOpen)));
+            },
+
+            Some(other) => {
+                Err(format_err!("unexpected response from daemon: {}", other.summary()))
+            },
+
+            None => {
+                Err(format_err!("connection closed (?)"))
+            },
+        }
+    }
+
+    fn poll_communicating<'a>(
+        state: &'a mut RentToOwn<'a, Communicating>
+    ) -> Poll<AfterCommunicating, Error> {
+        let mut saw_activity = false;
+        let mut saw_banner = false;
+
+        // News from the daemon? Once we know how the open ended, we're only
+        // waiting to finish passing along SSH's output, and must not read
+        // anything more. If the user is slow to take SSH's output, we stop
+        // reading it until they've caught up a bit.
+
+        while state.ending.is_none() && state.user_buf.len() < MAX_RELAY_BUF {
+            let msg = match state.rx_ssh.poll()? {
+                Async::Ready(msg) => msg,
+                Async::NotReady => break,
+            };
+
+            match msg {
+                Some(ServerMessage::SshData(data)) => {
+                    saw_activity = true;
+
+                    if let Some(ref mut observer) = state.hooks.observer {
+                        observer(&data);
+                    }
+
+                    if let Some(ref mut responder) = state.hooks.password {
+                        responder.notice(&data);
+                    }
+
+                    let st: &mut Communicating = &mut *state;
+                    saw_banner |= relay_ssh_output(&mut st.hooks, &mut st.user_buf, &data);
+                },
+
+                Some(ServerMessage::SshStderr(data)) => {
+                    saw_activity = true;
+
+                    match state.hooks.stderr {
+                        Some(ref mut on_stderr) => on_stderr(&data),
+                        None => state.user_buf.extend_from_slice(&data),
+                    }
+                },
+
+                Some(ServerMessage::Ok) => {
+                    // All done! But there may be SSH output that we haven't
+                    // passed along to the user yet.
+                    state.ending = Some(Ok(state.result.clone()));
+                },
+
+                Some(ServerMessage::TunnelKey(label)) => {
+                    match state.result {
+                        OpenResult::Success { ref mut key, .. } | OpenResult::Replaced { ref mut key, .. } => {
+                            *key = label;
+                        },
+                        _ => {},
+                    }
+                },
+
+                Some(ServerMessage::TunnelPid(n)) => {
+                    match state.result {
+                        OpenResult::Success { ref mut pid, .. } | OpenResult::Replaced { ref mut pid, .. } => {
+                            *pid = Some(n);
+                        },
+                        _ => {},
+                    }
+                },
+
+                Some(ServerMessage::Cancelled) => {
+                    state.ending = Some(Ok(OpenResult::Cancelled));
+                },
+
+                Some(ServerMessage::Ping) => {
+                    state.pong_wanted = true;
+                },
+
+                Some(ServerMessage::Error(e)) => {
+                    // Don't bail out yet: the daemon may have just sent us
+                    // SSH's last words, which explain the error and which
+                    // the user should see.
+                    state.ending = Some(Err(format_err!("{}", e)));
+                }
+
+                Some(ServerMessage::ErrorCode(code, message)) => {
+                    state.ending = Some(Err(DaemonError { code, message }.into()));
+                }
+
+                Some(ServerMessage::ConnectFailed(attempts)) => {
+                    // As with errors, SSH's output should reach the user
+                    // before we report this.
+                    state.ending = Some(Ok(OpenResult::ConnectFailed(attempts)));
+                }
+
+                Some(ServerMessage::HostUnreachable) => {
+                    state.ending = Some(Ok(OpenResult::HostUnreachable));
+                }
+
+                Some(other) => {
+                    return Err(format_err!("unexpected message from the daemon: {}", other.summary()));
+                },
+
+                None => {
+                    // The daemon hangs up on logins when it exits. We mustn't
+                    // poll the finished stream again, or we'd spin with
+                    // nobody to answer, but SSH's last words may still be
+                    // waiting to reach the user.
+                    state.ending = Some(Err(format_err!("the daemon unexpectedly closed the connection")));
+                },
+            }
+        }
+
+        // Is SSH waiting for the user behind a banner that we've held back?
+        // If the login has already ended, the banner only matters if it
+        // might explain why the login failed. While the user is being kept
+        // out of the login, it's the grace period, not a quiet spell, that
+        // decides when to give up waiting for a prompt.
+
+        {
+            let st: &mut Communicating = &mut *state;
+
+            if st.ending.is_none() {
+                poll_deferral(&mut st.hooks, &st.timers, &mut st.user_buf)?;
+            }
+
+            if st.hooks.deferral.is_none() {
+                poll_banner(&mut st.hooks, &st.timers, &mut st.user_buf, saw_banner)?;
+            }
+
+            if st.ending.is_some() {
+                if let Some(banner) = st.hooks.banner.take() {
+                    match st.ending {
+                        Some(Ok(OpenResult::Success { .. })) | Some(Ok(OpenResult::Replaced { .. })) => {},
+                        _ => {
+                            st.user_buf.splice(0..0, banner.held);
+                        },
+                    }
+                }
+            }
+        }
+
+        if state.ending.is_some() {
+            state.start_send_to_user()?;
+            try_ready!(state.tx_user.poll_complete());
+
+            if state.user_buf.is_empty() {
+                let result = state.ending.take().unwrap()?;
+                let state = state.take();
+                transition!(Finished((state.tx_ssh, state.rx_ssh, result)));
+            }
+
+            return Ok(Async::NotReady);
+        }
+
+        // Has our caller given up? If the sender has gone away, it never
+        // will. Once we're cancelling, there's no point in passing along
+        // anything more that the user types.
+
+        let cancel_fired = match state.hooks.cancel.as_mut().map(|rx| rx.poll()) {
+            Some(Ok(Async::Ready(()))) => Some(true),
+            Some(Err(oneshot::Canceled)) => Some(false),
+            _ => None,
+        };
+
+        if let Some(fired) = cancel_fired {
+            state.hooks.cancel = None;
+
+            if fired {
+                state.cancel_wanted = true;
+                state.cancelling = true;
+                state.ssh_buf.clear();
+            }
+        }
+
+        // New text from the user? There's none to be had until they've been
+        // brought into a deferred login.
+
+        let user_buf_full = state.user_buf.len() >= MAX_RELAY_BUF;
+
+        if !state.cancelling && state.hooks.deferral.is_none() {
+            while state.ssh_buf.len() < MAX_RELAY_BUF {
+                let bytes = match state.rx_user.poll()? {
+                    Async::Ready(bytes) => bytes,
+                    Async::NotReady => break,
+                };
+
+                match bytes {
+                    None => {
+                        // EOF on the user input. This can happen in --no-input mode or,
+                        // in principle, if stdin is redirected in some way.
+                        break;
+                    },
+
+                    Some(b) => {
+                        saw_activity = true;
+                        let interrupt = state.hooks.interrupt;
+                        queue_user_input(&mut state.ssh_buf, &b, interrupt);
+                    }
+                }
+            }
+        }
+
+        let ssh_buf_full = state.ssh_buf.len() >= MAX_RELAY_BUF;
+
+        // Is SSH waiting for a password that we were given?
+
+        if !state.cancelling {
+            let st: &mut Communicating = &mut *state;
+
+            if let Some(ref mut responder) = st.hooks.password {
+                responder.answer_into(&mut st.ssh_buf);
+            }
+        }
+
+        // Has the user's terminal changed size?
+
+        {
+            let st: &mut Communicating = &mut *state;
+            poll_window_sizes(&mut st.hooks, &mut st.window_size)?;
+        }
+
+        // Has everyone gone quiet for too long? If there was activity, push
+        // back the deadline; polling the timer also registers our interest
+        // in being woken when it fires.
+
+        {
+            let st: &mut Communicating = &mut *state;
+
+            if let Some(ref mut timer) = st.idle_timer {
+                let idle_timeout = st.idle_timeout.unwrap();
+
+                if saw_activity {
+                    *timer = st.timers.timer(idle_timeout)?;
+                }
+
+                if let Async::Ready(()) = timer.poll()? {
+                    return Err(format_err!("no activity from the user or SSH for {} seconds; \
+                                            giving up (is SSH waiting for something?)",
+                                           idle_timeout.as_secs()));
+                }
+            }
+        }
+
+        // Ready/able to send bytes to the user?
+
+        state.start_send_to_user()?;
+
+        // Ready/able to send bytes to the daemon?
+
+        if state.pong_wanted {
+            if let AsyncSink::Ready = state.tx_ssh.start_send(ClientMessage::Pong)? {
+                state.pong_wanted = false;
+            }
+        }
+
+        if state.cancel_wanted {
+            if let AsyncSink::Ready = state.tx_ssh.start_send(ClientMessage::Cancel)? {
+                state.cancel_wanted = false;
+            }
+        }
+
+        if let Some((rows, cols)) = state.window_size {
+            if state.cancelling {
+                state.window_size = None;
+            } else if let AsyncSink::Ready = state.tx_ssh.start_send(ClientMessage::WindowSize { rows, cols })? {
+                state.window_size = None;
+            }
+        }
+
+        if state.ssh_buf.len() != 0 && !state.cancelling {
+            let buf = state.ssh_buf.clone();
+
+            if let AsyncSink::Ready = state.tx_ssh.start_send(ClientMessage::UserData(buf))? {
+                state.ssh_buf.clear();
+            }
+        }
+
+        // Gotta flush those transmissions. Both sinks have to be polled
+        // every time, so that each can wake us when it makes progress: a
+        // user who is slow to take SSH's output mustn't hold up what they
+        // typed on its way to SSH.
+
+        state.tx_user.poll_complete()?;
+        state.tx_ssh.poll_complete()?;
+
+        // If we stopped reading because a buffer was full, and it now has
+        // room, nothing else will wake us to read more, so we must.
+
+        if (user_buf_full && state.user_buf.len() < MAX_RELAY_BUF) ||
+            (ssh_buf_full && state.ssh_buf.len() < MAX_RELAY_BUF) {
+            task::current().notify();
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+/// Queue up bytes that the user typed to be sent to SSH. If they include
+/// the interrupt byte, whatever came before it is dropped, so that the
+/// interrupt goes out with the next message instead of waiting its turn.
+fn queue_user_input(buf: &mut Vec<u8>, input: &[u8], interrupt: Option<u8>) {
+    match interrupt.and_then(|i| input.iter().rposition(|b| *b == i)) {
+        Some(pos) => {
+            buf.clear();
+            buf.extend_from_slice(&input[pos..]);
+        },
+        None => buf.extend_from_slice(input),
+    }
+}
+
+/// Collect any new terminal sizes for an open. Only the latest matters, so
+/// it replaces any that hasn't been sent yet.
+fn poll_window_sizes(hooks: &mut OpenHooks, pending: &mut Option<(u16, u16)>) -> Result<(), Error> {
+    loop {
+        let item = match hooks.window_sizes {
+            Some(ref mut sizes) => sizes.poll()?,
+            None => break,
+        };
+
+        match item {
+            Async::Ready(Some(size)) => *pending = Some(size),
+            Async::Ready(None) => hooks.window_sizes = None,
+            Async::NotReady => break,
+        }
+    }
+
+    Ok(())
+}
+
+impl Communicating {
+    /// Hand the output buffered up for the user to their sink, if it will
+    /// take it. If it won't, the output stays in `user_buf` -- with anything
+    /// that arrives in the meantime appended to it -- and the sink wakes us
+    /// once it's ready for another try.
+    fn start_send_to_user(&mut self) -> Result<(), Error> {
+        if !self.user_buf.is_empty() {
+            let buf = mem::take(&mut self.user_buf);
+
+            if let AsyncSink::NotReady(buf) = self.tx_user.start_send(buf)? {
+                self.user_buf = buf;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use clock::FakeClock;
+    use futures::sync::mpsc;
+    use futures::task;
+    use std::cell::Cell;
+    use std::fs;
+    use std::sync::Arc;
+    use super::*;
+
+    type ServerSer = WriteBincode<FramedWrite<WriteHalf<UnixStream>>, ServerMessage>;
+    type ServerDe = ReadBincode<FramedRead<ReadHalf<UnixStream>>, ClientMessage>;
+
+    /// Set up a `Connection` talking to a fake daemon, which has already
+    /// sent the given messages. The daemon's end of the socket is returned
+    /// so that it stays open and so that we can see what the client sent.
+    fn connection_with_replies(replies: Vec<ServerMessage>) -> (Connection, ServerSer, ServerDe) {
+        let mut core = Core::new().unwrap();
+        let (client, server) = UnixStream::pair(&core.handle()).unwrap();
+
+        let (read, write) = client.split();
+        let ser = WriteBincode::new(FramedWrite::new(write));
+        let de = ReadBincode::new(FramedRead::new(read));
+
+        let (read, write) = server.split();
+        let server_de: ServerDe = ReadBincode::new(FramedRead::new(read));
+        let mut server_ser: ServerSer = WriteBincode::new(FramedWrite::new(write));
+
+        for reply in replies {
+            server_ser = core.run(server_ser.send(reply)).unwrap();
+        }
+
+        let conn = Connection { core, ser, de, sock_path: PathBuf::new(), clock: SystemClock::shared(),
+                                window_sizes: None, hide_banners: false,
+                                interrupt_byte: Some(DEFAULT_INTERRUPT_BYTE), deferral: None };
+        (conn, server_ser, server_de)
+    }
+
+    /// Like `connection_with_replies`, but the daemon stops sending after
+    /// its replies, as if it had exited. It still reads what the client
+    /// sends, though.
+    fn connection_that_hangs_up(replies: Vec<ServerMessage>) -> (Connection, ServerDe) {
+        let mut core = Core::new().unwrap();
+        let (client, server) = StdUnixStream::pair().unwrap();
+        let server_shutdown = server.try_clone().unwrap();
+
+        let (read, write) = UnixStream::from_stream(client, &core.handle()).unwrap().split();
+        let ser = WriteBincode::new(FramedWrite::new(write));
+        let de = ReadBincode::new(FramedRead::new(read));
+
+        let (read, write) = UnixStream::from_stream(server, &core.handle()).unwrap().split();
+        let server_de: ServerDe = ReadBincode::new(FramedRead::new(read));
+        let mut server_ser: ServerSer = WriteBincode::new(FramedWrite::new(write));
+
+        for reply in replies {
+            server_ser = core.run(server_ser.send(reply)).unwrap();
+        }
+
+        server_shutdown.shutdown(::std::net::Shutdown::Write).unwrap();
+
+        let conn = Connection { core, ser, de, sock_path: PathBuf::new(), clock: SystemClock::shared(),
+                                window_sizes: None, hide_banners: false,
+                                interrupt_byte: Some(DEFAULT_INTERRUPT_BYTE), deferral: None };
+        (conn, server_de)
+    }
+
+    fn open_params(host: &str) -> OpenParameters {
+        OpenParameters {
+            host: host.to_owned(),
+            port: None,
+            force: false,
+            connect_retries: 0,
+            env: Vec::new(),
+            allow_askpass: false,
+            user: None,
+            separate_stderr: false,
+            report_key: true, // the client always sets this
+            agent_socket: None,
+            control_master: false,
+            connect_timeout_secs: None,
+            reap_after_idle_secs: None,
+            close_if_abandoned: false,
+            report_pid: true, // and this
+            verbose: 0,
+        }
+    }
+
+    /// Run an open against the canned replies, returning its result and
+    /// everything that was relayed to the user.
+    fn open_with_replies(replies: Vec<ServerMessage>) -> (Result<OpenResult, Error>, Vec<u8>) {
+        let (conn, _ser, _de) = connection_with_replies(replies);
+        let (tx, rx) = mpsc::unbounded();
+        let tx = tx.sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+
+        let result = conn.send_open(open_params("example.com"), tx, stream::empty(), None)
+            .map(|(result, _conn)| result);
+        let output = rx.concat2().wait().unwrap();
+        (result, output)
+    }
+
+    #[test]
+    fn jitter_only_ever_shortens_delays() {
+        let delay = Duration::from_millis(1000);
+
+        assert_eq!(jittered_delay(delay, 0.5, 0.), delay);
+        assert_eq!(jittered_delay(delay, 0.5, 0.5), Duration::from_millis(750));
+        assert_eq!(jittered_delay(delay, 0., 0.9), delay);
+        assert_eq!(jittered_delay(delay, 7., 0.25), Duration::from_millis(750));
+        assert_eq!(jittered_delay(delay, -1., 0.25), delay);
+    }
+
+    #[test]
+    fn establish_at_uses_the_given_socket() {
+        use std::os::unix::net::UnixListener as StdUnixListener;
+
+        let path = env::temp_dir().join(format!("stund-test-establish-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let err = Connection::establish_at(&path, false).err().unwrap();
+        assert_eq!(err.downcast_ref::<DaemonNotRunning>().unwrap().socket, path);
+
+        let _listener = StdUnixListener::bind(&path).unwrap();
+        let conn = Connection::establish_at(&path, false).unwrap();
+        assert_eq!(conn.sock_path, path);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ssh_output_right_behind_first_ack_is_relayed() {
+        let (result, output) = open_with_replies(vec![
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"Password: ".to_vec()),
+            ServerMessage::SshData(b"welcome".to_vec()),
+            ServerMessage::Ok,
+        ]);
+
+        assert_eq!(result.unwrap(), OpenResult::Success { key: "example.com".to_owned(), pid: None });
+        assert_eq!(output, b"Password: welcome");
+    }
+
+    #[test]
+    fn ssh_output_ahead_of_first_ack_is_relayed() {
+        let (result, output) = open_with_replies(vec![
+            ServerMessage::SshData(b"Password: ".to_vec()),
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"welcome".to_vec()),
+            ServerMessage::Ok,
+        ]);
+
+        assert_eq!(result.unwrap(), OpenResult::Success { key: "example.com".to_owned(), pid: None });
+        assert_eq!(output, b"Password: welcome");
+    }
+
+    #[test]
+    fn ssh_output_before_an_error_is_relayed() {
+        let (result, output) = open_with_replies(vec![
+            ServerMessage::TunnelReplaced,
+            ServerMessage::SshData(b"Permission denied".to_vec()),
+            ServerMessage::ErrorCode(ProtocolError::AuthFailed, "login failed".to_owned()),
+        ]);
+
+        let err = result.unwrap_err();
+        assert_eq!(err.downcast_ref::<DaemonError>().unwrap().code, ProtocolError::AuthFailed);
+        assert_eq!(output, b"Permission denied");
+    }
+
+    #[test]
+    fn ssh_output_before_a_hangup_is_relayed() {
+        let (conn, _de) = connection_that_hangs_up(vec![
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"Connection closed".to_vec()),
+        ]);
+
+        let (tx, rx) = mpsc::unbounded();
+        let tx = tx.sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+        let result = conn.send_open(open_params("example.com"), tx, stream::empty(), None);
+        let output = rx.concat2().wait().unwrap();
+
+        assert!(result.is_err());
+        assert_eq!(output, b"Connection closed");
+    }
+
+    #[test]
+    fn connect_failure_is_reported_after_output() {
+        let (result, output) = open_with_replies(vec![
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"Connection refused".to_vec()),
+            ServerMessage::ConnectFailed(3),
+        ]);
+
+        assert_eq!(result.unwrap(), OpenResult::ConnectFailed(3));
+        assert_eq!(output, b"Connection refused");
+    }
+
+    #[test]
+    fn cancel_before_first_ack_is_sent() {
+        let (conn, _ser, de) = connection_with_replies(vec![
+            ServerMessage::Ok,
+            ServerMessage::Cancelled,
+        ]);
+        let (tx_cancel, rx_cancel) = oneshot::channel();
+        tx_cancel.send(()).unwrap();
+
+        let tx_user = Vec::new().sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+        let (result, mut conn) = conn.send_open_cancellable(open_params("example.com"), tx_user,
+                                                            stream::empty(), None, rx_cancel)
+            .unwrap();
+        assert_eq!(result, OpenResult::Cancelled);
+
+        let sent = conn.core.run(de.take(2).collect()).unwrap();
+        assert_eq!(sent, vec![ClientMessage::Open(open_params("example.com")), ClientMessage::Cancel]);
+    }
+
+    #[test]
+    fn only_the_latest_window_size_before_first_ack_is_sent() {
+        let (mut conn, ser, de) = connection_with_replies(vec![ServerMessage::Ok]);
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let sent2 = sent.clone();
+
+        // The fake daemon finishes the login once it has heard about the
+        // terminal.
+
+        conn.core.handle().spawn(de.take(2).collect()
+            .map_err(|e| panic!("fake daemon failed to read: {}", e))
+            .and_then(move |msgs| {
+                *sent2.borrow_mut() = msgs;
+                ser.send(ServerMessage::Ok).map_err(|e| panic!("fake daemon failed to reply: {}", e))
+            })
+            .map(|_| ()));
+
+        conn.relay_window_sizes(stream::iter_ok(vec![(24, 80), (40, 100)]));
+        let tx_user = Vec::new().sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+        let (result, _conn) = conn.send_open(open_params("example.com"), tx_user, stream::empty(), None)
+            .unwrap();
+        assert_eq!(result, OpenResult::Success { key: "example.com".to_owned(), pid: None });
+
+        let mut expected = open_params("example.com");
+        expected.report_key = true;
+        assert_eq!(*sent.borrow(), vec![ClientMessage::Open(expected),
+                                        ClientMessage::WindowSize { rows: 40, cols: 100 }]);
+    }
+
+    #[test]
+    fn supplied_passwords_answer_only_the_first_prompt() {
+        let (conn, ser, de) = connection_with_replies(vec![
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"Warning: hello\r\nalice@example.com's pass".to_vec()),
+            ServerMessage::SshData(b"word: ".to_vec()),
+        ]);
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let sent2 = sent.clone();
+
+        conn.core.handle().spawn(de.take(2).collect()
+            .map_err(|e| panic!("fake daemon failed to read: {}", e))
+            .and_then(move |msgs| {
+                *sent2.borrow_mut() = msgs;
+                ser.send(ServerMessage::Ok).map_err(|e| panic!("fake daemon failed to reply: {}", e))
+            })
+            .map(|_| ()));
+
+        let tx_user = Vec::new().sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+        let (result, _conn) = conn.send_open_with_password(open_params("example.com"), "hunter2".to_owned(),
+                                                           tx_user, stream::empty(), None)
+            .unwrap();
+        assert_eq!(result, OpenResult::Success { key: "example.com".to_owned(), pid: None });
+        assert_eq!(sent.borrow()[1], ClientMessage::UserData(b"hunter2\n".to_vec()));
+
+        let mut responder = PasswordResponder::new("hunter2".to_owned());
+        let mut buf = Vec::new();
+        responder.notice(b"Enter passphrase for key '/home/alice/.ssh/id_rsa': ");
+        responder.answer_into(&mut buf);
+        assert!(buf.is_empty());
+
+        responder.notice(b"\r\nPassword: ");
+        responder.answer_into(&mut buf);
+        assert_eq!(buf, b"hunter2\n");
+        assert_eq!(responder.password, vec![0; 7]);
+
+        responder.notice(b"\r\nPermission denied, please try again.\r\nPassword: ");
+        responder.answer_into(&mut buf);
+        assert_eq!(buf, b"hunter2\n");
+    }
+
+    #[test]
+    fn interrupts_jump_ahead_of_unsent_input() {
+        let (conn, ser, de) = connection_with_replies(vec![ServerMessage::Ok]);
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let sent2 = sent.clone();
+
+        conn.core.handle().spawn(de.take(2).collect()
+            .map_err(|e| panic!("fake daemon failed to read: {}", e))
+            .and_then(move |msgs| {
+                *sent2.borrow_mut() = msgs;
+                ser.send(ServerMessage::Ok).map_err(|e| panic!("fake daemon failed to reply: {}", e))
+            })
+            .map(|_| ()));
+
+        let typed = vec![b"cat /dev/urandom".to_vec(), b"\r\x03ls".to_vec()];
+        let tx_user = Vec::new().sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+        let (result, _conn) = conn.send_open(open_params("example.com"), tx_user, stream::iter_ok(typed), None)
+            .unwrap();
+        assert_eq!(result, OpenResult::Success { key: "example.com".to_owned(), pid: None });
+        assert_eq!(sent.borrow()[1], ClientMessage::UserData(b"\x03ls".to_vec()));
+
+        let mut buf = b"cat".to_vec();
+        queue_user_input(&mut buf, b"\x03", None);
+        assert_eq!(buf, b"cat\x03");
+    }
+
+    #[test]
+    fn separate_stderr_is_kept_separate() {
+        let (conn, _ser, de) = connection_with_replies(vec![
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"welcome".to_vec()),
+            ServerMessage::SshStderr(b"Warning: new host key".to_vec()),
+            ServerMessage::Ok,
+        ]);
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let errors2 = errors.clone();
+
+        let (tx, rx) = mpsc::unbounded();
+        let tx = tx.sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+        let (result, mut conn) = conn.send_open_with_stderr(
+            open_params("example.com"), tx, stream::empty(), None,
+            move |data| errors2.borrow_mut().extend_from_slice(data)
+        ).unwrap();
+
+        assert_eq!(result, OpenResult::Success { key: "example.com".to_owned(), pid: None });
+        assert_eq!(&errors.borrow()[..], b"Warning: new host key");
+        assert_eq!(rx.concat2().wait().unwrap(), b"welcome");
+
+        let mut expected = open_params("example.com");
+        expected.separate_stderr = true;
+        let sent = conn.core.run(de.take(1).collect()).unwrap();
+        assert_eq!(sent, vec![ClientMessage::Open(expected)]);
+    }
+
+    #[test]
+    fn open_reports_the_daemons_key_and_pid_for_the_tunnel() {
+        let (conn, _ser, de) = connection_with_replies(vec![
+            ServerMessage::TunnelReplaced,
+            ServerMessage::SshData(b"welcome".to_vec()),
+            ServerMessage::TunnelPid(4321),
+            ServerMessage::TunnelKey("work".to_owned()),
+            ServerMessage::Ok,
+        ]);
+
+        let tx_user = Vec::new().sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+        let mut params = open_params("alice@example.com");
+        params.report_key = false;
+        params.report_pid = false;
+        let (result, mut conn) = conn.send_open(params, tx_user, stream::empty(), None).unwrap();
+        assert_eq!(result, OpenResult::Replaced { key: "work".to_owned(), pid: Some(4321) });
+
+        let sent = conn.core.run(de.take(1).collect()).unwrap();
+        assert_eq!(sent, vec![ClientMessage::Open(open_params("alice@example.com"))]);
+    }
+
+    #[test]
+    fn tunnel_handles_use_the_daemons_key() {
+        let (conn, _ser, de) = connection_with_replies(vec![
+            ServerMessage::Ok,
+            ServerMessage::TunnelKey("work".to_owned()),
+            ServerMessage::Ok,
+            ServerMessage::Ok,
+        ]);
+
+        let handle = conn.open_tunnel(open_params("example.com"), Duration::from_secs(5)).unwrap();
+        assert_eq!(handle.key(), "work");
+        assert_eq!(handle.open_result(), &OpenResult::Success { key: "work".to_owned(), pid: None });
+
+        let (result, mut conn) = handle.close().unwrap();
+        assert_eq!(result, CloseResult::Success);
+
+        let sent = conn.core.run(de.take(2).collect()).unwrap();
+        assert_eq!(sent[1], ClientMessage::Close(CloseParameters { host: "work".to_owned(), grace_secs: None }));
+    }
+
+    /// A writer that accepts a certain number of bytes and then won't take
+    /// any more.
+    struct FullWriter {
+        contents: Rc<RefCell<Vec<u8>>>,
+        space: usize,
+    }
+
+    impl Write for FullWriter {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            let n = data.len().min(self.space);
+            self.contents.borrow_mut().extend_from_slice(&data[..n]);
+            self.space -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn transcript_failure_does_not_stop_the_open() {
+        let (conn, _ser, _de) = connection_with_replies(vec![
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"Password: ".to_vec()),
+            ServerMessage::SshData(b"welcome".to_vec()),
+            ServerMessage::Ok,
+        ]);
+        let contents = Rc::new(RefCell::new(Vec::new()));
+        let transcript = FullWriter { contents: contents.clone(), space: 12 };
+
+        let (tx, rx) = mpsc::unbounded();
+        let tx = tx.sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+        let (result, failure, _conn) = conn.send_open_with_transcript(
+            open_params("example.com"), tx, stream::empty(), None, transcript
+        ).unwrap();
+
+        assert_eq!(result, OpenResult::Success { key: "example.com".to_owned(), pid: None });
+        assert_eq!(failure.unwrap().kind(), io::ErrorKind::WriteZero);
+        assert_eq!(&contents.borrow()[..], b"Password: we");
+        assert_eq!(rx.concat2().wait().unwrap(), b"Password: welcome");
+    }
+
+    /// A user sink that takes one chunk of output at a time, and only gets
+    /// a few bytes of it out each time it's flushed, like a slow terminal.
+    /// If `stuck`, it never gets anything out at all.
+    struct SlowSink {
+        pending: Vec<u8>,
+        written: Rc<RefCell<Vec<u8>>>,
+        stuck: bool,
+    }
+
+    impl Sink for SlowSink {
+        type SinkItem = Vec<u8>;
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, item: Vec<u8>) -> StartSend<Vec<u8>, io::Error> {
+            if !self.pending.is_empty() {
+                return Ok(AsyncSink::NotReady(item));
+            }
+
+            self.pending = item;
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            if self.stuck {
+                return Ok(Async::NotReady);
+            }
+
+            let n = self.pending.len().min(3);
+            self.written.borrow_mut().extend(self.pending.drain(..n));
+
+            if self.pending.is_empty() {
+                Ok(Async::Ready(()))
+            } else {
+                // Pretend that the terminal is ready for more straight away.
+                task::current().notify();
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    #[test]
+    fn slow_user_sinks_get_all_of_the_output() {
+        let (conn, _ser, _de) = connection_with_replies(vec![
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"Password: ".to_vec()),
+            ServerMessage::SshData(b"welcome to ".to_vec()),
+            ServerMessage::SshData(b"example.com".to_vec()),
+            ServerMessage::Ok,
+        ]);
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let tx_user = SlowSink { pending: Vec::new(), written: written.clone(), stuck: false };
+
+        let (result, _conn) = conn.send_open(open_params("example.com"), tx_user, stream::empty(), None)
+            .unwrap();
+
+        assert_eq!(result, OpenResult::Success { key: "example.com".to_owned(), pid: None });
+        assert_eq!(&written.borrow()[..], b"Password: welcome to example.com");
+    }
+
+    #[test]
+    fn backed_up_user_sinks_dont_hold_up_user_input() {
+        let (conn, _ser, de) = connection_with_replies(vec![
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"Password: ".to_vec()),
+        ]);
+        let Connection { mut core, ser, de: client_de, .. } = conn;
+
+        let tx_user = SlowSink { pending: Vec::new(), written: Rc::new(RefCell::new(Vec::new())), stuck: true };
+        let rx_user = stream::once(Ok(b"hunter2\n".to_vec()));
+        let mut wf = OpenWorkflow::start(ser.send(ClientMessage::Open(open_params("example.com"))),
+                                         client_de, Box::new(tx_user), Box::new(rx_user),
+                                         TimerSource { handle: core.handle(), clock: SystemClock::shared() },
+                                         None, OpenHooks::default());
+
+        for _ in 0..5 {
+            assert!(poll_once(&mut core, &mut wf).unwrap().is_not_ready());
+        }
+
+        // The user's terminal is wedged, but what they typed should still
+        // have been sent on its way.
+
+        let mut sent = de.take(2).collect();
+
+        match poll_once(&mut core, &mut sent) {
+            Ok(Async::Ready(sent)) => assert_eq!(sent[1], ClientMessage::UserData(b"hunter2\n".to_vec())),
+            Ok(Async::NotReady) => panic!("the user's input never reached the daemon"),
+            Err(e) => panic!("error reading from the client: {}", e),
+        }
+    }
+
+    #[test]
+    fn reopening_leaves_open_tunnels_alone() {
+        let (conn, _ser, de) = connection_with_replies(vec![ServerMessage::TunnelAlreadyOpen]);
+        let mut p = open_params("example.com");
+        p.force = true;
+
+        let (results, conn) = conn.reopen_all(vec![p], Duration::from_secs(5));
+        assert_eq!(results.len(), 1);
+        assert_eq!(*results[0].1.as_ref().unwrap(), OpenResult::AlreadyOpen);
+
+        let mut sent = de.take(1).collect();
+
+        match poll_once(&mut conn.unwrap().core, &mut sent) {
+            Ok(Async::Ready(sent)) => match sent[0] {
+                ClientMessage::Open(ref p) => assert!(!p.force),
+                ref other => panic!("unexpected message from the client: {:?}", other),
+            },
+            Ok(Async::NotReady) => panic!("the open never reached the daemon"),
+            Err(e) => panic!("error reading from the client: {}", e),
+        }
+    }
+
+    /// A user sink that takes a chunk of output of any size, but then
+    /// needs another poll before it will take the next, noting the size of
+    /// the largest chunk it has been given.
+    struct ChunkySink {
+        busy: bool,
+        total: Rc<RefCell<usize>>,
+        largest: Rc<RefCell<usize>>,
+    }
+
+    impl Sink for ChunkySink {
+        type SinkItem = Vec<u8>;
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, item: Vec<u8>) -> StartSend<Vec<u8>, io::Error> {
+            if self.busy {
+                return Ok(AsyncSink::NotReady(item));
+            }
+
+            self.busy = true;
+            *self.total.borrow_mut() += item.len();
+            let mut largest = self.largest.borrow_mut();
+            *largest = (*largest).max(item.len());
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            if self.busy {
+                self.busy = false;
+                task::current().notify();
+                return Ok(Async::NotReady);
+            }
+
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn floods_of_output_are_buffered_a_bit_at_a_time() {
+        const CHUNK: usize = 8 * 1024;
+        const N_CHUNKS: usize = 64;
+
+        let (conn, ser, _de) = connection_with_replies(vec![ServerMessage::Ok]);
+        let mut flood: Vec<Result<_, io::Error>> = (0..N_CHUNKS)
+            .map(|_| Ok(ServerMessage::SshData(vec![b'x'; CHUNK])))
+            .collect();
+        flood.push(Ok(ServerMessage::Ok));
+
+        conn.core.handle().spawn(ser.send_all(stream::iter_result(flood))
+            .map(|_| ())
+            .map_err(|e| panic!("fake daemon failed to send: {}", e)));
+
+        let total = Rc::new(RefCell::new(0));
+        let largest = Rc::new(RefCell::new(0));
+        let tx_user = ChunkySink { busy: false, total: total.clone(), largest: largest.clone() };
+        let (result, _conn) = conn.send_open(open_params("example.com"), tx_user, stream::empty(), None)
+            .unwrap();
+
+        assert_eq!(result, OpenResult::Success { key: "example.com".to_owned(), pid: None });
+        assert_eq!(*total.borrow(), CHUNK * N_CHUNKS);
+        assert!(*largest.borrow() < MAX_RELAY_BUF + CHUNK);
+    }
+
+    /// Poll a future once, in the context of a task, after giving the
+    /// reactor a chance to notice any I/O.
+    fn poll_once<F: Future>(core: &mut Core, f: &mut F) -> Poll<F::Item, F::Error> {
+        core.turn(Some(Duration::from_millis(10)));
+        core.run(future::lazy(|| Ok::<_, ()>(f.poll()))).unwrap()
+    }
+
+    /// Run an open against the canned replies with banners hidden,
+    /// returning its result and everything that was relayed to the user.
+    fn open_hiding_banners(replies: Vec<ServerMessage>) -> (Result<OpenResult, Error>, Vec<u8>) {
+        let (mut conn, _ser, _de) = connection_with_replies(replies);
+        conn.set_hide_banners(true);
+        let (tx, rx) = mpsc::unbounded();
+        let tx = tx.sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+
+        let result = conn.send_open(open_params("example.com"), tx, stream::empty(), None)
+            .map(|(result, _conn)| result);
+        let output = rx.concat2().wait().unwrap();
+        (result, output)
+    }
+
+    #[test]
+    fn hidden_banners_give_way_to_prompts() {
+        let (result, output) = open_hiding_banners(vec![
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"Welcome to example.com!\r\n\r\nalice@example.com's ".to_vec()),
+            ServerMessage::SshData(b"password: ".to_vec()),
+            ServerMessage::SshData(b"\r\nLast login: yesterday\r\n".to_vec()),
+            ServerMessage::Ok,
+        ]);
+        assert_eq!(result.unwrap(), OpenResult::Success { key: "example.com".to_owned(), pid: None });
+        assert_eq!(output, b"alice@example.com's password: \r\nLast login: yesterday\r\n");
+
+        let (result, output) = open_hiding_banners(vec![
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"Welcome to example.com!\r\n".to_vec()),
+            ServerMessage::Ok,
+        ]);
+        assert_eq!(result.unwrap(), OpenResult::Success { key: "example.com".to_owned(), pid: None });
+        assert_eq!(output, b"");
+
+        let (result, output) = open_hiding_banners(vec![
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"Welcome to example.com!\r\n".to_vec()),
+            ServerMessage::ErrorCode(ProtocolError::AuthFailed, "SSH exited".to_owned()),
+        ]);
+        assert!(result.is_err());
+        assert_eq!(output, b"Welcome to example.com!\r\n");
+    }
+
+    #[test]
+    fn hidden_banners_give_way_when_ssh_goes_quiet() {
+        let (conn, _ser, _de) = connection_with_replies(vec![
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"Welcome!\r\nType the magic word> ".to_vec()),
+        ]);
+        let Connection { mut core, ser, de, .. } = conn;
+        let clock = Arc::new(FakeClock::new());
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let tx_user = SlowSink { pending: Vec::new(), written: written.clone(), stuck: false };
+        let hooks = OpenHooks { banner: Some(BannerFilter::default()), ..OpenHooks::default() };
+
+        let mut wf = OpenWorkflow::start(ser.send(ClientMessage::Open(open_params("example.com"))),
+                                         de, Box::new(tx_user), Box::new(stream::empty()),
+                                         TimerSource { handle: core.handle(), clock: clock.clone() },
+                                         None, hooks);
+
+        for _ in 0..5 {
+            assert!(poll_once(&mut core, &mut wf).unwrap().is_not_ready());
+        }
+
+        assert_eq!(&written.borrow()[..], b"");
+        clock.advance(BANNER_QUIET_PERIOD);
+
+        for _ in 0..10 {
+            assert!(poll_once(&mut core, &mut wf).unwrap().is_not_ready());
+        }
+
+        assert_eq!(&written.borrow()[..], b"Type the magic word> ");
+    }
+
+    #[test]
+    fn deferred_opens_involve_the_user_only_when_prompted() {
+        // A login that needs nothing from the user never hears from them.
+
+        let (mut conn, _ser, _de) = connection_with_replies(vec![
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"Welcome to example.com!\r\n".to_vec()),
+            ServerMessage::Ok,
+        ]);
+        let called = Rc::new(Cell::new(false));
+        let called2 = called.clone();
+        conn.defer_interaction(Duration::from_secs(30), move || called2.set(true));
+
+        let (tx, rx) = mpsc::unbounded();
+        let tx = tx.sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+        let rx_user = stream::poll_fn(|| -> Poll<Option<Vec<u8>>, io::Error> {
+            panic!("the user was asked for input")
+        });
+        let (result, _conn) = conn.send_open(open_params("example.com"), tx, rx_user, None).unwrap();
+        assert_eq!(result, OpenResult::Success { key: "example.com".to_owned(), pid: None });
+        assert_eq!(rx.concat2().wait().unwrap(), b"");
+        assert!(!called.get());
+
+        // One that asks for a password brings them in.
+
+        let (mut conn, ser, de) = connection_with_replies(vec![
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"Welcome to example.com!\r\nalice@example.com's password: ".to_vec()),
+        ]);
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let sent2 = sent.clone();
+
+        conn.core.handle().spawn(de.take(2).collect()
+            .map_err(|e| panic!("fake daemon failed to read: {}", e))
+            .and_then(move |msgs| {
+                *sent2.borrow_mut() = msgs;
+                ser.send(ServerMessage::Ok).map_err(|e| panic!("fake daemon failed to reply: {}", e))
+            })
+            .map(|_| ()));
+
+        let called = Rc::new(Cell::new(false));
+        let called2 = called.clone();
+        conn.defer_interaction(Duration::from_secs(30), move || called2.set(true));
+
+        let (tx, rx) = mpsc::unbounded();
+        let tx = tx.sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+        let rx_user = stream::once(Ok(b"hunter2\n".to_vec()));
+        let (result, _conn) = conn.send_open(open_params("example.com"), tx, rx_user, None).unwrap();
+        assert_eq!(result, OpenResult::Success { key: "example.com".to_owned(), pid: None });
+        assert_eq!(rx.concat2().wait().unwrap(), b"alice@example.com's password: ");
+        assert_eq!(sent.borrow()[1], ClientMessage::UserData(b"hunter2\n".to_vec()));
+        assert!(called.get());
+    }
+
+    #[test]
+    fn deferred_opens_involve_the_user_after_the_grace_period() {
+        let (conn, _ser, _de) = connection_with_replies(vec![
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"Welcome!\r\nType the magic word> ".to_vec()),
+        ]);
+        let Connection { mut core, ser, de, .. } = conn;
+        let clock = Arc::new(FakeClock::new());
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let tx_user = SlowSink { pending: Vec::new(), written: written.clone(), stuck: false };
+        let called = Rc::new(Cell::new(false));
+        let called2 = called.clone();
+        let deferral = Deferral {
+            grace: Duration::from_secs(10),
+            timer: None,
+            on_interactive: Box::new(move || called2.set(true)),
+        };
+        let hooks = OpenHooks {
+            banner: Some(BannerFilter::default()),
+            deferral: Some(deferral),
+            ..OpenHooks::default()
+        };
+
+        let mut wf = OpenWorkflow::start(ser.send(ClientMessage::Open(open_params("example.com"))),
+                                         de, Box::new(tx_user), Box::new(stream::empty()),
+                                         TimerSource { handle: core.handle(), clock: clock.clone() },
+                                         None, hooks);
+
+        for _ in 0..5 {
+            assert!(poll_once(&mut core, &mut wf).unwrap().is_not_ready());
+        }
+
+        // A quiet spell isn't enough while the user is being kept out.
+
+        clock.advance(BANNER_QUIET_PERIOD);
+        assert!(poll_once(&mut core, &mut wf).unwrap().is_not_ready());
+        assert_eq!(&written.borrow()[..], b"");
+        assert!(!called.get());
+
+        clock.advance(Duration::from_secs(10));
+
+        for _ in 0..10 {
+            assert!(poll_once(&mut core, &mut wf).unwrap().is_not_ready());
+        }
+
+        assert_eq!(&written.borrow()[..], b"Type the magic word> ");
+        assert!(called.get());
+    }
+
+    #[test]
+    fn idle_open_gives_up_exactly_at_the_deadline() {
+        let (conn, _ser, _de) = connection_with_replies(vec![
+            ServerMessage::Ok,
+            ServerMessage::SshData(b"Password: ".to_vec()),
+        ]);
+        let Connection { mut core, ser, de, .. } = conn;
+        let clock = Arc::new(FakeClock::new());
+
+        let tx_user = Vec::new().sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+        let mut wf = OpenWorkflow::start(ser.send(ClientMessage::Open(open_params("example.com"))),
+                                         de, Box::new(tx_user), Box::new(stream::empty()),
+                                         TimerSource { handle: core.handle(), clock: clock.clone() },
+                                         Some(Duration::from_secs(10)), OpenHooks::default());
+
+        for _ in 0..5 {
+            assert!(poll_once(&mut core, &mut wf).unwrap().is_not_ready());
+        }
+
+        clock.advance(Duration::from_millis(9999));
+        assert!(poll_once(&mut core, &mut wf).unwrap().is_not_ready());
+
+        clock.advance(Duration::from_millis(1));
+        match poll_once(&mut core, &mut wf) {
+            Err(e) => assert!(e.to_string().starts_with("no activity"), "{}", e),
+            Ok(_) => panic!("the open didn't time out"),
+        }
     }
 }
@@ -0,0 +1,170 @@
+// Copyright 2018 Peter Williams <peter@newton.cx>
+// Licensed under the MIT License.
+
+//! Sources of time for the client and daemon timeouts.
+//!
+//! Everything that waits for a deadline asks a [`Clock`] for the time and
+//! for its timers, rather than going to the system directly. Normally this
+//! is the [`SystemClock`], but tests can substitute a [`FakeClock`] and move
+//! time forward by hand.
+
+use futures::{future, Future};
+use futures::sync::oneshot;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, Timeout};
+
+
+/// A future that resolves once a deadline has passed.
+pub type Timer = Box<Future<Item = (), Error = io::Error>>;
+
+/// A clock that can be shared between the parts of a program.
+pub type SharedClock = Arc<Clock + Send + Sync>;
+
+
+/// A source of the current time and of timers.
+pub trait Clock {
+    /// Get the current time.
+    fn now(&self) -> Instant;
+
+    /// Create a timer that fires at `deadline`. Timers can't be moved, so
+    /// callers wanting to push a deadline back should make a new one.
+    fn timer_at(&self, deadline: Instant, handle: &Handle) -> io::Result<Timer>;
+
+    /// Create a timer that fires once `duration` has passed.
+    fn timer(&self, duration: Duration, handle: &Handle) -> io::Result<Timer> {
+        self.timer_at(self.now() + duration, handle)
+    }
+}
+
+
+/// The real time, as kept by the system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl SystemClock {
+    /// Get a shareable handle on the system clock.
+    pub fn shared() -> SharedClock {
+        Arc::new(SystemClock)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn timer_at(&self, deadline: Instant, handle: &Handle) -> io::Result<Timer> {
+        Ok(Box::new(Timeout::new_at(deadline, handle)?))
+    }
+}
+
+
+/// A clock that only moves when it's told to, for testing.
+///
+/// It starts at the time that it was created. Its timers fire when `advance`
+/// takes it past their deadlines, and not otherwise.
+#[derive(Debug)]
+pub struct FakeClock {
+    inner: Mutex<FakeClockInner>,
+}
+
+#[derive(Debug)]
+struct FakeClockInner {
+    now: Instant,
+    timers: Vec<(Instant, oneshot::Sender<()>)>,
+}
+
+impl FakeClock {
+    /// Create a new fake clock, set to the current time.
+    pub fn new() -> Self {
+        FakeClock {
+            inner: Mutex::new(FakeClockInner {
+                now: Instant::now(),
+                timers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Move the clock forward, firing any timers whose deadlines have now
+    /// passed.
+    pub fn advance(&self, duration: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.now += duration;
+
+        let now = inner.now;
+        let (due, pending) = inner.timers.drain(..).partition(|t| t.0 <= now);
+        inner.timers = pending;
+
+        for (_, tx) in due {
+            let _r = tx.send(()); // fails if the timer was dropped
+        }
+    }
+}
+
+impl Default for FakeClock {
+    fn default() -> Self {
+        FakeClock::new()
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.inner.lock().unwrap().now
+    }
+
+    fn timer_at(&self, deadline: Instant, _handle: &Handle) -> io::Result<Timer> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if deadline <= inner.now {
+            return Ok(Box::new(future::ok(())));
+        }
+
+        // If the clock goes away, the timer will never fire, which is an
+        // error of sorts.
+        let (tx, rx) = oneshot::channel();
+        inner.timers.push((deadline, tx));
+        Ok(Box::new(rx.map_err(|_| io::ErrorKind::Other.into())))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::Async;
+    use tokio_core::reactor::Core;
+    use super::*;
+
+    #[test]
+    fn fake_timers_fire_exactly_at_their_deadlines() {
+        let mut core = Core::new().unwrap();
+        let clock = FakeClock::new();
+        let mut short = clock.timer(Duration::from_secs(10), &core.handle()).unwrap();
+        let mut long = clock.timer(Duration::from_secs(20), &core.handle()).unwrap();
+
+        let mut poll = |t: &mut Timer| core.run(future::lazy(|| Ok::<_, io::Error>(t.poll().unwrap())))
+            .unwrap();
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(poll(&mut short), Async::NotReady);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(poll(&mut short), Async::Ready(()));
+        assert_eq!(poll(&mut long), Async::NotReady);
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(poll(&mut long), Async::Ready(()));
+    }
+
+    #[test]
+    fn fake_timers_in_the_past_fire_at_once() {
+        let mut core = Core::new().unwrap();
+        let clock = FakeClock::new();
+        let then = clock.now();
+        clock.advance(Duration::from_secs(5));
+
+        let mut timer = clock.timer_at(then, &core.handle()).unwrap();
+        assert_eq!(core.run(future::lazy(|| timer.poll())).unwrap(), Async::Ready(()));
+    }
+}
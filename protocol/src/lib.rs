@@ -2,7 +2,7 @@
 // Licensed under the MIT License.
 
 #![deny(missing_docs)]
-#![doc(html_root_url = "https://docs.rs/stund_protocol/0.1.3")]
+#![doc(html_root_url = "https://docs.rs/stund_protocol/0.2.0")]
 
 //! The stund client-server communication protocol.
 //!
@@ -17,6 +17,7 @@
 #[macro_use] extern crate failure;
 #[macro_use] extern crate futures;
 extern crate libc;
+extern crate rand;
 extern crate serde;
 #[macro_use] extern crate serde_derive;
 #[macro_use] extern crate state_machine_future;
@@ -25,27 +26,140 @@ extern crate tokio_io;
 extern crate tokio_serde_bincode;
 extern crate tokio_uds;
 
-use failure::Error;
+use failure::{Error, Fail};
 use std::env;
-use std::path::PathBuf;
+use std::fmt;
+use std::mem;
+use std::path::{Path, PathBuf};
 
 pub mod client;
+pub mod clock;
+
+
+/// The version of this crate, which defines the protocol spoken by clients
+/// that use it.
+///
+/// Clients and daemons can only talk to each other if these agree in all
+/// but the last component; see `protocols_compatible`.
+pub const PROTOCOL_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+
+/// Determine whether two versions of the protocol can talk to each other.
+///
+/// Changes that break compatibility are marked by bumping the minor version
+/// (for these 0.x releases), so the last component doesn't matter.
+pub fn protocols_compatible(a: &str, b: &str) -> bool {
+    let significant = |v: &str| v.rsplit_once('.').map(|x| x.0.to_owned());
+    significant(a).is_some() && significant(a) == significant(b)
+}
+
+
+/// The names of optional features that a daemon may report in reply to a
+/// `Capabilities` message.
+///
+/// A client can check for these before relying on a feature, rather than
+/// sending a message that a daemon of a different version won't understand.
+/// Some depend on how the daemon was started, so they may be missing even
+/// from a daemon built with this version of the crate.
+pub mod capabilities {
+    /// The daemon pings idle clients, which must answer with `Pong`.
+    pub const KEEPALIVE: &str = "keepalive";
+
+    /// The daemon answers `Metrics` queries.
+    pub const METRICS: &str = "metrics";
+
+    /// The daemon answers `GetConfig` queries.
+    pub const CONFIG: &str = "config";
+
+    /// The daemon answers `GetLog` queries.
+    pub const LOG_QUERIES: &str = "log-queries";
+
+    /// The daemon answers `DumpState` queries.
+    pub const STATE_DUMPS: &str = "state-dumps";
+
+    /// The daemon understands `WaitForExit`.
+    pub const WAIT_FOR_EXIT: &str = "wait-for-exit";
+
+    /// The daemon understands `SignalTunnel`.
+    pub const SIGNALS: &str = "signals";
+
+    /// The daemon understands `TeeToFile` and `StopTee`.
+    pub const TEE: &str = "tee";
+
+    /// The daemon honors `OpenParameters::control_master`.
+    pub const CONTROL_MASTER: &str = "control-master";
+
+    /// The daemon understands `WindowSize` during opens.
+    pub const WINDOW_SIZE: &str = "window-size";
+
+    /// The daemon answers `QueryStatusFiltered` queries.
+    pub const FILTERED_STATUS: &str = "filtered-status";
+}
 
 
 /// Get the path to the Unix domain socket used for client/server communication.
 ///
-/// At the moment, this is fixed to `$HOME/.ssh/stund.sock`.
+/// This is the default, `$HOME/.ssh/stund.sock`; a daemon started with
+/// `--socket` listens elsewhere, and `client::Connection::establish_at`
+/// connects to it there. Returns an error if the path is too long to be used
+/// as a Unix socket address, which otherwise leads to obscure failures when
+/// binding or connecting.
 pub fn get_socket_path() -> Result<PathBuf, Error> {
     let mut p = env::home_dir().ok_or(format_err!("unable to determine your home directory"))?;
     p.push(".ssh");
     p.push("stund.sock");
+    check_socket_path(&p)?;
     Ok(p)
 }
 
 
+/// Check that a path can be used for the daemon's socket.
+pub fn check_socket_path(p: &Path) -> Result<(), Error> {
+    // The path has to fit in `sun_path` along with a terminating NUL.
+    let addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    let max_len = addr.sun_path.len() - 1;
+    let len = p.as_os_str().len();
+
+    if len > max_len {
+        return Err(format_err!("the socket path {} is {} bytes long, but this system only \
+                                allows Unix socket paths of up to {} bytes",
+                               p.display(), len, max_len));
+    }
+
+    Ok(())
+}
+
+
+/// Get the path of the SSH control socket for a tunnel opened with
+/// `control_master`, given the daemon's socket path and the tunnel's label.
+///
+/// The socket lives next to the daemon's. Characters in the label other
+/// than ASCII letters, digits, `.`, `-`, and `@` are spelled as `_` and two
+/// hex digits, so that every label gets a different file name that SSH won't
+/// try to expand.
+pub fn control_socket_path(sock_path: &Path, label: &str) -> PathBuf {
+    let mut name = "stund-".to_owned();
+
+    for b in label.bytes() {
+        if b.is_ascii_alphanumeric() || b == b'.' || b == b'-' || b == b'@' {
+            name.push(b as char);
+        } else {
+            name.push_str(&format!("_{:02x}", b));
+        }
+    }
+
+    name.push_str(".ctl");
+    sock_path.with_file_name(name)
+}
+
+
 /// A message that the client may send to the server.
 ///
-/// Some messages are only allowed in certain contexts.
+/// Some messages are only allowed in certain contexts. Messages are encoded
+/// by the index of their variant, so new variants must only ever be added at
+/// the end, and existing ones must never be reordered or change their
+/// payloads; otherwise clients and daemons of different versions will
+/// misunderstand each other.
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub enum ClientMessage {
     /// Open an SSH tunnel.
@@ -65,10 +179,272 @@ pub enum ClientMessage {
 
     /// End the session.
     Goodbye,
+
+    /// Change the label under which an existing tunnel is filed.
+    Relabel(RelabelParameters),
+
+    /// Ask the daemon for internal relay counters associated with a tunnel.
+    ///
+    /// This is a diagnostic tool for figuring out why a session has stalled.
+    QueryRelayCounters(RelayCountersParameters),
+
+    /// Check whether a tunnel is actually working.
+    CheckTunnel(CheckTunnelParameters),
+
+    /// Change the terminal size of a running tunnel's SSH process.
+    Resize(ResizeParameters),
+
+    /// In response to a `Ping` message, indicates that the client is still
+    /// alive.
+    Pong,
+
+    /// Ask the daemon for the most recent lines of its log.
+    ///
+    /// The daemon only honors this if it was started with log queries
+    /// enabled, since the log may contain sensitive information.
+    GetLog {
+        /// The maximum number of lines to return.
+        lines: usize,
+    },
+
+    /// Get ready for the daemon to be replaced by a new version.
+    ///
+    /// The daemon saves the parameters of its open tunnels to disk and stops
+    /// accepting requests to open new ones. Existing tunnels keep running
+    /// until the daemon is told to exit.
+    PrepareUpgrade,
+
+    /// Report the command line that would be used to open an SSH tunnel,
+    /// without actually opening it.
+    DryRunOpen(OpenParameters),
+
+    /// Give up on the tunnel that is being opened, killing its SSH process.
+    /// Only meaningful while an "Open" is being relayed; the daemon replies
+    /// with `Cancelled`, unless the login finished first.
+    Cancel,
+
+    /// Ask the daemon how many resources it is using.
+    QueryDaemonStats,
+
+    /// Restart a running tunnel with new parameters, keeping its label.
+    ///
+    /// The exchange that follows is the same as for `Open` with `force`
+    /// set, except that the daemon replies with `TunnelNotOpen` if there's
+    /// no such tunnel. If the new parameters are invalid, the daemon reports
+    /// an error and the old tunnel is left alone.
+    Reconfigure(ReconfigureParameters),
+
+    /// Tell the daemon to exit, with options controlling how. `Exit` is
+    /// equivalent to this with the default parameters.
+    ExitWithParameters(ExitParameters),
+
+    /// Ask the daemon which version of the protocol it speaks.
+    ///
+    /// Daemons that predate this message hang up on it, so clients should
+    /// send it first thing if they need to know.
+    QueryVersion,
+
+    /// Ask the daemon for a snapshot of everything it knows about its
+    /// tunnels, for diagnosing misbehaving ones.
+    ///
+    /// The daemon only honors this if it was started with state dumps
+    /// enabled.
+    DumpState,
+
+    /// Ask the daemon for the details of a single tunnel, given its label
+    /// or host. The daemon replies with a `ProtocolError::NotFound` error if
+    /// it has never heard of it.
+    TunnelStatus {
+        /// The label or host of the tunnel of interest.
+        host: String,
+    },
+
+    /// Tell the daemon to remove any sockets that it has had SSH create,
+    /// such as control sockets, that are no longer in use. The daemon also
+    /// does this when it starts up.
+    Cleanup,
+
+    /// Restrict this session to messages that only ask about the daemon's
+    /// state, such as `QueryStatus`. Anything that would change it is
+    /// refused with a `ProtocolError::Forbidden` error. There is no way to
+    /// lift the restriction short of starting a new session.
+    SetReadOnly,
+
+    /// Open a tunnel whose parameters are defined by the daemon, rather
+    /// than sent by the client.
+    ///
+    /// The daemon looks the name up in its profiles file, `stund.profiles.json`
+    /// next to its socket, which maps names to `OpenParameters` in JSON
+    /// form. It replies with a `ProtocolError::NotFound` error if there's no
+    /// such profile; otherwise the exchange is the same as for `Open`.
+    OpenProfile {
+        /// The name of the profile.
+        name: String,
+    },
+
+    /// Stop reading SSH's output for a tunnel, without otherwise disturbing
+    /// it. SSH blocks once the terminal's buffer fills, so nothing is lost.
+    /// The daemon replies with a `ProtocolError::NotFound` error if there's
+    /// no such running tunnel, and `ProtocolError::Busy` if it's still
+    /// logging in.
+    Pause {
+        /// The label or host of the tunnel to pause.
+        host: String,
+    },
+
+    /// Undo a `Pause`. Resuming a tunnel that isn't paused does nothing.
+    Resume {
+        /// The label or host of the tunnel to resume.
+        host: String,
+    },
+
+    /// Interrupt whatever a tunnel is running, without closing it.
+    ///
+    /// The daemon can't signal the remote process directly, so it types the
+    /// terminal's control character for the signal, such as Ctrl-C for
+    /// `SIGINT`, and leaves it to the remote terminal to deliver it. Only
+    /// `SIGINT`, `SIGQUIT` and `SIGTSTP` have such characters; other signals
+    /// are refused with a `ProtocolError::InvalidParameters` error. As with
+    /// `Pause`, the daemon replies with `ProtocolError::NotFound` if there's
+    /// no such running tunnel, and `ProtocolError::Busy` if it's still
+    /// logging in.
+    SignalTunnel {
+        /// The label or host of the tunnel to signal.
+        host: String,
+
+        /// The number of the signal to deliver.
+        signum: i32,
+    },
+
+    /// Wait for a tunnel's SSH process to go away, however that happens.
+    ///
+    /// The daemon replies with `TunnelExitResponse` once it does, which may
+    /// be much later, or straight away with a `ProtocolError::NotFound`
+    /// error if there's no such running tunnel.
+    WaitForExit {
+        /// The label or host of the tunnel of interest.
+        host: String,
+    },
+
+    /// Ask the daemon for its metrics, in the Prometheus text exposition
+    /// format, so that an exporter can pass them along to a monitoring
+    /// system. Metric names begin with `stund_`; per-tunnel metrics are
+    /// labeled with the tunnel's `label`, and with its `host` if it's
+    /// running.
+    Metrics,
+
+    /// Ask the daemon for the settings that it's running with, after
+    /// defaults have been applied. The daemon replies with `Config`.
+    GetConfig,
+
+    /// Have the daemon append a tunnel's terminal output to a file, as well
+    /// as doing whatever it would otherwise do with it, until told to stop.
+    ///
+    /// The path must be absolute. The file is created, readable only by the
+    /// daemon's user, if it doesn't exist. If writing to it fails, the
+    /// daemon logs the error and stops writing, leaving the tunnel alone.
+    /// As with `Pause`, the daemon replies with `ProtocolError::NotFound` if
+    /// there's no such running tunnel and `ProtocolError::Busy` if it's
+    /// still logging in; a bad path gets `ProtocolError::InvalidParameters`.
+    TeeToFile {
+        /// The label or host of the tunnel of interest.
+        host: String,
+
+        /// The file to write to.
+        path: String,
+    },
+
+    /// Undo a `TeeToFile`. Stopping a tunnel that isn't being written to a
+    /// file does nothing.
+    StopTee {
+        /// The label or host of the tunnel of interest.
+        host: String,
+    },
+
+    /// Ask the daemon which optional features it supports. The daemon
+    /// replies with `Capabilities`.
+    Capabilities,
+
+    /// During an open, tell the daemon that the user's terminal has changed
+    /// size, so that SSH's terminal can follow. There is no reply. The
+    /// daemon ignores any that arrive after the login has finished.
+    WindowSize {
+        /// The number of rows.
+        rows: u16,
+
+        /// The number of columns.
+        cols: u16,
+    },
+
+    /// Like `QueryStatus`, but the daemon only reports the tunnels that
+    /// pass the filter.
+    QueryStatusFiltered(StatusFilter),
+}
+
+impl ClientMessage {
+    /// Describe this message in a form that is safe to log.
+    ///
+    /// User input may contain passwords, so it is only described by its
+    /// size. The parameters of opens are reduced to the host, since the
+    /// environment variables given to SSH may hold secrets too.
+    pub fn summary(&self) -> String {
+        match self {
+            ClientMessage::UserData(data) => format!("UserData: {} bytes", data.len()),
+            ClientMessage::Open(params) => format!("Open: {}", params.host),
+            ClientMessage::DryRunOpen(params) => format!("DryRunOpen: {}", params.host),
+            ClientMessage::Reconfigure(p) => format!("Reconfigure: {} as {}", p.label, p.params.host),
+            other => format!("{:?}", other),
+        }
+    }
+
+    /// Whether this message leaves the daemon's state alone, so that it may
+    /// be sent by a session that has been made read-only.
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            ClientMessage::QueryStatus |
+            ClientMessage::Goodbye |
+            ClientMessage::QueryRelayCounters(_) |
+            ClientMessage::CheckTunnel(_) |
+            ClientMessage::Pong |
+            ClientMessage::GetLog { .. } |
+            ClientMessage::DryRunOpen(_) |
+            ClientMessage::QueryDaemonStats |
+            ClientMessage::QueryVersion |
+            ClientMessage::DumpState |
+            ClientMessage::TunnelStatus { .. } |
+            ClientMessage::SetReadOnly |
+            ClientMessage::WaitForExit { .. } |
+            ClientMessage::Metrics |
+            ClientMessage::GetConfig |
+            ClientMessage::Capabilities |
+            ClientMessage::QueryStatusFiltered(_) => true,
+
+            ClientMessage::Open(_) |
+            ClientMessage::UserData(_) |
+            ClientMessage::Close(_) |
+            ClientMessage::Exit |
+            ClientMessage::Relabel(_) |
+            ClientMessage::Resize(_) |
+            ClientMessage::PrepareUpgrade |
+            ClientMessage::Cancel |
+            ClientMessage::Reconfigure(_) |
+            ClientMessage::ExitWithParameters(_) |
+            ClientMessage::Cleanup |
+            ClientMessage::OpenProfile { .. } |
+            ClientMessage::Pause { .. } |
+            ClientMessage::Resume { .. } |
+            ClientMessage::SignalTunnel { .. } |
+            ClientMessage::TeeToFile { .. } |
+            ClientMessage::StopTee { .. } |
+            ClientMessage::WindowSize { .. } => false,
+        }
+    }
 }
 
 
 /// A message that the server may send to the client.
+///
+/// As with `ClientMessage`, new variants must only be added at the end.
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub enum ServerMessage {
     /// Generic message indicating success with whatever the client was asking
@@ -77,6 +453,10 @@ pub enum ServerMessage {
 
     /// Generic message indicating an error with whatever the client was
     /// asking for.
+    ///
+    /// Superseded by `ErrorCode`. Only older daemons send this, but clients
+    /// should continue to accept it from them. Conversely, clients that
+    /// predate `ErrorCode` can't decode the errors that newer daemons send.
     Error(String),
 
     /// Output from an SSH process to be reported to the user by the client.
@@ -86,39 +466,419 @@ pub enum ServerMessage {
     /// already open.
     TunnelAlreadyOpen,
 
-    /// In response to a `Close` message, indicates that no such tunnel was
-    /// open.
+    /// In response to a `Close`, `Relabel`, or `Reconfigure` message,
+    /// indicates that no such tunnel was open.
     TunnelNotOpen,
 
     /// In response to a `QueryStatus` message, information about the server
     /// status.
     StatusResponse(StatusInformation),
+
+    /// In response to a `QueryRelayCounters` message, the requested
+    /// counters.
+    RelayCountersResponse(RelayCounters),
+
+    /// In response to a `CheckTunnel` message, the results of the checks.
+    TunnelHealthResponse(TunnelHealth),
+
+    /// Check that the client is still alive. It should answer with a `Pong`
+    /// message. The daemon only sends these while relaying a login, and only
+    /// if it has been configured to.
+    Ping,
+
+    /// In response to a `GetLog` message, the most recent lines of the
+    /// daemon log, oldest first.
+    LogLines(Vec<String>),
+
+    /// In response to a `PrepareUpgrade` message, the path of the file to
+    /// which the tunnel parameters were saved.
+    UpgradePrepared(String),
+
+    /// In response to a `DryRunOpen` message, the command line that would
+    /// have been used to launch SSH.
+    CommandLine(Vec<String>),
+
+    /// In response to a `Cancel` message, indicates that the tunnel that was
+    /// being opened has been abandoned.
+    Cancelled,
+
+    /// In response to a `QueryDaemonStats` message, the daemon's resource
+    /// usage.
+    DaemonStatsResponse(DaemonStats),
+
+    /// In response to an `Open` message, indicates that the daemon is about
+    /// to exit, so that no tunnel was opened.
+    ShuttingDown,
+
+    /// In response to an `Open` message with its `force` flag set, indicates
+    /// that an existing tunnel has been killed and that the new one is being
+    /// opened. This takes the place of the first `Ok` message of the usual
+    /// sequence.
+    TunnelReplaced,
+
+    /// An error with whatever the client was asking for, along with a code
+    /// describing the general kind of problem and a message to show the
+    /// user.
+    ErrorCode(ProtocolError, String),
+
+    /// In response to a `QueryVersion` message, the versions of the daemon
+    /// and of the protocol that it speaks.
+    VersionResponse(VersionInformation),
+
+    /// In response to an `Open` message that asked for connection retries,
+    /// indicates that SSH died during the login after being allowed this
+    /// many connection attempts. SSH's final output, which explains what
+    /// went wrong, precedes this message. Unlike an error, this doesn't end
+    /// the session.
+    ConnectFailed(u32),
+
+    /// Output that an SSH process wrote to its standard error, for opens
+    /// that asked for it to be kept separate from the terminal.
+    SshStderr(Vec<u8>),
+
+    /// In response to a `DumpState` message, a snapshot of the daemon's
+    /// internal state.
+    StateDumpResponse(StateDump),
+
+    /// In response to an `Open` or `Reconfigure` message that set the
+    /// `report_key` flag, the label under which the new tunnel is filed.
+    /// This comes just before the `Ok` that ends a successful login.
+    TunnelKey(String),
+
+    /// In response to a `TunnelStatus` message, the details of the tunnel.
+    TunnelStatusResponse(TunnelDetails),
+
+    /// In response to an `Open` or `Reconfigure` message with a connect
+    /// timeout, indicates that SSH gave up because the host didn't answer
+    /// in time. Like `ConnectFailed`, this follows any last output from
+    /// SSH, and the tunnel was not opened.
+    HostUnreachable,
+
+    /// In response to a `Cleanup` message, the paths of the sockets that
+    /// were removed.
+    SocketsRemoved(Vec<String>),
+
+    /// In response to a `WaitForExit` message, how the tunnel's SSH process
+    /// ended.
+    TunnelExitResponse(TunnelExit),
+
+    /// In response to a `Metrics` message, the daemon's metrics as
+    /// Prometheus text.
+    Metrics(String),
+
+    /// In response to a `GetConfig` message, the daemon's settings.
+    Config(DaemonConfig),
+
+    /// In response to a `Capabilities` message, the names of the optional
+    /// features that the daemon supports, drawn from the `capabilities`
+    /// module. Clients should ignore names that they don't recognize.
+    Capabilities(Vec<String>),
+
+    /// In response to an `Open` or `Reconfigure` message that set the
+    /// `report_pid` flag, the process ID of the new tunnel's SSH process.
+    /// Like `TunnelKey`, this comes just before the `Ok` that ends a
+    /// successful login.
+    TunnelPid(u32),
+
+    /// In response to a `Close` message with a grace period, indicates
+    /// that the tunnel's SSH process is gone, and whether it exited of its
+    /// own accord rather than being killed.
+    TunnelClosed {
+        /// Whether SSH exited before the grace period was up.
+        graceful: bool,
+    },
+}
+
+impl ServerMessage {
+    /// Describe this message in a form that is safe to log.
+    ///
+    /// SSH's output may echo back what the user typed, so it is only
+    /// described by its size, as are log lines.
+    pub fn summary(&self) -> String {
+        match self {
+            ServerMessage::SshData(data) => format!("SshData: {} bytes", data.len()),
+            ServerMessage::SshStderr(data) => format!("SshStderr: {} bytes", data.len()),
+            ServerMessage::LogLines(lines) => format!("LogLines: {} lines", lines.len()),
+            ServerMessage::Metrics(text) => format!("Metrics: {} bytes", text.len()),
+            other => format!("{:?}", other),
+        }
+    }
+}
+
+
+/// A broad classification of the errors that the daemon can report.
+///
+/// As with the message enums, new variants must only be added at the end.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum ProtocolError {
+    /// The tunnel in question is not known to the daemon.
+    NotFound,
+
+    /// The tunnel in question is already open.
+    AlreadyOpen,
+
+    /// The SSH process could not be launched.
+    SpawnFailed,
+
+    /// SSH died or otherwise failed while logging in.
+    AuthFailed,
+
+    /// The daemon is too busy to handle the request.
+    Busy,
+
+    /// The request parameters were malformed.
+    InvalidParameters,
+
+    /// The daemon has been configured not to honor the request.
+    Forbidden,
+
+    /// Something went wrong inside the daemon.
+    Internal,
+
+    /// The requested label is already used by another tunnel.
+    LabelInUse,
+
+    /// The daemon can't write to the directory that holds its files, so it
+    /// isn't opening new tunnels.
+    StorageUnavailable,
+}
+
+
+/// An error reported by the daemon.
+///
+/// Errors returned by the functions in the [`client`] module may be
+/// downcast to this type to find out what kind of problem the daemon
+/// encountered.
+#[derive(Debug, Fail)]
+#[fail(display = "{}", message)]
+pub struct DaemonError {
+    /// The kind of error.
+    pub code: ProtocolError,
+
+    /// The explanatory message provided by the daemon.
+    pub message: String,
+}
+
+
+/// There was no daemon to talk to, and the client wasn't asked to start
+/// one.
+///
+/// Functions in the [`client`] module that can be told not to start the
+/// daemon return this, downcastable from their errors, when nothing is
+/// listening at the socket.
+#[derive(Debug)]
+pub struct DaemonNotRunning {
+    /// The path of the socket at which the daemon was expected.
+    pub socket: PathBuf,
+}
+
+impl fmt::Display for DaemonNotRunning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "no daemon is running at {}", self.socket.display())
+    }
+}
+
+impl Fail for DaemonNotRunning {}
+
+
+/// Parameters to the "ExitWithParameters" command.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ExitParameters {
+    /// If true, the daemon stops accepting new opens and does not reply
+    /// until any opens that are in progress have finished, so that they
+    /// aren't cut off halfway through.
+    pub wait_for_opens: bool,
+
+    /// The maximum number of seconds to wait for opens to finish. After this
+    /// time, the daemon exits regardless. The daemon won't wait for more
+    /// than a day, however large this is.
+    pub wait_timeout: u64,
 }
 
 
 /// Parameters to the "Open" command.
 ///
-/// This command takes only a single parameter. The model of `stund` is that
-/// configuration of details like usernames should be done via the
-/// `$HOME/.ssh/config` file, and so are not needed here.
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+/// The model of `stund` is that configuration of details like usernames
+/// should generally be done via the `$HOME/.ssh/config` file, so only a few
+/// parameters are needed here.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct OpenParameters {
-    /// The hostname to which to connect.
+    /// The host to which to connect.
+    ///
+    /// Besides plain hostnames, this may take the forms `user@host`,
+    /// `host:port`, `[ipv6-address]`, or `[ipv6-address]:port`. A bare IPv6
+    /// address is also accepted, but can't be combined with a port.
     pub host: String,
+
+    /// The port to which to connect, if not the default.
+    ///
+    /// It is an error to specify this if `host` also includes a port.
+    pub port: Option<u16>,
+
+    /// If true and a tunnel to this host is already open, kill it and open a
+    /// new one rather than leaving it alone.
+    #[serde(default)]
+    pub force: bool,
+
+    /// How many more times SSH should try to connect if its first attempt
+    /// fails. This is passed along as SSH's `ConnectionAttempts` option, so
+    /// it only covers making the network connection, not logging in. The
+    /// daemon refuses values over 100.
+    #[serde(default)]
+    pub connect_retries: u32,
+
+    /// Extra environment variables for the SSH process, as name/value
+    /// pairs. SSH otherwise sees only a few variables from the daemon's own
+    /// environment, such as `PATH`, `HOME`, and `SSH_AUTH_SOCK`.
+    #[serde(default)]
+    pub env: Vec<(String, String)>,
+
+    /// If true, `DISPLAY` and `SSH_ASKPASS` may be passed along in `env`,
+    /// letting SSH ask for passwords with a graphical program. They are
+    /// dropped otherwise.
+    #[serde(default)]
+    pub allow_askpass: bool,
+
+    /// The user as whom to log in, if not the default.
+    ///
+    /// It is an error to specify this if `host` also includes a user.
+    #[serde(default)]
+    pub user: Option<String>,
+
+    /// If true, SSH's standard error is kept apart from its terminal and
+    /// relayed as `SshStderr` messages during the login, so that its
+    /// complaints can be told apart from the session itself. Some programs
+    /// prompt on standard error, though, and with this set those prompts
+    /// won't appear in the terminal stream.
+    #[serde(default)]
+    pub separate_stderr: bool,
+
+    /// If true, the daemon sends a `TunnelKey` message with the label under
+    /// which it filed the tunnel just before reporting that the login
+    /// succeeded. The label may differ from `host` if an existing tunnel to
+    /// the same destination was replaced. The client library sets this
+    /// itself.
+    #[serde(default)]
+    pub report_key: bool,
+
+    /// The SSH agent socket for this tunnel to use, overriding the
+    /// `SSH_AUTH_SOCK` that SSH would otherwise get. This must be an
+    /// absolute path, and the socket must exist when the tunnel is opened.
+    #[serde(default)]
+    pub agent_socket: Option<PathBuf>,
+
+    /// If true, the tunnel's SSH process acts as a master for connection
+    /// sharing, so that other SSH commands can ride on its login rather
+    /// than logging in themselves. They do this by using the control
+    /// socket given by `control_socket_path`, which the daemon removes
+    /// when the tunnel goes away.
+    #[serde(default)]
+    pub control_master: bool,
+
+    /// How many seconds SSH should wait for each attempt to connect to the
+    /// host before giving up on it, if not the system's TCP default. This
+    /// is passed along as SSH's `ConnectTimeout` option and must be at least
+    /// 1. If it's what ends the open, the result is `HostUnreachable`.
+    #[serde(default)]
+    pub connect_timeout_secs: Option<u32>,
+
+    /// Kill the tunnel once it has been idle for this many seconds,
+    /// overriding the daemon's default; zero means never. Only traffic on
+    /// the tunnel's terminal counts as activity, since the daemon can't see
+    /// connections that SSH forwards or shares.
+    #[serde(default)]
+    pub reap_after_idle_secs: Option<u32>,
+
+    /// What to do if the client goes away, or its session otherwise fails,
+    /// partway through the open. A tunnel that is still logging in is
+    /// always killed, since nobody is left to answer SSH. If the login has
+    /// finished, the tunnel is kept unless this is true.
+    #[serde(default)]
+    pub close_if_abandoned: bool,
+
+    /// If true, the daemon sends a `TunnelPid` message with the process ID
+    /// of the tunnel's SSH process just before reporting that the login
+    /// succeeded. The client library sets this itself.
+    #[serde(default)]
+    pub report_pid: bool,
+
+    /// How much SSH should say about what it's doing, from 0 (its usual
+    /// self) to 3, like passing `-v` that many times. SSH's diagnostics go
+    /// to its standard error, so during the login they reach the client
+    /// with the rest of its output, or as `SshStderr` messages if
+    /// `separate_stderr` is set. In the latter case, the daemon also logs
+    /// whatever SSH says after the login.
+    #[serde(default)]
+    pub verbose: u8,
+}
+
+/// Parameters to the "Reconfigure" command.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ReconfigureParameters {
+    /// The label of the tunnel to restart.
+    pub label: String,
+
+    /// The parameters with which to restart it. The `force` flag is
+    /// ignored, and the new tunnel keeps the old label whatever `host` is.
+    pub params: OpenParameters,
 }
 
 /// Possible outcomes of the "Open" command.
 ///
 /// Besides these outcomes, an error may be signal by the return of a textual
 /// error message.
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum OpenResult {
-    /// Indicates that the tunnel was successfully opened.
-    Success,
+    /// Indicates that the tunnel was successfully opened. `key` is the label
+    /// under which the daemon filed it, which is what later commands about
+    /// the tunnel should use. It stays the same for as long as the tunnel
+    /// runs, unless the tunnel is relabeled.
+    Success {
+        /// The label of the new tunnel.
+        key: String,
+
+        /// The process ID of the new tunnel's SSH process, if the daemon
+        /// reported it.
+        pid: Option<u32>,
+    },
 
     /// Indicates that nothing was done because a tunnel to the specified
     /// host was already open.
     AlreadyOpen,
+
+    /// Indicates that a tunnel to the specified host was already open, but
+    /// that it was killed and successfully replaced with a new one, filed
+    /// under the label `key`.
+    Replaced {
+        /// The label of the new tunnel.
+        key: String,
+
+        /// The process ID of the new tunnel's SSH process, if the daemon
+        /// reported it.
+        pid: Option<u32>,
+    },
+
+    /// Indicates that the caller cancelled the open before it finished, and
+    /// that the half-opened tunnel was killed.
+    Cancelled,
+
+    /// Indicates that nothing was done because the daemon is about to exit.
+    DaemonShuttingDown,
+
+    /// In response to a reconfigure, indicates that nothing was done
+    /// because no tunnel with the specified label was open.
+    NotOpen,
+
+    /// Indicates that SSH gave up without opening the tunnel, after being
+    /// allowed the given number of connection attempts. This is only
+    /// reported if the open asked for connection retries; otherwise the
+    /// failure is an error as usual.
+    ConnectFailed(u32),
+
+    /// Indicates that SSH gave up without opening the tunnel because it
+    /// timed out trying to connect to the host. This is only reported if
+    /// the open set `connect_timeout_secs`.
+    HostUnreachable,
 }
 
 
@@ -127,6 +887,15 @@ pub enum OpenResult {
 pub struct CloseParameters {
     /// The hostname of the connection to be closed.
     pub host: String,
+
+    /// If given, SSH is asked to exit with SIGTERM, and only killed if it
+    /// hasn't done so after this many seconds, so that it gets a chance to
+    /// wind down any forwarded connections. The daemon then replies with
+    /// `TunnelClosed` once SSH is gone, rather than with `Ok` straight
+    /// away. The daemon won't wait for more than an hour, however large
+    /// this is.
+    #[serde(default)]
+    pub grace_secs: Option<u64>,
 }
 
 /// Possible outcomes of the "Close" command.
@@ -141,6 +910,43 @@ pub enum CloseResult {
     /// Indicates that nothing was done because no tunnel to the specified
     /// host was open.
     NotOpen,
+
+    /// For a close with a grace period, indicates that SSH exited on its
+    /// own before the period was up.
+    Graceful,
+
+    /// For a close with a grace period, indicates that SSH was still
+    /// running when the period was up, and was killed.
+    ForceKilled,
+}
+
+
+/// Parameters to the "Relabel" command.
+///
+/// Tunnels are initially labeled with the host that they connect to. This
+/// command changes the label without disturbing the tunnel itself. It is an
+/// error if the new label is already in use.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct RelabelParameters {
+    /// The current label of the tunnel.
+    pub old: String,
+
+    /// The new label for the tunnel.
+    pub new: String,
+}
+
+/// Possible outcomes of the "Relabel" command.
+///
+/// Besides these outcomes, an error may be signal by the return of a textual
+/// error message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RelabelResult {
+    /// Indicates that the tunnel was successfully relabeled.
+    Success,
+
+    /// Indicates that nothing was done because no tunnel with the specified
+    /// label was known.
+    NotFound,
 }
 
 
@@ -153,20 +959,132 @@ pub struct StatusInformation {
     /// This list includes tunnels that have been closed, but not any tunnels
     /// opened from any previous invocations of the server.
     pub tunnels: Vec<TunnelInformation>,
+
+    /// The most recent error that the daemon hit while writing to its log,
+    /// if there has been one.
+    #[serde(default)]
+    pub log_error: Option<String>,
+}
+
+/// Which tunnels a `QueryStatusFiltered` query is interested in.
+///
+/// The default filter lets everything through.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct StatusFilter {
+    /// Only report tunnels in one of these states. If this is empty, the
+    /// state doesn't matter.
+    pub states: Vec<TunnelState>,
+}
+
+impl StatusFilter {
+    /// Whether a tunnel in the given state passes the filter.
+    pub fn matches(&self, state: &TunnelState) -> bool {
+        self.states.is_empty() || self.states.contains(state)
+    }
+}
+
+/// The versions reported by the daemon.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct VersionInformation {
+    /// The version of the protocol crate that the daemon was built with.
+    pub protocol_version: String,
+
+    /// The version of the daemon program.
+    pub daemon_version: String,
 }
 
 /// Information about a single tunnel opened by the server.
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub struct TunnelInformation {
-    /// The hostname associated with the connection.
+    /// The label of the connection. This is the hostname that it was opened
+    /// with, unless it has been relabeled.
     pub host: String,
 
     /// The current state of the SSH tunnel.
     pub state: TunnelState,
+
+    /// Recent events in the life of the tunnel, oldest first. Only a limited
+    /// number of events are remembered.
+    pub history: Vec<TunnelEvent>,
+
+    /// The process ID of the tunnel's SSH process, if it is running.
+    #[serde(default)]
+    pub pid: Option<u32>,
 }
 
-/// The state of a single tunnel opened by the server.
+/// Everything worth knowing about a single tunnel, for a closer look than
+/// the status report gives.
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct TunnelDetails {
+    /// The label under which the tunnel is filed.
+    pub host: String,
+
+    /// The current state of the SSH tunnel.
+    pub state: TunnelState,
+
+    /// The process ID of the tunnel's SSH process, if it is running.
+    pub pid: Option<u32>,
+
+    /// How many seconds ago the tunnel's SSH process was started, if it is
+    /// running.
+    pub age_secs: Option<u64>,
+
+    /// The number of bytes of output received from SSH, if it is running.
+    /// This counts the login as well as anything that SSH prints after it.
+    pub bytes_from_ssh: Option<u64>,
+
+    /// The number of bytes of user input passed along to SSH, if it is
+    /// running.
+    pub bytes_to_ssh: Option<u64>,
+
+    /// Recent events in the life of the tunnel, oldest first, as in
+    /// `TunnelInformation`.
+    pub history: Vec<TunnelEvent>,
+
+    /// How SSH exited, if the tunnel died rather than being closed.
+    pub last_error: Option<String>,
+
+    /// Whether the daemon has been told to stop reading SSH's output.
+    #[serde(default)]
+    pub paused: bool,
+}
+
+/// Something that happened to a tunnel.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TunnelEvent {
+    /// When the event happened, in seconds since the Unix epoch.
+    pub timestamp: u64,
+
+    /// What happened.
+    pub kind: TunnelEventKind,
+
+    /// The exit code of the SSH process, if the event is that it exited of
+    /// its own accord with one.
+    pub exit_code: Option<i32>,
+}
+
+/// The kinds of events recorded in a tunnel's history.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum TunnelEventKind {
+    /// An SSH process was started for the tunnel.
+    Opened,
+
+    /// The tunnel was closed on request.
+    Closed,
+
+    /// The tunnel was killed so that it could be replaced by a new one.
+    Replaced,
+
+    /// SSH exited with the status that it uses to report errors such as
+    /// network problems.
+    NetworkError,
+
+    /// SSH exited unexpectedly for some other reason.
+    Crashed,
+}
+
+/// The state of a single tunnel opened by the server.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum TunnelState {
     /// The tunnel is currently opene.
     Open,
@@ -180,3 +1098,232 @@ pub enum TunnelState {
     /// killing the associated SSH process outside of the server’s knowledge.
     Died,
 }
+
+
+/// Parameters to the "QueryRelayCounters" command.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct RelayCountersParameters {
+    /// The hostname of the tunnel of interest.
+    pub host: String,
+}
+
+/// Internal counters describing the I/O relay between a client and an SSH
+/// process.
+///
+/// These are intended for diagnosing stalls: they can tell you whether data
+/// are piling up on the SSH side or the client side of the relay.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct RelayCounters {
+    /// The number of bytes received from SSH that are waiting to be sent to
+    /// the client.
+    pub client_buf_len: usize,
+
+    /// The number of bytes received from the client that are waiting to be
+    /// sent to SSH.
+    pub ssh_buf_len: usize,
+
+    /// Whether the client connection refused to accept our most recent
+    /// attempt to send it data.
+    pub client_blocked: bool,
+
+    /// Whether the SSH process refused to accept our most recent attempt to
+    /// send it data.
+    pub ssh_blocked: bool,
+
+    /// The number of milliseconds since data were last received from the
+    /// client, or `None` if none ever have been.
+    pub client_idle_ms: Option<u64>,
+
+    /// The number of milliseconds since data were last received from SSH, or
+    /// `None` if none ever have been.
+    pub ssh_idle_ms: Option<u64>,
+}
+
+
+/// The resources used by the daemon process.
+///
+/// Some of these can't be measured on every platform, in which case they
+/// are `None`.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct DaemonStats {
+    /// The daemon's current resident set size, in bytes.
+    pub rss_bytes: Option<u64>,
+
+    /// The largest resident set size that the daemon has had, in bytes.
+    pub max_rss_bytes: Option<u64>,
+
+    /// The number of file descriptors that the daemon has open.
+    pub num_fds: Option<u64>,
+
+    /// The number of tunnels whose SSH processes are running.
+    pub num_tunnels: usize,
+
+    /// The number of clients connected to the daemon, including the one
+    /// asking.
+    pub num_sessions: usize,
+}
+
+
+/// The settings that a daemon is running with, for figuring out why it
+/// behaves the way it does. Settings that weren't given on the daemon's
+/// command line have their default values.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DaemonConfig {
+    /// The path of the daemon's socket.
+    pub socket_path: String,
+
+    /// The permissions of the daemon's socket, in octal.
+    pub socket_mode: String,
+
+    /// Whether the daemon is running in the foreground.
+    pub foreground: bool,
+
+    /// Where the daemon's log messages go: "stdout", "file" or "syslog".
+    pub log_sink: String,
+
+    /// Whether the daemon logs each message from its clients.
+    pub log_messages: bool,
+
+    /// Whether clients may fetch the daemon's recent log messages.
+    pub allow_log_queries: bool,
+
+    /// Whether clients may fetch a dump of the daemon's internal state.
+    pub allow_state_dumps: bool,
+
+    /// How often the daemon pings a client whose login it is relaying, if
+    /// it does.
+    pub keepalive_secs: Option<u64>,
+
+    /// How long tunnels may sit idle before the daemon closes them, unless
+    /// they were opened with their own setting.
+    pub reap_after_idle_secs: Option<u64>,
+
+    /// The user that SSH runs as, if not the daemon's.
+    pub ssh_user: Option<String>,
+
+    /// How many connections may wait to be accepted.
+    pub listen_backlog: u16,
+
+    /// How many connections the daemon accepts per second, or 0 if there's
+    /// no limit.
+    pub max_accepts_per_sec: u32,
+
+    /// Whether SO_LINGER is turned on for client connections.
+    pub socket_linger: bool,
+}
+
+
+/// A snapshot of the daemon's internal state, for diagnosing problems.
+///
+/// This is more detailed than the status report, but still leaves out
+/// anything sensitive, such as the values of environment variables.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct StateDump {
+    /// Every tunnel that the daemon knows about, sorted by label.
+    pub tunnels: Vec<TunnelDump>,
+
+    /// The number of opens that are in progress.
+    pub opens_in_flight: usize,
+
+    /// The number of clients connected to the daemon, including the one
+    /// asking.
+    pub num_sessions: usize,
+
+    /// Whether the daemon has stopped accepting new tunnels in preparation
+    /// for an upgrade.
+    pub draining: bool,
+
+    /// Whether the daemon is exiting.
+    pub shutting_down: bool,
+}
+
+
+/// What the daemon knows about a single tunnel, as part of a `StateDump`.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct TunnelDump {
+    /// The label under which the tunnel is filed.
+    pub label: String,
+
+    /// The state of the tunnel.
+    pub state: TunnelState,
+
+    /// The daemon's internal ID for the tunnel, if it is running.
+    pub id: Option<u64>,
+
+    /// The process ID of the tunnel's SSH process, if it is running.
+    pub pid: Option<u32>,
+
+    /// The host that SSH was told to connect to, if it is running.
+    pub host: Option<String>,
+
+    /// The names of the environment variables given to SSH. Their values
+    /// are left out since they may be secrets.
+    pub env_names: Vec<String>,
+
+    /// The number of connection attempts that SSH was allowed beyond the
+    /// first, if it is running.
+    pub connect_retries: Option<u32>,
+
+    /// The state of the I/O relay to SSH, if it is running.
+    pub relay: Option<RelayCounters>,
+
+    /// Whether SSH's terminal has been closed while SSH is still running.
+    pub pty_closed: bool,
+
+    /// The exit status that SSH died with, if it died by itself.
+    pub exit_status: Option<String>,
+}
+
+
+/// Parameters to the "Resize" command.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct ResizeParameters {
+    /// The label of the tunnel to resize.
+    pub host: String,
+
+    /// The new number of rows.
+    pub rows: u16,
+
+    /// The new number of columns.
+    pub cols: u16,
+}
+
+
+/// Parameters to the "CheckTunnel" command.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct CheckTunnelParameters {
+    /// The label of the tunnel to check.
+    pub host: String,
+}
+
+
+/// How a tunnel's SSH process came to an end.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TunnelExit {
+    /// The exit status that SSH died with, or `None` if the daemon killed
+    /// it, as it does when a tunnel is closed.
+    pub exit_status: Option<String>,
+
+    /// SSH's exit code, if it died by itself and wasn't killed by a signal.
+    pub code: Option<i32>,
+}
+
+
+/// The result of checking up on a tunnel.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TunnelHealth {
+    /// Whether the SSH process is still running.
+    pub ssh_alive: bool,
+
+    /// Whether the SSH process's terminal is still open. If it has been
+    /// closed, the remote command has ended and the connection is on its way
+    /// down even if SSH hasn't exited yet.
+    pub pty_open: bool,
+}
+
+impl TunnelHealth {
+    /// Whether all of the checks passed.
+    pub fn is_healthy(&self) -> bool {
+        self.ssh_alive && self.pty_open
+    }
+}
@@ -6,25 +6,37 @@
 use base64;
 use daemonize;
 use failure::{Error, ResultExt};
-use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
-use futures::sink::Send;
-use futures::stream::{SplitSink, SplitStream, StreamFuture};
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::sink::{Send, SendAll};
+use futures::stream::{self, IterOk, SplitSink, SplitStream, StreamFuture};
 use futures::sync::{mpsc, oneshot};
+use futures::task::{self, Task};
 use libc;
 use rand::{self, RngCore};
+use serde_json;
 use state_machine_future::RentToOwn;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::env;
+use std::ffi::{CStr, CString};
 use std::fmt;
 use std::fs;
 use std::io::{self, Write};
 use std::marker::Send as StdSend;
+use std::str::FromStr;
 use std::mem;
-use std::os::unix::io::AsRawFd;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::os::unix::net::{SocketAddr, UnixStream as StdUnixStream};
-use std::path::PathBuf;
+use std::os::unix::process::CommandExt as StdCommandExt;
+use std::path::{Path, PathBuf};
 use std::process::ExitStatus;
-use std::sync::{Arc, Mutex};
+use std::ptr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::vec;
 use stund_protocol::*;
+use stund_protocol::clock::{SharedClock, SystemClock, Timer};
 use tokio_core::reactor::{Core, Handle};
 use tokio_io::AsyncRead;
 use tokio_io::codec::length_delimited::{FramedRead, FramedWrite};
@@ -55,80 +67,794 @@ const FATAL_SIGNALS: &[i32] = &[
 ];
 
 
+/// Where the daemon's log messages go.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LogSink {
+    /// Write log messages to standard output.
+    Stdout,
+
+    /// Write log messages to a file next to the daemon's socket.
+    File,
+
+    /// Send log messages to the system logger.
+    Syslog,
+}
+
+impl FromStr for LogSink {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "stdout" => Ok(LogSink::Stdout),
+            "file" => Ok(LogSink::File),
+            "syslog" => Ok(LogSink::Syslog),
+            _ => Err(format_err!("unrecognized log destination \"{}\"", s)),
+        }
+    }
+}
+
+impl fmt::Display for LogSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LogSink::Stdout => "stdout",
+            LogSink::File => "file",
+            LogSink::Syslog => "syslog",
+        })
+    }
+}
+
+
+/// Why the daemon stopped serving.
+#[derive(Debug)]
+pub enum ShutdownReason {
+    /// We caught one of the `FATAL_SIGNALS`.
+    Signal(i32),
+
+    /// A client told us to exit.
+    ClientRequested,
+
+    /// Something went wrong that we can't carry on after, such as the
+    /// listening socket failing.
+    FatalError(Error),
+}
+
+impl ShutdownReason {
+    /// The status that the daemon should exit with. Signals get the usual
+    /// 128 plus the signal number, as if we hadn't caught them.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ShutdownReason::Signal(sig) => 128 + sig,
+            ShutdownReason::ClientRequested => 0,
+            ShutdownReason::FatalError(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShutdownReason::Signal(sig) => write!(f, "caught signal {}", sig),
+            ShutdownReason::ClientRequested => f.write_str("a client asked us to exit"),
+            ShutdownReason::FatalError(e) => write!(f, "fatal error: {}", e),
+        }
+    }
+}
+
+
+/// How serious a log message is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Severity {
+    /// Routine news about what the daemon is up to.
+    Info,
+
+    /// Something went wrong.
+    Error,
+}
+
+impl Severity {
+    /// The corresponding syslog priority.
+    fn syslog_priority(self) -> libc::c_int {
+        match self {
+            Severity::Info => libc::LOG_INFO,
+            Severity::Error => libc::LOG_ERR,
+        }
+    }
+}
+
+
+/// Where log messages actually end up.
+enum LogOutput {
+    /// A stream that gets one line per message. It has no notion of
+    /// severity.
+    Stream(Box<Write + StdSend>),
+
+    /// The system logger, which gets each line of each message separately.
+    Syslog,
+}
+
+impl LogOutput {
+    fn syslog() -> Self {
+        // The identity string must live as long as we keep logging, hence
+        // the static byte string.
+        unsafe { libc::openlog(b"stund\0".as_ptr() as _, libc::LOG_PID, libc::LOG_DAEMON); }
+        LogOutput::Syslog
+    }
+
+    fn write(&mut self, severity: Severity, message: &str) -> io::Result<()> {
+        match *self {
+            LogOutput::Stream(ref mut w) => {
+                writeln!(w, "{}", message)?;
+                w.flush()
+            },
+
+            LogOutput::Syslog => {
+                for line in message.lines() {
+                    // Can't fail, since we've removed any NULs.
+                    let line = CString::new(line.replace('\0', "")).unwrap();
+
+                    unsafe {
+                        libc::syslog(severity.syslog_priority(), b"%s\0".as_ptr() as _, line.as_ptr());
+                    }
+                }
+
+                Ok(()) // syslog() can't tell us about problems
+            },
+        }
+    }
+}
+
+
 pub struct State {
     sock_path: PathBuf,
-    _opts: StundDaemonOptions,
-    log: Box<Write + StdSend>,
+    opts: StundDaemonOptions,
+    log: LogOutput,
+    log_sink: LogSink,
     children: HashMap<String, TunnelState>,
+    next_tunnel_id: u64,
+    next_session_id: u64,
+    keepalive: Option<Duration>,
+    recent_log: Option<VecDeque<String>>,
+    draining: bool,
+    shutting_down: bool,
+    letting_opens_finish: bool,
+    socket_linger: bool,
+    opens_in_flight: usize,
+    tx_opens_done: Vec<oneshot::Sender<()>>,
+    socket_mode: u32,
+    history: HashMap<String, VecDeque<TunnelEvent>>,
+    num_sessions: usize,
+    log_messages: bool,
+    allow_state_dumps: bool,
+    clock: SharedClock,
+    log_error: Option<String>,
+    lost_log_messages: usize,
+    ssh_user: Option<SshUser>,
+    owned_sockets: BTreeSet<PathBuf>,
+    reap_after_idle: Option<Duration>,
+    listen_backlog: u16,
+    max_accepts_per_sec: u32,
+    exit_waiters: HashMap<u64, Vec<oneshot::Sender<Option<ExitStatus>>>>,
+    started: Instant,
+    event_totals: HashMap<String, HashMap<TunnelEventKind, u64>>,
+}
+
+/// The account that SSH runs as, if the daemon was told to run it as
+/// someone other than itself.
+#[derive(Clone, Debug)]
+struct SshUser {
+    name: String,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    home: String,
+}
+
+/// Look up the account that SSH should run as. We insist that it exists,
+/// that it isn't root, and that we are, so that a mistake shows up when the
+/// daemon starts rather than at the first open.
+fn lookup_ssh_user(name: &str) -> Result<SshUser, Error> {
+    let cname = CString::new(name).map_err(|_| format_err!("invalid user name \"{}\"", name))?;
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut found = ptr::null_mut();
+
+    let rv = unsafe {
+        libc::getpwnam_r(cname.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found)
+    };
+
+    if found.is_null() {
+        return Err(match rv {
+            0 => format_err!("no such user \"{}\"", name),
+            e => format_err!("failed to look up user \"{}\": {}", name, io::Error::from_raw_os_error(e)),
+        });
+    }
+
+    if pwd.pw_uid == 0 {
+        return Err(format_err!("refusing to run SSH as {}: it is the superuser", name));
+    }
+
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(format_err!("the daemon must run as root to be able to run SSH as {}", name));
+    }
+
+    Ok(SshUser {
+        name: name.to_owned(),
+        uid: pwd.pw_uid,
+        gid: pwd.pw_gid,
+        home: unsafe { CStr::from_ptr(pwd.pw_dir) }.to_string_lossy().into_owned(),
+    })
+}
+
+/// Figure out where the daemon should listen. A relative `--socket` path is
+/// taken relative to where we were started, since daemonizing changes the
+/// working directory.
+fn socket_path(opts: &StundDaemonOptions) -> Result<PathBuf, Error> {
+    match opts.socket {
+        Some(ref p) => {
+            let p = if p.is_relative() { env::current_dir()?.join(p) } else { p.clone() };
+            check_socket_path(&p)?;
+            Ok(p)
+        },
+
+        None => get_socket_path(),
+    }
+}
+
+/// Parse the octal permissions requested for the daemon socket. We insist
+/// that we can use the socket ourselves, and refuse to open it up to
+/// everyone.
+fn parse_socket_mode(text: &str) -> Result<u32, Error> {
+    let mode = u32::from_str_radix(text, 8)
+        .map_err(|_| format_err!("invalid socket mode \"{}\": expected an octal number like 0600", text))?;
+
+    if mode & !0o777 != 0 {
+        return Err(format_err!("invalid socket mode {:o}: only permission bits may be set", mode));
+    }
+
+    if mode & 0o600 != 0o600 {
+        return Err(format_err!("invalid socket mode {:o}: the owner must be able to read and write", mode));
+    }
+
+    if mode & 0o007 != 0 {
+        return Err(format_err!("refusing to use socket mode {:o}: it would let any user control \
+                                your tunnels", mode));
+    }
+
+    Ok(mode)
+}
+
+/// Create (or truncate) a file that only we can read or write.
+fn private_file(path: &Path) -> Result<fs::File, Error> {
+    Ok(fs::OpenOptions::new()
+       .write(true)
+       .create(true)
+       .truncate(true)
+       .mode(0o600)
+       .open(path)?)
+}
+
+/// Figure out where log messages should go. By default, we log to stdout if
+/// we're staying in the foreground and to a file otherwise, but the two
+/// choices are independent. Except that daemonizing disconnects us from
+/// stdout, so logging there would be pointless.
+fn choose_log_sink(opts: &StundDaemonOptions) -> Result<LogSink, Error> {
+    let log_sink = match opts.log_sink {
+        Some(s) => s,
+        None if opts.foreground => LogSink::Stdout,
+        None => LogSink::File,
+    };
+
+    if log_sink == LogSink::Stdout && !opts.foreground {
+        return Err(format_err!("logging to standard output requires --foreground"));
+    }
+
+    Ok(log_sink)
+}
+
+/// What we found when checking for another daemon.
+enum ExistingDaemon {
+    /// No other daemon is running. A socket file may be left over from one
+    /// that died, but it's safe to remove.
+    None,
+
+    /// Another daemon is accepting connections on the socket.
+    Running,
+
+    /// The PID file names a live daemon, but it isn't accepting
+    /// connections. It may still be starting up.
+    Unresponsive(libc::pid_t),
+}
+
+/// Check that we can still write to the directory that holds the daemon's
+/// files, by writing out a scratch file next to its socket. If the disk is
+/// full or the directory has become read-only, our logs and saved state
+/// would silently go missing.
+///
+/// This does blocking I/O, so don't call it with the shared state locked.
+fn check_storage(sock_path: &Path) -> Result<(), Error> {
+    let path = sock_path.with_extension("probe");
+
+    let result = private_file(&path).and_then(|mut f| {
+        f.write_all(b"ok\n")?;
+        f.sync_all()?;
+        Ok(())
+    });
+
+    let _r = fs::remove_file(&path);
+    result.map_err(|e| format_err!("cannot write to {}: {}", path.display(), e))
+}
+
+/// Figure out whether another daemon is using the given socket.
+///
+/// Connecting to the socket isn't enough on its own: a daemon that is still
+/// starting up may not be listening yet. So we also check whether the
+/// process named in the PID file is alive, and is a stund. The latter is
+/// checked through `/proc` if we have it, in case the PID has been reused
+/// since a crash.
+fn existing_daemon(sock_path: &Path) -> ExistingDaemon {
+    if StdUnixStream::connect(sock_path).is_ok() {
+        return ExistingDaemon::Running;
+    }
+
+    let pid = match fs::read_to_string(sock_path.with_extension("pid")).ok()
+        .and_then(|text| text.trim().parse::<libc::pid_t>().ok()) {
+        Some(pid) if pid > 0 && pid != unsafe { libc::getpid() } => pid,
+        _ => return ExistingDaemon::None,
+    };
+
+    let alive = unsafe { libc::kill(pid, 0) } == 0 ||
+        io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+
+    if !alive {
+        return ExistingDaemon::None;
+    }
+
+    let theirs = fs::read_to_string(format!("/proc/{}/comm", pid));
+    let ours = fs::read_to_string("/proc/self/comm");
+
+    match (theirs, ours) {
+        (Ok(ref theirs), Ok(ref ours)) if theirs != ours => ExistingDaemon::None,
+        _ => ExistingDaemon::Unresponsive(pid),
+    }
+}
+
+/// Remove the socket left behind by a daemon that died without cleaning up.
+/// If we aren't allowed to, the bare error would be baffling, so we try to
+/// say why: usually the socket or its directory belongs to someone else.
+fn remove_stale_socket(sock_path: &Path) -> Result<(), Error> {
+    let e = match fs::remove_file(sock_path) {
+        Ok(()) => return Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => e,
+    };
+
+    if e.kind() != io::ErrorKind::PermissionDenied {
+        return Err(format_err!("cannot remove the stale socket {}: {}", sock_path.display(), e));
+    }
+
+    let dir = sock_path.parent().unwrap_or_else(|| Path::new("/"));
+    let uid = unsafe { libc::getuid() };
+
+    Err(match fs::symlink_metadata(sock_path) {
+        Ok(ref md) if md.uid() != uid => {
+            format_err!("refusing to start: the socket {} belongs to another user (UID {}), who may \
+                         be running a daemon of their own; use --socket to pick another path",
+                        sock_path.display(), md.uid())
+        },
+
+        _ => {
+            format_err!("refusing to start: cannot remove the stale socket {}: {}; check the \
+                         ownership and permissions of {}", sock_path.display(), e, dir.display())
+        },
+    })
+}
+
+/// Look for an executable in the directories listed in `$PATH`, the way
+/// that the shell would.
+fn find_program(name: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|p| match fs::metadata(p) {
+            Ok(md) => md.is_file() && md.permissions().mode() & 0o111 != 0,
+            Err(_) => false,
+        })
+}
+
+/// Check that the daemon would be able to start with the given options,
+/// printing a report as we go. Returns whether everything looked OK.
+///
+/// Nothing is modified, so this is safe to run while a daemon is running.
+pub fn check_config(opts: &StundDaemonOptions) -> bool {
+    let mut ok = true;
+
+    {
+        let mut report = |what: &str, result: Result<String, Error>| {
+            match result {
+                Ok(detail) => println!("ok    {}: {}", what, detail),
+                Err(e) => {
+                    println!("FAIL  {}: {}", what, e);
+                    ok = false;
+                },
+            }
+        };
+
+        let sock_path = socket_path(opts);
+        report("socket path", sock_path.as_ref().map(|p| p.display().to_string())
+               .map_err(|e| format_err!("{}", e)));
+
+        if let Ok(ref p) = sock_path {
+            let dir = p.parent().unwrap_or_else(|| Path::new("/"));
+            report("socket directory", match fs::metadata(dir) {
+                Ok(ref md) if md.is_dir() => Ok(dir.display().to_string()),
+                Ok(_) => Err(format_err!("{} is not a directory", dir.display())),
+                Err(e) => Err(format_err!("cannot access {}: {}", dir.display(), e)),
+            });
+
+            report("existing daemon", Ok(match existing_daemon(p) {
+                ExistingDaemon::None => "not running".to_owned(),
+                ExistingDaemon::Running => "running".to_owned(),
+                ExistingDaemon::Unresponsive(pid) => format!("PID {} is not accepting connections", pid),
+            }));
+
+            let state_path = state_file_path(p);
+
+            if state_path.exists() {
+                report("saved tunnels", load_persisted_tunnels(&state_path)
+                       .map(|t| format!("{} tunnel(s) in {}", t.len(), state_path.display())));
+            }
+        }
+
+        report("ssh program", find_program("ssh")
+               .map(|p| p.display().to_string())
+               .ok_or_else(|| format_err!("no \"ssh\" executable found in $PATH")));
+
+        report("socket mode", parse_socket_mode(&opts.socket_mode).map(|m| format!("{:o}", m)));
+
+        report("log destination", choose_log_sink(opts).map(|s| s.to_string()));
+
+        if let Some(ref name) = opts.ssh_user {
+            report("SSH user", lookup_ssh_user(name).map(|u| format!("{} (UID {})", u.name, u.uid)));
+        }
+    }
+
+    ok
 }
 
+/// How many events we remember for each tunnel.
+const MAX_TUNNEL_HISTORY: usize = 20;
+
+/// How many lines of the log we keep around for clients that ask for them.
+const LOG_HISTORY_LINES: usize = 500;
+
 macro_rules! log {
-    ($state:expr, $fmt:expr) => { $state.log_items(format_args!($fmt)) };
-    ($state:expr, $fmt:expr, $($args:tt)*) => { $state.log_items(format_args!($fmt, $($args)*)) };
+    ($state:expr, $fmt:expr) => {
+        $state.log_items(Severity::Info, format_args!($fmt))
+    };
+    ($state:expr, $fmt:expr, $($args:tt)*) => {
+        $state.log_items(Severity::Info, format_args!($fmt, $($args)*))
+    };
+}
+
+macro_rules! log_error {
+    ($state:expr, $fmt:expr) => {
+        $state.log_items(Severity::Error, format_args!($fmt))
+    };
+    ($state:expr, $fmt:expr, $($args:tt)*) => {
+        $state.log_items(Severity::Error, format_args!($fmt, $($args)*))
+    };
 }
 
 impl State {
     pub fn new(opts: StundDaemonOptions) -> Result<Self, Error> {
-        let p = get_socket_path()?;
+        let p = socket_path(&opts)?;
 
-        if StdUnixStream::connect(&p).is_ok() {
-            return Err(format_err!("refusing to start: another daemon is already running"));
-        }
+        match existing_daemon(&p) {
+            ExistingDaemon::None => {},
 
-        match fs::remove_file(&p) {
-            Ok(_) => {},
-            Err(e) => {
-                match e.kind() {
-                    io::ErrorKind::NotFound => {},
-                    _ => {
-                        return Err(e.into());
-                    },
-                }
+            ExistingDaemon::Running => {
+                return Err(format_err!("refusing to start: another daemon is already running"));
+            },
+
+            ExistingDaemon::Unresponsive(pid) => {
+                return Err(format_err!("refusing to start: another daemon (PID {}) is running but \
+                                        not accepting connections; it may still be starting up", pid));
             },
         }
 
-        // Make sure our socket and logs will be only accessible to us!
-        unsafe { libc::umask(0o177); }
+        // Any socket file now is left over from a daemon that died.
+
+        remove_stale_socket(&p)?;
+
+        let log_sink = choose_log_sink(&opts)?;
+        let socket_mode = parse_socket_mode(&opts.socket_mode)?;
+
+        let ssh_user = match opts.ssh_user {
+            Some(ref name) => Some(lookup_ssh_user(name)?),
+            None => None,
+        };
+
+        // Make sure our logs will be only accessible to us! (The socket is
+        // taken care of when we bind it.)
+
+        let log = match log_sink {
+            LogSink::Stdout => LogOutput::Stream(Box::new(io::stdout())),
+
+            LogSink::File => {
+                let mut log_path = p.clone();
+                log_path.set_extension("log");
+                LogOutput::Stream(Box::new(private_file(&log_path)?))
+            },
+
+            LogSink::Syslog => LogOutput::syslog(),
+        };
 
-        let log: Box<Write + StdSend> = if opts.foreground {
+        if opts.foreground {
             println!("stund daemon: staying in foreground");
-            Box::new(io::stdout())
         } else {
-            let mut log_path = p.clone();
-            log_path.set_extension("log");
-
-            let log = fs::File::create(&log_path)?;
             daemonize::Daemonize::new().start()?;
-            Box::new(log)
+        }
+
+        // Only now do we know our final PID.
+
+        writeln!(private_file(&p.with_extension("pid"))?, "{}", unsafe { libc::getpid() })?;
+
+        let keepalive = opts.keepalive.map(Duration::from_secs);
+        let reap_after_idle = opts.reap_after_idle.map(Duration::from_secs);
+        let socket_linger = opts.socket_linger;
+        let listen_backlog = opts.listen_backlog;
+        let max_accepts_per_sec = opts.max_accepts_per_sec;
+        let log_messages = opts.log_messages;
+        let allow_state_dumps = opts.allow_state_dumps;
+
+        // Only remember log lines if clients are allowed to ask for them.
+        let recent_log = if opts.allow_log_queries {
+            Some(VecDeque::with_capacity(LOG_HISTORY_LINES))
+        } else {
+            None
         };
 
         Ok(State {
             sock_path: p,
-            _opts: opts,
+            opts,
             log: log,
+            log_sink,
             children: HashMap::new(),
+            next_tunnel_id: 0,
+            next_session_id: 0,
+            keepalive,
+            recent_log,
+            draining: false,
+            shutting_down: false,
+            letting_opens_finish: false,
+            socket_linger,
+            opens_in_flight: 0,
+            tx_opens_done: Vec::new(),
+            socket_mode,
+            history: HashMap::new(),
+            num_sessions: 0,
+            log_messages,
+            allow_state_dumps,
+            clock: SystemClock::shared(),
+            log_error: None,
+            lost_log_messages: 0,
+            ssh_user,
+            owned_sockets: BTreeSet::new(),
+            reap_after_idle,
+            listen_backlog,
+            max_accepts_per_sec,
+            exit_waiters: HashMap::new(),
+            started: Instant::now(),
+            event_totals: HashMap::new(),
         })
     }
 
 
-    /// Don't use this directly; use the log!() macro.
-    fn log_items(&mut self, args: fmt::Arguments) {
-        let _r = writeln!(self.log, "{}", args);
-        let _r = self.log.flush();
+    /// Tell everyone waiting for the tunnel with the given ID to exit that it
+    /// has, and how.
+    fn notify_exit_waiters(&mut self, id: u64, status: Option<ExitStatus>) {
+        for tx in self.exit_waiters.remove(&id).unwrap_or_default() {
+            let _r = tx.send(status); // the waiter may have gone away
+        }
+    }
+
+
+    /// Find the key under which the running tunnel with the given ID is
+    /// filed. Tunnels can be relabeled, so the key can't be remembered.
+    fn running_key(&self, id: u64) -> Option<String> {
+        self.children.iter().filter_map(|(key, tunnel)| match tunnel {
+            TunnelState::Running { id: this_id, .. } if *this_id == id => Some(key.clone()),
+            _ => None,
+        }).next()
+    }
+
+    /// Find the process ID of the SSH process of the running tunnel with
+    /// the given ID.
+    fn running_pid(&self, id: u64) -> Option<u32> {
+        self.children.values().filter_map(|tunnel| match tunnel {
+            TunnelState::Running { id: this_id, pid, .. } if *this_id == id => Some(*pid),
+            _ => None,
+        }).next()
+    }
+
+
+    /// Find the key under which a running tunnel to the given destination is
+    /// filed. This goes by the parameters that the tunnel was opened with,
+    /// since relabeling can move it away from the key that matches its host,
+    /// and the same destination can be spelled in several ways.
+    fn running_key_for(&self, dest: &Destination) -> Option<String> {
+        self.children.iter().filter_map(|(key, tunnel)| match tunnel {
+            TunnelState::Running { params, .. } => match Destination::parse(params) {
+                Ok(ref d) if d.same_as(dest) => Some(key.clone()),
+                _ => None,
+            },
+            _ => None,
+        }).next()
+    }
+
+
+    /// Figure out which tunnel a client means by `name`. This is usually
+    /// just its label, but a running tunnel may also be named by any
+    /// spelling of its destination.
+    fn resolve_label(&self, name: &str) -> String {
+        if self.children.contains_key(name) {
+            return name.to_owned();
+        }
+
+        Destination::parse_parts(name, None, None).ok()
+            .and_then(|dest| self.running_key_for(&dest))
+            .unwrap_or_else(|| name.to_owned())
+    }
+
+
+    /// Note that we've told an SSH process to create a socket at `path`,
+    /// so that it can be cleaned up if it outlives its tunnel.
+    fn own_socket(&mut self, path: &Path) {
+        if self.owned_sockets.insert(path.to_owned()) {
+            self.save_owned_sockets();
+        }
+    }
+
+    /// Note that the socket at `path` has been dealt with.
+    fn disown_socket(&mut self, path: &Path) {
+        if self.owned_sockets.remove(path) {
+            self.save_owned_sockets();
+        }
+    }
+
+    /// Record the sockets that we own on disk, so that a later daemon can
+    /// clean them up if we crash.
+    fn save_owned_sockets(&mut self) {
+        let path = socket_record_path(&self.sock_path);
+
+        let r = private_file(&path)
+            .and_then(|f| serde_json::to_writer_pretty(f, &self.owned_sockets).map_err(Error::from));
+
+        if let Err(e) = r {
+            log_error!(self, "failed to record our sockets in {}: {}", path.display(), e);
+        }
+    }
+
+
+    /// Add an event to the history of the tunnel with the given label,
+    /// forgetting the oldest event if there are too many.
+    fn record_event(&mut self, label: &str, kind: TunnelEventKind, exit_code: Option<i32>) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let history = self.history.entry(label.to_owned()).or_default();
+
+        if history.len() == MAX_TUNNEL_HISTORY {
+            history.pop_front();
+        }
+
+        history.push_back(TunnelEvent { timestamp, kind, exit_code });
+
+        // The history is trimmed, so keep totals separately for the metrics.
+        *self.event_totals.entry(label.to_owned()).or_default().entry(kind).or_default() += 1;
+    }
+
+
+    /// Don't use this directly; use the log!() or log_error!() macros.
+    fn log_items(&mut self, severity: Severity, args: fmt::Arguments) {
+        let message = format!("{}", args);
+
+        match self.log.write(severity, &message) {
+            Ok(()) => {
+                // If messages went missing, now is our chance to say so.
+                if self.lost_log_messages > 0 {
+                    let note = format!("{} log message(s) were lost: {}", self.lost_log_messages,
+                                       self.log_error.as_deref().unwrap_or("?"));
+                    let _r = self.log.write(Severity::Error, &note);
+                    self.lost_log_messages = 0;
+                }
+            },
+
+            Err(e) => {
+                self.lost_log_messages += 1;
+                self.log_error = Some(e.to_string());
+            },
+        }
+
+        if let Some(ref mut recent) = self.recent_log {
+            for line in message.lines() {
+                if recent.len() == LOG_HISTORY_LINES {
+                    recent.pop_front();
+                }
+
+                recent.push_back(line.to_owned());
+            }
+        }
     }
 
 
-    pub fn serve(mut self) -> Result<(), Error> {
+    /// Serve clients until something tells us to stop. Errors are only
+    /// returned if we can't get started; otherwise, the result says why we
+    /// stopped.
+    pub fn serve(mut self) -> Result<ShutdownReason, Error> {
         let mut core = Core::new()?;
         let handle = core.handle();
-        let listener = UnixListener::bind(&self.sock_path, &handle)?;
+
+        // A client that goes away while we're writing to it must not take
+        // the whole daemon down with it. The Rust runtime already ignores
+        // SIGPIPE, but we depend on that, so make sure: writes to dead
+        // sockets then fail with EPIPE, which just ends that client's
+        // session with an error. (SSH children get the default disposition
+        // back when they're spawned.)
+
+        unsafe { libc::signal(libc::SIGPIPE, libc::SIG_IGN); }
+
+        // Bind with a restrictive umask so that nobody else can connect
+        // before we've set the socket's permissions, then put things back the
+        // way they were. The umask is process-global, so we shouldn't leave
+        // it modified.
+
+        let old_umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(&self.sock_path, &handle);
+        unsafe { libc::umask(old_umask); }
+        let listener = listener?;
+
+        // The standard library always asks for a backlog of 128. Listening
+        // again on the bound socket is how to change that.
+
+        if unsafe { libc::listen(listener.as_raw_fd(), libc::c_int::from(self.listen_backlog)) } != 0 {
+            return Err(format_err!("failed to set the socket's backlog: {}", io::Error::last_os_error()));
+        }
+
+        fs::set_permissions(&self.sock_path, fs::Permissions::from_mode(self.socket_mode))?;
 
         log!(self, "starting up");
 
+        if let Err(e) = check_storage(&self.sock_path) {
+            log_error!(self, "{}; new tunnels will be refused until this is fixed", e);
+        }
+        let (path, mode) = (self.sock_path.display().to_string(), self.socket_mode);
+        log!(self, "listening on {} with mode {:o}", path, mode);
+
+        // If a previous daemon crashed, it may have left sockets behind.
+
+        let record_path = socket_record_path(&self.sock_path);
+
+        match load_owned_sockets(&record_path) {
+            Ok(paths) => self.owned_sockets = paths,
+            Err(e) => log_error!(self, "failed to read {}: {}", record_path.display(), e),
+        }
+
+        for p in clean_up_sockets(&mut self) {
+            log!(self, "removed stale socket {}", p.display());
+        }
+
+        let pid_path = self.sock_path.with_extension("pid");
+        let sock_path = self.sock_path.clone();
+
         // Needed to command the creation of an SSH client
 
         let shared = Arc::new(Mutex::new(self));
         let shared3 = shared.clone();
+        let shared4 = shared.clone();
+        let shared5 = shared.clone();
+        let shared6 = shared.clone();
 
         // The "main task" is just going to hang out monitoring a channel
         // waiting for someone to tell it to exit, because we might want to
@@ -155,8 +881,11 @@ impl State {
             let stream = sig_stream
                 .map_err(|_| {})
                 .and_then(move |sig| {
-                    log!(shared2.lock().unwrap(), "exiting on signal {}", sig);
-                    tx_exit2.clone().send(()).map_err(|_| {})
+                    let mut sh = shared2.lock().unwrap();
+                    log!(sh, "exiting on signal {}", sig);
+                    sh.shutting_down = true;
+                    drop(sh);
+                    tx_exit2.clone().send(ShutdownReason::Signal(sig)).map_err(|_| {})
                 });
 
             let fut = stream.into_future().map(|_| {}).map_err(|_| {});
@@ -169,37 +898,95 @@ impl State {
 
         let handle2 = handle.clone();
         let tx_exit2 = tx_exit.clone();
+        let tx_exit3 = tx_exit.clone();
+        let mut limiter = {
+            let sh = shared.lock().unwrap();
+            AcceptLimiter::new(sh.max_accepts_per_sec, sh.clock.now())
+        };
 
         let server = listener.incoming().for_each(move |(socket, sockaddr)| {
             process_client(&handle2, socket, sockaddr, shared.clone(), tx_exit2.clone());
-            Ok(())
-        }).map_err(move |err| {
-            log!(shared3.lock().unwrap(), "accept error: {:?}", err);
+
+            // Waiting here holds up the next accept, so a storm of
+            // connections backs up in the listen queue rather than in here.
+
+            let mut sh = shared.lock().unwrap();
+
+            match limiter.note_accept(sh.clock.now()) {
+                None => future::Either::A(future::ok(())),
+
+                Some(delay) => {
+                    log!(sh, "too many connections; not accepting more for {} ms",
+                         delay.as_secs() * 1000 + u64::from(delay.subsec_millis()));
+                    future::Either::B(future::result(sh.clock.timer(delay, &handle2)).flatten())
+                },
+            }
+        }).or_else(move |err| {
+            // Without the listener, we're no use to anybody.
+            log_error!(shared3.lock().unwrap(), "accept error: {:?}", err);
+            tx_exit3.send(ShutdownReason::FatalError(err.into())).map(|_| {}).map_err(|_| {})
         });
 
         handle.spawn(server);
 
-        // The return and error values of the wait-to-die task are
-        // meaningless. Note that we don't need to explicitly close our SSH
-        // child processes since they'll get SIGHUP'ed when our controlling
-        // PTY goes away, which will cause them to exit as desired. Yay Unix!
+        // Idle tunnels are looked for periodically. Tunnels can ask to be
+        // reaped even if the daemon has no default, so this always runs.
 
-        let _r = core.run(rx_exit.into_future());
-        Ok(())
-    }
-}
+        let handle3 = handle.clone();
 
+        let reaper = future::loop_fn((), move |()| {
+            let shared = shared4.clone();
+            let clock = shared.lock().unwrap().clock.clone();
 
-// Supporting jazz for managing SSH processes
+            future::result(clock.timer(IDLE_CHECK_INTERVAL, &handle3))
+                .flatten()
+                .map(move |()| {
+                    reap_idle_tunnels(&mut shared.lock().unwrap());
+                    future::Loop::Continue(())
+                })
+        }).map_err(move |err: io::Error| {
+            log_error!(shared5.lock().unwrap(), "idle-tunnel timer failed: {}", err);
+        });
 
-type PtyStream = SplitStream<Framed<AsyncPtyMaster, BytesCodec>>;
-type PtySink = SplitSink<Framed<AsyncPtyMaster, BytesCodec>>;
+        handle.spawn(reaper);
 
-enum TunnelState {
-    /// An SSH process that we have launched and is, as far as we know, still
+        // Now we wait to be told why to exit. Note that we don't need to
+        // explicitly close our SSH child processes since they'll get
+        // SIGHUP'ed when our controlling PTY goes away, which will cause
+        // them to exit as desired. Yay Unix!
+
+        let reason = match core.run(rx_exit.into_future()) {
+            Ok((Some(reason), _)) => reason,
+            _ => ShutdownReason::FatalError(format_err!("lost the channel for exit requests")),
+        };
+
+        log!(shared6.lock().unwrap(), "shutting down: {}", reason);
+        let _r = fs::remove_file(sock_path);
+        let _r = fs::remove_file(pid_path);
+        Ok(reason)
+    }
+}
+
+
+// Supporting jazz for managing SSH processes
+
+type PtyFramed = Framed<AsyncPtyMaster, BytesCodec>;
+type PtyStream = SplitStream<PtyFramed>;
+type PtySink = SplitSink<PtyFramed>;
+type SshErrStream = Framed<UnixStream, BytesCodec>;
+type SendTrailing = SendAll<Ser, IterOk<vec::IntoIter<ServerMessage>, io::Error>>;
+
+enum TunnelState {
+    /// An SSH process that we have launched and is, as far as we know, still
     /// running.
     Running {
-        tx_kill: oneshot::Sender<()>,
+        id: u64,
+        pid: u32,
+        params: OpenParameters,
+        pty: fs::File,
+        tx_kill: oneshot::Sender<KillRequest>,
+        relay_stats: Arc<Mutex<RelayStats>>,
+        control_path: Option<PathBuf>,
     },
 
     /// An SSH process that we launched but is now dead. If the exit status is
@@ -210,17 +997,132 @@ enum TunnelState {
     },
 }
 
+/// A token representing an open that is in progress. The daemon keeps count
+/// of these so that it can wait for them to finish before exiting.
+///
+/// Creating or dropping one of these locks the shared state, so it must never
+/// happen while the lock is held, or the daemon deadlocks. In particular, be
+/// careful not to drop a client state that owns one while holding the guard
+/// returned by `ClientCommonState::shared`.
+struct OpenInFlight {
+    shared: Arc<Mutex<State>>,
+}
+
+impl OpenInFlight {
+    fn new(shared: Arc<Mutex<State>>) -> Self {
+        shared.lock().unwrap().opens_in_flight += 1;
+        OpenInFlight { shared }
+    }
+}
+
+impl Drop for OpenInFlight {
+    fn drop(&mut self) {
+        let mut sh = self.shared.lock().unwrap();
+        sh.opens_in_flight -= 1;
+
+        if sh.opens_in_flight == 0 {
+            for tx in sh.tx_opens_done.drain(..) {
+                let _r = tx.send(());
+            }
+        }
+    }
+}
+
+/// A request for a child monitor to kill its SSH process. The monitor
+/// signals `tx_done` once it has done so, with whether the process exited
+/// of its own accord. With a `grace` period, the process is first sent
+/// SIGTERM, and only killed if it's still running once the period is up.
+struct KillRequest {
+    tx_done: oneshot::Sender<bool>,
+    grace: Option<Duration>,
+}
+
+/// The longest that a close will let SSH take to exit after SIGTERM.
+const MAX_CLOSE_GRACE_SECS: u64 = 60 * 60;
+
+/// The capacity of the channel on which a child monitor reports that its
+/// SSH process has died. Kill requests go over a oneshot channel, which
+/// never blocks, but this one is an `mpsc` channel, and with no spare
+/// capacity a monitor's single death notice wouldn't count as sent until
+/// the login that it belongs to read it, which it may never do. With a
+/// slot to spare, the monitor can always finish as soon as its child does.
+const DIE_CHANNEL_CAPACITY: usize = 1;
+
+
+/// Bookkeeping about the I/O relay between a client and an SSH process, for
+/// diagnosing stalls. This is cheap to update, so we always do so. It also
+/// says whether the relay has been paused, since everything that would
+/// need to know already has it at hand.
+#[derive(Debug, Default)]
+struct RelayStats {
+    cl_buf_len: usize,
+    ssh_buf_len: usize,
+    cl_blocked: bool,
+    ssh_blocked: bool,
+    last_cl_activity: Option<Instant>,
+    last_ssh_activity: Option<Instant>,
+    pty_closed: bool,
+    bytes_from_ssh: u64,
+    bytes_to_ssh: u64,
+    handed_off: Option<Instant>,
+    paused: bool,
+    resume_task: Option<Task>,
+    tee: Option<(PathBuf, fs::File)>,
+}
+
+impl RelayStats {
+    fn to_counters(&self) -> RelayCounters {
+        fn idle_ms(t: Option<Instant>) -> Option<u64> {
+            t.map(|t| {
+                let d = t.elapsed();
+                d.as_secs() * 1000 + u64::from(d.subsec_millis())
+            })
+        }
+
+        RelayCounters {
+            client_buf_len: self.cl_buf_len,
+            ssh_buf_len: self.ssh_buf_len,
+            client_blocked: self.cl_blocked,
+            ssh_blocked: self.ssh_blocked,
+            client_idle_ms: idle_ms(self.last_cl_activity),
+            ssh_idle_ms: idle_ms(self.last_ssh_activity),
+        }
+    }
+
+    /// When the tunnel last saw any activity, or `None` if it's still
+    /// logging in.
+    fn last_activity(&self) -> Option<Instant> {
+        let handed_off = self.handed_off?;
+        Some([self.last_cl_activity, self.last_ssh_activity].iter()
+             .filter_map(|t| *t)
+             .fold(handed_off, |a, b| a.max(b)))
+    }
+}
+
 
 #[derive(StateMachineFuture)]
 #[allow(unused)] // get lots of these spuriously; custom derive stuff?
 enum ChildMonitor {
-    #[state_machine_future(start, transitions(NotifyingChildDied))]
+    #[state_machine_future(start, transitions(Terminating, NotifyingChildDied))]
     AwaitingChildEvent {
         shared: Arc<Mutex<State>>,
-        key: String,
+        id: u64,
         child: Child,
-        rx_kill: oneshot::Receiver<()>,
+        rx_kill: oneshot::Receiver<KillRequest>,
         tx_die: mpsc::Sender<Option<ExitStatus>>, // None if child was explicitly killed
+        control_path: Option<PathBuf>, // the SSH control socket, if any
+        handle: Handle,
+    },
+
+    #[state_machine_future(transitions(NotifyingChildDied))]
+    Terminating {
+        shared: Arc<Mutex<State>>,
+        id: u64,
+        child: Child,
+        grace: Timer,
+        tx_done: oneshot::Sender<bool>,
+        tx_die: mpsc::Sender<Option<ExitStatus>>,
+        control_path: Option<PathBuf>,
     },
 
     #[state_machine_future(transitions(ChildReaped))]
@@ -235,6 +1137,17 @@ enum ChildMonitor {
     ChildError(()),
 }
 
+/// Remove the control socket of an SSH process that has gone away. It
+/// usually does this itself, but not if it was killed. This has to happen
+/// before anyone hears of the death, in case they're about to start a new
+/// tunnel with the same label.
+fn remove_control_socket(shared: &Arc<Mutex<State>>, path: &Option<PathBuf>) {
+    if let Some(ref p) = *path {
+        let _r = fs::remove_file(p); // nothing to do if this fails
+        shared.lock().unwrap().disown_socket(p);
+    }
+}
+
 impl PollChildMonitor for ChildMonitor {
     fn poll_awaiting_child_event<'a>(
         state: &'a mut RentToOwn<'a, AwaitingChildEvent>
@@ -249,10 +1162,31 @@ impl PollChildMonitor for ChildMonitor {
                 // we should let other tasks know what happened.
 
                 let mut state = state.take();
+                remove_control_socket(&state.shared, &state.control_path);
                 {
                     let mut sh = state.shared.lock().unwrap();
-                    log!(sh, "SSH child for {} unexpectedly died: {:?}", state.key, status);
-                    sh.children.insert(state.key, TunnelState::Exited { status: Some(status) });
+
+                    match sh.running_key(state.id) {
+                        Some(key) => {
+                            log_error!(sh, "SSH child for {} unexpectedly died: {:?}", key, status);
+
+                            // SSH exits with 255 if it hit an error of its
+                            // own, like failing to connect.
+                            let kind = match status.code() {
+                                Some(255) => TunnelEventKind::NetworkError,
+                                _ => TunnelEventKind::Crashed,
+                            };
+
+                            sh.record_event(&key, kind, status.code());
+                            sh.children.insert(key, TunnelState::Exited { status: Some(status) });
+                        },
+
+                        None => {
+                            log!(sh, "SSH child for defunct tunnel #{} died: {:?}", state.id, status);
+                        },
+                    }
+
+                    sh.notify_exit_waiters(state.id, Some(status));
                 }
                 state.rx_kill.close();
                 transition!(NotifyingChildDied {
@@ -268,16 +1202,46 @@ impl PollChildMonitor for ChildMonitor {
                 return Err(());
             },
 
-            Ok(Async::Ready(_)) => {
+            Ok(Async::Ready(req)) => {
                 // We've been told to kill the child.
                 let mut state = state.take();
                 {
                     let mut sh = state.shared.lock().unwrap();
-                    log!(sh, "ordered to kill SSH child for {}", state.key);
-                    sh.children.insert(state.key, TunnelState::Exited { status: None });
+                    log!(sh, "ordered to kill SSH child for tunnel #{}", state.id);
+
+                    if let Some(key) = sh.running_key(state.id) {
+                        sh.children.insert(key, TunnelState::Exited { status: None });
+                    }
+
+                    sh.notify_exit_waiters(state.id, None);
                 }
-                let _r = state.child.kill(); // can't do anything if this fails
                 state.rx_kill.close();
+
+                let grace = req.grace.and_then(|d| {
+                    let clock = state.shared.lock().unwrap().clock.clone();
+                    clock.timer(d, &state.handle).ok() // no timer? just kill it
+                });
+
+                if let Some(grace) = grace {
+                    // Ask nicely first. If the signal can't be sent, the
+                    // child is already on its way out, and polling it will
+                    // tell us so.
+                    unsafe { libc::kill(state.child.id() as libc::pid_t, libc::SIGTERM); }
+
+                    transition!(Terminating {
+                        shared: state.shared,
+                        id: state.id,
+                        child: state.child,
+                        grace,
+                        tx_done: req.tx_done,
+                        tx_die: state.tx_die,
+                        control_path: state.control_path,
+                    });
+                }
+
+                let _r = state.child.kill(); // can't do anything if this fails
+                remove_control_socket(&state.shared, &state.control_path);
+                let _r = req.tx_done.send(false); // requester may not care
                 transition!(NotifyingChildDied {
                     tx_die: state.tx_die.send(None),
                 });
@@ -289,12 +1253,48 @@ impl PollChildMonitor for ChildMonitor {
         Ok(Async::NotReady)
     }
 
+    /// We've sent the child SIGTERM as part of a close with a grace period.
+    /// The tunnel has already been marked as closed, so all that's left is
+    /// to see whether the child exits in time, and kill it if not.
+    fn poll_terminating<'a>(
+        state: &'a mut RentToOwn<'a, Terminating>
+    ) -> Poll<AfterTerminating, ()> {
+        let graceful = match state.child.poll() {
+            Ok(Async::Ready(_)) => true,
+
+            Ok(Async::NotReady) => match state.grace.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(())) | Err(_) => false, // a broken timer is as good as expired
+            },
+
+            Err(_) => false,
+        };
+
+        let mut state = state.take();
+
+        if !graceful {
+            log!(state.shared.lock().unwrap(),
+                 "SSH child for tunnel #{} outlasted its grace period; killing it", state.id);
+            let _r = state.child.kill(); // can't do anything if this fails
+        }
+
+        remove_control_socket(&state.shared, &state.control_path);
+        let _r = state.tx_done.send(graceful); // requester may not care
+        transition!(NotifyingChildDied {
+            tx_die: state.tx_die.send(None),
+        });
+    }
+
     fn poll_notifying_child_died<'a>(
         state: &'a mut RentToOwn<'a, NotifyingChildDied>
     ) -> Poll<AfterNotifyingChildDied, ()> {
         match state.tx_die.poll() {
             Err(_) => {
-                return Err(());
+                // The receiver is gone -- say, because the open that was
+                // waiting on this child errored out. Nobody cares about the
+                // news anymore, and the child has been dealt with, so we're
+                // done all the same.
+                transition!(ChildReaped(()));
             },
 
             Ok(Async::Ready(_)) => {
@@ -309,21 +1309,72 @@ impl PollChildMonitor for ChildMonitor {
 }
 
 
+/// The period over which the daemon counts accepted connections.
+const ACCEPT_WINDOW: Duration = Duration::from_secs(1);
+
+/// Keeps count of the connections accepted recently, so that a client stuck
+/// in a loop can't make the daemon spend all of its time and file
+/// descriptors on new sessions.
+struct AcceptLimiter {
+    max_per_window: u32,
+    window_start: Instant,
+    count: u32,
+}
+
+impl AcceptLimiter {
+    /// Allow `max_per_window` connections each `ACCEPT_WINDOW`, or any
+    /// number if it's zero.
+    fn new(max_per_window: u32, now: Instant) -> Self {
+        AcceptLimiter {
+            max_per_window,
+            window_start: now,
+            count: 0,
+        }
+    }
+
+    /// Record a connection accepted at `now`. If it uses up the allowance,
+    /// returns how long to wait before accepting another.
+    fn note_accept(&mut self, now: Instant) -> Option<Duration> {
+        if self.max_per_window == 0 {
+            return None;
+        }
+
+        if now.saturating_duration_since(self.window_start) >= ACCEPT_WINDOW {
+            self.window_start = now;
+            self.count = 0;
+        }
+
+        self.count += 1;
+
+        if self.count < self.max_per_window {
+            None
+        } else {
+            Some(ACCEPT_WINDOW - now.saturating_duration_since(self.window_start))
+        }
+    }
+}
+
+
 // Oh right we actually want to handle clients too
 
 fn process_client(
     handle: &Handle, socket: UnixStream, addr: SocketAddr, shared: Arc<Mutex<State>>,
-    tx_exit: mpsc::Sender<()>,
+    tx_exit: mpsc::Sender<ShutdownReason>,
 ) {
-    // Without turning on linger, I find that the tokio-ized version loses
-    // the last bytes of the session. Let's just ignore the return value
+    // We used to always turn on linger here because sessions would lose
+    // their last bytes. We now make sure that our final messages are flushed
+    // before the connection is dropped, and clients wait for us to hang up,
+    // so this shouldn't be needed anymore; but keep it around as an option in
+    // case it turns out that it still is. Let's just ignore the return value
     // of setsockopt(), though.
 
-    unsafe {
-        let linger = libc::linger { l_onoff: 1, l_linger: 2 };
-        libc::setsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_LINGER,
-                         (&linger as *const libc::linger) as _,
-                         mem::size_of::<libc::linger>() as libc::socklen_t);
+    if shared.lock().unwrap().socket_linger {
+        unsafe {
+            let linger = libc::linger { l_onoff: 1, l_linger: 2 };
+            libc::setsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_LINGER,
+                             (&linger as *const libc::linger) as _,
+                             mem::size_of::<libc::linger>() as libc::socklen_t);
+        }
     }
 
     let (read, write) = socket.split();
@@ -333,25 +1384,47 @@ fn process_client(
     let de = ReadBincode::new(rdelim);
 
     let handle2 = handle.clone();
-    let shared2 = shared.clone();
     let shared3 = shared.clone();
+    let shared4 = shared.clone();
+    let (session_id, keepalive, clock) = {
+        let mut sh = shared.lock().unwrap();
+        sh.num_sessions += 1;
+        sh.next_session_id += 1;
+        (sh.next_session_id, sh.keepalive, sh.clock.clone())
+    };
 
     let common = ClientCommonState {
         handle: handle.clone(),
         shared: shared,
+        session_id,
         _addr: addr,
         tx_exit: tx_exit,
         exit_on_close: false,
+        keepalive,
+        clock,
+        read_only: false,
     };
 
-    let wrapped = Client::start(common, ser, de).map(move |(common, _ser, _de)| {
-        log!(shared2.lock().unwrap(), "client session finished (exit? {})", common.exit_on_close);
+    let wrapped = Client::start(common, ser, de).and_then(|(common, mut ser, _de)| {
+        // Everything we've sent should already be flushed, but make sure of
+        // it before we drop the connection. If that fails -- say, because the
+        // client hung up right after sending "Exit" -- we still need to
+        // honor an exit request, so the error travels alongside `common`.
+        future::poll_fn(move || ser.close()).then(move |r| Ok((common, r.map_err(Error::from))))
+    }).then(move |r| {
+        shared4.lock().unwrap().num_sessions -= 1;
 
-        if common.exit_on_close {
-            handle2.spawn(common.tx_exit.send(()).map(|_| {}).map_err(|_| {}));
-        }
-    }).map_err(move |err| {
-        log!(shared3.lock().unwrap(), "error from client session: {:?}", err);
+        r.and_then(|(common, close_result)| {
+            log!(common.shared(), "client session finished (exit? {})", common.exit_on_close);
+
+            if common.exit_on_close {
+                handle2.spawn(common.tx_exit.send(ShutdownReason::ClientRequested).map(|_| {}).map_err(|_| {}));
+            }
+
+            close_result
+        }).map_err(move |err| {
+            log_error!(shared3.lock().unwrap(), "[session {}] error from client session: {:?}", session_id, err);
+        })
     });
 
     handle.spawn(wrapped);
@@ -361,28 +1434,95 @@ fn process_client(
 struct ClientCommonState {
     handle: Handle,
     shared: Arc<Mutex<State>>,
+    session_id: u64,
     _addr: SocketAddr,
-    tx_exit: mpsc::Sender<()>,
+    tx_exit: mpsc::Sender<ShutdownReason>,
     exit_on_close: bool,
+    keepalive: Option<Duration>,
+    clock: SharedClock,
+    read_only: bool,
 }
 
 impl ClientCommonState {
-    pub fn shared(&self) -> ::std::sync::MutexGuard<State> {
-        self.shared.lock().unwrap()
+    pub fn shared(&self) -> SessionState {
+        SessionState {
+            state: self.shared.lock().unwrap(),
+            session_id: self.session_id,
+        }
+    }
+
+    /// Build an error reply. It names the session, so that the user can
+    /// find the matching lines in the daemon's log.
+    fn error_reply(&self, code: ProtocolError, message: String) -> ServerMessage {
+        ServerMessage::ErrorCode(code, format!("{} (session {})", message, self.session_id))
+    }
+
+    /// Log a message from the client, if we've been asked to. Only a
+    /// summary is logged, so that passwords never end up in the log.
+    fn log_message(&self, msg: &ClientMessage) {
+        let mut sh = self.shared();
+
+        if sh.log_messages {
+            log!(sh, "client sent {}", msg.summary());
+        }
+    }
+}
+
+/// The daemon's state, locked on behalf of a client session. Log messages
+/// written through it are marked with the session's ID, so that those of
+/// concurrent sessions can be told apart.
+struct SessionState<'a> {
+    state: MutexGuard<'a, State>,
+    session_id: u64,
+}
+
+impl<'a> SessionState<'a> {
+    fn log_items(&mut self, severity: Severity, args: fmt::Arguments) {
+        let session_id = self.session_id;
+        self.state.log_items(severity, format_args!("[session {}] {}", session_id, args));
+    }
+}
+
+impl<'a> Deref for SessionState<'a> {
+    type Target = State;
+
+    fn deref(&self) -> &State {
+        &self.state
+    }
+}
+
+impl<'a> DerefMut for SessionState<'a> {
+    fn deref_mut(&mut self) -> &mut State {
+        &mut self.state
     }
 }
 
 #[derive(StateMachineFuture)]
 #[allow(unused)] // get lots of these spuriously; custom derive stuff?
 enum Client {
-    #[state_machine_future(start, transitions(CommunicatingForOpen, FinalizingTxn, Finished, Aborting))]
+    #[state_machine_future(start, transitions(AwaitingCommand, CommunicatingForOpen,
+                                              ReplacingTunnel, WaitingForOpens,
+                                              WaitingForTunnelExit, WaitingForClose,
+                                              FinalizingTxn, Finished, Aborting))]
     AwaitingCommand {
         common: ClientCommonState,
         tx: Ser,
         rx: De,
     },
 
-    #[state_machine_future(transitions(Aborting, CommunicatingForOpen, FinalizingTxn))]
+    #[state_machine_future(transitions(CommunicatingForOpen, Aborting, FinalizingTxn))]
+    ReplacingTunnel {
+        common: ClientCommonState,
+        label: String,
+        params: OpenParameters,
+        tx: Ser,
+        rx: De,
+        rx_killed: oneshot::Receiver<bool>,
+        in_flight: OpenInFlight,
+    },
+
+    #[state_machine_future(transitions(Aborting, FlushingBeforeAbort, FlushingBeforeReply,
+                                       CommunicatingForOpen, FinalizingTxn))]
     CommunicatingForOpen {
         common: ClientCommonState,
         cl_tx: Ser,
@@ -390,10 +1530,51 @@ enum Client {
         cl_buf: Vec<u8>,
         ssh_tx: PtySink,
         ssh_rx: PtyStream,
+        ssh_eof: bool,
+        ssh_err: Option<SshErrStream>,
         ssh_buf: Vec<u8>,
+        err_buf: Vec<u8>,
         ssh_key: Vec<u8>,
         ssh_key_status: SshKeyStatus,
         ssh_die: StreamFuture<mpsc::Receiver<Option<ExitStatus>>>,
+        tunnel_id: u64,
+        relay_stats: Arc<Mutex<RelayStats>>,
+        ping_timer: Option<Timer>,
+        ping_wanted: bool,
+        awaiting_pong: bool,
+        connect_attempts: u32,
+        connect_timeout: bool,
+        ssh_tail: Vec<u8>,
+        report_key: bool,
+        report_pid: bool,
+        log_stderr: bool,
+        close_if_abandoned: bool,
+        _in_flight: OpenInFlight,
+    },
+
+    #[state_machine_future(transitions(FinalizingTxn))]
+    WaitingForOpens {
+        common: ClientCommonState,
+        tx: Ser,
+        rx: De,
+        rx_done: oneshot::Receiver<()>,
+        timeout: Timer,
+    },
+
+    #[state_machine_future(transitions(FinalizingTxn))]
+    WaitingForTunnelExit {
+        common: ClientCommonState,
+        tx: Ser,
+        rx: De,
+        rx_exit: oneshot::Receiver<Option<ExitStatus>>,
+    },
+
+    #[state_machine_future(transitions(FinalizingTxn))]
+    WaitingForClose {
+        common: ClientCommonState,
+        tx: Ser,
+        rx: De,
+        rx_done: oneshot::Receiver<bool>,
     },
 
     #[state_machine_future(transitions(AwaitingCommand))]
@@ -406,13 +1587,35 @@ enum Client {
     #[state_machine_future(ready)]
     Finished((ClientCommonState, Ser, De)),
 
-    #[state_machine_future(transitions(Aborting, Failed))]
+    #[state_machine_future(transitions(FinalizingTxn))]
+    FlushingBeforeReply {
+        common: ClientCommonState,
+        tx: SendTrailing,
+        rx: De,
+        reply: ServerMessage,
+    },
+
+    #[state_machine_future(transitions(Aborting))]
+    FlushingBeforeAbort {
+        common: ClientCommonState,
+        tx: SendTrailing,
+        rx: De,
+        code: ProtocolError,
+        message: String,
+    },
+
+    #[state_machine_future(transitions(ClosingAfterAbort, Failed))]
     Aborting {
         common: ClientCommonState,
         tx: Send<Ser>,
         rx: De,
     },
 
+    #[state_machine_future(transitions(Failed))]
+    ClosingAfterAbort {
+        tx: Ser,
+    },
+
     #[state_machine_future(error)]
     Failed(Error),
 }
@@ -431,7 +1634,16 @@ impl PollClient for Client {
         state: &'a mut RentToOwn<'a, AwaitingCommand>
     ) -> Poll<AfterAwaitingCommand, Error> {
         let msg = try_ready!(state.rx.poll());
-        let mut state = state.take();
+        let state = state.take();
+
+        if let Some(ref m) = msg {
+            state.common.log_message(m);
+
+            if state.common.read_only && !m.is_read_only() {
+                let text = format!("this session is read-only; refusing {}", m.summary());
+                transition!(reply_error(state.common, state.tx, state.rx, ProtocolError::Forbidden, text));
+            }
+        }
 
         match msg {
             None => {
@@ -447,184 +1659,322 @@ impl PollClient for Client {
                 return process_open_command(state.common, params, state.tx, state.rx);
             },
 
+            Some(ClientMessage::DryRunOpen(params)) => {
+                return process_dry_run_open_command(state.common, params, state.tx, state.rx);
+            },
+
+            Some(ClientMessage::Relabel(params)) => {
+                return process_relabel_command(state.common, params, state.tx, state.rx);
+            },
+
             Some(ClientMessage::Exit) => {
-                // To be able to close out this connection in a nice way, when we get
-                // this command we set a flag that will cause the exit message to be
-                // sent on connection close.
+                return process_exit_command(state.common, ExitParameters::default(), state.tx, state.rx);
+            },
 
-                log!(state.common.shared(), "commanded to exit after client disconnects");
-                state.common.exit_on_close = true;
-                let send = state.tx.send(ServerMessage::Ok);
+            Some(ClientMessage::ExitWithParameters(params)) => {
+                return process_exit_command(state.common, params, state.tx, state.rx);
+            },
 
-                transition!(FinalizingTxn {
+            Some(ClientMessage::Goodbye) => {
+                transition!(Finished((state.common, state.tx, state.rx)));
+            },
+
+            Some(ClientMessage::PrepareUpgrade) => {
+                return process_prepare_upgrade_command(state.common, state.tx, state.rx);
+            },
+
+            Some(ClientMessage::GetLog { lines }) => {
+                return process_get_log_query(state.common, lines, state.tx, state.rx);
+            },
+
+            Some(ClientMessage::Pong) | Some(ClientMessage::Cancel) |
+            Some(ClientMessage::WindowSize { .. }) => {
+                // The answer to a ping sent just before a login finished, or
+                // a cancellation or resize that arrived too late to matter.
+                transition!(AwaitingCommand {
                     common: state.common,
-                    tx: send,
+                    tx: state.tx,
                     rx: state.rx,
                 });
             },
 
-            Some(ClientMessage::Goodbye) => {
-                transition!(Finished((state.common, state.tx, state.rx)));
+            Some(ClientMessage::QueryStatus) => {
+                return process_status_query(state.common, StatusFilter::default(), state.tx, state.rx);
             },
 
-            Some(ClientMessage::QueryStatus) => {
-                return process_status_query(state.common, state.tx, state.rx);
+            Some(ClientMessage::QueryStatusFiltered(filter)) => {
+                return process_status_query(state.common, filter, state.tx, state.rx);
             },
 
-            Some(other) => {
-                return Err(format_err!("unexpected message from client: {:?}", other));
+            Some(ClientMessage::Resize(params)) => {
+                return process_resize_command(state.common, params, state.tx, state.rx);
             },
-        }
-    }
 
-    /// The main thread has successfully started SSH! Now we do some
-    /// uber-multiplexing to allow the client to communicate with the SSH
-    /// process interactively, while keeping tabs on whether SSH bites the
-    /// dust under us.
-    fn poll_communicating_for_open<'a>(
-        state: &'a mut RentToOwn<'a, CommunicatingForOpen>
-    ) -> Poll<AfterCommunicatingForOpen, Error> {
-        // New text from the user?
+            Some(ClientMessage::CheckTunnel(params)) => {
+                return process_check_tunnel_command(state.common, params, state.tx, state.rx);
+            },
 
-        while let Async::Ready(msg) = state.cl_rx.poll()? {
-            match msg {
-                Some(ClientMessage::UserData(data)) => {
-                    state.ssh_buf.extend_from_slice(&data);
-                },
+            Some(ClientMessage::QueryRelayCounters(params)) => {
+                return process_relay_counters_query(state.common, params, state.tx, state.rx);
+            },
 
-                Some(other) => {
-                    // Could consider aborting here, but if we didn't
-                    // understand the client then probably there's
-                    // something messed up about the channel.
-                    return Err(format_err!("unexpected message from the client: {:?}", other));
-                },
+            Some(ClientMessage::QueryVersion) => {
+                return process_version_query(state.common, state.tx, state.rx);
+            },
 
-                None => {
-                    return Err(format_err!("client connection unexpectedly closed"));
-                },
-            }
-        }
+            Some(ClientMessage::DumpState) => {
+                return process_dump_state_query(state.common, state.tx, state.rx);
+            },
 
-        // New text from SSH?
+            Some(ClientMessage::TunnelStatus { host }) => {
+                return process_tunnel_status_query(state.common, host, state.tx, state.rx);
+            },
 
-        loop {
-            let outcome = match state.ssh_rx.poll() {
-                Ok(x) => x,
-                Err(e) => {
-                    let msg = format!("something went wrong communicating with the SSH process: {}", e);
-                    let mut state = state.take();
-                    transition!(abort_client(state.common, state.cl_tx, state.cl_rx, msg));
-                },
-            };
+            Some(ClientMessage::Cleanup) => {
+                return process_cleanup_command(state.common, state.tx, state.rx);
+            },
 
-            match outcome {
-                Async::NotReady => break,
-
-                Async::Ready(maybe_bytes) => {
-                    if let Some(bytes) = maybe_bytes {
-                        // We need to search SSH's output for the "key" that
-                        // we use to figure out that login has completed
-                        // successfully.
-                        //
-                        // If we were cleverer we would not show the "key"
-                        // text to the client, but I don't want to figure out
-                        // the right state machine magic to ensure that output
-                        // is eventually showed in the event of an incomplete
-                        // match.
-                        if let SshKeyStatus::Searching(next_idx) = state.ssh_key_status {
-                            let mut n = next_idx;
-
-                            for b in &bytes {
-                                if b == state.ssh_key[n] {
-                                    n += 1;
-
-                                    if n == state.ssh_key.len() {
-                                        break;
-                                    }
-                                } else {
-                                    n = 0;
-                                }
-                            }
+            Some(ClientMessage::OpenProfile { name }) => {
+                return process_open_profile_command(state.common, name, state.tx, state.rx);
+            },
 
-                            if n == state.ssh_key.len() {
-                                state.ssh_key_status = SshKeyStatus::FoundIt;
-                            } else {
-                                state.ssh_key_status = SshKeyStatus::Searching(n);
-                            }
-                        }
+            Some(ClientMessage::Pause { host }) => {
+                return process_pause_command(state.common, host, true, state.tx, state.rx);
+            },
 
-                        state.cl_buf.extend_from_slice(&bytes);
-                    } else  {
-                        // EOF from SSH -- it has probably died.
-                        let msg = format!("unexpected EOF from SSH (program died?)");
-                        let mut state = state.take();
-                        transition!(abort_client(state.common, state.cl_tx, state.cl_rx, msg));
-                    }
-                }
-            }
-        }
+            Some(ClientMessage::Resume { host }) => {
+                return process_pause_command(state.common, host, false, state.tx, state.rx);
+            },
 
-        // Ready/able to send bytes to the client?
+            Some(ClientMessage::SignalTunnel { host, signum }) => {
+                return process_signal_command(state.common, host, signum, state.tx, state.rx);
+            },
 
-        if state.cl_buf.len() != 0 {
-            let buf = state.cl_buf.clone();
+            Some(ClientMessage::WaitForExit { host }) => {
+                return process_wait_for_exit_command(state.common, host, state.tx, state.rx);
+            },
 
-            if let AsyncSink::Ready = state.cl_tx.start_send(ServerMessage::SshData(buf))? {
-                state.cl_buf.clear();
-            }
-        }
+            Some(ClientMessage::TeeToFile { host, path }) => {
+                return process_tee_command(state.common, host, Some(path), state.tx, state.rx);
+            },
 
-        // Ready/able to send bytes to SSH?
+            Some(ClientMessage::StopTee { host }) => {
+                return process_tee_command(state.common, host, None, state.tx, state.rx);
+            },
 
-        if state.ssh_buf.len() != 0 {
-            let buf = state.ssh_buf.clone();
+            Some(ClientMessage::SetReadOnly) => {
+                let mut common = state.common;
+                common.read_only = true;
+                let send = state.tx.send(ServerMessage::Ok);
+                transition!(FinalizingTxn { common, tx: send, rx: state.rx });
+            },
 
-            if let AsyncSink::Ready = state.ssh_tx.start_send(buf.into())? {
-                state.ssh_buf.clear();
-            }
-        }
+            Some(ClientMessage::Capabilities) => {
+                return process_capabilities_query(state.common, state.tx, state.rx);
+            },
 
-        // Gotta flush those transmissions.
+            Some(ClientMessage::GetConfig) => {
+                return process_config_query(state.common, state.tx, state.rx);
+            },
 
-        try_ready!(state.cl_tx.poll_complete());
-        try_ready!(state.ssh_tx.poll_complete());
+            Some(ClientMessage::Metrics) => {
+                return process_metrics_query(state.common, state.tx, state.rx);
+            },
 
-        // What's next?
+            Some(ClientMessage::QueryDaemonStats) => {
+                return process_daemon_stats_query(state.common, state.tx, state.rx);
+            },
 
-        if let SshKeyStatus::FoundIt = state.ssh_key_status {
-            let state = state.take();
+            Some(ClientMessage::Reconfigure(params)) => {
+                return process_reconfigure_command(state.common, params, state.tx, state.rx);
+            },
 
-            hand_off_ssh_process(&state.common.handle, state.common.shared.clone(),
-                                 state.ssh_tx, state.ssh_rx);
+            Some(other) => {
+                return Err(format_err!("unexpected message from client: {}", other.summary()));
+            },
+        }
+    }
 
-            let send = state.cl_tx.send(ServerMessage::Ok);
-            transition!(FinalizingTxn {
-                common: state.common,
-                tx: send,
-                rx: state.cl_rx,
-            });
+    /// We've been asked to replace an existing tunnel. Once its SSH process
+    /// has been killed, we can start the new one.
+    fn poll_replacing_tunnel<'a>(
+        state: &'a mut RentToOwn<'a, ReplacingTunnel>
+    ) -> Poll<AfterReplacingTunnel, Error> {
+        match state.rx_killed.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(_)) | Err(oneshot::Canceled) => {},
         }
 
-        Ok(Async::NotReady)
-    }
+        let state = state.take();
 
-    /// OMG, we actually started SSH successfully. Once we make sure that the
-    /// client has received its success notification, we can go back to
-    /// waiting for its next command.
-    fn poll_finalizing_txn<'a>(
-        state: &'a mut RentToOwn<'a, FinalizingTxn>
-    ) -> Poll<AfterFinalizingTxn, Error> {
-        let mut state = state.take();
-        let ser = try_ready!(state.tx.poll());
+        // The daemon may have been told to exit while we were waiting, in
+        // which case there's no point in starting a new SSH process -- unless
+        // it's holding off so that opens like this one can finish.
 
-        transition!(AwaitingCommand {
-            common: state.common,
-            tx: ser,
-            rx: state.rx,
-        });
+        let give_up = {
+            let sh = state.common.shared();
+            sh.shutting_down && !sh.letting_opens_finish
+        };
+
+        if give_up {
+            let send = state.tx.send(ServerMessage::ShuttingDown);
+            transition!(FinalizingTxn { common: state.common, tx: send, rx: state.rx });
+        }
+
+        transition!(start_ssh::<AfterReplacingTunnel>(state.common, &state.label, &state.params, true,
+                                                      state.in_flight, state.tx, state.rx))
     }
 
-    /// Something has happened that forces us to send the client an error
+    /// We've been told to exit once any opens that are underway have
+    /// finished. Once they have, or we've gotten tired of waiting, we can
+    /// tell the client that we're going ahead.
+    fn poll_waiting_for_opens<'a>(
+        state: &'a mut RentToOwn<'a, WaitingForOpens>
+    ) -> Poll<AfterWaitingForOpens, Error> {
+        let timed_out = match state.rx_done.poll() {
+            Ok(Async::Ready(())) | Err(oneshot::Canceled) => false,
+            Ok(Async::NotReady) => {
+                try_ready!(state.timeout.poll());
+                true
+            },
+        };
+
+        let state = state.take();
+
+        {
+            let mut sh = state.common.shared();
+            sh.letting_opens_finish = false;
+
+            if timed_out {
+                log_error!(sh, "gave up waiting for opens to finish; exiting anyway");
+            }
+        }
+
+        let send = state.tx.send(ServerMessage::Ok);
+        transition!(FinalizingTxn {
+            common: state.common,
+            tx: send,
+            rx: state.rx,
+        });
+    }
+
+    fn poll_waiting_for_tunnel_exit<'a>(
+        state: &'a mut RentToOwn<'a, WaitingForTunnelExit>
+    ) -> Poll<AfterWaitingForTunnelExit, Error> {
+        let reply = match state.rx_exit.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+
+            Ok(Async::Ready(status)) => ServerMessage::TunnelExitResponse(TunnelExit {
+                exit_status: status.map(|s| s.to_string()),
+                code: status.and_then(|s| s.code()),
+            }),
+
+            Err(oneshot::Canceled) => state.common.error_reply(
+                ProtocolError::Internal, "lost track of the tunnel while waiting for it to exit".to_owned()
+            ),
+        };
+
+        let state = state.take();
+        let send = state.tx.send(reply);
+        transition!(FinalizingTxn {
+            common: state.common,
+            tx: send,
+            rx: state.rx,
+        });
+    }
+
+    /// We've asked a tunnel's SSH process to exit with a grace period, and
+    /// are waiting to hear whether it did so in time.
+    fn poll_waiting_for_close<'a>(
+        state: &'a mut RentToOwn<'a, WaitingForClose>
+    ) -> Poll<AfterWaitingForClose, Error> {
+        let reply = match state.rx_done.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+
+            Ok(Async::Ready(graceful)) => ServerMessage::TunnelClosed { graceful },
+
+            Err(oneshot::Canceled) => state.common.error_reply(
+                ProtocolError::Internal, "lost track of the tunnel while waiting for it to close".to_owned()
+            ),
+        };
+
+        let state = state.take();
+        let send = state.tx.send(reply);
+        transition!(FinalizingTxn {
+            common: state.common,
+            tx: send,
+            rx: state.rx,
+        });
+    }
+
+    /// The main thread has successfully started SSH! Now we do some
+    /// uber-multiplexing to allow the client to communicate with the SSH
+    /// process interactively, while keeping tabs on whether SSH bites the
+    /// dust under us.
+    ///
+    /// If anything goes wrong with the client, the SSH process is killed:
+    /// our handle on its PTY keeps it from noticing on its own, and it would
+    /// otherwise sit at a login prompt forever while appearing to be open.
+    /// The exception is a login that has already finished, which
+    /// `abandon_login` may have handed off to keep running.
+    fn poll_communicating_for_open<'a>(
+        state: &'a mut RentToOwn<'a, CommunicatingForOpen>
+    ) -> Poll<AfterCommunicatingForOpen, Error> {
+        let shared = state.common.shared.clone();
+        let session_id = state.common.session_id;
+        let id = state.tunnel_id;
+        let relay_stats = state.relay_stats.clone();
+
+        let result = relay_login(state);
+
+        if result.is_err() && relay_stats.lock().unwrap().handed_off.is_none() {
+            kill_half_opened_tunnel(&shared, session_id, id);
+        }
+
+        result
+    }
+
+    /// OMG, we actually started SSH successfully. Once we make sure that the
+    /// client has received its success notification, we can go back to
+    /// waiting for its next command.
+    fn poll_finalizing_txn<'a>(
+        state: &'a mut RentToOwn<'a, FinalizingTxn>
+    ) -> Poll<AfterFinalizingTxn, Error> {
+        let mut state = state.take();
+        let ser = try_ready!(state.tx.poll());
+
+        transition!(AwaitingCommand {
+            common: state.common,
+            tx: ser,
+            rx: state.rx,
+        });
+    }
+
+    /// SSH's final output is on its way to the client; once it's out, send
+    /// the reply that explains it.
+    fn poll_flushing_before_reply<'a>(
+        state: &'a mut RentToOwn<'a, FlushingBeforeReply>
+    ) -> Poll<AfterFlushingBeforeReply, Error> {
+        let (ser, _) = try_ready!(state.tx.poll());
+        let state = state.take();
+        transition!(FinalizingTxn {
+            common: state.common,
+            tx: ser.send(state.reply),
+            rx: state.rx,
+        });
+    }
+
+    /// SSH's final output is on its way to the client; once it's out, send
+    /// the error message.
+    fn poll_flushing_before_abort<'a>(
+        state: &'a mut RentToOwn<'a, FlushingBeforeAbort>
+    ) -> Poll<AfterFlushingBeforeAbort, Error> {
+        let (ser, _) = try_ready!(state.tx.poll());
+        let state = state.take();
+        transition!(abort_client(state.common, ser, state.rx, state.code, state.message));
+    }
+
+    /// Something has happened that forces us to send the client an error
     /// message and terminate its connection. Make sure the message gets out.
     /// (Note that we must *not* return Err states in our state machine here
     /// because there is an important message that we must send to the client!
@@ -633,189 +1983,4104 @@ impl PollClient for Client {
     fn poll_aborting<'a>(
         state: &'a mut RentToOwn<'a, Aborting>
     ) -> Poll<AfterAborting, Error> {
-        try_ready!(state.tx.poll());
+        let ser = try_ready!(state.tx.poll());
+        transition!(ClosingAfterAbort { tx: ser });
+    }
+
+    /// The error has been sent; shut down our side of the connection
+    /// properly, just as a session that finishes normally does, so that the
+    /// client gets to read all of it.
+    fn poll_closing_after_abort<'a>(
+        state: &'a mut RentToOwn<'a, ClosingAfterAbort>
+    ) -> Poll<AfterClosingAfterAbort, Error> {
+        try_ready!(state.tx.close());
         Err(format_err!("ending connection now that client has been notified"))
     }
 }
 
-fn process_open_command(
-    common: ClientCommonState, params: OpenParameters, mut tx: Ser, rx: De
-) -> Poll<AfterAwaitingCommand, Error> {
-    let never_mind = {
-        let mut sh = common.shared();
-        log!(sh, "got command to spawn SSH for {}", params.host);
+/// Where an SSH process should connect to, picked apart from the
+/// user-friendly form of `OpenParameters`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Destination {
+    user: Option<String>,
+    host: String,
+    port: Option<u16>,
+}
+
+impl Destination {
+    /// Parse the host specification of an "Open" request.
+    ///
+    /// We have to do this ourselves because SSH doesn't understand the
+    /// `host:port` or bracketed IPv6 forms on its command line.
+    fn parse(params: &OpenParameters) -> Result<Self, Error> {
+        Self::parse_parts(&params.host, params.port, params.user.as_deref())
+    }
+
+    /// Parse a host specification, along with a port and user that may have
+    /// been given separately.
+    fn parse_parts(spec: &str, sep_port: Option<u16>, sep_user: Option<&str>) -> Result<Self, Error> {
 
-        if let Some(&TunnelState::Running { .. }) = sh.children.get(&params.host) {
-            log!(sh, "tunnel already open -- notifying client");
-            true
+        let (user, rest) = match spec.rfind('@') {
+            Some(i) => (Some(&spec[..i]), &spec[i+1..]),
+            None => (None, spec),
+        };
+
+        if let Some(u) = user {
+            if u.is_empty() {
+                return Err(format_err!("empty username in host \"{}\"", spec));
+            }
+        }
+
+        let (host, port_text) = if rest.starts_with('[') {
+            let close = rest.find(']')
+                .ok_or_else(|| format_err!("unterminated \"[\" in host \"{}\"", spec))?;
+            let after = &rest[close+1..];
+
+            let port_text = match after.chars().next() {
+                None => None,
+                Some(':') => Some(&after[1..]),
+                Some(_) => {
+                    return Err(format_err!("unexpected text after \"]\" in host \"{}\"", spec));
+                },
+            };
+
+            (&rest[1..close], port_text)
         } else {
-            false
+            match rest.matches(':').count() {
+                0 => (rest, None),
+                1 => {
+                    let i = rest.find(':').unwrap();
+                    (&rest[..i], Some(&rest[i+1..]))
+                },
+                _ => (rest, None), // bare IPv6 address
+            }
+        };
+
+        if host.is_empty() {
+            return Err(format_err!("no hostname in \"{}\"", spec));
         }
-    };
 
-    if never_mind {
-        let send = tx.send(ServerMessage::TunnelAlreadyOpen);
-        transition!(FinalizingTxn { common, tx: send, rx });
-    }
+        // A leading dash would make SSH think it was being given an option.
+        if host.starts_with('-') || spec.starts_with('-') {
+            return Err(format_err!("hostnames may not start with \"-\": \"{}\"", spec));
+        }
 
-    // Generate a magic bit of text that we'll use to recognize when the
-    // login has succeeded.
+        if spec.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(format_err!("hostnames may not contain whitespace: \"{}\"", spec));
+        }
 
-    let mut rng = rand::thread_rng();
-    let mut buf = [0u8; 32];
-    rng.fill_bytes(&mut buf);
-    let key = format!("STUND:{}", base64::encode(&buf));
+        let embedded_port = match port_text {
+            Some(t) => Some(t.parse::<u16>().map_err(|_| {
+                format_err!("invalid port \"{}\" in host \"{}\"", t, spec)
+            })?),
+            None => None,
+        };
 
-    // Let's launch the process.
+        let port = match (embedded_port, sep_port) {
+            (Some(_), Some(_)) => {
+                return Err(format_err!("a port was specified both in the host \"{}\" and separately",
+                                       spec));
+            },
+            (Some(p), None) | (None, Some(p)) => Some(p),
+            (None, None) => None,
+        };
 
-    let (tx_die, rx_die) = mpsc::channel(0);
+        if let Some(u) = sep_user {
+            if u.is_empty() {
+                return Err(format_err!("the username may not be empty"));
+            }
 
-    fn inner(
-        common: &ClientCommonState, params: &OpenParameters,
-        tx_die: mpsc::Sender<Option<ExitStatus>>, key: &str
-    ) -> Result<Framed<AsyncPtyMaster, BytesCodec>, Error> {
-        let (tx_kill, rx_kill) = oneshot::channel();
-        let ptymaster = AsyncPtyMaster::open().context("failed to create PTY")?;
+            if u.starts_with('-') || u.contains('@') || u.chars().any(|c| c.is_whitespace() || c.is_control()) {
+                return Err(format_err!("usernames may not start with \"-\" or contain \"@\" or \
+                                        whitespace: \"{}\"", u));
+            }
+        }
 
-        // The -t arg allocates a PTY for the command so that "tail" will die
-        // with a SIGHUP when SSH dies. Otherwise it will linger forever!
+        let user = match (user, sep_user) {
+            (Some(_), Some(_)) => {
+                return Err(format_err!("a user was specified both in the host \"{}\" and separately",
+                                       spec));
+            },
+            (Some(u), None) | (None, Some(u)) => Some(u),
+            (None, None) => None,
+        };
 
-        let child = process::Command::new("ssh")
-            .arg("-t")
-            .arg(&params.host)
-            .arg(format!("echo \"{}\" && exec tail -f /dev/null", key))
-            .env_remove("DISPLAY")
-            .spawn_pty_async(&ptymaster).context("failed to launch SSH")?;
+        Ok(Destination {
+            user: user.map(|u| u.to_owned()),
+            host: host.to_owned(),
+            port,
+        })
+    }
 
-        // The task that will remember this child and wait around for it die.
+    /// Whether this is the same destination as another one. SSH's default
+    /// port is 22 and hostnames are case-insensitive, so `Example.com` and
+    /// `example.com:22` are the same place.
+    fn same_as(&self, other: &Destination) -> bool {
+        self.user == other.user &&
+            self.host.eq_ignore_ascii_case(&other.host) &&
+            self.port.unwrap_or(22) == other.port.unwrap_or(22)
+    }
 
-        common.handle.spawn(ChildMonitor::start(
-            common.shared.clone(), params.host.clone(), child, rx_kill, tx_die
-        ));
+    /// The arguments that tell SSH to connect to this destination.
+    fn ssh_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
 
-        // The kill channel gives us a way to control the process later. We hold
-        // on to the handles to the ptymaster and rx_die for now, because we care
-        // about them when completing the password entry stage of the daemon
-        // setup.
+        if let Some(p) = self.port {
+            args.push("-p".to_owned());
+            args.push(p.to_string());
+        }
 
-        common.shared().children.insert(params.host.clone(), TunnelState::Running {
-            tx_kill: tx_kill,
+        args.push(match self.user {
+            Some(ref u) => format!("{}@{}", u, self.host),
+            None => self.host.clone(),
         });
 
-        Ok(ptymaster.framed(BytesCodec::new()))
+        args
     }
+}
 
-    match inner(&common, &params, tx_die, &key) {
-        Ok(ptymaster) => {
-            let (ptywrite, ptyread) = ptymaster.split();
 
-            if let Ok(AsyncSink::Ready) = tx.start_send(ServerMessage::Ok) {
-            } else {
-                panic!("cmon");
-            }
+/// The full command line used to launch SSH for a tunnel, program name
+/// included. `key` is the text that the remote end prints to tell us that
+/// login has succeeded. `control_path` is where SSH should put its control
+/// socket if the client asked for connection sharing.
+fn ssh_command_line(
+    params: &OpenParameters, dest: &Destination, key: &str, control_path: Option<&Path>
+) -> Vec<String> {
+    let mut argv = vec!["ssh".to_owned()];
 
-            transition!(CommunicatingForOpen {
-                common: common,
-                cl_tx: tx,
-                cl_rx: rx,
-                cl_buf: Vec::new(),
-                ssh_tx: ptywrite,
-                ssh_rx: ptyread,
-                ssh_buf: Vec::new(),
-                ssh_key: key.into_bytes(),
-                ssh_key_status: SshKeyStatus::Searching(0),
-                ssh_die: rx_die.into_future(),
-            });
-        },
+    // The -t arg allocates a PTY for the command so that "tail" will die
+    // with a SIGHUP when SSH dies. Otherwise it will linger forever!
 
-        Err(e) => {
-            let msg = format!("failed to launch SSH: {}", e);
-            transition!(abort_client(common, tx, rx, msg));
+    argv.push("-t".to_owned());
+
+    if params.verbose > 0 {
+        argv.push(format!("-{}", "v".repeat(usize::from(params.verbose))));
+    }
+
+    if params.connect_retries > 0 {
+        argv.push("-o".to_owned());
+        argv.push(format!("ConnectionAttempts={}", params.connect_retries.saturating_add(1)));
+    }
+
+    if let Some(secs) = params.connect_timeout_secs {
+        argv.push("-o".to_owned());
+        argv.push(format!("ConnectTimeout={}", secs));
+    }
+
+    if let Some(path) = control_path {
+        // SSH expands "%" sequences in the path, so protect any that are
+        // really there.
+        argv.push("-o".to_owned());
+        argv.push("ControlMaster=auto".to_owned());
+        argv.push("-o".to_owned());
+        argv.push(format!("ControlPath={}", path.display().to_string().replace('%', "%%")));
+    }
+
+    argv.extend(dest.ssh_args());
+    argv.push(format!("echo \"{}\" && exec tail -f /dev/null", key));
+    argv
+}
+
+
+/// Variables that a tunnel's SSH process gets from the daemon's own
+/// environment, if they're set. Besides the basics, `SSH_AUTH_SOCK` lets it
+/// use the user's agent.
+const INHERITED_ENV_VARS: &[&str] = &["PATH", "HOME", "USER", "LOGNAME", "SSH_AUTH_SOCK"];
+
+/// Variables that can make SSH ask for passwords with a graphical program
+/// rather than on the terminal that the user is watching.
+const ASKPASS_ENV_VARS: &[&str] = &["DISPLAY", "SSH_ASKPASS"];
+
+/// Work out the exact environment of a tunnel's SSH process.
+///
+/// It contains the variables listed in `INHERITED_ENV_VARS`, taken from the
+/// daemon's own environment, and then the ones given by the client, which
+/// win if there is a conflict. `DISPLAY` and `SSH_ASKPASS` are dropped
+/// unless the client set `allow_askpass`. If the client named an agent
+/// socket, it becomes `SSH_AUTH_SOCK`, whatever else says otherwise.
+/// Nothing else gets through, so that tunnels behave the same no matter how
+/// the daemon was started.
+fn ssh_environment(params: &OpenParameters) -> Result<Vec<(String, String)>, Error> {
+    let mut vars: Vec<(String, String)> = INHERITED_ENV_VARS.iter()
+        .filter_map(|name| env::var(name).ok().map(|value| (name.to_string(), value)))
+        .collect();
+
+    for (name, value) in &params.env {
+        if name.is_empty() || name.contains('=') || name.contains('\0') {
+            return Err(format_err!("invalid environment variable name \"{}\"", name));
+        }
+
+        if value.contains('\0') {
+            return Err(format_err!("the value of environment variable {} contains a NUL byte", name));
+        }
+
+        if !params.allow_askpass && ASKPASS_ENV_VARS.contains(&name.as_str()) {
+            continue;
         }
+
+        vars.retain(|(n, _)| n != name);
+        vars.push((name.clone(), value.clone()));
+    }
+
+    if let Some(ref path) = params.agent_socket {
+        let value = check_agent_socket(path)?;
+        vars.retain(|(n, _)| n != "SSH_AUTH_SOCK");
+        vars.push(("SSH_AUTH_SOCK".to_owned(), value));
     }
+
+    Ok(vars)
 }
 
-// A task for monitoring each SSH process's PTY once it has successfully
-// finished the password entry phase.
+/// Check that an agent socket requested by a client is usable, returning it
+/// in the form of an environment variable value. The path has to be
+/// absolute, since the client's working directory means nothing to us.
+fn check_agent_socket(path: &Path) -> Result<String, Error> {
+    let text = match path.to_str() {
+        Some(t) if !t.contains('\0') => t,
+        _ => return Err(format_err!("the agent socket path {} is not valid text", path.display())),
+    };
 
-fn hand_off_ssh_process(
-    handle: &Handle, shared: Arc<Mutex<State>>, _ssh_tx: PtySink, ssh_rx: PtyStream
-) {
-    //println!("handing off SSH process to monitor");
-    let shared2 = shared.clone();
+    if !path.is_absolute() {
+        return Err(format_err!("the agent socket path {} is not absolute", path.display()));
+    }
 
-    let ssh_monitor = ssh_rx.for_each(move |bytes| {
-        log!(shared.lock().unwrap(), "SSH: {:?}", bytes);
-        Ok(())
-    }).map_err(move |err| {
-        log!(shared2.lock().unwrap(), "error polling SSH: {}", err);
-    });
+    let meta = fs::metadata(path)
+        .map_err(|e| format_err!("can't use agent socket {}: {}", path.display(), e))?;
 
-    handle.spawn(ssh_monitor);
+    if !meta.file_type().is_socket() {
+        return Err(format_err!("{} is not a socket, so it can't be an SSH agent", path.display()));
+    }
+
+    Ok(text.to_owned())
 }
 
 
-fn process_close_command(
-    common: ClientCommonState, params: CloseParameters, tx: Ser, rx: De
+fn process_dry_run_open_command(
+    common: ClientCommonState, params: OpenParameters, tx: Ser, rx: De
 ) -> Poll<AfterAwaitingCommand, Error> {
-    log!(common.shared(), "got command to close tunnel SSH for {}", params.host);
-
-    let tx_kill = match common.shared().children.remove(&params.host) {
-        Some(TunnelState::Running { tx_kill }) => Some(tx_kill),
-        Some(TunnelState::Exited { .. }) | None => None,
+    let dest = match validate_open_parameters(&params) {
+        Ok(d) => d,
+        Err(e) => {
+            let msg = format!("{}", e);
+            transition!(reply_error(common, tx, rx, ProtocolError::InvalidParameters, msg));
+        },
     };
 
-    let tx_kill = match tx_kill {
-        Some(t) => t,
-        None => {
-            log!(common.shared(), "no such tunnel -- notifying client");
-            let send = tx.send(ServerMessage::TunnelNotOpen);
-            transition!(FinalizingTxn { common, tx: send, rx });
-        },
+    // The real key is random, so there's no point in making one up here. The
+    // label might turn out different too, but the host is the usual one.
+    let control_path = if params.control_master {
+        Some(control_socket_path(&common.shared().sock_path, &params.host))
+    } else {
+        None
     };
 
-    if let Err(_) = tx_kill.send(()) {
-        let msg = "failed to send internal kill signal (?)".to_owned();
-        transition!(abort_client(common, tx, rx, msg));
+    let argv = ssh_command_line(&params, &dest, "STUND:<key>", control_path.as_deref());
+    let send = tx.send(ServerMessage::CommandLine(argv));
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+
+/// The most connection retries that a client may ask for. Anything more is
+/// surely a mistake, and would leave the login hanging for ages.
+const MAX_CONNECT_RETRIES: u32 = 100;
+
+/// The most verbose that SSH can be asked to be; it ignores any more `-v`s.
+const MAX_SSH_VERBOSITY: u8 = 3;
+
+/// Check that the parameters of an "Open", "Reconfigure", or "DryRunOpen"
+/// command make sense, before we do anything about them. If they do, the
+/// destination is returned.
+fn validate_open_parameters(params: &OpenParameters) -> Result<Destination, Error> {
+    let dest = Destination::parse(params)?;
+    ssh_environment(params)?;
+
+    if params.connect_retries > MAX_CONNECT_RETRIES {
+        return Err(format_err!("at most {} connection retries are allowed, not {}",
+                               MAX_CONNECT_RETRIES, params.connect_retries));
     }
 
-    let send = tx.send(ServerMessage::Ok);
-    transition!(FinalizingTxn { common, tx: send, rx });
+    if params.connect_timeout_secs == Some(0) {
+        return Err(format_err!("the connect timeout must be at least 1 second"));
+    }
+
+    if params.verbose > MAX_SSH_VERBOSITY {
+        return Err(format_err!("SSH's verbosity can be at most {}, not {}",
+                               MAX_SSH_VERBOSITY, params.verbose));
+    }
+
+    Ok(dest)
 }
 
 
-fn process_status_query(
-    common: ClientCommonState, tx: Ser, rx: De
+fn process_open_profile_command(
+    common: ClientCommonState, name: String, tx: Ser, rx: De
 ) -> Poll<AfterAwaitingCommand, Error> {
-    let mut info = StatusInformation {
-        tunnels: Vec::new(),
+    let path = profiles_path(&common.shared().sock_path);
+
+    let mut params = match load_profile(&path, &name) {
+        Ok(Some(p)) => p,
+
+        Ok(None) => {
+            let msg = format!("no such profile \"{}\"", name);
+            transition!(abort_client(common, tx, rx, ProtocolError::NotFound, msg));
+        },
+
+        Err(e) => {
+            let msg = format!("failed to read profiles from {}: {}", path.display(), e);
+            log_error!(common.shared(), "{}", msg);
+            transition!(abort_client(common, tx, rx, ProtocolError::Internal, msg));
+        },
     };
 
-    for (host, tinfo) in common.shared().children.iter() {
-        let state = match tinfo {
-            &TunnelState::Running { .. } => super::TunnelState::Open,
-            &TunnelState::Exited { status: None } => super::TunnelState::Closed,
-            &TunnelState::Exited { status: _other } => super::TunnelState::Died,
-        };
+    // These govern how we talk to the client, not how SSH is run, so they
+    // aren't up to the profile. Any client that knows about profiles knows
+    // what to do with the tunnel's key.
+    params.report_key = true;
+    params.report_pid = false;
+    params.separate_stderr = false;
 
-        info.tunnels.push(TunnelInformation {
-            host: host.clone(),
-            state: state,
-        });
+    log!(common.shared(), "opening profile {} for {}", name, params.host);
+    process_open_command(common, params, tx, rx)
+}
+
+fn process_open_command(
+    common: ClientCommonState, params: OpenParameters, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    if let Err(e) = validate_open_parameters(&params) {
+        let msg = format!("{}", e);
+        transition!(abort_client(common, tx, rx, ProtocolError::InvalidParameters, msg));
     }
 
-    let send = tx.send(ServerMessage::StatusResponse(info));
-    transition!(FinalizingTxn { common, tx: send, rx });
-}
+    // If we're about to exit, any SSH process we started would be killed
+    // moments later.
 
+    if common.shared().shutting_down {
+        log!(common.shared(), "refusing to open {}: shutting down", params.host);
+        let send = tx.send(ServerMessage::ShuttingDown);
+        transition!(FinalizingTxn { common, tx: send, rx });
+    }
 
-/// This function used to be much more elaborate; it can probably be ditched
-/// now.
-fn abort_client(common: ClientCommonState, tx: Ser, rx: De, message: String) -> Aborting
-{
-    Aborting {
-        common: common,
-        tx: tx.send(ServerMessage::Error(message)),
-        rx: rx,
+    if common.shared().draining {
+        let msg = "the daemon is about to be upgraded and is not opening new tunnels".to_owned();
+        transition!(abort_client(common, tx, rx, ProtocolError::Busy, msg));
+    }
+
+    // A tunnel that we can't keep records of would be a mystery later, so
+    // check that we can still write our files before starting one.
+
+    let sock_path = common.shared().sock_path.clone();
+
+    if let Err(e) = check_storage(&sock_path) {
+        let msg = format!("{}; not opening new tunnels until this is fixed", e);
+        log_error!(common.shared(), "{}", msg);
+        transition!(abort_client(common, tx, rx, ProtocolError::StorageUnavailable, msg));
+    }
+
+    // A tunnel to this host may be running under a different label, in
+    // which case that's the one to leave alone or replace.
+
+    let (label, never_mind, tx_kill) = {
+        let mut sh = common.shared();
+        log!(sh, "got command to spawn SSH for {}", params.host);
+
+        let label = Destination::parse(&params).ok()
+            .and_then(|dest| sh.running_key_for(&dest))
+            .unwrap_or_else(|| params.host.clone());
+
+        let (never_mind, tx_kill) = match sh.children.remove(&label) {
+            Some(TunnelState::Running { tx_kill, .. }) if params.force => {
+                log!(sh, "tunnel already open as {} -- replacing it", label);
+                sh.record_event(&label, TunnelEventKind::Replaced, None);
+                sh.children.insert(label.clone(), TunnelState::Exited { status: None });
+                (false, Some(tx_kill))
+            },
+
+            Some(running @ TunnelState::Running { .. }) => {
+                log!(sh, "tunnel already open as {} -- notifying client", label);
+                sh.children.insert(label.clone(), running);
+                (true, None)
+            },
+
+            Some(exited) => {
+                sh.children.insert(label.clone(), exited);
+                (false, None)
+            },
+
+            None => (false, None),
+        };
+
+        (label, never_mind, tx_kill)
+    };
+
+    if never_mind {
+        let send = tx.send(ServerMessage::TunnelAlreadyOpen);
+        transition!(FinalizingTxn { common, tx: send, rx });
+    }
+
+    // If we're replacing an existing tunnel, we wait for its monitor to
+    // confirm that the old SSH process has been killed before starting the
+    // new one. If the kill request can't be delivered, the monitor has
+    // already gone away, which means that the old process is dead anyway;
+    // its acknowledgement channel will be dropped and we'll proceed.
+
+    let in_flight = OpenInFlight::new(common.shared.clone());
+
+    if let Some(tx_kill) = tx_kill {
+        let (tx_done, rx_done) = oneshot::channel();
+        let _r = tx_kill.send(KillRequest { tx_done, grace: None });
+
+        transition!(ReplacingTunnel {
+            common,
+            label,
+            params,
+            tx,
+            rx,
+            rx_killed: rx_done,
+            in_flight,
+        });
+    }
+
+    transition!(start_ssh::<AfterAwaitingCommand>(common, &label, &params, false, in_flight, tx, rx))
+}
+
+
+fn process_reconfigure_command(
+    common: ClientCommonState, params: ReconfigureParameters, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let ReconfigureParameters { label, params } = params;
+
+    // If the new parameters are no good, the old tunnel stays up.
+
+    if let Err(e) = validate_open_parameters(&params) {
+        let msg = format!("{}", e);
+        transition!(abort_client(common, tx, rx, ProtocolError::InvalidParameters, msg));
+    }
+
+    if common.shared().shutting_down {
+        log!(common.shared(), "refusing to reconfigure {}: shutting down", label);
+        let send = tx.send(ServerMessage::ShuttingDown);
+        transition!(FinalizingTxn { common, tx: send, rx });
+    }
+
+    if common.shared().draining {
+        let msg = "the daemon is about to be upgraded and is not opening new tunnels".to_owned();
+        transition!(abort_client(common, tx, rx, ProtocolError::Busy, msg));
+    }
+
+    let tx_kill = {
+        let mut sh = common.shared();
+        log!(sh, "got command to restart tunnel {} with new parameters", label);
+
+        match sh.children.remove(&label) {
+            Some(TunnelState::Running { tx_kill, .. }) => {
+                sh.record_event(&label, TunnelEventKind::Replaced, None);
+                sh.children.insert(label.clone(), TunnelState::Exited { status: None });
+                Some(tx_kill)
+            },
+
+            Some(exited) => {
+                sh.children.insert(label.clone(), exited);
+                None
+            },
+
+            None => None,
+        }
+    };
+
+    let tx_kill = match tx_kill {
+        Some(t) => t,
+        None => {
+            log!(common.shared(), "no such tunnel -- notifying client");
+            let send = tx.send(ServerMessage::TunnelNotOpen);
+            transition!(FinalizingTxn { common, tx: send, rx });
+        },
+    };
+
+    // From here on, this works just like a forced open, except that the
+    // new tunnel is filed under the old label.
+
+    let in_flight = OpenInFlight::new(common.shared.clone());
+    let (tx_done, rx_done) = oneshot::channel();
+    let _r = tx_kill.send(KillRequest { tx_done, grace: None });
+
+    transition!(ReplacingTunnel {
+        common,
+        label,
+        params,
+        tx,
+        rx,
+        rx_killed: rx_done,
+        in_flight,
+    });
+}
+
+
+/// Launch SSH for an "Open" command and start relaying between it and the
+/// client. The tunnel is filed under `label`, which is usually its host.
+fn start_ssh<T>(
+    common: ClientCommonState, label: &str, params: &OpenParameters, replacing: bool,
+    in_flight: OpenInFlight, mut tx: Ser, rx: De
+) -> T
+    where T: From<CommunicatingForOpen> + From<Aborting>
+{
+    // The parameters were checked when the command arrived.
+
+    let dest = match Destination::parse(params) {
+        Ok(d) => d,
+        Err(e) => {
+            let msg = format!("{}", e);
+            return abort_client(common, tx, rx, ProtocolError::InvalidParameters, msg).into();
+        },
+    };
+
+    // Generate a magic bit of text that we'll use to recognize when the
+    // login has succeeded.
+
+    let mut rng = rand::thread_rng();
+    let mut buf = [0u8; 32];
+    rng.fill_bytes(&mut buf);
+    let key = format!("STUND:{}", base64::encode(&buf));
+
+    // Let's launch the process.
+
+    let (tx_die, rx_die) = mpsc::channel(DIE_CHANNEL_CAPACITY);
+
+    /// The PTY, SSH's separate standard error, the tunnel ID, and the relay
+    /// counters.
+    type Launched = (PtyFramed, Option<SshErrStream>, u64, Arc<Mutex<RelayStats>>);
+
+    fn inner(
+        common: &ClientCommonState, label: &str, params: &OpenParameters, dest: &Destination,
+        tx_die: mpsc::Sender<Option<ExitStatus>>, key: &str
+    ) -> Result<Launched, Error> {
+        let (tx_kill, rx_kill) = oneshot::channel();
+        let ptymaster = AsyncPtyMaster::open().context("failed to create PTY")?;
+
+        // We keep our own handle to the PTY master so that we can adjust its
+        // settings after the I/O halves have been handed off elsewhere. Make
+        // sure that it doesn't leak into the SSH processes we launch.
+
+        let pty = unsafe {
+            let fd = libc::fcntl(ptymaster.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0);
+
+            if fd < 0 {
+                return Err(format_err!("failed to duplicate PTY handle: {}", io::Error::last_os_error()));
+            }
+
+            fs::File::from_raw_fd(fd)
+        };
+
+        // Only one tunnel has a given label at a time, and the old one's
+        // monitor cleans up after it, so anything at the control path is
+        // left over from a crash. SSH wouldn't be able to replace it.
+
+        let control_path = if params.control_master {
+            let mut sh = common.shared();
+            let path = control_socket_path(&sh.sock_path, label);
+            let _r = fs::remove_file(&path);
+            sh.own_socket(&path);
+            Some(path)
+        } else {
+            None
+        };
+
+        let argv = ssh_command_line(params, dest, key, control_path.as_deref());
+        let mut vars = ssh_environment(params)?;
+        let ssh_user = common.shared().ssh_user.clone();
+
+        // If SSH runs as someone else, its idea of who it is and where its
+        // files are had better match.
+
+        if let Some(ref user) = ssh_user {
+            for &(name, ref value) in &[("HOME", &user.home), ("USER", &user.name), ("LOGNAME", &user.name)] {
+                vars.retain(|(n, _)| n != name);
+                vars.push((name.to_owned(), value.to_string()));
+            }
+        }
+
+        let path = vars.iter().find(|v| v.0 == "PATH").map(|v| v.1.clone()).unwrap_or_default();
+
+        let mut cmd = process::Command::new(&argv[0]);
+        cmd.args(&argv[1..]).env_clear().envs(vars);
+
+        // Drop our privileges in the child. As root, setuid() changes the
+        // saved UID too, so there's no getting them back. Everything is
+        // prepared beforehand, since we can't safely allocate after forking.
+
+        if let Some(user) = ssh_user {
+            let name = CString::new(user.name).context("invalid SSH user name")?;
+            let (uid, gid) = (user.uid, user.gid);
+
+            unsafe {
+                cmd.pre_exec(move || {
+                    if libc::initgroups(name.as_ptr(), gid) != 0 ||
+                        libc::setgid(gid) != 0 ||
+                        libc::setuid(uid) != 0
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+
+                    Ok(())
+                });
+            }
+        }
+
+        // If the client wants SSH's standard error kept separate, it goes to
+        // a socket of its own rather than the PTY. The PTY is hooked up to
+        // the standard streams before this runs, so this wins.
+
+        let mut ssh_err_theirs = None;
+
+        let ssh_err = if params.separate_stderr {
+            let (ours, theirs) = StdUnixStream::pair()
+                .context("failed to create socket for SSH's standard error")?;
+            let fd = theirs.as_raw_fd();
+            ssh_err_theirs = Some(theirs);
+
+            unsafe {
+                cmd.pre_exec(move || {
+                    if libc::dup2(fd, 2) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+
+                    Ok(())
+                });
+            }
+
+            let ours = UnixStream::from_stream(ours, &common.handle)
+                .context("failed to set up socket for SSH's standard error")?;
+            Some(ours.framed(BytesCodec::new()))
+        } else {
+            None
+        };
+
+        let child = match cmd.spawn_pty_async(&ptymaster) {
+            Ok(c) => c,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(format_err!("{} executable not found (is OpenSSH installed / on PATH?); \
+                                        searched PATH={}", argv[0], path));
+            },
+            Err(e) => return Err(e.context("failed to launch SSH").into()),
+        };
+
+        drop(ssh_err_theirs); // only SSH should be holding its end now
+
+        // The task that will remember this child and wait around for it die.
+
+        let id = {
+            let mut sh = common.shared();
+            sh.next_tunnel_id += 1;
+            sh.next_tunnel_id
+        };
+
+        let pid = child.id();
+
+        common.handle.spawn(ChildMonitor::start(
+            common.shared.clone(), id, child, rx_kill, tx_die, control_path.clone(),
+            common.handle.clone()
+        ));
+
+        // The kill channel gives us a way to control the process later. We hold
+        // on to the handles to the ptymaster and rx_die for now, because we care
+        // about them when completing the password entry stage of the daemon
+        // setup.
+
+        let relay_stats = Arc::new(Mutex::new(RelayStats::default()));
+
+        {
+            let mut sh = common.shared();
+            sh.record_event(label, TunnelEventKind::Opened, None);
+            sh.children.insert(label.to_owned(), TunnelState::Running {
+                id,
+                pid,
+                params: params.clone(),
+                pty,
+                tx_kill: tx_kill,
+                relay_stats: relay_stats.clone(),
+                control_path,
+            });
+        }
+
+        Ok((ptymaster.framed(BytesCodec::new()), ssh_err, id, relay_stats))
+    }
+
+    let ping_timer = match common.keepalive {
+        Some(d) => common.clock.timer(d, &common.handle).map(Some),
+        None => Ok(None),
+    };
+
+    let ping_timer = match ping_timer {
+        Ok(t) => t,
+        Err(e) => {
+            let msg = format!("failed to create keepalive timer: {}", e);
+            return abort_client(common, tx, rx, ProtocolError::Internal, msg).into();
+        }
+    };
+
+    match inner(&common, label, params, &dest, tx_die, &key) {
+        Ok((ptymaster, ssh_err, tunnel_id, relay_stats)) => {
+            let (ptywrite, ptyread) = ptymaster.split();
+
+            let ack = if replacing {
+                ServerMessage::TunnelReplaced
+            } else {
+                ServerMessage::Ok
+            };
+
+            if let Ok(AsyncSink::Ready) = tx.start_send(ack) {
+            } else {
+                panic!("cmon");
+            }
+
+            CommunicatingForOpen {
+                common: common,
+                cl_tx: tx,
+                cl_rx: rx,
+                cl_buf: Vec::new(),
+                ssh_tx: ptywrite,
+                ssh_rx: ptyread,
+                ssh_eof: false,
+                ssh_err,
+                ssh_buf: Vec::new(),
+                err_buf: Vec::new(),
+                ssh_key: key.into_bytes(),
+                ssh_key_status: SshKeyStatus::Searching(0),
+                ssh_die: rx_die.into_future(),
+                tunnel_id,
+                relay_stats,
+                ping_timer,
+                ping_wanted: false,
+                awaiting_pong: false,
+                connect_attempts: params.connect_retries.saturating_add(1),
+                connect_timeout: params.connect_timeout_secs.is_some(),
+                ssh_tail: Vec::new(),
+                report_key: params.report_key,
+                report_pid: params.report_pid,
+                log_stderr: params.verbose > 0,
+                close_if_abandoned: params.close_if_abandoned,
+                _in_flight: in_flight,
+            }.into()
+        },
+
+        Err(e) => {
+            let msg = format!("failed to launch SSH: {}", e);
+            abort_client(common, tx, rx, ProtocolError::SpawnFailed, msg).into()
+        }
+    }
+}
+
+// A task for monitoring each SSH process's PTY once it has successfully
+// finished the password entry phase. `ssh_rx` is None if the PTY has
+// already closed. If `log_stderr`, whatever SSH writes to a separate
+// standard error is logged as text, rather than just counted.
+
+fn hand_off_ssh_process(
+    handle: &Handle, shared: Arc<Mutex<State>>, relay_stats: Arc<Mutex<RelayStats>>,
+    _ssh_tx: PtySink, ssh_rx: Option<PtyStream>, ssh_err: Option<SshErrStream>, log_stderr: bool
+) {
+    //println!("handing off SSH process to monitor");
+    let shared2 = shared.clone();
+
+    // If SSH has a separate standard error, we have to keep reading it, or
+    // SSH will eventually block writing to it. Unlike its terminal, it
+    // never echoes what the user typed, so the text is safe to log.
+
+    if let Some(ssh_err) = ssh_err {
+        let shared3 = shared.clone();
+
+        handle.spawn(ssh_err.for_each(move |bytes| {
+            let mut sh = shared3.lock().unwrap();
+
+            if log_stderr {
+                for line in String::from_utf8_lossy(&bytes).lines() {
+                    log!(sh, "SSH: {}", line.trim_end());
+                }
+            } else {
+                log!(sh, "SSH: {} bytes of error output", bytes.len());
+            }
+
+            Ok(())
+        }).map_err(|_| ()));
+    }
+
+    {
+        let mut stats = relay_stats.lock().unwrap();
+        stats.cl_buf_len = 0;
+        stats.ssh_buf_len = 0;
+        stats.cl_blocked = false;
+        stats.ssh_blocked = false;
+        stats.handed_off = Some(Instant::now());
+    }
+
+    // If SSH has already closed its terminal, there's nothing to watch.
+
+    let ssh_rx = match ssh_rx {
+        Some(rx) => rx,
+        None => return,
+    };
+
+    let relay_stats2 = relay_stats.clone();
+    let ssh_rx = PausableStream { inner: ssh_rx, relay_stats: relay_stats.clone() };
+
+    let ssh_monitor = ssh_rx.for_each(move |bytes| {
+        let tee_failure = {
+            let mut stats = relay_stats.lock().unwrap();
+            stats.last_ssh_activity = Some(Instant::now());
+            stats.bytes_from_ssh += bytes.len() as u64;
+
+            let failure = match stats.tee {
+                Some((ref path, ref mut file)) => file.write_all(&bytes).err().map(|e| (path.clone(), e)),
+                None => None,
+            };
+
+            if failure.is_some() {
+                stats.tee = None;
+            }
+
+            failure
+        };
+
+        // The output may include things that the user typed, so don't log
+        // the text itself.
+        let mut sh = shared.lock().unwrap();
+        log!(sh, "SSH: {} bytes of output", bytes.len());
+
+        if let Some((path, e)) = tee_failure {
+            log_error!(sh, "failed to write to {}: {}; no longer writing output there", path.display(), e);
+        }
+
+        Ok(())
+    }).then(move |r| {
+        // Either way, the PTY is done for.
+        relay_stats2.lock().unwrap().pty_closed = true;
+
+        // As during the login, reading from a PTY whose other end has
+        // closed usually yields EIO rather than an EOF.
+        match r {
+            Err(ref err) if err.raw_os_error() != Some(libc::EIO) => {
+                log_error!(shared2.lock().unwrap(), "error polling SSH: {}", err);
+            },
+            _ => {
+                log!(shared2.lock().unwrap(), "SSH closed its terminal");
+            },
+        }
+
+        Ok(())
+    });
+
+    handle.spawn(ssh_monitor);
+}
+
+
+fn process_close_command(
+    common: ClientCommonState, params: CloseParameters, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    log!(common.shared(), "got command to close tunnel SSH for {}", params.host);
+
+    // We mark the tunnel as closed right away; the child monitor will only
+    // update entries that it finds still running.
+
+    let tx_kill = {
+        let mut sh = common.shared();
+        let label = sh.resolve_label(&params.host);
+
+        match sh.children.remove(&label) {
+            Some(TunnelState::Running { tx_kill, .. }) => {
+                sh.record_event(&label, TunnelEventKind::Closed, None);
+                sh.children.insert(label, TunnelState::Exited { status: None });
+                Some(tx_kill)
+            },
+
+            Some(exited) => {
+                sh.children.insert(label, exited);
+                None
+            },
+
+            None => None,
+        }
+    };
+
+    let tx_kill = match tx_kill {
+        Some(t) => t,
+        None => {
+            log!(common.shared(), "no such tunnel -- notifying client");
+            let send = tx.send(ServerMessage::TunnelNotOpen);
+            transition!(FinalizingTxn { common, tx: send, rx });
+        },
+    };
+
+    let grace = params.grace_secs.map(|secs| Duration::from_secs(secs.min(MAX_CLOSE_GRACE_SECS)));
+    let wait = grace.is_some();
+    let (tx_done, rx_done) = oneshot::channel();
+
+    if let Err(_) = tx_kill.send(KillRequest { tx_done, grace }) {
+        let msg = "failed to send internal kill signal (?)".to_owned();
+        transition!(reply_error(common, tx, rx, ProtocolError::Internal, msg));
+    }
+
+    if wait {
+        transition!(WaitingForClose { common, tx, rx, rx_done });
+    }
+
+    let send = tx.send(ServerMessage::Ok);
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+
+/// How often the daemon looks for tunnels that have been idle for too long.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Find the running tunnels that have been idle for longer than they're
+/// allowed to be as of `now`. A tunnel's own setting takes precedence over
+/// the daemon's default, and zero means that it's never reaped.
+fn idle_tunnels(sh: &State, now: Instant) -> Vec<String> {
+    let mut labels: Vec<_> = sh.children.iter().filter_map(|(label, tunnel)| {
+        let (params, relay_stats) = match tunnel {
+            TunnelState::Running { params, relay_stats, .. } => (params, relay_stats),
+            TunnelState::Exited { .. } => return None,
+        };
+
+        let limit = match params.reap_after_idle_secs {
+            Some(0) => return None,
+            Some(secs) => Duration::from_secs(u64::from(secs)),
+            None => sh.reap_after_idle?,
+        };
+
+        let last_activity = relay_stats.lock().unwrap().last_activity()?;
+
+        if now.saturating_duration_since(last_activity) >= limit {
+            Some(label.clone())
+        } else {
+            None
+        }
+    }).collect();
+
+    labels.sort();
+    labels
+}
+
+/// Close the tunnels that have been idle for too long, the same way that a
+/// "Close" command would.
+fn reap_idle_tunnels(sh: &mut State) {
+    let now = Instant::now();
+
+    for label in idle_tunnels(sh, now) {
+        if let Some(TunnelState::Running { tx_kill, .. }) = sh.children.remove(&label) {
+            log!(sh, "closing tunnel {} because it has been idle for too long", label);
+            sh.record_event(&label, TunnelEventKind::Closed, None);
+            sh.children.insert(label, TunnelState::Exited { status: None });
+
+            let (tx_done, _rx_done) = oneshot::channel();
+            let _r = tx_kill.send(KillRequest { tx_done, grace: None });
+        }
+    }
+}
+
+
+/// Relay a login between the client and SSH; see
+/// `poll_communicating_for_open`.
+fn relay_login<'a>(
+    state: &'a mut RentToOwn<'a, CommunicatingForOpen>
+) -> Poll<AfterCommunicatingForOpen, Error> {
+    let mut saw_cl_data = false;
+    let mut saw_ssh_data = false;
+    let mut n_cl_bytes = 0;
+    let mut n_ssh_bytes = 0;
+
+    // New text from the user?
+
+    loop {
+        let msg = match state.cl_rx.poll() {
+            Ok(Async::Ready(msg)) => msg,
+            Ok(Async::NotReady) => break,
+            Err(e) => return Err(abandon_login(state.take(), e.into())),
+        };
+
+        if let Some(ref m) = msg {
+            state.common.log_message(m);
+        }
+
+        match msg {
+            Some(ClientMessage::UserData(data)) => {
+                saw_cl_data = true;
+                n_cl_bytes += data.len() as u64;
+                state.ssh_buf.extend_from_slice(&data);
+            },
+
+            Some(ClientMessage::Pong) => {
+                state.awaiting_pong = false;
+            },
+
+            Some(ClientMessage::WindowSize { rows, cols }) => {
+                resize_half_opened_tunnel(&state.common, state.tunnel_id, rows, cols);
+            },
+
+            Some(ClientMessage::Cancel) => {
+                // The user has given up on logging in. Dropping our
+                // handles on the PTY would probably get rid of SSH
+                // eventually, but let's be definite about it.
+                let state = state.take();
+                kill_half_opened_tunnel(&state.common.shared, state.common.session_id, state.tunnel_id);
+                let send = state.cl_tx.send(ServerMessage::Cancelled);
+
+                transition!(FinalizingTxn {
+                    common: state.common,
+                    tx: send,
+                    rx: state.cl_rx,
+                });
+            },
+
+            Some(other) => {
+                // Could consider aborting here, but if we didn't
+                // understand the client then probably there's
+                // something messed up about the channel.
+                return Err(format_err!("unexpected message from the client: {}", other.summary()));
+            },
+
+            None => {
+                let err = format_err!("client connection unexpectedly closed");
+                return Err(abandon_login(state.take(), err));
+            },
+        }
+    }
+
+    // New complaints from SSH? We check these first so that, if SSH has
+    // just died, we have its last words before we find out.
+
+    {
+        let st: &mut CommunicatingForOpen = &mut *state;
+        let n_old = st.err_buf.len();
+
+        if poll_ssh_stderr(&mut st.ssh_err, &mut st.err_buf) {
+            saw_ssh_data = true;
+            remember_ssh_output(&mut st.ssh_tail, &st.err_buf[n_old..]);
+        }
+    }
+
+    // New text from SSH? Once SSH has closed its terminal, there's never
+    // going to be any more.
+
+    while !state.ssh_eof {
+        let outcome = match state.ssh_rx.poll() {
+            Ok(x) => x,
+            Err(e) => {
+                // Note that on Linux, this is what usually happens when
+                // SSH exits, since reading from a PTY master whose slave
+                // has been closed yields EIO. That means the login failed,
+                // just like an EOF would; anything else is our problem.
+                let ssh_died = e.raw_os_error() == Some(libc::EIO);
+
+                if ssh_died && state.ssh_key_status == SshKeyStatus::FoundIt {
+                    note_eof_after_login(&mut *state);
+                    break;
+                }
+
+                let msg = if ssh_died {
+                    format!("SSH exited before the login finished{}",
+                            attempts_note(state.connect_attempts))
+                } else {
+                    format!("something went wrong communicating with the SSH process: {}", e)
+                };
+
+                let mut state = state.take();
+                poll_ssh_stderr(&mut state.ssh_err, &mut state.err_buf);
+
+                if !ssh_died {
+                    transition!(abort_client_after_output(state.common, state.cl_tx, state.cl_rx,
+                                                          state.cl_buf, state.err_buf,
+                                                          ProtocolError::Internal, msg));
+                }
+
+                if let Some(reply) = connect_failure_reply(&state) {
+                    log_error!(state.common.shared(), "{}", msg);
+                    transition!(report_connect_failure(state.common, state.cl_tx, state.cl_rx,
+                                                       state.cl_buf, state.err_buf, reply));
+                }
+
+                transition!(abort_client_after_output(state.common, state.cl_tx, state.cl_rx,
+                                                      state.cl_buf, state.err_buf,
+                                                      ProtocolError::AuthFailed, msg));
+            },
+        };
+
+        match outcome {
+            Async::NotReady => break,
+
+            Async::Ready(maybe_bytes) => {
+                if let Some(bytes) = maybe_bytes {
+                    saw_ssh_data = true;
+                    n_ssh_bytes += bytes.len() as u64;
+                    remember_ssh_output(&mut state.ssh_tail, &bytes);
+
+                    // We need to search SSH's output for the "key" that
+                    // we use to figure out that login has completed
+                    // successfully.
+                    //
+                    // If we were cleverer we would not show the "key"
+                    // text to the client, but I don't want to figure out
+                    // the right state machine magic to ensure that output
+                    // is eventually showed in the event of an incomplete
+                    // match.
+                    if let SshKeyStatus::Searching(next_idx) = state.ssh_key_status {
+                        let mut n = next_idx;
+
+                        for b in &bytes {
+                            if b == state.ssh_key[n] {
+                                n += 1;
+
+                                if n == state.ssh_key.len() {
+                                    break;
+                                }
+                            } else {
+                                n = 0;
+                            }
+                        }
+
+                        if n == state.ssh_key.len() {
+                            state.ssh_key_status = SshKeyStatus::FoundIt;
+                        } else {
+                            state.ssh_key_status = SshKeyStatus::Searching(n);
+                        }
+                    }
+
+                    state.cl_buf.extend_from_slice(&bytes);
+                } else if state.ssh_key_status == SshKeyStatus::FoundIt {
+                    note_eof_after_login(&mut *state);
+                    break;
+                } else {
+                    // EOF from SSH -- it has probably died.
+                    let msg = format!("unexpected EOF from SSH (program died?){}",
+                                      attempts_note(state.connect_attempts));
+                    let mut state = state.take();
+                    poll_ssh_stderr(&mut state.ssh_err, &mut state.err_buf);
+
+                    if let Some(reply) = connect_failure_reply(&state) {
+                        log_error!(state.common.shared(), "{}", msg);
+                        transition!(report_connect_failure(state.common, state.cl_tx,
+                                                           state.cl_rx, state.cl_buf,
+                                                           state.err_buf, reply));
+                    }
+
+                    transition!(abort_client_after_output(state.common, state.cl_tx,
+                                                          state.cl_rx, state.cl_buf, state.err_buf,
+                                                          ProtocolError::AuthFailed, msg));
+                }
+            }
+        }
+    }
+
+    // Time to check that the client is still there? If it hasn't answered
+    // our last ping by the time the next one is due, it's presumably
+    // wedged, and we'd better not keep the SSH process hanging around on
+    // its behalf. Polling the timer after resetting it registers our
+    // interest in being woken again.
+
+    let mut wedged = false;
+
+    {
+        let st: &mut CommunicatingForOpen = &mut *state;
+
+        if let Some(ref mut timer) = st.ping_timer {
+            while let Async::Ready(()) = timer.poll()? {
+                if st.awaiting_pong {
+                    wedged = true;
+                    break;
+                }
+
+                st.ping_wanted = true;
+                st.awaiting_pong = true;
+                *timer = st.common.clock.timer(st.common.keepalive.unwrap(), &st.common.handle)?;
+            }
+        }
+    }
+
+    if wedged {
+        let err = format_err!("client did not answer keepalive ping; dropping its session");
+        return Err(abandon_login(state.take(), err));
+    }
+
+    // Ready/able to send bytes to the client?
+
+    if let Err(e) = start_sends_to_client(&mut *state) {
+        return Err(abandon_login(state.take(), e));
+    }
+
+    // Ready/able to send bytes to SSH?
+
+    if state.ssh_buf.len() != 0 {
+        let buf = state.ssh_buf.clone();
+
+        if let AsyncSink::Ready = state.ssh_tx.start_send(buf.into())? {
+            state.ssh_buf.clear();
+        }
+    }
+
+    // Keep the diagnostic counters up to date. If a buffer is still
+    // nonempty at this point, its recipient refused our send.
+
+    {
+        let mut stats = state.relay_stats.lock().unwrap();
+        let now = Instant::now();
+
+        stats.cl_buf_len = state.cl_buf.len();
+        stats.ssh_buf_len = state.ssh_buf.len();
+        stats.cl_blocked = !state.cl_buf.is_empty();
+        stats.ssh_blocked = !state.ssh_buf.is_empty();
+        stats.bytes_to_ssh += n_cl_bytes;
+        stats.bytes_from_ssh += n_ssh_bytes;
+
+        if saw_cl_data {
+            stats.last_cl_activity = Some(now);
+        }
+
+        if saw_ssh_data {
+            stats.last_ssh_activity = Some(now);
+        }
+    }
+
+    // Gotta flush those transmissions.
+
+    match state.cl_tx.poll_complete() {
+        Ok(Async::Ready(())) => {},
+        Ok(Async::NotReady) => return Ok(Async::NotReady),
+        Err(e) => return Err(abandon_login(state.take(), e.into())),
+    }
+
+    try_ready!(state.ssh_tx.poll_complete());
+
+    // What's next?
+
+    // SSH's complaints can't follow it into the background, so make sure
+    // that they've all gone out first.
+
+    if state.ssh_key_status == SshKeyStatus::FoundIt && state.err_buf.is_empty() {
+        let state = state.take();
+
+        let ssh_rx = if state.ssh_eof { None } else { Some(state.ssh_rx) };
+        hand_off_ssh_process(&state.common.handle, state.common.shared.clone(),
+                             state.relay_stats, state.ssh_tx, ssh_rx, state.ssh_err, state.log_stderr);
+
+        // Look the label up afresh, in case the tunnel was relabeled during
+        // the login.
+
+        let mut news = Vec::new();
+
+        if state.report_pid {
+            if let Some(pid) = state.common.shared().running_pid(state.tunnel_id) {
+                news.push(ServerMessage::TunnelPid(pid));
+            }
+        }
+
+        if state.report_key {
+            if let Some(key) = state.common.shared().running_key(state.tunnel_id) {
+                news.push(ServerMessage::TunnelKey(key));
+            }
+        }
+
+        if !news.is_empty() {
+            let send = state.cl_tx.send_all(stream::iter_ok(news));
+            transition!(FlushingBeforeReply {
+                common: state.common,
+                tx: send,
+                rx: state.cl_rx,
+                reply: ServerMessage::Ok,
+            });
+        }
+
+        let send = state.cl_tx.send(ServerMessage::Ok);
+        transition!(FinalizingTxn {
+            common: state.common,
+            tx: send,
+            rx: state.cl_rx,
+        });
+    }
+
+    Ok(Async::NotReady)
+}
+
+
+/// Queue up whatever we have for the client, as far as it will take it.
+fn start_sends_to_client(state: &mut CommunicatingForOpen) -> Result<(), Error> {
+    if state.ping_wanted {
+        if let AsyncSink::Ready = state.cl_tx.start_send(ServerMessage::Ping)? {
+            state.ping_wanted = false;
+        }
+    }
+
+    if !state.cl_buf.is_empty() {
+        let buf = state.cl_buf.clone();
+
+        if let AsyncSink::Ready = state.cl_tx.start_send(ServerMessage::SshData(buf))? {
+            state.cl_buf.clear();
+        }
+    }
+
+    if !state.err_buf.is_empty() {
+        let buf = state.err_buf.clone();
+
+        if let AsyncSink::Ready = state.cl_tx.start_send(ServerMessage::SshStderr(buf))? {
+            state.err_buf.clear();
+        }
+    }
+
+    Ok(())
+}
+
+/// Our session with the client has failed partway through an open, most
+/// likely because the client went away. If SSH has already logged in, the
+/// tunnel is kept running without the client, unless the client asked us not
+/// to; otherwise `poll_communicating_for_open` kills it. Returns `err` for
+/// passing along.
+fn abandon_login(state: CommunicatingForOpen, err: Error) -> Error {
+    if state.ssh_key_status != SshKeyStatus::FoundIt {
+        return err;
+    }
+
+    if state.close_if_abandoned {
+        log!(state.common.shared(), "client went away after the login finished; \
+                                     closing the tunnel as asked");
+        return err;
+    }
+
+    log!(state.common.shared(), "client went away after the login finished; keeping the tunnel");
+    let ssh_rx = if state.ssh_eof { None } else { Some(state.ssh_rx) };
+    hand_off_ssh_process(&state.common.handle, state.common.shared.clone(),
+                         state.relay_stats, state.ssh_tx, ssh_rx, state.ssh_err, state.log_stderr);
+    err
+}
+
+
+/// SSH closed its terminal after logging in successfully, most likely
+/// because it exited. The login still worked, so we finish it as usual once
+/// SSH's last output has reached the client, but we stop reading from the
+/// terminal. The child monitor will notice that SSH has gone.
+fn note_eof_after_login(state: &mut CommunicatingForOpen) {
+    log!(state.common.shared(), "SSH closed its terminal right after logging in");
+    state.ssh_eof = true;
+    state.relay_stats.lock().unwrap().pty_closed = true;
+}
+
+
+/// Collect whatever SSH has written to its separate standard error, if it
+/// has one, returning whether there was anything new. If the stream ends or
+/// breaks, we stop listening to it; we'll hear about SSH's fate from the
+/// PTY.
+fn poll_ssh_stderr(ssh_err: &mut Option<SshErrStream>, err_buf: &mut Vec<u8>) -> bool {
+    let mut saw_data = false;
+
+    while let Some(stream) = ssh_err.as_mut() {
+        match stream.poll() {
+            Ok(Async::Ready(Some(bytes))) => {
+                saw_data = true;
+                err_buf.extend_from_slice(&bytes);
+            },
+            Ok(Async::NotReady) => break,
+            Ok(Async::Ready(None)) | Err(_) => {
+                *ssh_err = None;
+            },
+        }
+    }
+
+    saw_data
+}
+
+
+/// Kill the SSH process of a tunnel whose login was abandoned partway
+/// through. It may have died on its own already, in which case there's
+/// nothing to do.
+fn kill_half_opened_tunnel(shared: &Arc<Mutex<State>>, session_id: u64, id: u64) {
+    let mut sh = SessionState { state: shared.lock().unwrap(), session_id };
+
+    let key = match sh.running_key(id) {
+        Some(k) => k,
+        None => return,
+    };
+
+    if let Some(TunnelState::Running { tx_kill, .. }) = sh.children.remove(&key) {
+        log!(sh, "login for {} abandoned; killing SSH", key);
+        sh.record_event(&key, TunnelEventKind::Closed, None);
+        sh.children.insert(key, TunnelState::Exited { status: None });
+
+        let (tx_done, _rx_done) = oneshot::channel();
+        let _r = tx_kill.send(KillRequest { tx_done, grace: None }); // fails if it's already dead
+    }
+}
+
+
+/// Resize the terminal of a tunnel that is still logging in. Failing to is
+/// no reason to give up on the login, so we just note it.
+fn resize_half_opened_tunnel(common: &ClientCommonState, id: u64, rows: u16, cols: u16) {
+    let mut sh = common.shared();
+
+    let key = match sh.running_key(id) {
+        Some(k) => k,
+        None => return,
+    };
+
+    let result = match sh.children.get(&key) {
+        Some(TunnelState::Running { pty, .. }) => set_window_size(pty, rows, cols),
+        _ => return,
+    };
+
+    if let Err(e) = result {
+        log!(sh, "failed to resize terminal for {}: {}", key, e);
+    }
+}
+
+
+fn process_relabel_command(
+    common: ClientCommonState, params: RelabelParameters, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let reply = {
+        let mut sh = common.shared();
+        log!(sh, "got command to relabel tunnel {} as {}", params.old, params.new);
+
+        if sh.children.contains_key(&params.new) {
+            log!(sh, "new label already in use -- notifying client");
+            common.error_reply(ProtocolError::LabelInUse,
+                               format!("a tunnel labeled \"{}\" already exists", params.new))
+        } else if let Some(tunnel) = sh.children.remove(&params.old) {
+            if let Some(history) = sh.history.remove(&params.old) {
+                sh.history.insert(params.new.clone(), history);
+            }
+
+            if let Some(totals) = sh.event_totals.remove(&params.old) {
+                sh.event_totals.insert(params.new.clone(), totals);
+            }
+
+            sh.children.insert(params.new, tunnel);
+            ServerMessage::Ok
+        } else {
+            log!(sh, "no such tunnel -- notifying client");
+            ServerMessage::TunnelNotOpen
+        }
+    };
+
+    let send = tx.send(reply);
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+
+fn process_version_query(
+    common: ClientCommonState, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let info = VersionInformation {
+        protocol_version: PROTOCOL_VERSION.to_owned(),
+        daemon_version: env!("CARGO_PKG_VERSION").to_owned(),
+    };
+
+    let send = tx.send(ServerMessage::VersionResponse(info));
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+
+fn process_status_query(
+    common: ClientCommonState, filter: StatusFilter, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let sh = common.shared();
+
+    let mut info = StatusInformation {
+        tunnels: Vec::new(),
+        log_error: sh.log_error.clone(),
+    };
+
+    for (host, tinfo) in sh.children.iter() {
+        let (state, pid) = match tinfo {
+            &TunnelState::Running { pid, .. } => (super::TunnelState::Open, Some(pid)),
+            &TunnelState::Exited { status: None } => (super::TunnelState::Closed, None),
+            &TunnelState::Exited { status: _other } => (super::TunnelState::Died, None),
+        };
+
+        if !filter.matches(&state) {
+            continue;
+        }
+
+        let history = match sh.history.get(host) {
+            Some(h) => h.iter().cloned().collect(),
+            None => Vec::new(),
+        };
+
+        info.tunnels.push(TunnelInformation {
+            host: host.clone(),
+            state: state,
+            history,
+            pid,
+        });
+    }
+
+    drop(sh);
+
+    let send = tx.send(ServerMessage::StatusResponse(info));
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+
+/// Handle an "Exit" command.
+/// The longest that an exit will wait for opens to finish, whatever the
+/// client asks for. Logins that take longer than this have surely been
+/// forgotten about.
+const MAX_EXIT_WAIT_SECS: u64 = 24 * 60 * 60;
+
+fn process_exit_command(
+    mut common: ClientCommonState, params: ExitParameters, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    // To be able to close out this connection in a nice way, when we get
+    // this command we set a flag that will cause the exit message to be
+    // sent on connection close.
+
+    {
+        let mut sh = common.shared();
+        log!(sh, "commanded to exit after client disconnects");
+        sh.shutting_down = true;
+    }
+
+    common.exit_on_close = true;
+
+    // If asked, hold off on replying until any opens that are
+    // underway have finished. No new ones may start in the meantime.
+
+    let rx_done = if params.wait_for_opens {
+        let mut sh = common.shared();
+        sh.draining = true;
+
+        let n = sh.opens_in_flight;
+
+        if n > 0 {
+            log!(sh, "waiting for {} open(s) to finish before exiting", n);
+            sh.letting_opens_finish = true;
+            let (tx_done, rx_done) = oneshot::channel();
+            sh.tx_opens_done.push(tx_done);
+            Some(rx_done)
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+
+    if let Some(rx_done) = rx_done {
+        let secs = params.wait_timeout.min(MAX_EXIT_WAIT_SECS);
+        let timeout = common.clock.timer(Duration::from_secs(secs), &common.handle)?;
+
+        transition!(WaitingForOpens {
+            common,
+            tx,
+            rx,
+            rx_done,
+            timeout,
+        });
+    }
+
+    let send = tx.send(ServerMessage::Ok);
+
+    transition!(FinalizingTxn {
+        common,
+        tx: send,
+        rx,
+    });
+}
+
+
+/// The version of the layout of the file written by `PrepareUpgrade`. Bump
+/// this whenever the layout changes, and teach `load_persisted_tunnels` how
+/// to upgrade the old one.
+///
+/// Version 0 was a bare list of tunnels, with no version field.
+const STATE_FILE_VERSION: u64 = 1;
+
+/// The contents of the file written by `PrepareUpgrade`.
+#[derive(Debug, Deserialize, Serialize)]
+struct PersistedState {
+    version: u64,
+    tunnels: Vec<PersistedTunnel>,
+}
+
+/// A tunnel as recorded in the file written by `PrepareUpgrade`.
+#[derive(Debug, Deserialize, Serialize)]
+struct PersistedTunnel {
+    label: String,
+    params: OpenParameters,
+}
+
+/// Where `PrepareUpgrade` saves the parameters of the open tunnels.
+fn state_file_path(sock_path: &Path) -> PathBuf {
+    sock_path.with_extension("state.json")
+}
+
+/// Read a file written by `PrepareUpgrade`, possibly by an older or newer
+/// daemon, upgrading its contents to the current layout if needed.
+///
+/// Unknown fields are ignored, so that a newer daemon can add fields
+/// without bumping the version as long as older daemons can do without
+/// them. A file from a daemon newer than us is refused, since we can't know
+/// what it means.
+fn load_persisted_tunnels(path: &Path) -> Result<Vec<PersistedTunnel>, Error> {
+    let file = fs::File::open(path)?;
+    let value: serde_json::Value = serde_json::from_reader(io::BufReader::new(file))?;
+
+    let version = match value {
+        serde_json::Value::Array(_) => 0,
+        serde_json::Value::Object(ref map) => match map.get("version").and_then(|v| v.as_u64()) {
+            Some(v) => v,
+            None => return Err(format_err!("{} has no valid version field", path.display())),
+        },
+        _ => return Err(format_err!("{} does not contain saved tunnels", path.display())),
+    };
+
+    if version > STATE_FILE_VERSION {
+        return Err(format_err!("{} was written by a newer version of stund (file format {}; \
+                                this daemon understands up to {})",
+                               path.display(), version, STATE_FILE_VERSION));
+    }
+
+    let state = match version {
+        0 => PersistedState { version: STATE_FILE_VERSION, tunnels: serde_json::from_value(value)? },
+        _ => serde_json::from_value(value)?,
+    };
+
+    Ok(state.tunnels)
+}
+
+/// Where the daemon records the sockets that it has told SSH processes to
+/// create; see `State::own_socket`.
+fn profiles_path(sock_path: &Path) -> PathBuf {
+    sock_path.with_extension("profiles.json")
+}
+
+/// Look up a profile in the profiles file, a JSON object mapping names to
+/// `OpenParameters`. It's read anew each time so that changes take effect
+/// without restarting the daemon. A missing file has no profiles in it.
+fn load_profile(path: &Path, name: &str) -> Result<Option<OpenParameters>, Error> {
+    let f = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut profiles: HashMap<String, OpenParameters> = serde_json::from_reader(f)?;
+    Ok(profiles.remove(name))
+}
+
+fn socket_record_path(sock_path: &Path) -> PathBuf {
+    sock_path.with_extension("sockets.json")
+}
+
+/// Read the record of the sockets that a daemon owned. It's fine if there
+/// isn't one.
+fn load_owned_sockets(path: &Path) -> Result<BTreeSet<PathBuf>, Error> {
+    match fs::File::open(path) {
+        Ok(f) => Ok(serde_json::from_reader(io::BufReader::new(f))?),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Remove the sockets that we own but that none of our running tunnels is
+/// using, returning the paths of the ones that were removed.
+///
+/// A socket might still be in use by an SSH process left behind by a daemon
+/// that crashed, so any socket that accepts a connection is left alone. We
+/// keep track of it in case its SSH process goes away later.
+fn clean_up_sockets(sh: &mut State) -> Vec<PathBuf> {
+    let live: HashSet<_> = sh.children.values().filter_map(|t| match t {
+        TunnelState::Running { control_path, .. } => control_path.clone(),
+        TunnelState::Exited { .. } => None,
+    }).collect();
+
+    let candidates: Vec<_> = sh.owned_sockets.iter()
+        .filter(|p| !live.contains(*p))
+        .cloned()
+        .collect();
+
+    let mut removed = Vec::new();
+
+    for path in candidates {
+        if StdUnixStream::connect(&path).is_ok() {
+            continue;
+        }
+
+        match fs::remove_file(&path) {
+            Ok(()) => removed.push(path.clone()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => {
+                log_error!(sh, "failed to remove stale socket {}: {}", path.display(), e);
+                continue;
+            },
+        }
+
+        sh.disown_socket(&path);
+    }
+
+    removed
+}
+
+fn process_cleanup_command(
+    common: ClientCommonState, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let removed = {
+        let mut sh = common.shared();
+        let removed = clean_up_sockets(&mut sh);
+
+        for p in &removed {
+            log!(sh, "removed stale socket {}", p.display());
+        }
+
+        removed
+    };
+
+    let paths = removed.iter().map(|p| p.display().to_string()).collect();
+    let send = tx.send(ServerMessage::SocketsRemoved(paths));
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+fn process_prepare_upgrade_command(
+    common: ClientCommonState, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let result = {
+        let mut sh = common.shared();
+        log!(sh, "preparing for upgrade: no longer opening new tunnels");
+        sh.draining = true;
+
+        let tunnels: Vec<_> = sh.children.iter().filter_map(|(label, tinfo)| match tinfo {
+            TunnelState::Running { params, .. } => Some(PersistedTunnel {
+                label: label.clone(),
+                params: params.clone(),
+            }),
+            TunnelState::Exited { .. } => None,
+        }).collect();
+
+        let state = PersistedState { version: STATE_FILE_VERSION, tunnels };
+        let path = state_file_path(&sh.sock_path);
+
+        let r = private_file(&path)
+            .and_then(|f| serde_json::to_writer_pretty(f, &state).map_err(Error::from));
+
+        match r {
+            Ok(()) => {
+                log!(sh, "saved parameters of {} tunnel(s) to {}", state.tunnels.len(), path.display());
+                Ok(path.display().to_string())
+            },
+            Err(e) => {
+                log_error!(sh, "failed to save tunnel parameters to {}: {}", path.display(), e);
+                Err(format!("failed to save tunnel parameters to {}: {}", path.display(), e))
+            },
+        }
+    };
+
+    match result {
+        Ok(path) => {
+            let send = tx.send(ServerMessage::UpgradePrepared(path));
+            transition!(FinalizingTxn { common, tx: send, rx });
+        },
+        Err(msg) => {
+            transition!(reply_error(common, tx, rx, ProtocolError::Internal, msg));
+        },
+    }
+}
+
+
+fn process_get_log_query(
+    common: ClientCommonState, lines: usize, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let recent = common.shared().recent_log.as_ref().map(|recent| {
+        let skip = recent.len().saturating_sub(lines);
+        recent.iter().skip(skip).cloned().collect()
+    });
+
+    let msg = match recent {
+        Some(lines) => ServerMessage::LogLines(lines),
+        None => common.error_reply(
+            ProtocolError::Forbidden,
+            "the daemon was not started with --allow-log-queries".to_owned()
+        ),
+    };
+
+    let send = tx.send(msg);
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+
+/// A stream that yields nothing while its tunnel is paused, so that data
+/// stays wherever it was until the tunnel is resumed.
+struct PausableStream<S> {
+    inner: S,
+    relay_stats: Arc<Mutex<RelayStats>>,
+}
+
+impl<S: Stream> Stream for PausableStream<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        {
+            let mut stats = self.relay_stats.lock().unwrap();
+
+            if stats.paused {
+                stats.resume_task = Some(task::current());
+                return Ok(Async::NotReady);
+            }
+        }
+
+        self.inner.poll()
+    }
+}
+
+fn process_pause_command(
+    common: ClientCommonState, host: String, pause: bool, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let reply = {
+        let mut sh = common.shared();
+        let verb = if pause { "pause" } else { "resume" };
+        log!(sh, "got command to {} tunnel {}", verb, host);
+
+        let label = sh.resolve_label(&host);
+
+        let result = match sh.children.get(&label) {
+            Some(TunnelState::Running { relay_stats, .. }) => {
+                let mut stats = relay_stats.lock().unwrap();
+
+                if stats.handed_off.is_none() {
+                    Err((ProtocolError::Busy, format!("tunnel \"{}\" is still logging in", host)))
+                } else {
+                    stats.paused = pause;
+
+                    if !pause {
+                        if let Some(task) = stats.resume_task.take() {
+                            task.notify();
+                        }
+                    }
+
+                    Ok(())
+                }
+            },
+
+            _ => Err((ProtocolError::NotFound, format!("no running tunnel labeled \"{}\"", host))),
+        };
+
+        match result {
+            Ok(()) => ServerMessage::Ok,
+            Err((code, msg)) => {
+                log!(sh, "{}", msg);
+                common.error_reply(code, msg)
+            },
+        }
+    };
+
+    let send = tx.send(reply);
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+/// Open the file that a tunnel's output is to be copied to. The daemon's
+/// working directory is meaningless to clients, so relative paths are
+/// refused.
+fn open_tee_file(path: &Path) -> Result<fs::File, Error> {
+    if !path.is_absolute() {
+        return Err(format_err!("the path \"{}\" is not absolute", path.display()));
+    }
+
+    fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| format_err!("cannot open {}: {}", path.display(), e))
+}
+
+fn process_tee_command(
+    common: ClientCommonState, host: String, path: Option<String>, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let reply = {
+        let mut sh = common.shared();
+
+        match path {
+            Some(ref p) => log!(sh, "got command to copy the output of tunnel {} to {}", host, p),
+            None => log!(sh, "got command to stop copying the output of tunnel {}", host),
+        }
+
+        let label = sh.resolve_label(&host);
+
+        let result = match sh.children.get(&label) {
+            Some(TunnelState::Running { relay_stats, .. }) => {
+                let mut stats = relay_stats.lock().unwrap();
+
+                match path {
+                    None => {
+                        stats.tee = None;
+                        Ok(())
+                    },
+
+                    Some(_) if stats.handed_off.is_none() => {
+                        Err((ProtocolError::Busy, format!("tunnel \"{}\" is still logging in", host)))
+                    },
+
+                    Some(p) => {
+                        let p = PathBuf::from(p);
+
+                        match open_tee_file(&p) {
+                            Ok(file) => {
+                                stats.tee = Some((p, file));
+                                Ok(())
+                            },
+                            Err(e) => Err((ProtocolError::InvalidParameters, e.to_string())),
+                        }
+                    },
+                }
+            },
+
+            _ => Err((ProtocolError::NotFound, format!("no running tunnel labeled \"{}\"", host))),
+        };
+
+        match result {
+            Ok(()) => ServerMessage::Ok,
+            Err((code, msg)) => {
+                log!(sh, "{}", msg);
+                common.error_reply(code, msg)
+            },
+        }
+    };
+
+    let send = tx.send(reply);
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+fn process_wait_for_exit_command(
+    common: ClientCommonState, host: String, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let rx_exit = {
+        let mut sh = common.shared();
+        log!(sh, "got command to wait for tunnel {} to exit", host);
+
+        let label = sh.resolve_label(&host);
+
+        let id = match sh.children.get(&label) {
+            Some(TunnelState::Running { id, .. }) => Some(*id),
+            _ => None,
+        };
+
+        id.map(|id| {
+            let (tx_exit, rx_exit) = oneshot::channel();
+            sh.exit_waiters.entry(id).or_default().push(tx_exit);
+            rx_exit
+        })
+    };
+
+    match rx_exit {
+        Some(rx_exit) => transition!(WaitingForTunnelExit { common, tx, rx, rx_exit }),
+
+        None => {
+            let msg = format!("no running tunnel labeled \"{}\"", host);
+            log!(common.shared(), "{}", msg);
+            let send = tx.send(common.error_reply(ProtocolError::NotFound, msg));
+            transition!(FinalizingTxn { common, tx: send, rx });
+        },
+    }
+}
+
+/// The terminal control character that makes the remote end deliver a
+/// signal, for the few signals that have one. These are the usual defaults;
+/// a remote terminal with unusual settings may not honor them.
+fn control_char_for_signal(signum: i32) -> Option<u8> {
+    match signum {
+        libc::SIGINT => Some(0x03),
+        libc::SIGQUIT => Some(0x1c),
+        libc::SIGTSTP => Some(0x1a),
+        _ => None,
+    }
+}
+
+fn process_signal_command(
+    common: ClientCommonState, host: String, signum: i32, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let reply = {
+        let mut sh = common.shared();
+        log!(sh, "got command to send signal {} to tunnel {}", signum, host);
+
+        let label = sh.resolve_label(&host);
+
+        let result = match (sh.children.get(&label), control_char_for_signal(signum)) {
+            (_, None) => Err((ProtocolError::InvalidParameters,
+                              format!("signal {} can't be sent through a terminal", signum))),
+
+            (Some(TunnelState::Running { pty, relay_stats, .. }), Some(c)) => {
+                if relay_stats.lock().unwrap().handed_off.is_none() {
+                    Err((ProtocolError::Busy, format!("tunnel \"{}\" is still logging in", host)))
+                } else {
+                    // The PTY is nonblocking, but a single byte only fails
+                    // to go through if SSH has stopped reading altogether.
+                    let mut pty = pty;
+                    pty.write_all(&[c]).map_err(|e| {
+                        (ProtocolError::Internal, format!("failed to write to terminal: {}", e))
+                    })
+                }
+            },
+
+            _ => Err((ProtocolError::NotFound, format!("no running tunnel labeled \"{}\"", host))),
+        };
+
+        match result {
+            Ok(()) => ServerMessage::Ok,
+            Err((code, msg)) => {
+                log!(sh, "{}", msg);
+                common.error_reply(code, msg)
+            },
+        }
+    };
+
+    let send = tx.send(reply);
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+/// Set the size of a tunnel's terminal. SSH hears about it through SIGWINCH.
+fn set_window_size(pty: &fs::File, rows: u16, cols: u16) -> io::Result<()> {
+    let ws = libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    if unsafe { libc::ioctl(pty.as_raw_fd(), libc::TIOCSWINSZ, &ws) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn process_resize_command(
+    common: ClientCommonState, params: ResizeParameters, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let reply = {
+        let mut sh = common.shared();
+        log!(sh, "got command to resize tunnel {} to {}x{}", params.host, params.cols, params.rows);
+
+        let label = sh.resolve_label(&params.host);
+
+        let result = match sh.children.get(&label) {
+            Some(TunnelState::Running { pty, .. }) => {
+                set_window_size(pty, params.rows, params.cols).map_err(|e| {
+                    (ProtocolError::Internal, format!("failed to resize terminal: {}", e))
+                })
+            },
+
+            _ => Err((ProtocolError::NotFound,
+                      format!("no running tunnel labeled \"{}\"", params.host))),
+        };
+
+        match result {
+            Ok(()) => ServerMessage::Ok,
+            Err((code, msg)) => {
+                log!(sh, "{}", msg);
+                common.error_reply(code, msg)
+            },
+        }
+    };
+
+    let send = tx.send(reply);
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+
+fn process_check_tunnel_command(
+    common: ClientCommonState, params: CheckTunnelParameters, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let health = {
+        let sh = common.shared();
+
+        match sh.children.get(&sh.resolve_label(&params.host)) {
+            Some(TunnelState::Running { relay_stats, .. }) => Some(TunnelHealth {
+                ssh_alive: true,
+                pty_open: !relay_stats.lock().unwrap().pty_closed,
+            }),
+            Some(TunnelState::Exited { .. }) => Some(TunnelHealth {
+                ssh_alive: false,
+                pty_open: false,
+            }),
+            None => None,
+        }
+    };
+
+    let msg = match health {
+        Some(h) => ServerMessage::TunnelHealthResponse(h),
+        None => ServerMessage::TunnelNotOpen,
+    };
+
+    let send = tx.send(msg);
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+
+fn process_relay_counters_query(
+    common: ClientCommonState, params: RelayCountersParameters, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let counters = {
+        let sh = common.shared();
+
+        match sh.children.get(&sh.resolve_label(&params.host)) {
+            Some(TunnelState::Running { relay_stats, .. }) => {
+                Some(relay_stats.lock().unwrap().to_counters())
+            },
+            Some(TunnelState::Exited { .. }) | None => None,
+        }
+    };
+
+    let msg = match counters {
+        Some(c) => ServerMessage::RelayCountersResponse(c),
+        None => ServerMessage::TunnelNotOpen,
+    };
+
+    let send = tx.send(msg);
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+
+/// Describe one tunnel in detail, or return None if we've never heard of
+/// it. The age of a running tunnel is taken from the last time that it was
+/// opened, according to its history.
+fn tunnel_details(sh: &State, label: &str) -> Option<TunnelDetails> {
+    let history: Vec<_> = match sh.history.get(label) {
+        Some(h) => h.iter().cloned().collect(),
+        None => Vec::new(),
+    };
+
+    let mut details = TunnelDetails {
+        host: label.to_owned(),
+        state: super::TunnelState::Open,
+        pid: None,
+        age_secs: None,
+        bytes_from_ssh: None,
+        bytes_to_ssh: None,
+        history: Vec::new(),
+        last_error: None,
+        paused: false,
+    };
+
+    match sh.children.get(label)? {
+        TunnelState::Running { pid, relay_stats, .. } => {
+            let stats = relay_stats.lock().unwrap();
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+            details.pid = Some(*pid);
+            details.age_secs = history.iter().rev()
+                .find(|e| e.kind == TunnelEventKind::Opened)
+                .map(|e| now.saturating_sub(e.timestamp));
+            details.bytes_from_ssh = Some(stats.bytes_from_ssh);
+            details.bytes_to_ssh = Some(stats.bytes_to_ssh);
+            details.paused = stats.paused;
+        },
+
+        TunnelState::Exited { status: None } => {
+            details.state = super::TunnelState::Closed;
+        },
+
+        TunnelState::Exited { status: Some(status) } => {
+            details.state = super::TunnelState::Died;
+            details.last_error = Some(status.to_string());
+        },
+    }
+
+    details.history = history;
+    Some(details)
+}
+
+
+fn process_tunnel_status_query(
+    common: ClientCommonState, host: String, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let details = {
+        let sh = common.shared();
+        tunnel_details(&sh, &sh.resolve_label(&host))
+    };
+
+    match details {
+        Some(d) => {
+            let send = tx.send(ServerMessage::TunnelStatusResponse(d));
+            transition!(FinalizingTxn { common, tx: send, rx });
+        },
+
+        None => {
+            let msg = format!("no tunnel to \"{}\" is known", host);
+            transition!(reply_error(common, tx, rx, ProtocolError::NotFound, msg));
+        },
+    }
+}
+
+
+/// Describe everything we know about our tunnels. The parameters of an
+/// open may include secrets, so only the names of environment variables are
+/// included, and the user name is left out.
+fn dump_state(sh: &State) -> StateDump {
+    let mut tunnels: Vec<_> = sh.children.iter().map(|(label, tinfo)| match tinfo {
+        TunnelState::Running { id, pid, params, relay_stats, .. } => {
+            let stats = relay_stats.lock().unwrap();
+
+            TunnelDump {
+                label: label.clone(),
+                state: super::TunnelState::Open,
+                id: Some(*id),
+                pid: Some(*pid),
+                host: Some(params.host.clone()),
+                env_names: params.env.iter().map(|(name, _)| name.clone()).collect(),
+                connect_retries: Some(params.connect_retries),
+                relay: Some(stats.to_counters()),
+                pty_closed: stats.pty_closed,
+                exit_status: None,
+            }
+        },
+
+        TunnelState::Exited { status } => TunnelDump {
+            label: label.clone(),
+            state: if status.is_some() { super::TunnelState::Died } else { super::TunnelState::Closed },
+            id: None,
+            pid: None,
+            host: None,
+            env_names: Vec::new(),
+            connect_retries: None,
+            relay: None,
+            pty_closed: false,
+            exit_status: status.map(|s| s.to_string()),
+        },
+    }).collect();
+
+    tunnels.sort_by(|a, b| a.label.cmp(&b.label));
+
+    StateDump {
+        tunnels,
+        opens_in_flight: sh.opens_in_flight,
+        num_sessions: sh.num_sessions,
+        draining: sh.draining,
+        shutting_down: sh.shutting_down,
+    }
+}
+
+
+/// Measure our own resource usage. Clients may ask for this often, so it
+/// has to be cheap; and it mustn't fail on systems without `/proc`.
+fn daemon_stats(sh: &State) -> DaemonStats {
+    // The second field of `statm` is the resident set size in pages.
+    let rss_bytes = fs::read_to_string("/proc/self/statm").ok()
+        .and_then(|text| text.split_whitespace().nth(1).and_then(|f| f.parse::<u64>().ok()))
+        .map(|pages| pages * unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64);
+
+    // `ru_maxrss` is in kilobytes, except on macOS, where it's in bytes.
+    let max_rss_bytes = {
+        let mut usage: libc::rusage = unsafe { mem::zeroed() };
+
+        if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } == 0 {
+            let scale = if cfg!(target_os = "macos") { 1 } else { 1024 };
+            Some(usage.ru_maxrss as u64 * scale)
+        } else {
+            None
+        }
+    };
+
+    // Listing the directory takes a descriptor of its own, which we don't
+    // count.
+    let num_fds = fs::read_dir("/dev/fd").ok()
+        .map(|entries| entries.count().saturating_sub(1) as u64);
+
+    let num_tunnels = sh.children.values().filter(|t| match t {
+        TunnelState::Running { .. } => true,
+        TunnelState::Exited { .. } => false,
+    }).count();
+
+    DaemonStats {
+        rss_bytes,
+        max_rss_bytes,
+        num_fds,
+        num_tunnels,
+        num_sessions: sh.num_sessions,
+    }
+}
+
+
+fn process_dump_state_query(
+    common: ClientCommonState, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let dump = {
+        let sh = common.shared();
+
+        if sh.allow_state_dumps {
+            Some(dump_state(&sh))
+        } else {
+            None
+        }
+    };
+
+    match dump {
+        Some(dump) => {
+            let send = tx.send(ServerMessage::StateDumpResponse(dump));
+            transition!(FinalizingTxn { common, tx: send, rx });
+        },
+
+        None => {
+            let msg = "the daemon was not started with --allow-state-dumps".to_owned();
+            transition!(reply_error(common, tx, rx, ProtocolError::Forbidden, msg));
+        },
+    }
+}
+
+
+fn process_daemon_stats_query(
+    common: ClientCommonState, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let stats = daemon_stats(&common.shared());
+    let send = tx.send(ServerMessage::DaemonStatsResponse(stats));
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+
+/// Describe the settings that the daemon is running with. These mostly come
+/// straight from the command line, but the log destination and socket path
+/// may have been chosen for us.
+fn daemon_config(sh: &State) -> DaemonConfig {
+    DaemonConfig {
+        socket_path: sh.sock_path.display().to_string(),
+        socket_mode: format!("{:04o}", sh.socket_mode),
+        foreground: sh.opts.foreground,
+        log_sink: sh.log_sink.to_string(),
+        log_messages: sh.log_messages,
+        allow_log_queries: sh.recent_log.is_some(),
+        allow_state_dumps: sh.allow_state_dumps,
+        keepalive_secs: sh.keepalive.map(|d| d.as_secs()),
+        reap_after_idle_secs: sh.reap_after_idle.map(|d| d.as_secs()),
+        ssh_user: sh.ssh_user.as_ref().map(|u| u.name.clone()),
+        listen_backlog: sh.listen_backlog,
+        max_accepts_per_sec: sh.max_accepts_per_sec,
+        socket_linger: sh.socket_linger,
+    }
+}
+
+fn process_config_query(
+    common: ClientCommonState, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let config = daemon_config(&common.shared());
+    let send = tx.send(ServerMessage::Config(config));
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+
+/// List the optional features that this daemon supports. Those that can be
+/// turned off are only listed if they're on.
+fn daemon_capabilities(sh: &State) -> Vec<String> {
+    let mut names = vec![
+        capabilities::METRICS,
+        capabilities::CONFIG,
+        capabilities::WAIT_FOR_EXIT,
+        capabilities::SIGNALS,
+        capabilities::TEE,
+        capabilities::CONTROL_MASTER,
+        capabilities::WINDOW_SIZE,
+        capabilities::FILTERED_STATUS,
+    ];
+
+    if sh.keepalive.is_some() {
+        names.push(capabilities::KEEPALIVE);
+    }
+
+    if sh.recent_log.is_some() {
+        names.push(capabilities::LOG_QUERIES);
+    }
+
+    if sh.allow_state_dumps {
+        names.push(capabilities::STATE_DUMPS);
+    }
+
+    names.into_iter().map(|n| n.to_owned()).collect()
+}
+
+fn process_capabilities_query(
+    common: ClientCommonState, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let names = daemon_capabilities(&common.shared());
+    let send = tx.send(ServerMessage::Capabilities(names));
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+
+/// Quote a Prometheus label value.
+fn metric_label(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
+
+/// Render the daemon's metrics in the Prometheus text exposition format.
+/// Tunnels are listed in order of label, so that the output is stable.
+fn render_metrics(sh: &State) -> String {
+    let stats = daemon_stats(sh);
+    let mut out = String::new();
+
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, u64)>| {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+
+        for (labels, value) in samples {
+            out.push_str(&format!("{}{} {}\n", name, labels, value));
+        }
+    };
+
+    let uptime = sh.clock.now().saturating_duration_since(sh.started).as_secs();
+    metric("stund_uptime_seconds", "gauge", "How long the daemon has been running.",
+           vec![(String::new(), uptime)]);
+    metric("stund_sessions", "gauge", "The number of connected clients.",
+           vec![(String::new(), stats.num_sessions as u64)]);
+    metric("stund_tunnels", "gauge", "The number of running tunnels.",
+           vec![(String::new(), stats.num_tunnels as u64)]);
+
+    if let Some(n) = stats.num_fds {
+        metric("stund_open_fds", "gauge", "The number of file descriptors that the daemon has open.",
+               vec![(String::new(), n)]);
+    }
+
+    let mut running: Vec<_> = sh.children.iter().filter_map(|(label, tunnel)| match tunnel {
+        TunnelState::Running { params, relay_stats, .. } => {
+            let stats = relay_stats.lock().unwrap();
+            let labels = format!("{{label={},host={}}}", metric_label(label), metric_label(&params.host));
+            Some((label, labels, stats.bytes_from_ssh, stats.bytes_to_ssh))
+        },
+        TunnelState::Exited { .. } => None,
+    }).collect();
+    running.sort();
+
+    metric("stund_tunnel_bytes_from_ssh_total", "counter", "Bytes of terminal output read from a tunnel's SSH.",
+           running.iter().map(|(_, labels, n, _)| (labels.clone(), *n)).collect());
+    metric("stund_tunnel_bytes_to_ssh_total", "counter", "Bytes of terminal input written to a tunnel's SSH.",
+           running.iter().map(|(_, labels, _, n)| (labels.clone(), *n)).collect());
+
+    let mut events = Vec::new();
+
+    for (label, totals) in &sh.event_totals {
+        for (kind, n) in totals {
+            let event = match kind {
+                TunnelEventKind::Opened => "opened",
+                TunnelEventKind::Closed => "closed",
+                TunnelEventKind::Replaced => "replaced",
+                TunnelEventKind::NetworkError => "network_error",
+                TunnelEventKind::Crashed => "crashed",
+            };
+
+            events.push((format!("{{label={},event=\"{}\"}}", metric_label(label), event), *n));
+        }
+    }
+
+    events.sort();
+    metric("stund_tunnel_events_total", "counter",
+           "Events in the lives of tunnels; \"replaced\" counts restarts.", events);
+
+    out
+}
+
+fn process_metrics_query(
+    common: ClientCommonState, tx: Ser, rx: De
+) -> Poll<AfterAwaitingCommand, Error> {
+    let text = render_metrics(&common.shared());
+    let send = tx.send(ServerMessage::Metrics(text));
+    transition!(FinalizingTxn { common, tx: send, rx });
+}
+
+
+/// The most trailing SSH output that we'll relay to the client when SSH dies
+/// while it is logging in.
+const MAX_TRAILING_OUTPUT: usize = 65536;
+
+/// If SSH was told to make several connection attempts, a note saying so to
+/// be appended to an error message.
+fn attempts_note(attempts: u32) -> String {
+    if attempts > 1 {
+        format!(" (after up to {} connection attempts)", attempts)
+    } else {
+        String::new()
+    }
+}
+
+/// SSH died during login. Its final words usually explain what went wrong,
+/// so send along whatever output we haven't relayed yet before the error
+/// message. If there's an unreasonable amount of it, keep only the end.
+fn abort_client_after_output(
+    common: ClientCommonState, tx: Ser, rx: De, output: Vec<u8>, errors: Vec<u8>,
+    code: ProtocolError, mut message: String
+) -> AfterCommunicatingForOpen {
+    if output.is_empty() && errors.is_empty() {
+        return abort_client(common, tx, rx, code, message).into();
+    }
+
+    let (send, n_dropped) = send_trailing_output(&common, tx, output, errors);
+
+    if n_dropped > 0 {
+        message.push_str(&format!(" ({} bytes of earlier SSH output were dropped)", n_dropped));
+    }
+
+    FlushingBeforeAbort {
+        common,
+        tx: send,
+        rx,
+        code,
+        message,
+    }.into()
+}
+
+
+/// How much of SSH's most recent output we keep in `ssh_tail`.
+const SSH_TAIL_LEN: usize = 256;
+
+/// What SSH says when a connect timeout expires.
+const SSH_TIMEOUT_MESSAGE: &[u8] = b"Connection timed out";
+
+/// Add some of SSH's output to the tail of it that we keep around, so we
+/// can tell why it died, should it do so during the login.
+fn remember_ssh_output(tail: &mut Vec<u8>, bytes: &[u8]) {
+    tail.extend_from_slice(bytes);
+
+    if tail.len() > SSH_TAIL_LEN {
+        let excess = tail.len() - SSH_TAIL_LEN;
+        tail.drain(..excess);
+    }
+}
+
+/// SSH died during a login. If the client asked for a connect timeout and
+/// SSH's last words say that it expired, or the client asked for connection
+/// retries, this returns the reply to give in place of an error.
+fn connect_failure_reply(state: &CommunicatingForOpen) -> Option<ServerMessage> {
+    let timed_out = |text: &[u8]| text.windows(SSH_TIMEOUT_MESSAGE.len()).any(|w| w == SSH_TIMEOUT_MESSAGE);
+
+    if state.connect_timeout && (timed_out(&state.ssh_tail) || timed_out(&state.err_buf)) {
+        Some(ServerMessage::HostUnreachable)
+    } else if state.connect_attempts > 1 {
+        Some(ServerMessage::ConnectFailed(state.connect_attempts))
+    } else {
+        None
+    }
+}
+
+/// SSH died during a login in a way that `connect_failure_reply` recognized.
+/// Rather than an error, the client gets `reply`, after SSH's last words,
+/// and the session carries on.
+fn report_connect_failure(
+    common: ClientCommonState, tx: Ser, rx: De, output: Vec<u8>, errors: Vec<u8>, reply: ServerMessage
+) -> AfterCommunicatingForOpen {
+    if output.is_empty() && errors.is_empty() {
+        return FinalizingTxn { common, tx: tx.send(reply), rx }.into();
+    }
+
+    let (send, _) = send_trailing_output(&common, tx, output, errors);
+
+    FlushingBeforeReply {
+        common,
+        tx: send,
+        rx,
+        reply,
+    }.into()
+}
+
+/// Start sending SSH's last output, and its last complaints if it has a
+/// separate standard error, to the client. Returns the number of bytes
+/// that were dropped for being excessive.
+fn send_trailing_output(
+    common: &ClientCommonState, tx: Ser, mut output: Vec<u8>, mut errors: Vec<u8>
+) -> (SendTrailing, usize) {
+    let n_dropped = trim_trailing_output(common, &mut output) +
+        trim_trailing_output(common, &mut errors);
+    let mut msgs = Vec::new();
+
+    if !output.is_empty() {
+        msgs.push(ServerMessage::SshData(output));
+    }
+
+    if !errors.is_empty() {
+        msgs.push(ServerMessage::SshStderr(errors));
+    }
+
+    (tx.send_all(stream::iter_ok(msgs)), n_dropped)
+}
+
+/// Keep only the end of SSH's trailing output if there's an unreasonable
+/// amount of it, returning the number of bytes dropped.
+fn trim_trailing_output(common: &ClientCommonState, output: &mut Vec<u8>) -> usize {
+    if output.len() <= MAX_TRAILING_OUTPUT {
+        return 0;
+    }
+
+    let n_dropped = output.len() - MAX_TRAILING_OUTPUT;
+    output.drain(..n_dropped);
+    log!(common.shared(), "dropped {} bytes of trailing SSH output", n_dropped);
+    n_dropped
+}
+
+
+/// Report that a request failed, without ending the client's session. This
+/// is for requests that fail cleanly; a failed open uses `abort_client`.
+fn reply_error(
+    common: ClientCommonState, tx: Ser, rx: De, code: ProtocolError, message: String
+) -> FinalizingTxn {
+    let reply = common.error_reply(code, message);
+
+    FinalizingTxn {
+        common,
+        tx: tx.send(reply),
+        rx,
+    }
+}
+
+
+/// This function used to be much more elaborate; it can probably be ditched
+/// now.
+fn abort_client(
+    common: ClientCommonState, tx: Ser, rx: De, code: ProtocolError, message: String
+) -> Aborting {
+    let reply = common.error_reply(code, message);
+
+    Aborting {
+        common: common,
+        tx: tx.send(reply),
+        rx: rx,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::future::Either;
+    use tokio_core::reactor::Timeout;
+    use super::*;
+
+    fn params(host: &str) -> OpenParameters {
+        OpenParameters {
+            host: host.to_owned(),
+            port: None,
+            force: false,
+            connect_retries: 0,
+            env: Vec::new(),
+            allow_askpass: false,
+            user: None,
+            separate_stderr: false,
+            report_key: false,
+            agent_socket: None,
+            control_master: false,
+            connect_timeout_secs: None,
+            reap_after_idle_secs: None,
+            close_if_abandoned: false,
+            report_pid: false,
+            verbose: 0,
+        }
+    }
+
+    fn dest(user: Option<&str>, host: &str, port: Option<u16>) -> Destination {
+        Destination {
+            user: user.map(|u| u.to_owned()),
+            host: host.to_owned(),
+            port,
+        }
+    }
+
+    #[test]
+    fn parse_plain_hosts() {
+        assert_eq!(Destination::parse(&params("example.com")).unwrap(),
+                   dest(None, "example.com", None));
+        assert_eq!(Destination::parse(&params("example.com:2222")).unwrap(),
+                   dest(None, "example.com", Some(2222)));
+        assert_eq!(Destination::parse(&params("alice@example.com")).unwrap(),
+                   dest(Some("alice"), "example.com", None));
+        assert_eq!(Destination::parse(&params("alice@example.com:22")).unwrap(),
+                   dest(Some("alice"), "example.com", Some(22)));
+    }
+
+    #[test]
+    fn parse_ipv6_hosts() {
+        assert_eq!(Destination::parse(&params("[::1]")).unwrap(), dest(None, "::1", None));
+        assert_eq!(Destination::parse(&params("[::1]:2222")).unwrap(),
+                   dest(None, "::1", Some(2222)));
+        assert_eq!(Destination::parse(&params("fe80::1")).unwrap(), dest(None, "fe80::1", None));
+        assert_eq!(Destination::parse(&params("bob@[fe80::1]:22")).unwrap(),
+                   dest(Some("bob"), "fe80::1", Some(22)));
+
+        assert!(Destination::parse(&params("[::1")).is_err());
+        assert!(Destination::parse(&params("[::1]2222")).is_err());
+    }
+
+    #[test]
+    fn parse_separate_port_and_user() {
+        let mut p = params("example.com");
+        p.port = Some(2222);
+        p.user = Some("alice".to_owned());
+        assert_eq!(Destination::parse(&p).unwrap(), dest(Some("alice"), "example.com", Some(2222)));
+
+        let mut p = params("example.com:22");
+        p.port = Some(2222);
+        assert!(Destination::parse(&p).is_err());
+
+        let mut p = params("alice@example.com");
+        p.user = Some("bob".to_owned());
+        assert!(Destination::parse(&p).is_err());
+    }
+
+    #[test]
+    fn same_destination_spelled_differently() {
+        let plain = Destination::parse(&params("example.com")).unwrap();
+
+        for spec in &["example.com:22", "Example.COM", "[example.com]:22"] {
+            assert!(Destination::parse(&params(spec)).unwrap().same_as(&plain), "{}", spec);
+        }
+
+        for spec in &["example.com:2222", "alice@example.com", "example.org"] {
+            assert!(!Destination::parse(&params(spec)).unwrap().same_as(&plain), "{}", spec);
+        }
+
+        let mut p = params("example.com");
+        p.user = Some("alice".to_owned());
+        assert!(Destination::parse(&p).unwrap()
+                .same_as(&Destination::parse(&params("alice@example.com:22")).unwrap()));
+    }
+
+    fn dry_run_argv(p: &OpenParameters) -> Result<Vec<String>, Error> {
+        let dest = validate_open_parameters(p)?;
+        Ok(ssh_command_line(p, &dest, "KEY", None))
+    }
+
+    #[test]
+    fn command_line_basics() {
+        assert_eq!(dry_run_argv(&params("example.com")).unwrap(),
+                   vec!["ssh", "-t", "example.com", "echo \"KEY\" && exec tail -f /dev/null"]);
+
+        let mut p = params("alice@[::1]:2222");
+        p.connect_retries = 2;
+        assert_eq!(dry_run_argv(&p).unwrap(),
+                   vec!["ssh", "-t", "-o", "ConnectionAttempts=3", "-p", "2222", "alice@::1",
+                        "echo \"KEY\" && exec tail -f /dev/null"]);
+
+        let mut p = params("example.com");
+        p.port = Some(22);
+        p.user = Some("bob".to_owned());
+        assert_eq!(&dry_run_argv(&p).unwrap()[2..5], &["-p", "22", "bob@example.com"]);
+
+        let mut p = params("example.com");
+        p.connect_timeout_secs = Some(10);
+        assert_eq!(&dry_run_argv(&p).unwrap()[2..4], &["-o", "ConnectTimeout=10"]);
+
+        let mut p = params("example.com");
+        p.verbose = 2;
+        assert_eq!(&dry_run_argv(&p).unwrap()[..3], &["ssh", "-t", "-vv"]);
+        p.verbose = 4;
+        assert!(dry_run_argv(&p).is_err());
+    }
+
+    #[test]
+    fn control_sockets_get_distinct_names() {
+        let sock = Path::new("/home/%u/.ssh/stund.sock");
+        let a = control_socket_path(sock, "alice@[::1]:2222");
+        let b = control_socket_path(sock, "alice@_5b::1]:2222");
+        assert_eq!(a, Path::new("/home/%u/.ssh/stund-alice@_5b_3a_3a1_5d_3a2222.ctl"));
+        assert_ne!(a, b);
+
+        let dest = Destination::parse(&params("example.com")).unwrap();
+        let argv = ssh_command_line(&params("example.com"), &dest, "KEY", Some(&a));
+        assert_eq!(&argv[2..6], &["-o", "ControlMaster=auto", "-o",
+                                  "ControlPath=/home/%%u/.ssh/stund-alice@_5b_3a_3a1_5d_3a2222.ctl"]);
+    }
+
+    #[test]
+    fn command_line_rejects_bad_parameters() {
+        let mut p = params("example.com");
+        p.connect_retries = u32::MAX;
+        assert!(dry_run_argv(&p).is_err());
+
+        let mut p = params("example.com");
+        p.env.push(("A=B".to_owned(), "c".to_owned()));
+        assert!(dry_run_argv(&p).is_err());
+
+        assert!(dry_run_argv(&params("-oProxyCommand=evil")).is_err());
+
+        let mut p = params("example.com");
+        p.connect_timeout_secs = Some(0);
+        assert!(dry_run_argv(&p).is_err());
+    }
+
+    #[test]
+    fn agent_socket_overrides_ssh_auth_sock() {
+        let dir = env::temp_dir().join(format!("stund-test-agent-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let sock = dir.join("agent.sock");
+        let _r = fs::remove_file(&sock);
+        let _listener = ::std::os::unix::net::UnixListener::bind(&sock).unwrap();
+
+        let mut p = params("example.com");
+        p.env.push(("SSH_AUTH_SOCK".to_owned(), "/elsewhere".to_owned()));
+        p.agent_socket = Some(sock.clone());
+        let agents: Vec<_> = ssh_environment(&p).unwrap().into_iter()
+            .filter(|(name, _)| name == "SSH_AUTH_SOCK")
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(agents, [sock.to_str().unwrap()]);
+
+        for bad in &[dir.join("missing.sock"), dir.clone(), PathBuf::from("agent.sock")] {
+            p.agent_socket = Some(bad.clone());
+            assert!(ssh_environment(&p).is_err(), "accepted {}", bad.display());
+        }
+
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn ssh_user_must_be_a_real_unprivileged_account() {
+        assert!(lookup_ssh_user("root").is_err());
+        assert!(lookup_ssh_user("no-such-user-for-stund").is_err());
+        assert!(lookup_ssh_user("bad\0name").is_err());
+    }
+
+    #[test]
+    fn parse_rejects_bad_specs() {
+        for spec in &["", "alice@", "@example.com", ":22", "example.com:ssh",
+                      "example.com:99999", "-oProxyCommand=evil", "alice@-oProxyCommand=evil",
+                      "example.com -v", "example.com\n"] {
+            assert!(Destination::parse(&params(spec)).is_err(), "accepted {:?}", spec);
+        }
+
+        for user in &["", "-oProxyCommand=evil", "a@b", "a b"] {
+            let mut p = params("example.com");
+            p.user = Some(user.to_string());
+            assert!(Destination::parse(&p).is_err(), "accepted user {:?}", user);
+        }
+    }
+
+    type TestSer = WriteBincode<FramedWrite<WriteHalf<UnixStream>>, ClientMessage>;
+    type TestDe = ReadBincode<FramedRead<ReadHalf<UnixStream>>, ServerMessage>;
+
+    /// A daemon state that logs nowhere and keeps its files in `dir`.
+    fn test_state(dir: &Path) -> State {
+        use structopt::StructOpt;
+
+        State {
+            sock_path: dir.join("stund.sock"),
+            opts: StundDaemonOptions::from_iter(&["stund-daemon", "--foreground"]),
+            log: LogOutput::Stream(Box::new(io::sink())),
+            log_sink: LogSink::Stdout,
+            children: HashMap::new(),
+            next_tunnel_id: 0,
+            next_session_id: 0,
+            keepalive: None,
+            recent_log: None,
+            draining: false,
+            shutting_down: false,
+            letting_opens_finish: false,
+            socket_linger: false,
+            opens_in_flight: 0,
+            tx_opens_done: Vec::new(),
+            socket_mode: 0o600,
+            history: HashMap::new(),
+            num_sessions: 0,
+            log_messages: false,
+            allow_state_dumps: false,
+            clock: SystemClock::shared(),
+            log_error: None,
+            lost_log_messages: 0,
+            ssh_user: None,
+            owned_sockets: BTreeSet::new(),
+            reap_after_idle: None,
+            listen_backlog: 128,
+            max_accepts_per_sec: 0,
+            exit_waiters: HashMap::new(),
+            started: Instant::now(),
+            event_totals: HashMap::new(),
+        }
+    }
+
+    /// Wait for the daemon's next message to the client. If it's wedged,
+    /// fail rather than hanging the test run.
+    fn next_reply(core: &mut Core, de: TestDe) -> (ServerMessage, TestDe) {
+        let timeout = Timeout::new(Duration::from_secs(30), &core.handle()).unwrap();
+
+        match core.run(de.into_future().select2(timeout)) {
+            Ok(Either::A(((Some(msg), de), _))) => (msg, de),
+            Ok(Either::A(((None, _), _))) => panic!("the daemon hung up"),
+            Ok(Either::B(_)) => panic!("the daemon didn't reply within 30 seconds"),
+            Err(Either::A(((e, _), _))) => panic!("error reading from the daemon: {}", e),
+            Err(Either::B((e, _))) => panic!("error waiting for the daemon: {}", e),
+        }
+    }
+
+    /// Make a scratch directory holding a stand-in for SSH, which runs
+    /// `script`. Put the directory at the front of the `PATH` that a tunnel
+    /// gets, and the daemon will use it.
+    fn fake_ssh_dir(name: &str, script: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("stund-test-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let ssh = dir.join("ssh");
+        fs::write(&ssh, script).unwrap();
+        fs::set_permissions(&ssh, fs::Permissions::from_mode(0o755)).unwrap();
+        dir
+    }
+
+    /// Start a client session with the daemon.
+    fn connect_test_client(
+        core: &Core, shared: &Arc<Mutex<State>>, tx_exit: &mpsc::Sender<ShutdownReason>
+    ) -> (TestSer, TestDe) {
+        let handle = core.handle();
+        let (ours, theirs) = UnixStream::pair(&handle).unwrap();
+        let addr = theirs.peer_addr().unwrap();
+        process_client(&handle, theirs, addr, shared.clone(), tx_exit.clone());
+
+        let (read, write) = ours.split();
+        (WriteBincode::new(FramedWrite::new(write)), ReadBincode::new(FramedRead::new(read)))
+    }
+
+    /// Set up the daemon's end of a session without starting it, so that a
+    /// test can run the session's state machine from whichever state it
+    /// likes. The daemon's send buffer is kept small, so that it's easy to
+    /// leave a send waiting on the client. The client's end of the
+    /// connection is returned too.
+    fn test_session(
+        core: &Core, shared: &Arc<Mutex<State>>, tx_exit: &mpsc::Sender<ShutdownReason>
+    ) -> (ClientCommonState, Ser, De, TestSer, TestDe) {
+        let handle = core.handle();
+        let (ours, theirs) = UnixStream::pair(&handle).unwrap();
+
+        unsafe {
+            let size: libc::c_int = 4096;
+            libc::setsockopt(theirs.as_raw_fd(), libc::SOL_SOCKET, libc::SO_SNDBUF,
+                             (&size as *const libc::c_int) as _,
+                             mem::size_of::<libc::c_int>() as libc::socklen_t);
+        }
+
+        let common = ClientCommonState {
+            handle: handle.clone(),
+            shared: shared.clone(),
+            session_id: 1,
+            _addr: theirs.peer_addr().unwrap(),
+            tx_exit: tx_exit.clone(),
+            exit_on_close: false,
+            keepalive: None,
+            clock: shared.lock().unwrap().clock.clone(),
+            read_only: false,
+        };
+
+        let (read, write) = theirs.split();
+        let (our_read, our_write) = ours.split();
+        (common, WriteBincode::new(FramedWrite::new(write)), ReadBincode::new(FramedRead::new(read)),
+         WriteBincode::new(FramedWrite::new(our_write)), ReadBincode::new(FramedRead::new(our_read)))
+    }
+
+    /// Read replies to an open until the login finishes, returning the
+    /// first one.
+    fn finish_login(core: &mut Core, de: TestDe) -> (ServerMessage, TestDe) {
+        let (first, mut de) = next_reply(core, de);
+
+        loop {
+            let (reply, rest) = next_reply(core, de);
+            de = rest;
+
+            match reply {
+                ServerMessage::SshData(_) => {},
+                ServerMessage::Ok => return (first, de),
+                other => panic!("unexpected message during the login: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn interactive_opens_do_not_block_each_other() {
+        // A stand-in for SSH that asks for a password and then pretends to
+        // log in, by running the command that it's given.
+
+        let dir = fake_ssh_dir("prompt", "#!/bin/sh\nfor a; do last=\"$a\"; done\nprintf 'Password: '\n\
+                                           read pw\nexec sh -c \"$last\"\n");
+
+        let mut core = Core::new().unwrap();
+        let shared = Arc::new(Mutex::new(test_state(&dir)));
+        let (tx_exit, _rx_exit) = mpsc::channel(8);
+        let path = format!("{}:/bin:/usr/bin", dir.display());
+
+        let mut clients = Vec::new();
+
+        for host in &["h1", "h2"] {
+            let (ser, de) = connect_test_client(&core, &shared, &tx_exit);
+            let mut p = params(host);
+            p.env.push(("PATH".to_owned(), path.clone()));
+            let ser = core.run(ser.send(ClientMessage::Open(p))).unwrap();
+            clients.push((ser, de));
+        }
+
+        // Both logins should get as far as the password prompt without
+        // either of them being answered.
+
+        let mut waiting = Vec::new();
+
+        for (ser, de) in clients {
+            let (reply, mut de) = next_reply(&mut core, de);
+            assert_eq!(reply, ServerMessage::Ok);
+            let mut output = Vec::new();
+
+            while !output.ends_with(b"Password: ") {
+                let (reply, rest) = next_reply(&mut core, de);
+                de = rest;
+
+                match reply {
+                    ServerMessage::SshData(data) => output.extend_from_slice(&data),
+                    other => panic!("unexpected message before the prompt: {:?}", other),
+                }
+            }
+
+            waiting.push((ser, de));
+        }
+
+        // Now both can finish logging in.
+
+        for (ser, de) in waiting {
+            core.run(ser.send(ClientMessage::UserData(b"hunter2\n".to_vec()))).unwrap();
+            let mut de = de;
+
+            loop {
+                let (reply, rest) = next_reply(&mut core, de);
+                de = rest;
+
+                match reply {
+                    ServerMessage::SshData(_) => {},
+                    ServerMessage::Ok => break,
+                    other => panic!("unexpected message during the login: {:?}", other),
+                }
+            }
+        }
+
+        let n_open = shared.lock().unwrap().children.values()
+            .filter(|t| matches!(t, TunnelState::Running { .. }))
+            .count();
+        assert_eq!(n_open, 2);
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn logins_follow_the_window_size() {
+        // A stand-in for SSH that reports its terminal's size once it has
+        // been given a password.
+
+        let dir = fake_ssh_dir("winsize", "#!/bin/sh\nfor a; do last=\"$a\"; done\nprintf 'Password: '\n\
+                                            read pw\necho \"size: $(stty size)\"\nexec sh -c \"$last\"\n");
+
+        let mut core = Core::new().unwrap();
+        let shared = Arc::new(Mutex::new(test_state(&dir)));
+        let (tx_exit, _rx_exit) = mpsc::channel(8);
+        let (ser, de) = connect_test_client(&core, &shared, &tx_exit);
+
+        let mut p = params("h1");
+        p.env.push(("PATH".to_owned(), format!("{}:/bin:/usr/bin", dir.display())));
+        let ser = core.run(ser.send(ClientMessage::Open(p))).unwrap();
+
+        let (reply, mut de) = next_reply(&mut core, de);
+        assert_eq!(reply, ServerMessage::Ok);
+        let mut output = Vec::new();
+
+        while !output.ends_with(b"Password: ") {
+            let (reply, rest) = next_reply(&mut core, de);
+            de = rest;
+
+            match reply {
+                ServerMessage::SshData(data) => output.extend_from_slice(&data),
+                other => panic!("unexpected message before the prompt: {:?}", other),
+            }
+        }
+
+        let ser = core.run(ser.send(ClientMessage::WindowSize { rows: 40, cols: 100 })).unwrap();
+        core.run(ser.send(ClientMessage::UserData(b"hunter2\n".to_vec()))).unwrap();
+
+        loop {
+            let (reply, rest) = next_reply(&mut core, de);
+            de = rest;
+
+            match reply {
+                ServerMessage::SshData(data) => output.extend_from_slice(&data),
+                ServerMessage::Ok => break,
+                other => panic!("unexpected message during the login: {:?}", other),
+            }
+        }
+
+        let text = String::from_utf8_lossy(&output);
+        assert!(text.contains("size: 40 100"), "unexpected output: {:?}", text);
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn sessions_are_named_in_logs_and_errors() {
+        let mut core = Core::new().unwrap();
+        let mut sh = test_state(&env::temp_dir());
+        sh.recent_log = Some(VecDeque::new());
+        let shared = Arc::new(Mutex::new(sh));
+        let (tx_exit, _rx_exit) = mpsc::channel(8);
+
+        let (_ser1, _de1) = connect_test_client(&core, &shared, &tx_exit);
+        let (ser2, de2) = connect_test_client(&core, &shared, &tx_exit);
+
+        let _ser2 = core.run(ser2.send(ClientMessage::Pause { host: "nowhere".to_owned() })).unwrap();
+        let (reply, _de2) = next_reply(&mut core, de2);
+
+        match reply {
+            ServerMessage::ErrorCode(ProtocolError::NotFound, ref msg) if msg.ends_with(" (session 2)") => {},
+            other => panic!("unexpected reply to pausing a missing tunnel: {:?}", other),
+        }
+
+        let sh = shared.lock().unwrap();
+        let recent = sh.recent_log.as_ref().unwrap();
+        assert!(recent.iter().any(|l| l.contains("[session 2] no running tunnel labeled \"nowhere\"")),
+                "unexpected log: {:?}", recent);
+    }
+
+    #[test]
+    fn exit_requests_are_reported_as_the_reason() {
+        let mut core = Core::new().unwrap();
+        let shared = Arc::new(Mutex::new(test_state(&env::temp_dir())));
+        let (tx_exit, rx_exit) = mpsc::channel(8);
+
+        let (ser, de) = connect_test_client(&core, &shared, &tx_exit);
+        let ser = core.run(ser.send(ClientMessage::Exit)).unwrap();
+        let (reply, de) = next_reply(&mut core, de);
+        assert_eq!(reply, ServerMessage::Ok);
+        drop((ser, de));
+
+        let timeout = Timeout::new(Duration::from_secs(30), &core.handle()).unwrap();
+
+        match core.run(rx_exit.into_future().select2(timeout)) {
+            Ok(Either::A(((Some(reason), _), _))) => {
+                assert!(matches!(reason, ShutdownReason::ClientRequested), "{:?}", reason);
+                assert_eq!(reason.exit_code(), 0);
+            },
+            _ => panic!("the daemon wasn't told to exit"),
+        }
+
+        assert_eq!(ShutdownReason::Signal(libc::SIGTERM).exit_code(), 128 + libc::SIGTERM);
+    }
+
+    #[test]
+    fn state_dumps_leave_out_secrets() {
+        let mut sh = test_state(&env::temp_dir());
+        let (tx_kill, _rx_kill) = oneshot::channel();
+        let mut p = params("example.com");
+        p.user = Some("alice".to_owned());
+        p.env.push(("TOKEN".to_owned(), "hunter2".to_owned()));
+
+        sh.children.insert("b".to_owned(), TunnelState::Running {
+            id: 7,
+            pid: 1234,
+            params: p,
+            pty: fs::File::open("/dev/null").unwrap(),
+            tx_kill,
+            relay_stats: Arc::new(Mutex::new(RelayStats::default())),
+            control_path: None,
+        });
+        sh.children.insert("a".to_owned(), TunnelState::Exited { status: None });
+
+        let dump = dump_state(&sh);
+        let labels: Vec<_> = dump.tunnels.iter().map(|t| t.label.as_str()).collect();
+        assert_eq!(labels, ["a", "b"]);
+        assert_eq!(dump.tunnels[0].state, stund_protocol::TunnelState::Closed);
+        assert_eq!(dump.tunnels[1].pid, Some(1234));
+        assert_eq!(dump.tunnels[1].env_names, ["TOKEN"]);
+
+        let text = format!("{:?}", dump);
+        assert!(!text.contains("hunter2"));
+        assert!(!text.contains("alice"));
+    }
+
+    #[test]
+    fn child_monitor_finishes_if_nobody_hears_of_the_death() {
+        let mut core = Core::new().unwrap();
+        let shared = Arc::new(Mutex::new(test_state(&env::temp_dir())));
+        let ptymaster = AsyncPtyMaster::open().unwrap();
+        let child = process::Command::new("true").spawn_pty_async(&ptymaster).unwrap();
+        let pid = child.id();
+        let (_tx_kill, rx_kill) = oneshot::channel();
+        let (tx_die, rx_die) = mpsc::channel(DIE_CHANNEL_CAPACITY);
+        drop(rx_die);
+
+        let monitor = ChildMonitor::start(shared, 1, child, rx_kill, tx_die, None, core.handle());
+        let timeout = Timeout::new(Duration::from_secs(30), &core.handle()).unwrap();
+
+        match core.run(monitor.select2(timeout)) {
+            Ok(Either::A(_)) => {},
+            Ok(Either::B(_)) => panic!("the child monitor didn't finish within 30 seconds"),
+            Err(_) => panic!("the child monitor failed"),
+        }
+
+        // If the monitor reaped the child, there's nothing left to wait for.
+        let r = unsafe { libc::waitpid(pid as libc::pid_t, ptr::null_mut(), libc::WNOHANG) };
+        assert_eq!(r, -1);
+        assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::ECHILD));
+    }
+
+    #[test]
+    fn tunnel_details_cover_one_tunnel() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let mut sh = test_state(&env::temp_dir());
+        let (tx_kill, _rx_kill) = oneshot::channel();
+        let relay_stats = Arc::new(Mutex::new(RelayStats::default()));
+        relay_stats.lock().unwrap().bytes_from_ssh = 100;
+
+        sh.record_event("a", TunnelEventKind::Opened, None);
+        sh.children.insert("a".to_owned(), TunnelState::Running {
+            id: 1,
+            pid: 1234,
+            params: params("a"),
+            pty: fs::File::open("/dev/null").unwrap(),
+            tx_kill,
+            relay_stats,
+            control_path: None,
+        });
+
+        sh.record_event("b", TunnelEventKind::Opened, None);
+        sh.record_event("b", TunnelEventKind::NetworkError, Some(255));
+        sh.children.insert("b".to_owned(), TunnelState::Exited {
+            status: Some(ExitStatus::from_raw(255 << 8)),
+        });
+
+        let a = tunnel_details(&sh, "a").unwrap();
+        assert_eq!(a.state, stund_protocol::TunnelState::Open);
+        assert_eq!(a.pid, Some(1234));
+        assert!(a.age_secs.unwrap() < 60);
+        assert_eq!((a.bytes_from_ssh, a.bytes_to_ssh), (Some(100), Some(0)));
+        assert_eq!(a.last_error, None);
+
+        let b = tunnel_details(&sh, "b").unwrap();
+        assert_eq!(b.state, stund_protocol::TunnelState::Died);
+        assert_eq!((b.pid, b.age_secs, b.bytes_from_ssh), (None, None, None));
+        assert_eq!(b.history.len(), 2);
+        assert_eq!(b.last_error, Some("exit status: 255".to_owned()));
+
+        assert!(tunnel_details(&sh, "c").is_none());
+    }
+
+    #[test]
+    fn connect_timeouts_are_reported_as_unreachable_hosts() {
+        let dir = fake_ssh_dir("timeout", "#!/bin/sh\n\
+            echo 'ssh: connect to host example.com port 22: Connection timed out' >&2\n\
+            exit 255\n");
+        let mut core = Core::new().unwrap();
+        let shared = Arc::new(Mutex::new(test_state(&dir)));
+        let (tx_exit, _rx_exit) = mpsc::channel(8);
+
+        let mut p = params("example.com");
+        p.connect_timeout_secs = Some(5);
+        p.env.push(("PATH".to_owned(), format!("{}:/bin:/usr/bin", dir.display())));
+
+        let (ser, de) = connect_test_client(&core, &shared, &tx_exit);
+        let _ser = core.run(ser.send(ClientMessage::Open(p))).unwrap();
+        let (first, mut de) = next_reply(&mut core, de);
+        assert_eq!(first, ServerMessage::Ok);
+
+        loop {
+            let (reply, rest) = next_reply(&mut core, de);
+            de = rest;
+
+            match reply {
+                ServerMessage::SshData(_) => {},
+                ServerMessage::HostUnreachable => break,
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn stale_sockets_are_cleaned_up_at_startup() {
+        use std::os::unix::net::UnixListener as StdUnixListener;
+
+        let dir = env::temp_dir().join(format!("stund-test-stale-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let sock = dir.join("stund.sock");
+
+        // A daemon that dies without cleaning up leaves its socket behind,
+        // with nobody listening on it.
+        drop(StdUnixListener::bind(&sock).unwrap());
+        assert!(sock.exists());
+
+        let opts = StundDaemonOptions::from_iter(&["stund-daemon", "--foreground", "--log", "file",
+                                                   "--socket", sock.to_str().unwrap()]);
+        let sh = State::new(opts).unwrap();
+        assert_eq!(sh.sock_path, sock);
+        assert!(!sock.exists());
+
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn config_reports_settings_as_given() {
+        let dir = env::temp_dir();
+        let mut sh = test_state(&dir);
+        sh.reap_after_idle = Some(Duration::from_secs(3600));
+        sh.socket_mode = 0o660;
+
+        let config = daemon_config(&sh);
+        assert_eq!(config.socket_path, dir.join("stund.sock").display().to_string());
+        assert_eq!(config.socket_mode, "0660");
+        assert_eq!(config.log_sink, "stdout");
+        assert_eq!(config.keepalive_secs, None);
+        assert_eq!(config.reap_after_idle_secs, Some(3600));
+        assert!(config.foreground);
+    }
+
+    #[test]
+    fn capabilities_follow_settings() {
+        let mut sh = test_state(&env::temp_dir());
+        let names = daemon_capabilities(&sh);
+        assert!(names.iter().any(|n| n == capabilities::METRICS));
+        assert!(!names.iter().any(|n| n == capabilities::KEEPALIVE));
+        assert!(!names.iter().any(|n| n == capabilities::STATE_DUMPS));
+
+        sh.keepalive = Some(Duration::from_secs(30));
+        sh.allow_state_dumps = true;
+        let names = daemon_capabilities(&sh);
+        assert!(names.iter().any(|n| n == capabilities::KEEPALIVE));
+        assert!(names.iter().any(|n| n == capabilities::STATE_DUMPS));
+    }
+
+    #[test]
+    fn metrics_total_up_tunnel_events() {
+        let mut sh = test_state(&env::temp_dir());
+        sh.record_event("b", TunnelEventKind::Opened, None);
+        sh.record_event("b", TunnelEventKind::Replaced, None);
+        sh.record_event("b", TunnelEventKind::Replaced, None);
+        sh.record_event("a\"1", TunnelEventKind::Crashed, Some(1));
+
+        for _ in 0..MAX_TUNNEL_HISTORY {
+            sh.record_event("b", TunnelEventKind::Opened, None);
+        }
+
+        let text = render_metrics(&sh);
+        let lines: Vec<_> = text.lines()
+            .filter(|l| l.starts_with("stund_tunnel_events_total"))
+            .collect();
+
+        assert_eq!(lines, vec![
+            "stund_tunnel_events_total{label=\"a\\\"1\",event=\"crashed\"} 1",
+            "stund_tunnel_events_total{label=\"b\",event=\"opened\"} 21",
+            "stund_tunnel_events_total{label=\"b\",event=\"replaced\"} 2",
+        ]);
+
+        assert!(text.contains("# TYPE stund_uptime_seconds gauge\nstund_uptime_seconds "));
+        assert!(text.contains("\nstund_tunnels 0\n"));
+    }
+
+    #[test]
+    fn accepts_are_limited_per_window() {
+        let start = Instant::now();
+        let mut limiter = AcceptLimiter::new(3, start);
+
+        assert_eq!(limiter.note_accept(start), None);
+        assert_eq!(limiter.note_accept(start + Duration::from_millis(100)), None);
+        assert_eq!(limiter.note_accept(start + Duration::from_millis(400)),
+                   Some(Duration::from_millis(600)));
+
+        // Once the window is over, the count starts again.
+        let later = start + ACCEPT_WINDOW;
+        assert_eq!(limiter.note_accept(later), None);
+        assert_eq!(limiter.note_accept(later), None);
+        assert_eq!(limiter.note_accept(later), Some(ACCEPT_WINDOW));
+
+        let mut unlimited = AcceptLimiter::new(0, start);
+
+        for _ in 0..1000 {
+            assert_eq!(unlimited.note_accept(start), None);
+        }
+    }
+
+    #[test]
+    fn idle_tunnels_honor_their_own_timeouts() {
+        let mut sh = test_state(&env::temp_dir());
+        sh.reap_after_idle = Some(Duration::from_secs(60));
+        let start = Instant::now();
+
+        let tunnels = vec![
+            ("default", None, true),
+            ("longer", Some(120), true),
+            ("never", Some(0), true),
+            ("shorter", Some(10), true),
+            ("logging-in", Some(10), false),
+        ];
+
+        for (i, (label, secs, handed_off)) in tunnels.into_iter().enumerate() {
+            let mut p = params(label);
+            p.reap_after_idle_secs = secs;
+            let (tx_kill, _rx_kill) = oneshot::channel();
+            let relay_stats = Arc::new(Mutex::new(RelayStats::default()));
+
+            if handed_off {
+                relay_stats.lock().unwrap().handed_off = Some(start);
+            }
+
+            sh.children.insert(label.to_owned(), TunnelState::Running {
+                id: i as u64,
+                pid: 1234,
+                params: p,
+                pty: fs::File::open("/dev/null").unwrap(),
+                tx_kill,
+                relay_stats,
+                control_path: None,
+            });
+        }
+
+        assert!(idle_tunnels(&sh, start + Duration::from_secs(5)).is_empty());
+        assert_eq!(idle_tunnels(&sh, start + Duration::from_secs(30)), vec!["shorter"]);
+        assert_eq!(idle_tunnels(&sh, start + Duration::from_secs(90)), vec!["default", "shorter"]);
+
+        // Activity puts off the reaping.
+
+        if let Some(TunnelState::Running { relay_stats, .. }) = sh.children.get("shorter") {
+            relay_stats.lock().unwrap().last_ssh_activity = Some(start + Duration::from_secs(25));
+        }
+
+        assert!(idle_tunnels(&sh, start + Duration::from_secs(30)).is_empty());
+        assert_eq!(idle_tunnels(&sh, start + Duration::from_secs(600)), vec!["default", "longer", "shorter"]);
+
+        sh.reap_after_idle = None;
+        assert_eq!(idle_tunnels(&sh, start + Duration::from_secs(600)), vec!["longer", "shorter"]);
+    }
+
+    #[test]
+    fn profiles_are_looked_up_by_name() {
+        let dir = env::temp_dir().join(format!("stund-test-profiles-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = profiles_path(&dir.join("stund.sock"));
+
+        assert_eq!(load_profile(&path, "db").unwrap(), None);
+
+        fs::write(&path, br#"{"db": {"host": "db.example.com", "port": 2222, "connect_retries": 2}}"#).unwrap();
+        let p = load_profile(&path, "db").unwrap().unwrap();
+        assert_eq!(p.host, "db.example.com");
+        assert_eq!(p.port, Some(2222));
+        assert_eq!(p.connect_retries, 2);
+        assert!(!p.force);
+        assert_eq!(load_profile(&path, "web").unwrap(), None);
+
+        fs::write(&path, b"{").unwrap();
+        assert!(load_profile(&path, "db").is_err());
+
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn cleanup_removes_only_stale_sockets() {
+        use std::os::unix::net::UnixListener as StdUnixListener;
+
+        let dir = env::temp_dir().join(format!("stund-test-cleanup-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut sh = test_state(&dir);
+
+        let stale = dir.join("stale.ctl");
+        let served = dir.join("served.ctl");
+        let ours = dir.join("ours.ctl");
+        let gone = dir.join("gone.ctl");
+        fs::write(&stale, b"").unwrap();
+        let _listener = StdUnixListener::bind(&served).unwrap();
+
+        let (tx_kill, _rx_kill) = oneshot::channel();
+        sh.children.insert("a".to_owned(), TunnelState::Running {
+            id: 1,
+            pid: 1234,
+            params: params("a"),
+            pty: fs::File::open("/dev/null").unwrap(),
+            tx_kill,
+            relay_stats: Arc::new(Mutex::new(RelayStats::default())),
+            control_path: Some(ours.clone()),
+        });
+
+        for p in &[&stale, &served, &ours, &gone] {
+            sh.own_socket(p);
+        }
+
+        assert_eq!(clean_up_sockets(&mut sh), vec![stale.clone()]);
+        assert!(!stale.exists());
+        assert!(served.exists());
+
+        let expected: BTreeSet<_> = vec![served.clone(), ours.clone()].into_iter().collect();
+        assert_eq!(sh.owned_sockets, expected);
+        assert_eq!(load_owned_sockets(&socket_record_path(&sh.sock_path)).unwrap(), expected);
+
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn read_only_sessions_can_only_ask_questions() {
+        let dir = env::temp_dir().join(format!("stund-test-read-only-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut core = Core::new().unwrap();
+        let shared = Arc::new(Mutex::new(test_state(&dir)));
+        let (tx_exit, _rx_exit) = mpsc::channel(8);
+
+        let (ser, de) = connect_test_client(&core, &shared, &tx_exit);
+        let ser = core.run(ser.send(ClientMessage::SetReadOnly)).unwrap();
+        let (reply, de) = next_reply(&mut core, de);
+        assert_eq!(reply, ServerMessage::Ok);
+
+        let ser = core.run(ser.send(ClientMessage::Exit)).unwrap();
+        let (reply, de) = next_reply(&mut core, de);
+
+        match reply {
+            ServerMessage::ErrorCode(ProtocolError::Forbidden, _) => {},
+            other => panic!("unexpected reply to a forbidden message: {:?}", other),
+        }
+
+        assert!(!shared.lock().unwrap().shutting_down);
+
+        let _ser = core.run(ser.send(ClientMessage::QueryStatus)).unwrap();
+        let (reply, _de) = next_reply(&mut core, de);
+
+        match reply {
+            ServerMessage::StatusResponse(_) => {},
+            other => panic!("unexpected reply to a query: {:?}", other),
+        }
+
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn status_queries_can_be_filtered_by_state() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let dir = env::temp_dir().join(format!("stund-test-filtered-status-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut core = Core::new().unwrap();
+        let shared = Arc::new(Mutex::new(test_state(&dir)));
+        let (tx_exit, _rx_exit) = mpsc::channel(8);
+
+        {
+            let mut sh = shared.lock().unwrap();
+            sh.children.insert("closed".to_owned(), TunnelState::Exited { status: None });
+            sh.children.insert("died".to_owned(),
+                               TunnelState::Exited { status: Some(ExitStatus::from_raw(255 << 8)) });
+        }
+
+        let (ser, de) = connect_test_client(&core, &shared, &tx_exit);
+        let filter = StatusFilter { states: vec![stund_protocol::TunnelState::Died] };
+        let ser = core.run(ser.send(ClientMessage::QueryStatusFiltered(filter))).unwrap();
+        let (reply, de) = next_reply(&mut core, de);
+
+        match reply {
+            ServerMessage::StatusResponse(info) => {
+                let hosts: Vec<_> = info.tunnels.iter().map(|t| t.host.as_str()).collect();
+                assert_eq!(hosts, ["died"]);
+            },
+            other => panic!("unexpected reply to a filtered query: {:?}", other),
+        }
+
+        let _ser = core.run(ser.send(ClientMessage::QueryStatusFiltered(StatusFilter::default()))).unwrap();
+        let (reply, _de) = next_reply(&mut core, de);
+
+        match reply {
+            ServerMessage::StatusResponse(info) => assert_eq!(info.tunnels.len(), 2),
+            other => panic!("unexpected reply to an unfiltered query: {:?}", other),
+        }
+
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn opens_can_report_the_ssh_pid() {
+        let dir = fake_ssh_dir("report-pid", "#!/bin/sh\nfor a; do last=\"$a\"; done\n\
+                                               sh -c \"${last%% && exec*}\"\nexec sleep 30\n");
+        let mut core = Core::new().unwrap();
+        let shared = Arc::new(Mutex::new(test_state(&dir)));
+        let (tx_exit, _rx_exit) = mpsc::channel(8);
+
+        let mut p = params("example.com");
+        p.env.push(("PATH".to_owned(), format!("{}:/bin:/usr/bin", dir.display())));
+        p.report_pid = true;
+
+        let (ser, de) = connect_test_client(&core, &shared, &tx_exit);
+        let _ser = core.run(ser.send(ClientMessage::Open(p))).unwrap();
+        let (first, mut de) = next_reply(&mut core, de);
+        assert_eq!(first, ServerMessage::Ok);
+
+        let pid = loop {
+            let (reply, rest) = next_reply(&mut core, de);
+            de = rest;
+
+            match reply {
+                ServerMessage::SshData(_) => {},
+                ServerMessage::TunnelPid(pid) => break pid,
+                other => panic!("unexpected message during the login: {:?}", other),
+            }
+        };
+
+        let (last, _de) = next_reply(&mut core, de);
+        assert_eq!(last, ServerMessage::Ok);
+
+        match shared.lock().unwrap().children.get("example.com") {
+            Some(TunnelState::Running { pid: running, .. }) => assert_eq!(*running, pid),
+            _ => panic!("the tunnel should be running"),
+        }
+
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn closes_with_a_grace_period_say_how_ssh_went() {
+        // One stand-in for SSH exits when asked to, and the other ignores
+        // SIGTERM, so that it has to be killed.
+
+        let polite = fake_ssh_dir("grace-polite", "#!/bin/sh\nfor a; do last=\"$a\"; done\n\
+                                                   sh -c \"${last%% && exec*}\"\nexec sleep 30\n");
+        let stubborn = fake_ssh_dir("grace-stubborn", "#!/bin/sh\nfor a; do last=\"$a\"; done\n\
+                                                       sh -c \"${last%% && exec*}\"\n\
+                                                       trap '' TERM\nexec sleep 30\n");
+        let mut core = Core::new().unwrap();
+        let shared = Arc::new(Mutex::new(test_state(&polite)));
+        let (tx_exit, _rx_exit) = mpsc::channel(8);
+
+        for &(host, dir, grace_secs, graceful) in &[("polite", &polite, 30, true),
+                                                   ("stubborn", &stubborn, 1, false)] {
+            let mut p = params(host);
+            p.env.push(("PATH".to_owned(), format!("{}:/bin:/usr/bin", dir.display())));
+
+            let (ser, de) = connect_test_client(&core, &shared, &tx_exit);
+            let ser = core.run(ser.send(ClientMessage::Open(p))).unwrap();
+            let (first, de) = finish_login(&mut core, de);
+            assert_eq!(first, ServerMessage::Ok);
+
+            let close = ClientMessage::Close(CloseParameters {
+                host: host.to_owned(),
+                grace_secs: Some(grace_secs),
+            });
+            let _ser = core.run(ser.send(close)).unwrap();
+            let (reply, _de) = next_reply(&mut core, de);
+            assert_eq!(reply, ServerMessage::TunnelClosed { graceful });
+
+            match shared.lock().unwrap().children.get(host) {
+                Some(TunnelState::Exited { status: None }) => {},
+                _ => panic!("the tunnel to {} should be closed", host),
+            }
+        }
+
+        let _r = fs::remove_dir_all(&polite);
+        let _r = fs::remove_dir_all(&stubborn);
+    }
+
+    #[test]
+    fn aborts_reach_the_client_even_behind_a_pending_send() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let dir = env::temp_dir().join(format!("stund-test-abort-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut core = Core::new().unwrap();
+        let shared = Arc::new(Mutex::new(test_state(&dir)));
+        let (tx_exit, _rx_exit) = mpsc::channel(8);
+
+        // With nothing else on its way, the error goes straight out, and
+        // then the daemon hangs up.
+
+        let (common, ser, de, _our_ser, our_de) = test_session(&core, &shared, &tx_exit);
+        let aborting = abort_client(common, ser, de, ProtocolError::InvalidParameters, "bad".to_owned());
+        core.handle().spawn(ClientFuture(Some(ClientStates::Aborting(aborting))).then(|_| Ok(())));
+
+        let (reply, our_de) = next_reply(&mut core, our_de);
+        assert_eq!(reply, ServerMessage::ErrorCode(ProtocolError::InvalidParameters,
+                                                   "bad (session 1)".to_owned()));
+        assert!(core.run(our_de.into_future()).ok().unwrap().0.is_none());
+
+        // SSH's last words are too much for the socket to take at once, so
+        // the error has to wait for the client to read them.
+
+        let (common, ser, de, _our_ser, our_de) = test_session(&core, &shared, &tx_exit);
+        let output = vec![b'x'; MAX_TRAILING_OUTPUT];
+        let flushing = match abort_client_after_output(common, ser, de, output.clone(),
+                                                       b"Permission denied".to_vec(),
+                                                       ProtocolError::AuthFailed, "no".to_owned()) {
+            AfterCommunicatingForOpen::FlushingBeforeAbort(s) => s,
+            _ => panic!("the trailing output should have been sent first"),
+        };
+
+        let finished = Rc::new(Cell::new(false));
+        let finished2 = finished.clone();
+        core.handle().spawn(ClientFuture(Some(ClientStates::FlushingBeforeAbort(flushing))).then(move |_| {
+            finished2.set(true);
+            Ok(())
+        }));
+
+        for _ in 0..10 {
+            core.turn(Some(Duration::from_millis(10)));
+        }
+
+        assert!(!finished.get());
+
+        let (reply, our_de) = next_reply(&mut core, our_de);
+        assert_eq!(reply, ServerMessage::SshData(output));
+        let (reply, our_de) = next_reply(&mut core, our_de);
+        assert_eq!(reply, ServerMessage::SshStderr(b"Permission denied".to_vec()));
+        let (reply, our_de) = next_reply(&mut core, our_de);
+        assert_eq!(reply, ServerMessage::ErrorCode(ProtocolError::AuthFailed, "no (session 1)".to_owned()));
+        assert!(core.run(our_de.into_future()).ok().unwrap().0.is_none());
+        assert!(finished.get());
+
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn paused_tunnels_keep_their_output_until_resumed() {
+        // After logging in, SSH prints something once we tell it to.
+
+        let dir = env::temp_dir().join(format!("stund-test-pause-{}", process::id()));
+        let go = dir.join("go");
+        let dir = fake_ssh_dir("pause", &format!("#!/bin/sh\nfor a; do last=\"$a\"; done\n\
+                                                  sh -c \"${{last%% && exec*}}\"\n\
+                                                  while [ ! -e {} ]; do sleep 0.05; done\n\
+                                                  echo later\nexec sleep 30\n", go.display()));
+        let mut core = Core::new().unwrap();
+        let shared = Arc::new(Mutex::new(test_state(&dir)));
+        let (tx_exit, _rx_exit) = mpsc::channel(8);
+
+        let mut p = params("example.com");
+        p.env.push(("PATH".to_owned(), format!("{}:/bin:/usr/bin", dir.display())));
+
+        let (ser, de) = connect_test_client(&core, &shared, &tx_exit);
+        let ser = core.run(ser.send(ClientMessage::Open(p))).unwrap();
+        let (first, de) = finish_login(&mut core, de);
+        assert_eq!(first, ServerMessage::Ok);
+
+        let relay_stats = match shared.lock().unwrap().children.get("example.com") {
+            Some(TunnelState::Running { relay_stats, .. }) => relay_stats.clone(),
+            _ => panic!("the tunnel should be running"),
+        };
+
+        let ser = core.run(ser.send(ClientMessage::Pause { host: "example.com".to_owned() })).unwrap();
+        let (reply, de) = next_reply(&mut core, de);
+        assert_eq!(reply, ServerMessage::Ok);
+
+        let n_before = relay_stats.lock().unwrap().bytes_from_ssh;
+        fs::write(&go, b"").unwrap();
+
+        for _ in 0..10 {
+            core.turn(Some(Duration::from_millis(50)));
+        }
+
+        assert_eq!(relay_stats.lock().unwrap().bytes_from_ssh, n_before);
+
+        let _ser = core.run(ser.send(ClientMessage::Resume { host: "example.com".to_owned() })).unwrap();
+        let (reply, _de) = next_reply(&mut core, de);
+        assert_eq!(reply, ServerMessage::Ok);
+
+        for _ in 0..600 {
+            if relay_stats.lock().unwrap().bytes_from_ssh > n_before {
+                break;
+            }
+
+            core.turn(Some(Duration::from_millis(50)));
+        }
+
+        assert!(relay_stats.lock().unwrap().bytes_from_ssh > n_before);
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn signals_are_typed_into_the_terminal() {
+        // Like the real SSH, the fake one puts its terminal in raw mode, so
+        // that control characters reach it rather than signalling it.
+
+        let dir = env::temp_dir().join(format!("stund-test-signal-{}", process::id()));
+        let got = dir.join("got");
+        let dir = fake_ssh_dir("signal", &format!("#!/bin/sh\nfor a; do last=\"$a\"; done\n\
+                                                   stty raw -echo\n\
+                                                   sh -c \"${{last%% && exec*}}\"\n\
+                                                   dd bs=1 count=1 2>/dev/null | od -An -tx1 > {}.tmp\n\
+                                                   mv {}.tmp {}\nexec sleep 30\n",
+                                                  got.display(), got.display(), got.display()));
+        let mut core = Core::new().unwrap();
+        let shared = Arc::new(Mutex::new(test_state(&dir)));
+        let (tx_exit, _rx_exit) = mpsc::channel(8);
+
+        let mut p = params("example.com");
+        p.env.push(("PATH".to_owned(), format!("{}:/bin:/usr/bin", dir.display())));
+
+        let (ser, de) = connect_test_client(&core, &shared, &tx_exit);
+        let ser = core.run(ser.send(ClientMessage::Open(p))).unwrap();
+        let (first, de) = finish_login(&mut core, de);
+        assert_eq!(first, ServerMessage::Ok);
+
+        let msg = ClientMessage::SignalTunnel { host: "example.com".to_owned(), signum: libc::SIGHUP };
+        let ser = core.run(ser.send(msg)).unwrap();
+        let (reply, de) = next_reply(&mut core, de);
+
+        match reply {
+            ServerMessage::ErrorCode(ProtocolError::InvalidParameters, _) => {},
+            other => panic!("unexpected reply to an unsupported signal: {:?}", other),
+        }
+
+        let msg = ClientMessage::SignalTunnel { host: "nowhere".to_owned(), signum: libc::SIGINT };
+        let ser = core.run(ser.send(msg)).unwrap();
+        let (reply, de) = next_reply(&mut core, de);
+
+        match reply {
+            ServerMessage::ErrorCode(ProtocolError::NotFound, _) => {},
+            other => panic!("unexpected reply to signalling a missing tunnel: {:?}", other),
+        }
+
+        let msg = ClientMessage::SignalTunnel { host: "example.com".to_owned(), signum: libc::SIGINT };
+        let _ser = core.run(ser.send(msg)).unwrap();
+        let (reply, _de) = next_reply(&mut core, de);
+        assert_eq!(reply, ServerMessage::Ok);
+
+        for _ in 0..600 {
+            if got.exists() {
+                break;
+            }
+
+            core.turn(Some(Duration::from_millis(50)));
+        }
+
+        assert_eq!(fs::read_to_string(&got).unwrap().trim(), "03");
+
+        match shared.lock().unwrap().children.get("example.com") {
+            Some(TunnelState::Running { .. }) => {},
+            _ => panic!("the tunnel should still be running"),
+        }
+
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn waiters_hear_how_tunnels_exit() {
+        let dir = env::temp_dir().join(format!("stund-test-wait-{}", process::id()));
+        let go = dir.join("go");
+        let dir = fake_ssh_dir("wait", &format!("#!/bin/sh\nfor a; do last=\"$a\"; done\n\
+                                                 sh -c \"${{last%% && exec*}}\"\n\
+                                                 while [ ! -e {} ]; do sleep 0.05; done\n\
+                                                 exit 3\n", go.display()));
+        let mut core = Core::new().unwrap();
+        let shared = Arc::new(Mutex::new(test_state(&dir)));
+        let (tx_exit, _rx_exit) = mpsc::channel(8);
+
+        let mut p = params("example.com");
+        p.env.push(("PATH".to_owned(), format!("{}:/bin:/usr/bin", dir.display())));
+
+        let (ser, de) = connect_test_client(&core, &shared, &tx_exit);
+        let ser = core.run(ser.send(ClientMessage::Open(p))).unwrap();
+        let (first, de) = finish_login(&mut core, de);
+        assert_eq!(first, ServerMessage::Ok);
+
+        let ser = core.run(ser.send(ClientMessage::WaitForExit { host: "nowhere".to_owned() })).unwrap();
+        let (reply, de) = next_reply(&mut core, de);
+
+        match reply {
+            ServerMessage::ErrorCode(ProtocolError::NotFound, _) => {},
+            other => panic!("unexpected reply to waiting for a missing tunnel: {:?}", other),
+        }
+
+        let _ser = core.run(ser.send(ClientMessage::WaitForExit { host: "example.com".to_owned() })).unwrap();
+
+        for _ in 0..600 {
+            if !shared.lock().unwrap().exit_waiters.is_empty() {
+                break;
+            }
+
+            core.turn(Some(Duration::from_millis(50)));
+        }
+
+        fs::write(&go, b"").unwrap();
+        let (reply, _de) = next_reply(&mut core, de);
+
+        match reply {
+            ServerMessage::TunnelExitResponse(exit) => assert_eq!(exit.code, Some(3)),
+            other => panic!("unexpected reply to waiting for a tunnel: {:?}", other),
+        }
+
+        assert!(shared.lock().unwrap().exit_waiters.is_empty());
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn tunnel_output_can_be_copied_to_a_file() {
+        let dir = env::temp_dir().join(format!("stund-test-tee-{}", process::id()));
+        let go = dir.join("go");
+        let dir = fake_ssh_dir("tee", &format!("#!/bin/sh\nfor a; do last=\"$a\"; done\n\
+                                                sh -c \"${{last%% && exec*}}\"\n\
+                                                while [ ! -e {} ]; do sleep 0.05; done\n\
+                                                echo later\nexec sleep 30\n", go.display()));
+        let mut core = Core::new().unwrap();
+        let shared = Arc::new(Mutex::new(test_state(&dir)));
+        let (tx_exit, _rx_exit) = mpsc::channel(8);
+
+        let mut p = params("example.com");
+        p.env.push(("PATH".to_owned(), format!("{}:/bin:/usr/bin", dir.display())));
+
+        let (ser, de) = connect_test_client(&core, &shared, &tx_exit);
+        let ser = core.run(ser.send(ClientMessage::Open(p))).unwrap();
+        let (first, de) = finish_login(&mut core, de);
+        assert_eq!(first, ServerMessage::Ok);
+
+        let msg = ClientMessage::TeeToFile { host: "example.com".to_owned(), path: "output.txt".to_owned() };
+        let ser = core.run(ser.send(msg)).unwrap();
+        let (reply, de) = next_reply(&mut core, de);
+
+        match reply {
+            ServerMessage::ErrorCode(ProtocolError::InvalidParameters, _) => {},
+            other => panic!("unexpected reply to teeing to a relative path: {:?}", other),
+        }
+
+        let output = dir.join("output.txt");
+        let msg = ClientMessage::TeeToFile {
+            host: "example.com".to_owned(),
+            path: output.to_str().unwrap().to_owned(),
+        };
+        let _ser = core.run(ser.send(msg)).unwrap();
+        let (reply, _de) = next_reply(&mut core, de);
+        assert_eq!(reply, ServerMessage::Ok);
+
+        fs::write(&go, b"").unwrap();
+
+        for _ in 0..600 {
+            if fs::read(&output).unwrap().ends_with(b"later\n") {
+                break;
+            }
+
+            core.turn(Some(Duration::from_millis(50)));
+        }
+
+        assert!(fs::read(&output).unwrap().ends_with(b"later\n"));
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    /// Open a tunnel through the fake SSH in `dir`, then hang up, without
+    /// reading any of the login, once `ready` says that the daemon has
+    /// gotten far enough. Returns the fate of the tunnel once the daemon has
+    /// dealt with the hangup: `None` if it's still running.
+    fn abandon_open<F>(dir: &Path, close_if_abandoned: bool, ready: F) -> Option<Option<ExitStatus>>
+        where F: Fn(&RelayStats) -> bool
+    {
+        let mut core = Core::new().unwrap();
+        let shared = Arc::new(Mutex::new(test_state(dir)));
+        let (tx_exit, _rx_exit) = mpsc::channel(8);
+
+        let mut p = params("example.com");
+        p.close_if_abandoned = close_if_abandoned;
+        p.env.push(("PATH".to_owned(), format!("{}:/bin:/usr/bin", dir.display())));
+
+        let (ser, de) = connect_test_client(&core, &shared, &tx_exit);
+        let ser = core.run(ser.send(ClientMessage::Open(p))).unwrap();
+        let (first, de) = next_reply(&mut core, de);
+        assert_eq!(first, ServerMessage::Ok);
+
+        let relay_stats = match shared.lock().unwrap().children.get("example.com") {
+            Some(TunnelState::Running { relay_stats, .. }) => relay_stats.clone(),
+            _ => panic!("the tunnel should be running"),
+        };
+
+        for _ in 0..600 {
+            if ready(&relay_stats.lock().unwrap()) {
+                break;
+            }
+
+            core.turn(Some(Duration::from_millis(50)));
+        }
+
+        assert!(ready(&relay_stats.lock().unwrap()), "the login never got far enough");
+        drop((ser, de));
+
+        for _ in 0..600 {
+            if shared.lock().unwrap().opens_in_flight == 0 {
+                break;
+            }
+
+            core.turn(Some(Duration::from_millis(50)));
+        }
+
+        let sh = shared.lock().unwrap();
+        assert_eq!(sh.opens_in_flight, 0, "the daemon never noticed the hangup");
+
+        match sh.children.get("example.com") {
+            Some(TunnelState::Running { .. }) => None,
+            Some(TunnelState::Exited { status }) => Some(*status),
+            None => panic!("the tunnel should still be known"),
+        }
+    }
+
+    #[test]
+    fn abandoned_logins_are_killed() {
+        let dir = fake_ssh_dir("abandon-login", "#!/bin/sh\nprintf 'Password: '\nread pw\n");
+        let prompted = |stats: &RelayStats| stats.last_ssh_activity.is_some();
+        assert_eq!(abandon_open(&dir, false, prompted), Some(None));
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn abandoned_opens_keep_finished_logins_unless_asked() {
+        // SSH produces more output than the client will take and then logs
+        // in, so that the open is stuck flushing the output when the client
+        // hangs up. The login is done once the 50-byte key has arrived.
+
+        let dir = fake_ssh_dir("abandon-open", "#!/bin/sh\nfor a; do last=\"$a\"; done\n\
+                                                 head -c 1000000 /dev/zero | tr '\\0' x\n\
+                                                 sh -c \"${last%% && exec*}\"\n\
+                                                 exec sleep 30\n");
+        let stuck = |stats: &RelayStats| stats.bytes_from_ssh >= 1_000_050;
+
+        assert_eq!(abandon_open(&dir, false, stuck), None);
+        assert_eq!(abandon_open(&dir, true, stuck), Some(None));
+
+        let _r = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn many_tunnels_can_be_replaced_and_closed_at_once() {
+        // Every forced open has to wait for its old SSH process to be
+        // killed, so this exercises the kill handshake with lots of child
+        // monitors busy at the same time.
+
+        const N_TUNNELS: usize = 16;
+
+        let dir = fake_ssh_dir("many", "#!/bin/sh\nfor a; do last=\"$a\"; done\nexec sh -c \"$last\"\n");
+        let mut core = Core::new().unwrap();
+        let shared = Arc::new(Mutex::new(test_state(&dir)));
+        let (tx_exit, _rx_exit) = mpsc::channel(8);
+        let path = format!("{}:/bin:/usr/bin", dir.display());
+        let hosts: Vec<_> = (0..N_TUNNELS).map(|i| format!("h{}", i)).collect();
+
+        let open = |host: &str, force: bool| {
+            let mut p = params(host);
+            p.force = force;
+            p.env.push(("PATH".to_owned(), path.clone()));
+            ClientMessage::Open(p)
+        };
+
+        let mut clients = Vec::new();
+
+        for host in &hosts {
+            let (ser, de) = connect_test_client(&core, &shared, &tx_exit);
+            let ser = core.run(ser.send(open(host, false))).unwrap();
+            clients.push((ser, de));
+        }
+
+        clients = clients.into_iter().map(|(ser, de)| {
+            let (first, de) = finish_login(&mut core, de);
+            assert_eq!(first, ServerMessage::Ok);
+            (ser, de)
+        }).collect();
+
+        // Replace them all at once, then close them all at once.
+
+        let mut sent = Vec::new();
+
+        for ((ser, de), host) in clients.into_iter().zip(&hosts) {
+            sent.push((core.run(ser.send(open(host, true))).unwrap(), de));
+        }
+
+        clients = sent.into_iter().map(|(ser, de)| {
+            let (first, de) = finish_login(&mut core, de);
+            assert_eq!(first, ServerMessage::TunnelReplaced);
+            (ser, de)
+        }).collect();
+
+        let mut sent = Vec::new();
+
+        for ((ser, de), host) in clients.into_iter().zip(&hosts) {
+            let close = ClientMessage::Close(CloseParameters { host: host.clone(), grace_secs: None });
+            sent.push((core.run(ser.send(close)).unwrap(), de));
+        }
+
+        for (_ser, de) in sent {
+            assert_eq!(next_reply(&mut core, de).0, ServerMessage::Ok);
+        }
+
+        {
+            let sh = shared.lock().unwrap();
+            assert_eq!(sh.children.len(), N_TUNNELS);
+            assert!(sh.children.values().all(|t| matches!(t, TunnelState::Exited { status: None })));
+            assert_eq!(sh.opens_in_flight, 0);
+        }
+
+        let _r = fs::remove_dir_all(&dir);
     }
 }
@@ -3,17 +3,18 @@
 
 //! The main CLI driver logic.
 
-extern crate atty;
 extern crate base64;
 extern crate daemonize;
 #[macro_use] extern crate failure;
 #[macro_use] extern crate futures;
 extern crate libc;
 extern crate rand;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
 #[macro_use] extern crate state_machine_future;
 #[macro_use] extern crate structopt;
 extern crate stund_protocol;
-extern crate tokio_borrow_stdio;
 extern crate tokio_core;
 extern crate tokio_io;
 extern crate tokio_pty_process;
@@ -22,29 +23,97 @@ extern crate tokio_signal;
 extern crate tokio_uds;
 
 use failure::{Error, Fail};
+use futures::Stream;
+use futures::sync::oneshot;
+use std::cell::RefCell;
+use std::env;
 use std::io;
 use std::mem;
 use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
 use std::process;
+use std::rc::Rc;
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::Duration;
 use structopt::StructOpt;
 use stund_protocol::*;
-use stund_protocol::client::Connection;
+use stund_protocol::client::{self, Connection, ForegroundOutcome, DEFAULT_SPAWN_ATTEMPTS};
+use tokio_core::reactor::Core;
 
 mod daemon;
 
 
+#[derive(Debug, StructOpt)]
+pub struct StundCheckOptions {
+    #[structopt(help = "The host of the tunnel to check.")]
+    host: String,
+}
+
+impl StundCheckOptions {
+    fn cli(self) -> Result<i32, Error> {
+        let params = CheckTunnelParameters { host: self.host.clone() };
+
+        let mut conn = establish()?;
+        let health = conn.check_tunnel(params)?;
+        conn.close()?;
+
+        let health = match health {
+            Some(h) => h,
+
+            None => {
+                println!("[No tunnel for \"{}\" is known.]", self.host);
+                return Ok(1);
+            },
+        };
+
+        println!("SSH process:   {}", if health.ssh_alive { "running" } else { "dead" });
+        println!("SSH terminal:  {}", if health.pty_open { "open" } else { "closed" });
+
+        Ok(if health.is_healthy() { 0 } else { 1 })
+    }
+}
+
+
+#[derive(Debug, StructOpt)]
+pub struct StundCleanupOptions {
+}
+
+impl StundCleanupOptions {
+    fn cli(self) -> Result<i32, Error> {
+        let mut conn = establish()?;
+        let removed = conn.clean_up()?;
+        conn.close()?;
+
+        if removed.is_empty() {
+            println!("[No stale sockets were found.]");
+        }
+
+        for path in &removed {
+            println!("Removed {}", path);
+        }
+
+        Ok(0)
+    }
+}
+
+
 #[derive(Debug, StructOpt)]
 pub struct StundCloseOptions {
+    #[structopt(long = "grace", value_name = "seconds")]
+    /// Ask SSH to exit, and only kill it if it's still running after this many seconds
+    grace: Option<u64>,
+
     #[structopt(help = "The host for which the tunnel should be closed.")]
     host: String,
 }
 
 impl StundCloseOptions {
     fn cli(self) -> Result<i32, Error> {
-        let params = CloseParameters { host: self.host.clone() };
+        let params = CloseParameters { host: self.host.clone(), grace_secs: self.grace };
 
-        let conn = Connection::establish()?;
-        let (result, conn) = conn.send_close(params)?;
+        let mut conn = establish()?;
+        let result = conn.send_close(params)?;
 
         match result {
             CloseResult::Success => {},
@@ -52,6 +121,14 @@ impl StundCloseOptions {
             CloseResult::NotOpen => {
                 println!("[No tunnel for \"{}\" was open.]", self.host);
             },
+
+            CloseResult::Graceful => {
+                println!("[SSH exited on its own.]");
+            },
+
+            CloseResult::ForceKilled => {
+                println!("[SSH was still running after the grace period, so it was killed.]");
+            },
         }
 
         conn.close()?;
@@ -63,13 +140,163 @@ impl StundCloseOptions {
 #[derive(Debug, StructOpt)]
 pub struct StundDaemonOptions {
     #[structopt(long = "foreground")]
+    /// Do not detach from the terminal
     foreground: bool,
+
+    #[structopt(long = "log", value_name = "stdout|file|syslog")]
+    /// Where to send log messages (default: stdout if in the foreground, a file otherwise)
+    log_sink: Option<daemon::LogSink>,
+
+    #[structopt(long = "keepalive", value_name = "seconds", parse(try_from_str = "parse_interval"))]
+    /// While relaying a login, ping the client this often (at least 1) and drop it if it stops answering
+    keepalive: Option<u64>,
+
+    #[structopt(long = "reap-after-idle", value_name = "seconds", parse(try_from_str = "parse_interval"))]
+    /// Close tunnels after this many seconds without terminal activity, unless they were opened with their own setting
+    reap_after_idle: Option<u64>,
+
+    #[structopt(long = "allow-log-queries")]
+    /// Let clients fetch recent log messages with `stund logs`
+    allow_log_queries: bool,
+
+    #[structopt(long = "allow-state-dumps")]
+    /// Let clients fetch a snapshot of the daemon's internal state with `stund debug dump`
+    allow_state_dumps: bool,
+
+    #[structopt(long = "socket", value_name = "path", parse(from_os_str))]
+    /// Listen on this socket rather than on $HOME/.ssh/stund.sock
+    socket: Option<PathBuf>,
+
+    #[structopt(long = "socket-mode", value_name = "octal", default_value = "0600")]
+    /// The permissions of the daemon socket; use 0660 to let your group use it
+    socket_mode: String,
+
+    #[structopt(long = "listen-backlog", value_name = "count", default_value = "128")]
+    /// How many connections may wait to be accepted; the system may impose a lower limit
+    listen_backlog: u16,
+
+    #[structopt(long = "max-accepts-per-sec", value_name = "count", default_value = "200")]
+    /// Stop accepting connections for the rest of a second after this many (0 for no limit)
+    max_accepts_per_sec: u32,
+
+    #[structopt(long = "socket-linger")]
+    /// Turn on SO_LINGER for client connections (a workaround for lost messages)
+    socket_linger: bool,
+
+    #[structopt(long = "log-messages")]
+    /// Log the type of each message from clients (user input is only logged by size)
+    log_messages: bool,
+
+    #[structopt(long = "check-config")]
+    /// Check that the daemon could start with these settings, then exit without starting it
+    check_config: bool,
+
+    #[structopt(long = "ssh-user", value_name = "user")]
+    /// Run SSH as this user rather than as the daemon's; the daemon must be running as root
+    ssh_user: Option<String>,
 }
 
 impl StundDaemonOptions {
     fn cli(self) -> Result<i32, Error> {
+        if self.check_config {
+            return Ok(if daemon::check_config(&self) { 0 } else { 1 });
+        }
+
         let d = daemon::State::new(self)?;
-        d.serve()?;
+
+        match d.serve()? {
+            daemon::ShutdownReason::FatalError(e) => Err(e),
+            reason => Ok(reason.exit_code()),
+        }
+    }
+}
+
+
+#[derive(Debug, StructOpt)]
+pub enum StundDebugCommand {
+    #[structopt(name = "relay")]
+    /// Print internal counters about the I/O relay for a tunnel
+    Relay {
+        #[structopt(help = "The host of the tunnel of interest.")]
+        host: String,
+    },
+
+    #[structopt(name = "stats")]
+    /// Print the daemon's resource usage
+    Stats,
+
+    #[structopt(name = "dump")]
+    /// Print a snapshot of the daemon's internal state
+    Dump,
+
+    #[structopt(name = "metrics")]
+    /// Print the daemon's metrics in the Prometheus text format
+    Metrics,
+
+    #[structopt(name = "config")]
+    /// Print the settings that the daemon is running with
+    Config,
+
+    #[structopt(name = "capabilities")]
+    /// Print the optional features that the daemon supports
+    Capabilities,
+}
+
+impl StundDebugCommand {
+    fn cli(self) -> Result<i32, Error> {
+        match self {
+            StundDebugCommand::Relay { host } => {
+                let params = RelayCountersParameters { host: host.clone() };
+
+                let mut conn = establish()?;
+                let counters = conn.query_relay_counters(params)?;
+                conn.close()?;
+
+                match counters {
+                    Some(c) => println!("{:#?}", c),
+                    None => println!("[No tunnel for \"{}\" is open.]", host),
+                }
+            },
+
+            StundDebugCommand::Stats => {
+                let mut conn = establish()?;
+                let stats = conn.daemon_stats()?;
+                conn.close()?;
+                println!("{:#?}", stats);
+            },
+
+            StundDebugCommand::Dump => {
+                let mut conn = establish()?;
+                let dump = conn.dump_state()?;
+                conn.close()?;
+                println!("{:#?}", dump);
+            },
+
+            StundDebugCommand::Metrics => {
+                let mut conn = establish()?;
+                let text = conn.metrics()?;
+                conn.close()?;
+                print!("{}", text);
+            },
+
+            StundDebugCommand::Config => {
+                let mut conn = establish()?;
+                let config = conn.get_config()?;
+                conn.close()?;
+                println!("{:#?}", config);
+            },
+
+            StundDebugCommand::Capabilities => {
+                let mut conn = establish()?;
+                let names = conn.capabilities()?;
+                conn.close()?;
+
+                for name in names {
+                    println!("{}", name);
+                }
+            },
+        }
+
         Ok(0)
     }
 }
@@ -77,11 +304,18 @@ impl StundDaemonOptions {
 
 #[derive(Debug, StructOpt)]
 pub struct StundExitOptions {
+    #[structopt(long = "wait")]
+    /// Let any tunnels that are being opened finish before exiting
+    wait: bool,
+
+    #[structopt(long = "wait-timeout", value_name = "seconds", default_value = "60")]
+    /// The longest time to wait for tunnels that are being opened
+    wait_timeout: u64,
 }
 
 impl StundExitOptions {
     fn cli(self) -> Result<i32, Error> {
-        let conn = match Connection::try_establish()? {
+        let mut conn = match Connection::try_establish()? {
             Some(c) => c,
 
             None => {
@@ -90,8 +324,43 @@ impl StundExitOptions {
             },
         };
 
-        let conn = conn.send_exit()?;
+        let params = ExitParameters {
+            wait_for_opens: self.wait,
+            wait_timeout: self.wait_timeout,
+        };
+
+        // Waiting for opens can take arbitrarily long, so only check up on
+        // the daemon if we aren't doing that.
+
+        if self.wait {
+            conn.send_exit(params)?;
+            conn.close()?;
+        } else {
+            conn.shutdown_daemon()?;
+        }
+
+        Ok(0)
+    }
+}
+
+
+#[derive(Debug, StructOpt)]
+pub struct StundLogsOptions {
+    #[structopt(short = "n", long = "lines", default_value = "20")]
+    /// The number of lines to print
+    lines: usize,
+}
+
+impl StundLogsOptions {
+    fn cli(self) -> Result<i32, Error> {
+        let mut conn = establish()?;
+        let lines = conn.get_log(self.lines)?;
         conn.close()?;
+
+        for line in &lines {
+            println!("{}", line);
+        }
+
         Ok(0)
     }
 }
@@ -103,6 +372,22 @@ pub struct StundOpenOptions {
     /// The host for which the tunnel should be opened
     host: String,
 
+    #[structopt(short = "f", long = "force")]
+    /// If the tunnel is already open, kill it and open a new one
+    force: bool,
+
+    #[structopt(short = "n", long = "dry-run")]
+    /// Print the SSH command that would be run, but don't run it
+    dry_run: bool,
+
+    #[structopt(short = "p", long = "port")]
+    /// The port to connect to on the remote host
+    port: Option<u16>,
+
+    #[structopt(short = "l", long = "login", value_name = "user")]
+    /// The user to log in as on the remote host
+    user: Option<String>,
+
     #[structopt(short = "q", long = "quiet")]
     /// Suppress low-importance UI messages
     quiet: bool,
@@ -111,6 +396,66 @@ pub struct StundOpenOptions {
     /// Do not try to read any user input when logging in
     no_input: bool,
 
+    #[structopt(long = "retries", value_name = "count", default_value = "0")]
+    /// Have SSH retry this many times if it fails to connect
+    retries: u32,
+
+    #[structopt(long = "connect-timeout", value_name = "seconds")]
+    /// Have SSH give up on each connection attempt after this many seconds (at least 1)
+    connect_timeout: Option<u32>,
+
+    #[structopt(long = "ssh-verbose", parse(from_occurrences))]
+    /// Have SSH print diagnostics while logging in (may be repeated, up to three times)
+    ssh_verbose: u8,
+
+    #[structopt(long = "idle-timeout", value_name = "seconds", parse(try_from_str = "parse_interval"))]
+    /// Give up if the login shows no activity for this many seconds (at least 1)
+    idle_timeout: Option<u64>,
+
+    #[structopt(long = "reap-after-idle", value_name = "seconds")]
+    /// Close the tunnel after this many seconds without terminal activity, overriding the daemon's default (0 for never)
+    reap_after_idle: Option<u32>,
+
+    #[structopt(long = "close-if-abandoned")]
+    /// If this command dies partway through the open, close the tunnel even if SSH has logged in
+    close_if_abandoned: bool,
+
+    #[structopt(short = "e", long = "env", value_name = "name=value", number_of_values = 1)]
+    /// Set an environment variable for SSH (may be repeated)
+    env: Vec<String>,
+
+    #[structopt(long = "allow-askpass")]
+    /// Let SSH ask for passwords graphically, using this terminal's DISPLAY and SSH_ASKPASS
+    allow_askpass: bool,
+
+    #[structopt(long = "agent-socket", value_name = "path", parse(from_os_str))]
+    /// Have SSH use the agent listening on this socket rather than the usual one
+    agent_socket: Option<PathBuf>,
+
+    #[structopt(long = "control-master")]
+    /// Let other SSH commands share this tunnel's connection through a control socket
+    control_master: bool,
+
+    #[structopt(long = "hide-banner")]
+    /// Hide what SSH prints before it asks for anything, such as the server's banner, unless the login fails
+    hide_banner: bool,
+
+    #[structopt(long = "defer-interaction", value_name = "seconds", parse(try_from_str = "parse_interval"))]
+    /// Log in without involving the terminal unless SSH asks for something, or is still logging in after this many seconds
+    defer_interaction: Option<u64>,
+
+    #[structopt(long = "password-from-env", value_name = "name")]
+    /// Answer SSH's password prompt with the value of this environment variable (discouraged: use keys or an agent if you possibly can)
+    password_from_env: Option<String>,
+
+    #[structopt(long = "reconfigure", value_name = "label")]
+    /// Restart the open tunnel with this label using the new settings
+    reconfigure: Option<String>,
+
+    #[structopt(long = "foreground")]
+    /// Stay in the foreground until the tunnel dies; Ctrl-C closes it, unless it was already open
+    foreground: bool,
+
     #[structopt(raw(last = "true"), value_name = "after-command")]
     /// If specified, exec this command after opening the tunnel
     after_command: Vec<String>,
@@ -124,35 +469,159 @@ pub struct StundOpenOptions {
 }
 
 impl StundOpenOptions {
+    /// Gather the environment variables to give to SSH.
+    fn ssh_env(&self) -> Result<Vec<(String, String)>, Error> {
+        let mut vars = Vec::new();
+
+        for item in &self.env {
+            match item.find('=') {
+                Some(i) => vars.push((item[..i].to_owned(), item[i+1..].to_owned())),
+                None => return Err(format_err!("expected --env argument of the form NAME=VALUE, got \"{}\"", item)),
+            }
+        }
+
+        if self.allow_askpass {
+            for name in &["DISPLAY", "SSH_ASKPASS"] {
+                if let Ok(value) = env::var(name) {
+                    vars.push((name.to_string(), value));
+                }
+            }
+        }
+
+        Ok(vars)
+    }
+
+    /// The daemon runs elsewhere, so it needs an absolute path to the agent
+    /// socket.
+    fn agent_socket(&self) -> Result<Option<PathBuf>, Error> {
+        Ok(match self.agent_socket {
+            Some(ref p) if p.is_relative() => Some(env::current_dir()?.join(p)),
+            ref other => other.clone(),
+        })
+    }
+
+    /// Get the password that we've been asked to type for the user. It's
+    /// removed from our environment so that a post-open command doesn't
+    /// inherit it.
+    fn password(&self) -> Result<Option<String>, Error> {
+        let name = match self.password_from_env {
+            Some(ref n) => n,
+            None => return Ok(None),
+        };
+
+        let password = env::var(name).map_err(|_| {
+            format_err!("--password-from-env: the environment variable {} is not set (or not valid UTF-8)", name)
+        })?;
+
+        env::remove_var(name);
+        Ok(Some(password))
+    }
+
+    /// Tell the user how other SSH commands can share the tunnel's
+    /// connection, if it has been set up for that.
+    fn print_control_path(&self, label: &str) -> Result<(), Error> {
+        if self.control_master {
+            let path = control_socket_path(&get_socket_path()?, label);
+            println!("[Other SSH commands can share this connection with: -o ControlPath={}]",
+                     shell_quote(&path.display().to_string().replace('%', "%%")));
+        }
+
+        Ok(())
+    }
+
     fn cli(self) -> Result<i32, Error> {
-        let params = OpenParameters { host: self.host.clone() };
+        if self.foreground && (self.reconfigure.is_some() || !self.after_command.is_empty()) {
+            return Err(format_err!("--foreground can't be combined with --reconfigure or a post-open command"));
+        }
+
+        if self.password_from_env.is_some() && (self.foreground || self.reconfigure.is_some()) {
+            return Err(format_err!("--password-from-env can't be combined with --foreground or --reconfigure"));
+        }
+
+        if self.defer_interaction.is_some() && (self.foreground || self.no_input) {
+            return Err(format_err!("--defer-interaction can't be combined with --foreground or --no-input"));
+        }
+
+        let params = OpenParameters {
+            host: self.host.clone(),
+            port: self.port,
+            force: self.force,
+            connect_retries: self.retries,
+            env: self.ssh_env()?,
+            allow_askpass: self.allow_askpass,
+            user: self.user.clone(),
+            separate_stderr: false,
+            report_key: false,
+            agent_socket: self.agent_socket()?,
+            control_master: self.control_master,
+            connect_timeout_secs: self.connect_timeout,
+            reap_after_idle_secs: self.reap_after_idle,
+            close_if_abandoned: self.close_if_abandoned,
+            report_pid: false,
+            verbose: self.ssh_verbose,
+        };
+        let idle_timeout = self.idle_timeout.map(Duration::from_secs);
 
-        let conn = Connection::establish()?;
+        let mut conn = establish()?;
+        conn.set_hide_banners(self.hide_banner);
+
+        if self.dry_run {
+            let argv = conn.dry_run_open(params)?;
+            conn.close()?;
+
+            let quoted: Vec<_> = argv.iter().map(|a| shell_quote(a)).collect();
+            println!("{}", quoted.join(" "));
+            return Ok(0);
+        }
+
+        if self.foreground {
+            return self.run_foreground(conn, params, idle_timeout);
+        }
+
+        // If the user hits Ctrl-C while logging in, abandon the login
+        // cleanly instead of dying on the spot. A reconfigure can't be
+        // abandoned halfway, since the old tunnel is already gone by then.
+        // Logins with a supplied password can't be either, for now.
+
+        let kind = match (self.reconfigure.clone(), self.password()?) {
+            (Some(label), _) => OpenKind::Reconfigure(label),
+            (None, Some(password)) => OpenKind::WithPassword(password),
+            (None, None) => OpenKind::Cancellable(catch_interrupts()),
+        };
 
         let r = if self.no_input {
             // Big hack: we just ignore any output that we ought to print.
             use futures::Sink;
-            let mut buf = Vec::new();
-            conn.send_open(params,
-                           buf.sink_map_err(|_| io::ErrorKind::Other.into()),
-                           futures::stream::empty())
-                .map_err(|_| io::ErrorKind::Other.into())
-        } else {
-            toggle_terminal_echo(false);
-            let r = tokio_borrow_stdio::borrow_stdio(|stdin, stdout| {
-                conn.send_open(params, stdout, stdin)
-                    .map_err(|_| io::ErrorKind::Other.into())
+            let buf = Vec::new();
+            open_or_reconfigure(conn, params, kind,
+                                buf.sink_map_err(|_| io::ErrorKind::Other.into()),
+                                futures::stream::empty(), idle_timeout)
+        } else if let Some(secs) = self.defer_interaction {
+            // Only take over the terminal if SSH turns out to need the user.
+            // If that fails, there's no way to say so from here, but the
+            // login can still carry on with the terminal as it is.
+            let terminal = Rc::new(RefCell::new(None));
+            let terminal2 = terminal.clone();
+            conn.defer_interaction(Duration::from_secs(secs), move || {
+                *terminal2.borrow_mut() = client::RawTerminalGuard::new().ok();
             });
-            toggle_terminal_echo(true);
-            r
+
+            let (stdout, stdin) = client::terminal_io();
+            conn.relay_window_sizes(watch_window_size());
+            open_or_reconfigure(conn, params, kind, stdout, stdin, idle_timeout)
+        } else {
+            let (_terminal, stdout, stdin) = client::raw_terminal_io()?;
+            conn.relay_window_sizes(watch_window_size());
+            open_or_reconfigure(conn, params, kind, stdout, stdin, idle_timeout)
         };
 
         let (result, conn) = r?;
 
         match result {
-            OpenResult::Success => {
+            OpenResult::Success { ref key, .. } => {
                 if !self.quiet {
                     println!("[Tunnel successfully opened.]");
+                    self.print_control_path(key)?;
                 }
             },
 
@@ -161,6 +630,47 @@ impl StundOpenOptions {
                     println!("[Tunnel is already open.]");
                 }
             },
+
+            OpenResult::Replaced { ref key, .. } => {
+                if !self.quiet {
+                    match self.reconfigure {
+                        Some(_) => println!("[Tunnel successfully restarted with the new settings.]"),
+                        None => println!("[Existing tunnel successfully replaced.]"),
+                    }
+                    self.print_control_path(key)?;
+                }
+            },
+
+            OpenResult::NotOpen => {
+                println!("[No such tunnel is open; nothing was done.]");
+                conn.close()?;
+                return Ok(1);
+            },
+
+            OpenResult::DaemonShuttingDown => {
+                println!("[The daemon is shutting down; try again once it has exited.]");
+                conn.close()?;
+                return Ok(1);
+            },
+
+            OpenResult::Cancelled => {
+                println!("[Login cancelled; the tunnel was not opened.]");
+                conn.close()?;
+                return Ok(1);
+            },
+
+            OpenResult::ConnectFailed(attempts) => {
+                println!("[SSH failed after up to {} connection attempts; the tunnel was not opened.]",
+                         attempts);
+                conn.close()?;
+                return Ok(1);
+            },
+
+            OpenResult::HostUnreachable => {
+                println!("[SSH timed out connecting to the host; the tunnel was not opened.]");
+                conn.close()?;
+                return Ok(1);
+            },
         }
 
         conn.close()?;
@@ -182,16 +692,295 @@ impl StundOpenOptions {
 
         Ok(0)
     }
+
+    /// Open the tunnel and wait for it to die, like `ssh -N`.
+    fn run_foreground(
+        &self, mut conn: Connection, params: OpenParameters, idle_timeout: Option<Duration>
+    ) -> Result<i32, Error> {
+        let stop = catch_interrupts();
+
+        let r = if self.no_input {
+            use futures::Sink;
+            let buf = Vec::new();
+            conn.run_forever(params, buf.sink_map_err(|_| io::ErrorKind::Other.into()),
+                             futures::stream::empty(), idle_timeout, stop)
+        } else {
+            if !self.quiet {
+                println!("[Once the tunnel is open, press Ctrl-C to close it.]");
+            }
+
+            let (_terminal, stdout, stdin) = client::raw_terminal_io()?;
+            conn.relay_window_sizes(watch_window_size());
+            conn.run_forever(params, stdout, stdin, idle_timeout, stop)
+        };
+
+        let (outcome, conn) = r?;
+        conn.close()?;
+
+        match outcome {
+            ForegroundOutcome::NotOpened(OpenResult::Cancelled) => {
+                println!("[Login cancelled; the tunnel was not opened.]");
+                Ok(1)
+            },
+
+            ForegroundOutcome::NotOpened(OpenResult::DaemonShuttingDown) => {
+                println!("[The daemon is shutting down; try again once it has exited.]");
+                Ok(1)
+            },
+
+            ForegroundOutcome::NotOpened(OpenResult::ConnectFailed(attempts)) => {
+                println!("[SSH failed after up to {} connection attempts; the tunnel was not opened.]",
+                         attempts);
+                Ok(1)
+            },
+
+            ForegroundOutcome::NotOpened(OpenResult::HostUnreachable) => {
+                println!("[SSH timed out connecting to the host; the tunnel was not opened.]");
+                Ok(1)
+            },
+
+            ForegroundOutcome::NotOpened(other) => {
+                Err(format_err!("unexpected result from the daemon: {:?}", other))
+            },
+
+            ForegroundOutcome::Interrupted => {
+                if !self.quiet {
+                    println!("[Tunnel closed.]");
+                }
+                Ok(0)
+            },
+
+            ForegroundOutcome::Detached => {
+                if !self.quiet {
+                    println!("[The tunnel was already open, so it has been left running.]");
+                }
+                Ok(0)
+            },
+
+            ForegroundOutcome::Ended(state, code) => {
+                println!("[Tunnel is no longer open ({:?}).]", state);
+                Ok(code.unwrap_or(1))
+            },
+        }
+    }
+}
+
+
+#[derive(Debug, StructOpt)]
+pub struct StundOpenProfileOptions {
+    #[structopt()]
+    /// The name of the profile, as defined in the daemon's stund.profiles.json
+    name: String,
+
+    #[structopt(long = "no-input")]
+    /// Do not try to read any user input when logging in
+    no_input: bool,
+
+    #[structopt(short = "q", long = "quiet")]
+    /// Suppress low-importance UI messages
+    quiet: bool,
+}
+
+impl StundOpenProfileOptions {
+    fn cli(self) -> Result<i32, Error> {
+        let conn = establish()?;
+
+        let (result, conn) = if self.no_input {
+            use futures::Sink;
+            let buf = Vec::new();
+            conn.send_open_profile(&self.name, buf.sink_map_err(|_| io::ErrorKind::Other.into()),
+                                   futures::stream::empty(), None)?
+        } else {
+            let (_terminal, stdout, stdin) = client::raw_terminal_io()?;
+            conn.send_open_profile(&self.name, stdout, stdin, None)?
+        };
+
+        conn.close()?;
+
+        let failure = match result {
+            OpenResult::Success { .. } | OpenResult::Replaced { .. } => {
+                if !self.quiet {
+                    println!("[Tunnel successfully opened.]");
+                }
+                return Ok(0);
+            },
+
+            OpenResult::AlreadyOpen => {
+                if !self.quiet {
+                    println!("[Tunnel is already open.]");
+                }
+                return Ok(0);
+            },
+
+            OpenResult::NotOpen => "no such tunnel is open".to_owned(),
+            OpenResult::DaemonShuttingDown => "the daemon is shutting down".to_owned(),
+            OpenResult::Cancelled => "the login was cancelled".to_owned(),
+            OpenResult::ConnectFailed(n) => format!("SSH failed after up to {} connection attempts", n),
+            OpenResult::HostUnreachable => "SSH timed out connecting to the host".to_owned(),
+        };
+
+        println!("[The tunnel was not opened: {}.]", failure);
+        Ok(1)
+    }
+}
+
+
+#[derive(Debug, StructOpt)]
+pub struct StundPauseOptions {
+    #[structopt(help = "The host of the tunnel to pause.")]
+    host: String,
+}
+
+impl StundPauseOptions {
+    fn cli(self) -> Result<i32, Error> {
+        let mut conn = establish()?;
+        conn.pause_tunnel(&self.host)?;
+        conn.close()?;
+        Ok(0)
+    }
+}
+
+
+#[derive(Debug, StructOpt)]
+pub struct StundPrepareUpgradeOptions {
+}
+
+impl StundPrepareUpgradeOptions {
+    fn cli(self) -> Result<i32, Error> {
+        let mut conn = establish()?;
+        let path = conn.prepare_upgrade()?;
+        conn.close()?;
+
+        println!("[Tunnel parameters saved to {}.]", path);
+        println!("[The daemon will not open new tunnels until it is told to exit.]");
+        Ok(0)
+    }
+}
+
+
+#[derive(Debug, StructOpt)]
+pub struct StundRelabelOptions {
+    #[structopt(help = "The current label of the tunnel.")]
+    old: String,
+
+    #[structopt(help = "The new label for the tunnel.")]
+    new: String,
+}
+
+impl StundRelabelOptions {
+    fn cli(self) -> Result<i32, Error> {
+        let params = RelabelParameters { old: self.old.clone(), new: self.new };
+
+        let mut conn = establish()?;
+        let result = conn.send_relabel(params)?;
+
+        match result {
+            RelabelResult::Success => {},
+
+            RelabelResult::NotFound => {
+                println!("[No tunnel labeled \"{}\" is known.]", self.old);
+            },
+        }
+
+        conn.close()?;
+        Ok(0)
+    }
+}
+
+
+#[derive(Debug, StructOpt)]
+pub struct StundResizeOptions {
+    #[structopt(help = "The host of the tunnel to resize.")]
+    host: String,
+
+    #[structopt(long = "rows")]
+    /// The number of rows (default: that of the current terminal)
+    rows: Option<u16>,
+
+    #[structopt(long = "cols")]
+    /// The number of columns (default: that of the current terminal)
+    cols: Option<u16>,
+}
+
+impl StundResizeOptions {
+    fn cli(self) -> Result<i32, Error> {
+        let (rows, cols) = match (self.rows, self.cols) {
+            (Some(r), Some(c)) => (r, c),
+
+            (r, c) => {
+                let (rows, cols) = terminal_size().ok_or_else(|| {
+                    format_err!("cannot determine the terminal size; use --rows and --cols")
+                })?;
+
+                (r.unwrap_or(rows), c.unwrap_or(cols))
+            },
+        };
+
+        let params = ResizeParameters { host: self.host, rows, cols };
+
+        let mut conn = establish()?;
+        conn.send_resize(params)?;
+        conn.close()?;
+        Ok(0)
+    }
+}
+
+
+#[derive(Debug, StructOpt)]
+pub struct StundResumeOptions {
+    #[structopt(help = "The host of the tunnel to resume.")]
+    host: String,
+}
+
+impl StundResumeOptions {
+    fn cli(self) -> Result<i32, Error> {
+        let mut conn = establish()?;
+        conn.resume_tunnel(&self.host)?;
+        conn.close()?;
+        Ok(0)
+    }
+}
+
+
+#[derive(Debug, StructOpt)]
+pub struct StundSignalOptions {
+    #[structopt(long = "signal", short = "s", value_name = "INT|QUIT|TSTP", default_value = "INT",
+                parse(try_from_str = "parse_signal"))]
+    /// The signal to send to the remote command
+    signal: i32,
+
+    #[structopt(help = "The host of the tunnel to signal.")]
+    host: String,
+}
+
+impl StundSignalOptions {
+    fn cli(self) -> Result<i32, Error> {
+        let mut conn = establish()?;
+        conn.signal_tunnel(&self.host, self.signal)?;
+        conn.close()?;
+        Ok(0)
+    }
 }
 
 
 #[derive(Debug, StructOpt)]
 pub struct StundStatusOptions {
+    #[structopt(long = "history")]
+    /// Also show recent events in the life of each tunnel
+    history: bool,
+
+    #[structopt(long = "state", value_name = "open|closed|died", number_of_values = 1,
+                parse(try_from_str = "parse_tunnel_state"))]
+    /// Only show tunnels in this state (may be repeated)
+    states: Vec<TunnelState>,
+
+    #[structopt(help = "Show the details of just the tunnel for this host.")]
+    host: Option<String>,
 }
 
 impl StundStatusOptions {
     fn cli(self) -> Result<i32, Error> {
-        let conn = match Connection::try_establish()? {
+        let mut conn = match Connection::try_establish()? {
             Some(c) => c,
 
             None => {
@@ -200,11 +989,22 @@ impl StundStatusOptions {
             },
         };
 
-        let (info, conn) = conn.query_status()?;
+        if let Some(ref host) = self.host {
+            return show_tunnel_details(conn, host);
+        }
+
+        let info = if self.states.is_empty() {
+            conn.query_status()?
+        } else {
+            conn.list_filtered(StatusFilter { states: self.states.clone() })?
+        };
         conn.close()?;
 
         if info.tunnels.len() == 0 {
-            println!("No tunnels are open.");
+            match self.states.len() {
+                0 => println!("No tunnels are open."),
+                _ => println!("No tunnels are in the requested state."),
+            }
         } else {
             let mut longest = 4; // "Host"
 
@@ -217,9 +1017,178 @@ impl StundStatusOptions {
 
             for tun in &info.tunnels {
                 println!("{0:1$}  {2:?}", tun.host, longest, tun.state);
+
+                if self.history {
+                    for event in &tun.history {
+                        match event.exit_code {
+                            Some(c) => println!("    {}  {:?} (exit code {})", event.timestamp, event.kind, c),
+                            None => println!("    {}  {:?}", event.timestamp, event.kind),
+                        }
+                    }
+                }
             }
         }
 
+        if let Some(ref e) = info.log_error {
+            eprintln!("Warning: the daemon has had trouble writing its log: {}", e);
+        }
+
+        Ok(0)
+    }
+}
+
+
+/// Print the details of one tunnel for `stund status <host>`.
+fn show_tunnel_details(mut conn: Connection, host: &str) -> Result<i32, Error> {
+    let details = match conn.tunnel_status(host) {
+        Ok(d) => d,
+
+        Err(e) => {
+            if let Some(&DaemonError { code: ProtocolError::NotFound, .. }) = e.downcast_ref() {
+                println!("[No tunnel for \"{}\" is known.]", host);
+                conn.close()?;
+                return Ok(1);
+            }
+
+            return Err(e);
+        },
+    };
+
+    conn.close()?;
+
+    println!("Host:          {}", details.host);
+    println!("Status:        {:?}", details.state);
+
+    if let Some(pid) = details.pid {
+        println!("SSH PID:       {}", pid);
+    }
+
+    if let Some(age) = details.age_secs {
+        println!("Open for:      {} seconds", age);
+    }
+
+    if let (Some(from), Some(to)) = (details.bytes_from_ssh, details.bytes_to_ssh) {
+        println!("SSH output:    {} bytes", from);
+        println!("User input:    {} bytes", to);
+    }
+
+    if details.paused {
+        println!("Paused:        yes");
+    }
+
+    if let Some(ref e) = details.last_error {
+        println!("Died with:     {}", e);
+    }
+
+    if !details.history.is_empty() {
+        println!();
+
+        for event in &details.history {
+            match event.exit_code {
+                Some(c) => println!("    {}  {:?} (exit code {})", event.timestamp, event.kind, c),
+                None => println!("    {}  {:?}", event.timestamp, event.kind),
+            }
+        }
+    }
+
+    Ok(0)
+}
+
+
+#[derive(Debug, StructOpt)]
+pub struct StundTeeOptions {
+    #[structopt(long = "stop")]
+    /// Stop copying the tunnel's output to a file
+    stop: bool,
+
+    #[structopt(help = "The host of the tunnel of interest.")]
+    host: String,
+
+    #[structopt(help = "The file to copy the tunnel's output to.", parse(from_os_str),
+                required_unless = "stop", conflicts_with = "stop")]
+    path: Option<PathBuf>,
+}
+
+impl StundTeeOptions {
+    fn cli(self) -> Result<i32, Error> {
+        let mut conn = establish()?;
+
+        // Without --stop, there's always a path. The daemon runs elsewhere,
+        // so it needs to be absolute.
+
+        match self.path {
+            Some(ref p) if !self.stop => conn.tee_to_file(&self.host, &env::current_dir()?.join(p))?,
+            _ => conn.stop_tee(&self.host)?,
+        }
+
+        conn.close()?;
+        Ok(0)
+    }
+}
+
+
+#[derive(Debug, StructOpt)]
+pub struct StundVersionOptions {
+}
+
+impl StundVersionOptions {
+    fn cli(self) -> Result<i32, Error> {
+        println!("Client version:   {}", env!("CARGO_PKG_VERSION"));
+        println!("Protocol version: {}", PROTOCOL_VERSION);
+
+        let mut conn = match Connection::try_establish()? {
+            Some(c) => c,
+
+            None => {
+                println!("Daemon:           not running");
+                return Ok(0);
+            },
+        };
+
+        let diags = conn.diagnostics()?;
+
+        println!("Daemon socket:    {}", diags.socket_path.display());
+
+        match (&diags.daemon_version, &diags.daemon_protocol_version) {
+            (Some(dv), Some(pv)) => {
+                conn.close()?;
+                println!("Daemon version:   {} (protocol {})", dv, pv);
+            },
+
+            _ => {
+                // The daemon hung up on us, so there's nothing to close.
+                println!("Daemon version:   unknown (too old to report it)");
+            },
+        }
+
+        if !diags.is_compatible() {
+            println!("[The daemon speaks an incompatible version of the protocol; restart it with \
+                      \"stund exit\" to use this client.]");
+            return Ok(1);
+        }
+
+        Ok(0)
+    }
+}
+
+
+#[derive(Debug, StructOpt)]
+pub struct StundWaitOptions {
+    #[structopt(help = "The host of the tunnel to wait for.")]
+    host: String,
+}
+
+impl StundWaitOptions {
+    fn cli(self) -> Result<i32, Error> {
+        let mut conn = establish()?;
+        let exit = conn.wait_for_exit(&self.host)?;
+        conn.close()?;
+
+        match exit.exit_status {
+            Some(status) => println!("[SSH exited: {}.]", status),
+            None => println!("[Tunnel closed.]"),
+        }
+
         Ok(0)
     }
 }
@@ -228,6 +1197,14 @@ impl StundStatusOptions {
 #[derive(Debug, StructOpt)]
 #[structopt(name = "stund", about = "Maintain SSH tunnels in the background.")]
 pub enum StundCli {
+    #[structopt(name = "check")]
+    /// Check whether an SSH tunnel is working
+    Check(StundCheckOptions),
+
+    #[structopt(name = "cleanup")]
+    /// Remove sockets left behind by SSH processes that are gone
+    Cleanup(StundCleanupOptions),
+
     #[structopt(name = "close")]
     /// Close an existing SSH tunnel
     Close(StundCloseOptions),
@@ -236,27 +1213,89 @@ pub enum StundCli {
     /// Manually start the daemon that manages your SSH tunnels
     Daemon(StundDaemonOptions),
 
+    #[structopt(name = "debug", raw(setting = "structopt::clap::AppSettings::Hidden"))]
+    /// Diagnostic tools for maintainers
+    Debug(StundDebugCommand),
+
     #[structopt(name = "exit")]
     /// Manually tell the daemon to shut down
     Exit(StundExitOptions),
 
+    #[structopt(name = "logs")]
+    /// Print recent messages from the daemon log
+    Logs(StundLogsOptions),
+
     #[structopt(name = "open")]
     /// Open a new SSH tunnel
     Open(StundOpenOptions),
 
+    #[structopt(name = "open-profile")]
+    /// Open an SSH tunnel defined by a profile in the daemon's configuration
+    OpenProfile(StundOpenProfileOptions),
+
+    #[structopt(name = "pause")]
+    /// Stop reading an SSH tunnel's output until it is resumed
+    Pause(StundPauseOptions),
+
+    #[structopt(name = "prepare-upgrade")]
+    /// Save the open tunnels' parameters and stop opening new ones
+    PrepareUpgrade(StundPrepareUpgradeOptions),
+
+    #[structopt(name = "relabel")]
+    /// Change the label of an existing SSH tunnel
+    Relabel(StundRelabelOptions),
+
+    #[structopt(name = "resize")]
+    /// Change the terminal size of an existing SSH tunnel
+    Resize(StundResizeOptions),
+
+    #[structopt(name = "resume")]
+    /// Resume reading a paused SSH tunnel's output
+    Resume(StundResumeOptions),
+
+    #[structopt(name = "signal")]
+    /// Interrupt the command running in an SSH tunnel, leaving the tunnel open
+    Signal(StundSignalOptions),
+
     #[structopt(name = "status")]
     /// Get information about known SSH tunnels
     Status(StundStatusOptions),
+
+    #[structopt(name = "tee")]
+    /// Copy an SSH tunnel's output to a file, or stop doing so
+    Tee(StundTeeOptions),
+
+    #[structopt(name = "version")]
+    /// Print the versions of this program and of the daemon it talks to
+    Version(StundVersionOptions),
+
+    #[structopt(name = "wait")]
+    /// Wait for an SSH tunnel to exit
+    Wait(StundWaitOptions),
 }
 
 impl StundCli {
     fn cli(self) -> Result<i32, Error> {
         match self {
+            StundCli::Check(opts) => opts.cli(),
+            StundCli::Cleanup(opts) => opts.cli(),
             StundCli::Close(opts) => opts.cli(),
             StundCli::Daemon(opts) => opts.cli(),
+            StundCli::Debug(cmd) => cmd.cli(),
             StundCli::Exit(opts) => opts.cli(),
+            StundCli::Logs(opts) => opts.cli(),
             StundCli::Open(opts) => opts.cli(),
+            StundCli::OpenProfile(opts) => opts.cli(),
+            StundCli::Pause(opts) => opts.cli(),
+            StundCli::PrepareUpgrade(opts) => opts.cli(),
+            StundCli::Relabel(opts) => opts.cli(),
+            StundCli::Resize(opts) => opts.cli(),
+            StundCli::Resume(opts) => opts.cli(),
+            StundCli::Signal(opts) => opts.cli(),
             StundCli::Status(opts) => opts.cli(),
+            StundCli::Tee(opts) => opts.cli(),
+            StundCli::Version(opts) => opts.cli(),
+            StundCli::Wait(opts) => opts.cli(),
         }
     }
 }
@@ -279,25 +1318,180 @@ fn main() {
 }
 
 
-fn toggle_terminal_echo(active: bool) {
-    if atty::isnt(atty::Stream::Stdout) {
-        return;
+/// The longest interval that may be given on the command line: a year is
+/// plenty, and keeps timer arithmetic well away from overflowing.
+const MAX_INTERVAL_SECS: u64 = 365 * 24 * 60 * 60;
+
+/// Parse an option giving an interval in seconds. Zero is refused, since a
+/// timer that fires immediately would make the option useless; leave the
+/// option out to disable the feature instead.
+fn parse_interval(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(n) if (1..=MAX_INTERVAL_SECS).contains(&n) => Ok(n),
+        Ok(_) => Err(format!("the interval must be between 1 and {} seconds", MAX_INTERVAL_SECS)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+
+/// Parse the name of a signal that can be sent to a tunnel. The `SIG` prefix
+/// is optional, and case doesn't matter.
+fn parse_signal(s: &str) -> Result<i32, String> {
+    let upper = s.to_uppercase();
+
+    match upper.trim_start_matches("SIG") {
+        "INT" => Ok(libc::SIGINT),
+        "QUIT" => Ok(libc::SIGQUIT),
+        "TSTP" => Ok(libc::SIGTSTP),
+        _ => Err(format!("can't send signal \"{}\"; try INT, QUIT or TSTP", s)),
     }
+}
 
-    let mut attrs: libc::termios = unsafe { mem::zeroed() };
 
-    if unsafe { libc::tcgetattr(0, &mut attrs as _) } != 0 {
-        println!("error querying terminal attributes?!");
-        return;
+/// Parse the name of a tunnel state, as shown by `stund status`. Case
+/// doesn't matter.
+fn parse_tunnel_state(s: &str) -> Result<TunnelState, String> {
+    match s.to_lowercase().as_str() {
+        "open" => Ok(TunnelState::Open),
+        "closed" => Ok(TunnelState::Closed),
+        "died" => Ok(TunnelState::Died),
+        _ => Err(format!("unknown tunnel state \"{}\"; try open, closed or died", s)),
     }
+}
 
-    if active {
-        attrs.c_lflag |= libc::ECHO;
+
+/// Connect to the daemon, starting it if need be. Starting it is retried a
+/// few times, and the user hears about each failed attempt.
+fn establish() -> Result<Connection, Error> {
+    Connection::establish_with_spawn_attempts(DEFAULT_SPAWN_ATTEMPTS, |attempt, e| {
+        eprintln!("[Attempt {} of {} to start the stund daemon failed: {}; retrying.]",
+                  attempt, DEFAULT_SPAWN_ATTEMPTS, e);
+    })
+}
+
+
+/// Quote a string so that it can be pasted into a shell command line.
+fn shell_quote(s: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_.,:/@=+%".contains(c);
+
+    if !s.is_empty() && s.chars().all(is_safe) {
+        s.to_owned()
     } else {
-        attrs.c_lflag &= !libc::ECHO;
+        format!("'{}'", s.replace('\'', "'\\''"))
     }
+}
+
+
+/// The ways in which `stund open` can ask the daemon for a tunnel.
+enum OpenKind {
+    /// Restart the tunnel with this label.
+    Reconfigure(String),
 
-    if unsafe { libc::tcsetattr(0, libc::TCSANOW, &attrs as _) } != 0 {
-        println!("error setting terminal attributes?!");
+    /// Open a new tunnel, giving up if the receiver fires.
+    Cancellable(oneshot::Receiver<()>),
+
+    /// Open a new tunnel, typing this password at SSH's prompt.
+    WithPassword(String),
+}
+
+/// Issue either an "Open" or a "Reconfigure" command, relaying the login
+/// through `tx_user` and `rx_user`.
+fn open_or_reconfigure<T, R>(
+    conn: Connection, params: OpenParameters, kind: OpenKind, tx_user: T, rx_user: R,
+    idle_timeout: Option<Duration>
+) -> Result<(OpenResult, Connection), Error>
+    where T: 'static + futures::Sink<SinkItem = Vec<u8>, SinkError = io::Error>,
+          R: 'static + Stream<Item = Vec<u8>, Error = io::Error>
+{
+    match kind {
+        OpenKind::Reconfigure(label) => conn.reconfigure(label, params, tx_user, rx_user, idle_timeout),
+        OpenKind::Cancellable(cancel) => conn.send_open_cancellable(params, tx_user, rx_user, idle_timeout, cancel),
+        OpenKind::WithPassword(password) => {
+            conn.send_open_with_password(params, password, tx_user, rx_user, idle_timeout)
+        },
     }
 }
+
+
+/// The size of our terminal, as rows and columns, if we're in one.
+fn terminal_size() -> Option<(u16, u16)> {
+    let mut ws: libc::winsize = unsafe { mem::zeroed() };
+
+    if unsafe { libc::ioctl(1, libc::TIOCGWINSZ, &mut ws) } != 0 {
+        return None;
+    }
+
+    Some((ws.ws_row, ws.ws_col))
+}
+
+
+/// Start a thread that reports the size of our terminal now and whenever it
+/// changes, so that a login can be shown at the right size. If SIGWINCH
+/// can't be caught, only the current size is reported.
+fn watch_window_size() -> client::WindowSizeStream {
+    let (tx, rx) = futures::sync::mpsc::unbounded();
+
+    thread::spawn(move || {
+        let mut core = match Core::new() {
+            Ok(c) => c,
+            Err(_) => return,
+        };
+
+        // Catch the signal before looking at the size, so that we can't
+        // miss a change in between.
+
+        let handle = core.handle();
+        let stream = core.run(tokio_signal::unix::Signal::new(libc::SIGWINCH, &handle));
+
+        if let Some(size) = terminal_size() {
+            if tx.unbounded_send(size).is_err() {
+                return;
+            }
+        }
+
+        if let Ok(stream) = stream {
+            // Once the stream is dropped, sends fail and we can stop.
+            let _r = core.run(stream.for_each(|_| {
+                match terminal_size() {
+                    Some(size) => tx.unbounded_send(size).map_err(|_| io::ErrorKind::BrokenPipe.into()),
+                    None => Ok(()),
+                }
+            }));
+        }
+    });
+
+    Box::new(rx.map_err(|()| unreachable!("mpsc receivers cannot fail")))
+}
+
+
+/// Start a thread that catches SIGINT, so that Ctrl-C results in a message
+/// on the returned channel rather than killing us. The handler is in place
+/// by the time this returns. Signal handlers are reset when we exec, so a
+/// post-open command isn't affected.
+fn catch_interrupts() -> oneshot::Receiver<()> {
+    let (tx, rx) = oneshot::channel();
+    let (tx_ready, rx_ready) = std_mpsc::channel();
+
+    thread::spawn(move || {
+        let mut core = match Core::new() {
+            Ok(c) => c,
+            Err(_) => return,
+        };
+
+        let stream = match core.run(tokio_signal::ctrl_c(&core.handle())) {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+
+        let _r = tx_ready.send(());
+
+        if let Ok((Some(()), _)) = core.run(stream.into_future()) {
+            let _r = tx.send(());
+        }
+    });
+
+    // If setting up the handler failed, the sender is dropped and we
+    // carry on without it.
+    let _r = rx_ready.recv();
+    rx
+}